@@ -0,0 +1,59 @@
+use crate::configuration::AccessControl;
+
+// clients may only call the prompt targets listed for their identity, requests without an identity
+// may call none
+pub fn is_allowed(
+    access_control: &AccessControl,
+    identity: Option<&str>,
+    prompt_target: &str,
+) -> bool {
+    let identity = match identity {
+        Some(identity) => identity,
+        None => return false,
+    };
+    access_control
+        .clients
+        .iter()
+        .filter(|client| client.identity == identity)
+        .any(|client| {
+            client
+                .prompt_targets
+                .iter()
+                .any(|name| name == prompt_target)
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_allowed;
+    use crate::configuration::{AccessControl, ClientAccess};
+
+    #[test]
+    fn test_is_allowed() {
+        let access_control = AccessControl {
+            header: "x-client-id".to_string(),
+            clients: vec![ClientAccess {
+                identity: "support-bot".to_string(),
+                prompt_targets: vec!["get_weather".to_string()],
+            }],
+            on_unauthorized: None,
+        };
+
+        assert!(is_allowed(
+            &access_control,
+            Some("support-bot"),
+            "get_weather"
+        ));
+        assert!(!is_allowed(
+            &access_control,
+            Some("support-bot"),
+            "reboot_devices"
+        ));
+        assert!(!is_allowed(
+            &access_control,
+            Some("sales-bot"),
+            "get_weather"
+        ));
+        assert!(!is_allowed(&access_control, None, "get_weather"));
+    }
+}
@@ -0,0 +1,83 @@
+use serde::Serialize;
+
+pub const REDACTED: &str = "[REDACTED]";
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AccessLogEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub experiment_arm: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_to_first_token_ms: Option<u64>,
+}
+
+impl AccessLogEntry {
+    // serialize the entry replacing the value of every field listed in redact
+    pub fn to_json(&self, redact: &[String]) -> String {
+        let mut entry = serde_json::to_value(self).unwrap();
+        if let Some(fields) = entry.as_object_mut() {
+            for field in redact {
+                if let Some(value) = fields.get_mut(field) {
+                    *value = serde_json::Value::String(REDACTED.to_string());
+                }
+            }
+        }
+        entry.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AccessLogEntry;
+
+    #[test]
+    fn test_access_log_entry_redaction() {
+        let entry = AccessLogEntry {
+            request_id: Some("req-1".to_string()),
+            model: Some("gpt-4o".to_string()),
+            user_prompt: Some("my account number is 1234".to_string()),
+            input_tokens: Some(7),
+            ..Default::default()
+        };
+
+        let logged: serde_json::Value = serde_json::from_str(&entry.to_json(&[])).unwrap();
+        assert_eq!(
+            logged,
+            serde_json::json!({
+                "request_id": "req-1",
+                "model": "gpt-4o",
+                "user_prompt": "my account number is 1234",
+                "input_tokens": 7,
+            })
+        );
+
+        let redact = vec!["user_prompt".to_string(), "prompt_target".to_string()];
+        let logged: serde_json::Value = serde_json::from_str(&entry.to_json(&redact)).unwrap();
+        assert_eq!(
+            logged,
+            serde_json::json!({
+                "request_id": "req-1",
+                "model": "gpt-4o",
+                "user_prompt": "[REDACTED]",
+                "input_tokens": 7,
+            })
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::open_ai::{ChatCompletionsRequest, ChatCompletionsResponse, Message, Usage};
+use crate::consts::USER_ROLE;
+
+// legacy /v1/completions parameters that chat completions has no counterpart for
+const COMPLETIONS_ONLY_FIELDS: &[&str] = &["best_of", "echo", "logprobs", "suffix"];
+
+// legacy /v1/completions request, sampling parameters like temperature, top_p and max_tokens are
+// carried through to chat completions as they are
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionsRequest {
+    #[serde(default)]
+    pub model: String,
+    pub prompt: CompletionsPrompt,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(flatten)]
+    pub extra_fields: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CompletionsPrompt {
+    Text(String),
+    Array(Vec<String>),
+}
+
+impl TryFrom<CompletionsRequest> for ChatCompletionsRequest {
+    type Error = String;
+
+    fn try_from(request: CompletionsRequest) -> Result<Self, Self::Error> {
+        if request.stream {
+            return Err("streaming is not supported for /v1/completions".to_string());
+        }
+
+        let prompt = match request.prompt {
+            CompletionsPrompt::Text(prompt) => prompt,
+            CompletionsPrompt::Array(mut prompts) if prompts.len() == 1 => prompts.remove(0),
+            CompletionsPrompt::Array(_) => {
+                return Err("only a single prompt is supported for /v1/completions".to_string())
+            }
+        };
+
+        let mut extra_fields = request.extra_fields;
+        extra_fields.retain(|field, _| !COMPLETIONS_ONLY_FIELDS.contains(&field.as_str()));
+
+        Ok(ChatCompletionsRequest {
+            model: request.model,
+            messages: vec![Message {
+                role: USER_ROLE.to_string(),
+                content: Some(prompt.into()),
+                model: None,
+                tool_calls: None,
+                tool_call_id: None,
+                name: None,
+            }],
+            tools: None,
+            stream: false,
+            stream_options: None,
+            metadata: None,
+            extra_fields,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionsResponse {
+    pub object: String,
+    pub model: String,
+    pub choices: Vec<CompletionsChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionsChoice {
+    pub text: String,
+    pub index: usize,
+    pub finish_reason: Option<String>,
+}
+
+impl From<ChatCompletionsResponse> for CompletionsResponse {
+    fn from(response: ChatCompletionsResponse) -> Self {
+        CompletionsResponse {
+            object: "text_completion".to_string(),
+            model: response.model,
+            choices: response
+                .choices
+                .into_iter()
+                .enumerate()
+                .map(|(index, choice)| CompletionsChoice {
+                    text: choice
+                        .message
+                        .content
+                        .map(|content| content.text())
+                        .unwrap_or_default(),
+                    index: choice.index.unwrap_or(index),
+                    finish_reason: choice.finish_reason,
+                })
+                .collect(),
+            usage: response.usage,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CompletionsRequest, CompletionsResponse};
+    use crate::api::open_ai::{ChatCompletionsRequest, ChatCompletionsResponse};
+
+    #[test]
+    fn test_completions_request_to_chat_completions() {
+        let request: CompletionsRequest =
+            serde_json::from_str(r#"{"model": "gpt-3.5-turbo-instruct", "prompt": "Say hi"}"#)
+                .unwrap();
+        let chat_request = ChatCompletionsRequest::try_from(request).unwrap();
+        assert_eq!(chat_request.model, "gpt-3.5-turbo-instruct");
+        assert_eq!(chat_request.messages.len(), 1);
+        assert_eq!(chat_request.messages[0].role, "user");
+        assert_eq!(
+            chat_request.messages[0].content.as_ref().unwrap().text(),
+            "Say hi"
+        );
+
+        let request: CompletionsRequest = serde_json::from_str(
+            r#"{"prompt": "Say hi", "temperature": 0.2, "top_p": 0.9, "max_tokens": 16, "echo": true}"#,
+        )
+        .unwrap();
+        let chat_request = ChatCompletionsRequest::try_from(request).unwrap();
+        assert_eq!(
+            serde_json::to_value(&chat_request.extra_fields).unwrap(),
+            serde_json::json!({"temperature": 0.2, "top_p": 0.9, "max_tokens": 16})
+        );
+
+        let request: CompletionsRequest =
+            serde_json::from_str(r#"{"prompt": ["Say hi", "Say bye"]}"#).unwrap();
+        assert!(ChatCompletionsRequest::try_from(request).is_err());
+
+        let request: CompletionsRequest =
+            serde_json::from_str(r#"{"prompt": "Say hi", "stream": true}"#).unwrap();
+        assert!(ChatCompletionsRequest::try_from(request).is_err());
+    }
+
+    #[test]
+    fn test_chat_completions_response_to_completions() {
+        let response: ChatCompletionsResponse = serde_json::from_str(
+            r#"{
+                "model": "gpt-4o",
+                "choices": [{"index": 0, "finish_reason": "stop", "message": {"role": "assistant", "content": "hi"}}],
+                "usage": {"completion_tokens": 1}
+            }"#,
+        )
+        .unwrap();
+
+        let completions_response = CompletionsResponse::from(response);
+        assert_eq!(
+            serde_json::to_value(&completions_response).unwrap(),
+            serde_json::json!({
+                "object": "text_completion",
+                "model": "gpt-4o",
+                "choices": [{"text": "hi", "index": 0, "finish_reason": "stop"}],
+                "usage": {"completion_tokens": 1}
+            })
+        );
+    }
+}
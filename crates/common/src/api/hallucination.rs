@@ -35,7 +35,7 @@ pub fn extract_messages_for_hallucination(messages: &[Message]) -> Vec<String> {
             if let Some(model) = message.model.as_ref() {
                 if !model.starts_with(CURVE_MODEL_PREFIX) {
                     if let Some(content) = &message.content {
-                        if !content.starts_with(HALLUCINATION_TEMPLATE) {
+                        if !content.text().starts_with(HALLUCINATION_TEMPLATE) {
                             break;
                         }
                     }
@@ -43,13 +43,13 @@ pub fn extract_messages_for_hallucination(messages: &[Message]) -> Vec<String> {
             }
             if message.role == USER_ROLE {
                 if let Some(content) = &message.content {
-                    user_messages.push(content.clone());
+                    user_messages.push(content.text());
                 }
             }
         }
     } else if let Some(message) = messages.last() {
         if let Some(content) = &message.content {
-            user_messages.push(content.clone());
+            user_messages.push(content.text());
         }
     }
     user_messages.reverse(); // Reverse to maintain the original order
@@ -0,0 +1,47 @@
+use crate::api::open_ai::{ChatCompletionsResponse, Message};
+use crate::configuration::HookPoint;
+use serde::{Deserialize, Serialize};
+
+// posted to the endpoint of a hook, the messages are the ones about to go on at the hook point.
+// Post response hooks get the llm response too.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HookRequest {
+    pub hook: String,
+    pub point: HookPoint,
+    pub messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<ChatCompletionsResponse>,
+}
+
+// a hook answers with the messages to continue with, or with a response that is returned to the
+// client instead. An empty object leaves the request as it is. Post response hooks can only
+// replace the response.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HookResponse {
+    pub messages: Option<Vec<Message>>,
+    pub response: Option<ChatCompletionsResponse>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::HookResponse;
+
+    #[test]
+    fn test_hook_response() {
+        let hook_response: HookResponse = serde_json::from_str("{}").unwrap();
+        assert!(hook_response.messages.is_none());
+        assert!(hook_response.response.is_none());
+
+        let hook_response: HookResponse = serde_json::from_str(
+            r#"{"messages": [{"role": "user", "content": "what is the weather in [CITY]?"}]}"#,
+        )
+        .unwrap();
+        let messages = hook_response.messages.unwrap();
+        assert_eq!(
+            messages[0].content.as_ref().unwrap().text(),
+            "what is the weather in [CITY]?"
+        );
+    }
+}
@@ -1,4 +1,8 @@
+pub mod completions;
 pub mod hallucination;
+pub mod hooks;
+pub mod moderation;
 pub mod open_ai;
 pub mod prompt_guard;
+pub mod responses;
 pub mod zero_shot;
@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// openai moderations api, each input gets a result at the same index
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationRequest {
+    pub input: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationResponse {
+    pub results: Vec<ModerationResult>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationResult {
+    pub flagged: bool,
+    #[serde(default)]
+    pub categories: HashMap<String, bool>,
+    #[serde(default)]
+    pub category_scores: HashMap<String, f64>,
+}
+
+impl ModerationResult {
+    // categories with a threshold are violated when their score reaches it, the others when the
+    // provider flags them
+    pub fn violations(&self, thresholds: Option<&HashMap<String, f64>>) -> Vec<String> {
+        let no_thresholds = HashMap::new();
+        let thresholds = thresholds.unwrap_or(&no_thresholds);
+        let mut violations: Vec<String> = self
+            .categories
+            .iter()
+            .filter(|(category, _)| !thresholds.contains_key(*category))
+            .filter(|(_, flagged)| **flagged)
+            .map(|(category, _)| category.clone())
+            .chain(
+                thresholds
+                    .iter()
+                    .filter(|(category, threshold)| {
+                        self.category_scores
+                            .get(*category)
+                            .is_some_and(|score| score >= threshold)
+                    })
+                    .map(|(category, _)| category.clone()),
+            )
+            .collect();
+        violations.sort();
+        violations
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ModerationResponse;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_moderation_violations() {
+        let moderation_response: ModerationResponse = serde_json::from_str(
+            r#"{
+                "id": "modr-123",
+                "model": "omni-moderation-latest",
+                "results": [
+                    {
+                        "flagged": true,
+                        "categories": {"harassment": true, "violence": false, "self-harm": true},
+                        "category_scores": {"harassment": 0.6, "violence": 0.4, "self-harm": 0.9}
+                    },
+                    {"flagged": false}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let result = &moderation_response.results[0];
+        assert_eq!(result.violations(None), vec!["harassment", "self-harm"]);
+
+        let thresholds = HashMap::from([
+            ("harassment".to_string(), 0.8),
+            ("violence".to_string(), 0.3),
+        ]);
+        assert_eq!(
+            result.violations(Some(&thresholds)),
+            vec!["self-harm", "violence"]
+        );
+        assert!(moderation_response.results[1]
+            .violations(Some(&thresholds))
+            .is_empty());
+    }
+}
@@ -2,7 +2,7 @@ use crate::consts::{CURVE_FC_MODEL_NAME, ASSISTANT_ROLE};
 use serde::{ser::SerializeMap, Deserialize, Serialize};
 use serde_yaml::Value;
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, HashMap, VecDeque},
     fmt::Display,
 };
 
@@ -19,6 +19,10 @@ pub struct ChatCompletionsRequest {
     pub stream_options: Option<StreamOptions>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metadata: Option<HashMap<String, String>>,
+    // fields the gateway doesn't act on e.g. temperature or response_format, kept as sent by the
+    // client so that they reach the llm provider
+    #[serde(flatten)]
+    pub extra_fields: HashMap<String, serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -144,6 +148,65 @@ impl ParameterType {
     pub fn string() -> ParameterType {
         ParameterType::String
     }
+
+    pub fn json_schema_type(&self) -> &'static str {
+        match self {
+            ParameterType::Int => "integer",
+            ParameterType::Float => "number",
+            ParameterType::Bool => "boolean",
+            ParameterType::String => "string",
+            ParameterType::List => "array",
+            ParameterType::Dict => "object",
+        }
+    }
+}
+
+impl ChatCompletionTool {
+    // openai compatible providers expect json schema types and an object schema for parameters
+    pub fn to_openai_tool(&self) -> serde_json::Value {
+        let properties: serde_json::Map<String, serde_json::Value> = self
+            .function
+            .parameters
+            .properties
+            .iter()
+            .map(|(name, parameter)| {
+                let mut property = serde_json::json!({
+                    "type": parameter.parameter_type.json_schema_type(),
+                    "description": parameter.description,
+                });
+                if let Some(enum_values) = &parameter.enum_values {
+                    property["enum"] = serde_json::json!(enum_values);
+                }
+                if let Some(default) = &parameter.default {
+                    property["default"] = serde_json::json!(default);
+                }
+                (name.clone(), property)
+            })
+            .collect();
+
+        let mut required: Vec<&String> = self
+            .function
+            .parameters
+            .properties
+            .iter()
+            .filter(|(_, parameter)| parameter.required.unwrap_or(false))
+            .map(|(name, _)| name)
+            .collect();
+        required.sort();
+
+        serde_json::json!({
+            "type": "function",
+            "function": {
+                "name": self.function.name,
+                "description": self.function.description,
+                "parameters": {
+                    "type": "object",
+                    "properties": properties,
+                    "required": required,
+                },
+            },
+        })
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -156,7 +219,7 @@ pub struct Message {
     pub role: String,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub content: Option<String>,
+    pub content: Option<MessageContent>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub model: Option<String>,
@@ -166,6 +229,90 @@ pub struct Message {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_call_id: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+// content is either plain text or an array of content parts e.g. text and image_url
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContentPart {
+    #[serde(rename = "type")]
+    pub part_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    // payload of non text parts e.g. image_url or input_audio, passed through as is
+    #[serde(flatten)]
+    pub extra_fields: HashMap<String, serde_json::Value>,
+}
+
+impl MessageContent {
+    // text of the content, non text parts are skipped
+    pub fn text(&self) -> String {
+        match self {
+            MessageContent::Text(text) => text.clone(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| part.text.as_deref())
+                .collect::<Vec<&str>>()
+                .join("\n"),
+        }
+    }
+
+    // the text and the text parts, to be rewritten in place
+    pub fn texts_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            MessageContent::Text(text) => vec![text],
+            MessageContent::Parts(parts) => parts
+                .iter_mut()
+                .filter_map(|part| part.text.as_mut())
+                .collect(),
+        }
+    }
+
+    pub fn has_non_text_parts(&self) -> bool {
+        match self {
+            MessageContent::Text(_) => false,
+            MessageContent::Parts(parts) => parts.iter().any(|part| part.text.is_none()),
+        }
+    }
+
+    // adds the response of a prompt target to the user message, content parts are kept so that
+    // images etc. still reach the llm
+    pub fn with_context(self, context: &str) -> Self {
+        match self {
+            MessageContent::Text(text) => {
+                MessageContent::Text(format!("{}\ncontext: {}", text, context))
+            }
+            MessageContent::Parts(mut parts) => {
+                parts.push(ContentPart {
+                    part_type: String::from("text"),
+                    text: Some(format!("context: {}", context)),
+                    extra_fields: HashMap::new(),
+                });
+                MessageContent::Parts(parts)
+            }
+        }
+    }
+}
+
+impl From<String> for MessageContent {
+    fn from(text: String) -> Self {
+        MessageContent::Text(text)
+    }
+}
+
+impl Display for MessageContent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.text())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -186,9 +333,45 @@ pub struct ToolCall {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FunctionCallDetail {
     pub name: String,
+    #[serde(
+        serialize_with = "serialize_arguments",
+        deserialize_with = "deserialize_arguments"
+    )]
     pub arguments: HashMap<String, Value>,
 }
 
+// Curve FC returns arguments as an object while openai compatible providers return a json encoded string
+fn deserialize_arguments<'de, D>(deserializer: D) -> Result<HashMap<String, Value>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Arguments {
+        Map(HashMap<String, Value>),
+        Encoded(String),
+    }
+
+    match Arguments::deserialize(deserializer)? {
+        Arguments::Map(arguments) => Ok(arguments),
+        Arguments::Encoded(arguments) => {
+            serde_json::from_str(&arguments).map_err(serde::de::Error::custom)
+        }
+    }
+}
+
+// arguments are always sent as a json encoded string, as expected by openai compatible providers
+fn serialize_arguments<S>(
+    arguments: &HashMap<String, Value>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    let arguments = serde_json::to_string(arguments).map_err(serde::ser::Error::custom)?;
+    serializer.serialize_str(&arguments)
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ToolCallState {
     pub key: String,
@@ -197,10 +380,17 @@ pub struct ToolCallState {
     pub tool_response: String,
 }
 
+// prompt targets the user was asked to pick from, the next turn is matched against these only
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ClarificationState {
+    pub candidates: Vec<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum CurveState {
     ToolCall(Vec<ToolCallState>),
+    Clarification(ClarificationState),
 }
 #[derive(Deserialize, Serialize)]
 #[serde(untagged)]
@@ -229,10 +419,11 @@ impl ChatCompletionsResponse {
             choices: vec![Choice {
                 message: Message {
                     role: ASSISTANT_ROLE.to_string(),
-                    content: Some(message),
+                    content: Some(message.into()),
                     model: Some(CURVE_FC_MODEL_NAME.to_string()),
                     tool_calls: None,
                     tool_call_id: None,
+                    name: None,
                 },
                 index: Some(0),
                 finish_reason: Some("done".to_string()),
@@ -273,6 +464,7 @@ impl ChatCompletionStreamResponse {
                     model: None,
                     tool_call_id: None,
                 },
+                index: Some(0),
                 finish_reason: None,
             }],
         }
@@ -298,17 +490,10 @@ impl Display for ChatCompletionStreamResponseServerEvents {
         let tokens_str = self
             .events
             .iter()
-            .map(|response_chunk| {
-                if response_chunk.choices.is_empty() {
-                    return "".to_string();
-                }
-                response_chunk.choices[0]
-                    .delta
-                    .content
-                    .clone()
-                    .unwrap_or("".to_string())
-            })
-            .collect::<Vec<String>>()
+            // with n > 1 a chunk may carry the deltas of several choices
+            .flat_map(|response_chunk| response_chunk.choices.iter())
+            .filter_map(|choice| choice.delta.content.as_deref())
+            .collect::<Vec<&str>>()
             .join("");
 
         write!(f, "{}", tokens_str)
@@ -333,9 +518,171 @@ impl TryFrom<&str> for ChatCompletionStreamResponseServerEvents {
     }
 }
 
+// tool calls of a streamed response arrive in pieces, the first delta of a call carries its id and
+// name, the following ones carry fragments of the json encoded arguments
+#[derive(Debug, Deserialize)]
+struct ToolCallChunk {
+    model: Option<String>,
+    choices: Vec<ToolCallChunkChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ToolCallChunkChoice {
+    #[serde(default)]
+    index: usize,
+    delta: ToolCallChunkDelta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ToolCallChunkDelta {
+    content: Option<String>,
+    tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ToolCallDelta {
+    #[serde(default)]
+    index: usize,
+    id: Option<String>,
+    function: Option<FunctionCallDelta>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FunctionCallDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+pub fn is_server_events(body: &str) -> bool {
+    body.trim_start().starts_with("data:")
+}
+
+impl TryFrom<&str> for ChatCompletionsResponse {
+    type Error = ChatCompletionChunkResponseError;
+
+    // assembles the chunks of a streamed response into the response it would have been unstreamed
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let chunks = value
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(str::trim)
+            .filter(|data_chunk| *data_chunk != "[DONE]")
+            .map(serde_json::from_str::<ToolCallChunk>)
+            .collect::<Result<Vec<ToolCallChunk>, _>>()?;
+        if chunks.is_empty() {
+            return Err(ChatCompletionChunkResponseError::NoChunks);
+        }
+
+        let mut model = String::new();
+        // the deltas of the choices by their index, a response asked for with n > 1 interleaves
+        // the chunks of its choices
+        let mut choices: BTreeMap<usize, ChoiceDeltas> = BTreeMap::new();
+        for chunk in chunks {
+            if let Some(chunk_model) = chunk.model {
+                model = chunk_model;
+            }
+            for choice in chunk.choices {
+                let deltas = choices.entry(choice.index).or_default();
+                if let Some(delta_content) = choice.delta.content {
+                    deltas.content.push_str(&delta_content);
+                }
+                for delta in choice.delta.tool_calls.unwrap_or_default() {
+                    let tool_call = deltas.tool_calls.entry(delta.index).or_default();
+                    if let Some(id) = delta.id {
+                        tool_call.0 = id;
+                    }
+                    if let Some(function) = delta.function {
+                        if let Some(name) = function.name {
+                            tool_call.1.push_str(&name);
+                        }
+                        if let Some(arguments) = function.arguments {
+                            tool_call.2.push_str(&arguments);
+                        }
+                    }
+                }
+                if choice.finish_reason.is_some() {
+                    deltas.finish_reason = choice.finish_reason;
+                }
+            }
+        }
+
+        // a stream without deltas is an empty response
+        if choices.is_empty() {
+            choices.insert(0, ChoiceDeltas::default());
+        }
+        let choices = choices
+            .into_iter()
+            .map(|(index, deltas)| deltas.into_choice(index, &model))
+            .collect::<Result<Vec<Choice>, serde_json::Error>>()?;
+
+        Ok(ChatCompletionsResponse {
+            usage: None,
+            choices,
+            model,
+            metadata: None,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct ChoiceDeltas {
+    content: String,
+    finish_reason: Option<String>,
+    // id, name and arguments of the tool calls by their index
+    tool_calls: BTreeMap<usize, (String, String, String)>,
+}
+
+impl ChoiceDeltas {
+    fn into_choice(self, index: usize, model: &str) -> Result<Choice, serde_json::Error> {
+        let ChoiceDeltas {
+            content,
+            finish_reason,
+            tool_calls,
+        } = self;
+
+        let tool_calls = tool_calls
+            .into_values()
+            .map(|(id, name, arguments)| {
+                let arguments = match arguments.trim() {
+                    "" => HashMap::new(),
+                    arguments => serde_json::from_str(arguments)?,
+                };
+                Ok(ToolCall {
+                    id,
+                    tool_type: ToolType::Function,
+                    function: FunctionCallDetail { name, arguments },
+                })
+            })
+            .collect::<Result<Vec<ToolCall>, serde_json::Error>>()?;
+
+        let (content, tool_calls) = match tool_calls.is_empty() {
+            true => (Some(content.into()), None),
+            false if content.is_empty() => (None, Some(tool_calls)),
+            false => (Some(content.into()), Some(tool_calls)),
+        };
+
+        Ok(Choice {
+            finish_reason,
+            index: Some(index),
+            message: Message {
+                role: ASSISTANT_ROLE.to_string(),
+                content,
+                model: Some(model.to_string()),
+                tool_calls,
+                tool_call_id: None,
+                name: None,
+            },
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChunkChoice {
     pub delta: Delta,
+    // the choice the delta belongs to when more than one was asked for with n
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
     // TODO: could this be an enum?
     pub finish_reason: Option<String>,
 }
@@ -358,6 +705,35 @@ pub struct Delta {
     pub tool_call_id: Option<String>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingsRequest {
+    #[serde(default)]
+    pub model: String,
+    pub input: EmbeddingsInput,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingsInput {
+    Text(String),
+    Array(Vec<String>),
+}
+
+impl EmbeddingsInput {
+    pub fn text(&self) -> String {
+        match self {
+            EmbeddingsInput::Text(text) => text.clone(),
+            EmbeddingsInput::Array(texts) => texts.join(" "),
+        }
+    }
+}
+
 pub fn to_server_events(chunks: Vec<ChatCompletionStreamResponse>) -> String {
     let mut response_str = String::new();
     for chunk in chunks.iter() {
@@ -368,9 +744,41 @@ pub fn to_server_events(chunks: Vec<ChatCompletionStreamResponse>) -> String {
     response_str
 }
 
+// server event with the routing decisions of the gateway, sent before [DONE] when the client asks
+// for it with the x-curve-include-metadata header
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CurveMetadataEvent {
+    pub object: String,
+    pub request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_target: Option<String>,
+    pub llm_provider: String,
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub experiment_arm: Option<String>,
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+}
+
+impl CurveMetadataEvent {
+    // adds the event in front of the [DONE] event of the chunk, None when the chunk has no [DONE]
+    pub fn insert_before_done(&self, chunk: &str) -> Option<String> {
+        let done_index = chunk.find("data: [DONE]")?;
+        let mut event = String::from("data: ");
+        event.push_str(&serde_json::to_string(self).unwrap());
+        event.push_str("\n\n");
+
+        let mut chunk = chunk.to_string();
+        chunk.insert_str(done_index, &event);
+        Some(chunk)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{ChatCompletionStreamResponseServerEvents, Message};
+    use super::{
+        ChatCompletionStreamResponseServerEvents, CurveMetadataEvent, EmbeddingsRequest, Message,
+    };
     use pretty_assertions::assert_eq;
     use std::collections::HashMap;
 
@@ -439,10 +847,15 @@ mod test {
             model: "gpt-3.5-turbo".to_string(),
             messages: vec![Message {
                 role: "user".to_string(),
-                content: Some("What city do you want to know the weather for?".to_string()),
+                content: Some(
+                    "What city do you want to know the weather for?"
+                        .to_string()
+                        .into(),
+                ),
                 model: None,
                 tool_calls: None,
                 tool_call_id: None,
+                name: None,
             }],
             tools: Some(vec![ChatCompletionTool {
                 tool_type: ToolType::Function,
@@ -453,6 +866,7 @@ mod test {
                 include_usage: true,
             }),
             metadata: None,
+            extra_fields: HashMap::new(),
         };
 
         let serialized = serde_json::to_string_pretty(&chat_completions_request).unwrap();
@@ -507,6 +921,74 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_openai_tool() {
+        use super::{
+            ChatCompletionTool, FunctionDefinition, FunctionParameter, FunctionParameters,
+            ParameterType, ToolType,
+        };
+
+        let tool = ChatCompletionTool {
+            tool_type: ToolType::Function,
+            function: FunctionDefinition {
+                name: "weather_forecast".to_string(),
+                description: "function to retrieve weather forecast".to_string(),
+                parameters: FunctionParameters {
+                    properties: HashMap::from([(
+                        "days".to_string(),
+                        FunctionParameter {
+                            parameter_type: ParameterType::Int,
+                            description: "the number of days".to_string(),
+                            required: Some(true),
+                            enum_values: None,
+                            default: None,
+                            format: None,
+                        },
+                    )]),
+                },
+            },
+        };
+
+        assert_eq!(
+            tool.to_openai_tool(),
+            serde_json::json!({
+                "type": "function",
+                "function": {
+                    "name": "weather_forecast",
+                    "description": "function to retrieve weather forecast",
+                    "parameters": {
+                        "type": "object",
+                        "properties": {
+                            "days": {
+                                "type": "integer",
+                                "description": "the number of days",
+                            },
+                        },
+                        "required": ["days"],
+                    },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_function_call_detail_encoded_arguments() {
+        use super::FunctionCallDetail;
+
+        let from_map: FunctionCallDetail =
+            serde_json::from_str(r#"{"name": "weather", "arguments": {"city": "seattle"}}"#)
+                .unwrap();
+        let from_string: FunctionCallDetail =
+            serde_json::from_str(r#"{"name": "weather", "arguments": "{\"city\": \"seattle\"}"}"#)
+                .unwrap();
+
+        assert_eq!(from_map.arguments, from_string.arguments);
+        assert_eq!(
+            from_string.arguments.get("city").unwrap().as_str(),
+            Some("seattle")
+        );
+    }
+
     #[test]
     fn stream_chunk_parse() {
         const CHUNK_RESPONSE: &str = r#"data: {"id":"chatcmpl-ALmdmtKulBMEq3fRLbrnxJwcKOqvS","object":"chat.completion.chunk","created":1729755226,"model":"gpt-3.5-turbo-0125","system_fingerprint":null,"choices":[{"index":0,"delta":{"role":"assistant","content":"","refusal":null},"logprobs":null,"finish_reason":null}]}
@@ -669,4 +1151,184 @@ data: [DONE]
             "Hello! How can I assist you today?"
         );
     }
+
+    #[test]
+    fn test_metadata_event_before_done() {
+        let event = CurveMetadataEvent {
+            object: "curve.metadata".to_string(),
+            request_id: "req-1".to_string(),
+            prompt_target: None,
+            llm_provider: "open-ai-gpt-4".to_string(),
+            model: "gpt-4".to_string(),
+            experiment_arm: None,
+            input_tokens: 4,
+            output_tokens: 9,
+        };
+
+        assert_eq!(event.insert_before_done("data: {}\n\n"), None);
+        assert_eq!(
+            event
+                .insert_before_done("data: {}\n\ndata: [DONE]\n")
+                .unwrap(),
+            "data: {}\n\n\
+             data: {\"object\":\"curve.metadata\",\"request_id\":\"req-1\",\
+             \"llm_provider\":\"open-ai-gpt-4\",\"model\":\"gpt-4\",\"input_tokens\":4,\
+             \"output_tokens\":9}\n\n\
+             data: [DONE]\n"
+        );
+    }
+
+    #[test]
+    fn test_embeddings_request_input() {
+        let request: EmbeddingsRequest =
+            serde_json::from_str(r#"{"model": "text-embedding-3-small", "input": "hello"}"#)
+                .unwrap();
+        assert_eq!(request.input.text(), "hello");
+
+        let request: EmbeddingsRequest =
+            serde_json::from_str(r#"{"input": ["hello", "world"], "encoding_format": "float"}"#)
+                .unwrap();
+        assert_eq!(request.model, "");
+        assert_eq!(request.input.text(), "hello world");
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"model":"","input":["hello","world"],"encoding_format":"float"}"#
+        );
+    }
+
+    #[test]
+    fn test_multimodal_message_content() {
+        let message_str = r#"{"role":"user","content":[{"type":"text","text":"what is in this image?"},{"type":"image_url","image_url":{"url":"https://example.com/cat.png","detail":"low"}}]}"#;
+        let message: Message = serde_json::from_str(message_str).unwrap();
+        let content = message.content.as_ref().unwrap();
+        assert_eq!(content.text(), "what is in this image?");
+        assert!(content.has_non_text_parts());
+
+        // image parts are passed through unchanged
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            serde_json::from_str::<serde_json::Value>(message_str).unwrap()
+        );
+
+        let content = content.clone().with_context("a cat");
+        assert_eq!(content.text(), "what is in this image?\ncontext: a cat");
+        assert!(content.has_non_text_parts());
+
+        let message: Message =
+            serde_json::from_str(r#"{"role":"user","content":"hello"}"#).unwrap();
+        assert!(!message.content.as_ref().unwrap().has_non_text_parts());
+        assert_eq!(
+            message.content.unwrap().with_context("world").text(),
+            "hello\ncontext: world"
+        );
+    }
+
+    #[test]
+    fn test_tool_call_messages_round_trip() {
+        let messages_str = r#"[
+            {"role":"assistant","tool_calls":[{"id":"call_1","type":"function","function":{"name":"get_weather","arguments":"{\"city\":\"seattle\"}"}}]},
+            {"role":"tool","tool_call_id":"call_1","name":"get_weather","content":"sunny"}
+        ]"#;
+        let messages: Vec<Message> = serde_json::from_str(messages_str).unwrap();
+        assert_eq!(messages[1].tool_call_id.as_deref(), Some("call_1"));
+        assert_eq!(messages[1].name.as_deref(), Some("get_weather"));
+
+        assert_eq!(
+            serde_json::to_value(&messages).unwrap(),
+            serde_json::from_str::<serde_json::Value>(messages_str).unwrap()
+        );
+    }
+
+    #[test]
+    fn stream_chunk_assemble_tool_calls() {
+        use super::ChatCompletionsResponse;
+
+        const CHUNK_RESPONSE: &str = r#"data: {"id":"chatcmpl-1","object":"chat.completion.chunk","model":"gpt-4o-mini","choices":[{"index":0,"delta":{"role":"assistant","content":null,"tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"get_weather","arguments":""}}]},"finish_reason":null}]}
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","model":"gpt-4o-mini","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"city\":"}}]},"finish_reason":null}]}
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","model":"gpt-4o-mini","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":" \"seattle\"}"}}]},"finish_reason":null}]}
+
+data: {"id":"chatcmpl-1","object":"chat.completion.chunk","model":"gpt-4o-mini","choices":[{"index":0,"delta":{},"finish_reason":"tool_calls"}]}
+
+data: [DONE]
+"#;
+
+        assert!(super::is_server_events(CHUNK_RESPONSE));
+        let response = ChatCompletionsResponse::try_from(CHUNK_RESPONSE).unwrap();
+        assert_eq!(response.model, "gpt-4o-mini");
+        assert_eq!(
+            response.choices[0].finish_reason.as_deref(),
+            Some("tool_calls")
+        );
+        let message = &response.choices[0].message;
+        assert!(message.content.is_none());
+        let tool_calls = message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0].id, "call_1");
+        assert_eq!(tool_calls[0].function.name, "get_weather");
+        assert_eq!(
+            tool_calls[0].function.arguments["city"],
+            serde_yaml::Value::from("seattle")
+        );
+
+        const CLARIFICATION_RESPONSE: &str = r#"data: {"model":"gpt-4o-mini","choices":[{"index":0,"delta":{"role":"assistant","content":"Which"},"finish_reason":null}]}
+
+data: {"model":"gpt-4o-mini","choices":[{"index":0,"delta":{"content":" city?"},"finish_reason":"stop"}]}
+
+data: [DONE]
+"#;
+
+        let response = ChatCompletionsResponse::try_from(CLARIFICATION_RESPONSE).unwrap();
+        let message = &response.choices[0].message;
+        assert!(message.tool_calls.is_none());
+        assert_eq!(message.content.as_ref().unwrap().text(), "Which city?");
+
+        const MULTIPLE_CHOICES_RESPONSE: &str = r#"data: {"model":"gpt-4o-mini","choices":[{"index":0,"delta":{"role":"assistant","content":"Sunny"},"finish_reason":null}]}
+
+data: {"model":"gpt-4o-mini","choices":[{"index":1,"delta":{"role":"assistant","content":"Rainy"},"finish_reason":null}]}
+
+data: {"model":"gpt-4o-mini","choices":[{"index":1,"delta":{"content":" later"},"finish_reason":"stop"},{"index":0,"delta":{"content":" all day"},"finish_reason":"stop"}]}
+
+data: [DONE]
+"#;
+
+        let response = ChatCompletionsResponse::try_from(MULTIPLE_CHOICES_RESPONSE).unwrap();
+        let texts: Vec<String> = response
+            .choices
+            .iter()
+            .map(|choice| choice.message.content.as_ref().unwrap().text())
+            .collect();
+        assert_eq!(texts, vec!["Sunny all day", "Rainy later"]);
+        assert_eq!(response.choices[1].index, Some(1));
+        let events =
+            super::ChatCompletionStreamResponseServerEvents::try_from(MULTIPLE_CHOICES_RESPONSE)
+                .unwrap();
+        assert_eq!(events.to_string(), "SunnyRainy later all day");
+
+        assert!(!super::is_server_events(r#"{"choices":[]}"#));
+        assert!(ChatCompletionsResponse::try_from("data: [DONE]\n").is_err());
+    }
+
+    #[test]
+    fn clarification_state_round_trip() {
+        use super::{ClarificationState, CurveState};
+
+        let curve _state = vec![CurveState::Clarification(ClarificationState {
+            candidates: vec!["weather_forecast".to_string(), "flight_status".to_string()],
+        })];
+        let curve _state_str = serde_json::to_string(&curve _state).unwrap();
+        assert_eq!(
+            curve _state_str,
+            r#"[{"candidates":["weather_forecast","flight_status"]}]"#
+        );
+
+        let curve _state: Vec<CurveState> = serde_json::from_str(&curve _state_str).unwrap();
+        match &curve _state[0] {
+            CurveState::Clarification(clarification) => {
+                assert_eq!(clarification.candidates.len(), 2)
+            }
+            _ => panic!("expected clarification state"),
+        }
+    }
 }
@@ -14,6 +14,9 @@ pub enum PromptGuardTask {
 pub struct PromptGuardRequest {
     pub input: String,
     pub task: PromptGuardTask,
+    // guard model of the model server, its default guard when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,4 +25,13 @@ pub struct PromptGuardResponse {
     pub jailbreak_prob: Option<f64>,
     pub toxic_verdict: Option<bool>,
     pub jailbreak_verdict: Option<bool>,
+    // the model server answers the verdict of the task it was asked for
+    pub prob: Option<f64>,
+    pub verdict: Option<bool>,
+}
+
+impl PromptGuardResponse {
+    pub fn is_jailbreak(&self) -> bool {
+        self.verdict.or(self.jailbreak_verdict).unwrap_or_default()
+    }
 }
@@ -0,0 +1,263 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::open_ai::{ChatCompletionsRequest, ChatCompletionsResponse, Message};
+use crate::consts::{ASSISTANT_ROLE, SYSTEM_ROLE, USER_ROLE};
+
+// /v1/responses parameters that chat completions has no counterpart for
+const RESPONSES_ONLY_FIELDS: &[&str] = &[
+    "background",
+    "include",
+    "previous_response_id",
+    "reasoning",
+    "store",
+    "text",
+    "truncation",
+];
+
+// /v1/responses request, only text input that maps onto chat completions is supported, sampling
+// parameters like temperature and top_p are carried through as they are
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponsesRequest {
+    #[serde(default)]
+    pub model: String,
+    pub input: ResponsesInput,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instructions: Option<String>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(flatten)]
+    pub extra_fields: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ResponsesInput {
+    Text(String),
+    Items(Vec<ResponsesInputItem>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponsesInputItem {
+    pub role: String,
+    pub content: ResponsesContent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ResponsesContent {
+    Text(String),
+    Parts(Vec<ResponsesContentPart>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponsesContentPart {
+    #[serde(rename = "type")]
+    pub part_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+impl ResponsesContent {
+    fn text(self) -> String {
+        match self {
+            ResponsesContent::Text(text) => text,
+            ResponsesContent::Parts(parts) => parts
+                .into_iter()
+                .filter_map(|part| part.text)
+                .collect::<Vec<String>>()
+                .join("\n"),
+        }
+    }
+}
+
+fn message(role: &str, content: String) -> Message {
+    Message {
+        role: role.to_string(),
+        content: Some(content.into()),
+        model: None,
+        tool_calls: None,
+        tool_call_id: None,
+        name: None,
+    }
+}
+
+impl TryFrom<ResponsesRequest> for ChatCompletionsRequest {
+    type Error = String;
+
+    fn try_from(request: ResponsesRequest) -> Result<Self, Self::Error> {
+        if request.stream {
+            return Err("streaming is not supported for /v1/responses".to_string());
+        }
+
+        let mut messages = Vec::new();
+        if let Some(instructions) = request.instructions {
+            messages.push(message(SYSTEM_ROLE, instructions));
+        }
+
+        match request.input {
+            ResponsesInput::Text(text) => messages.push(message(USER_ROLE, text)),
+            ResponsesInput::Items(items) => {
+                for item in items {
+                    let role = match item.role.as_str() {
+                        "developer" => SYSTEM_ROLE,
+                        role => role,
+                    };
+                    messages.push(message(role, item.content.text()));
+                }
+            }
+        }
+
+        let mut extra_fields = request.extra_fields;
+        extra_fields.retain(|field, _| !RESPONSES_ONLY_FIELDS.contains(&field.as_str()));
+        if let Some(max_output_tokens) = extra_fields.remove("max_output_tokens") {
+            extra_fields.insert("max_tokens".to_string(), max_output_tokens);
+        }
+
+        Ok(ChatCompletionsRequest {
+            model: request.model,
+            messages,
+            tools: None,
+            stream: false,
+            stream_options: None,
+            metadata: None,
+            extra_fields,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponsesResponse {
+    pub object: String,
+    pub status: String,
+    pub model: String,
+    pub output: Vec<ResponsesOutputItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<ResponsesUsage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponsesOutputItem {
+    #[serde(rename = "type")]
+    pub item_type: String,
+    pub role: String,
+    pub content: Vec<ResponsesOutputContent>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponsesOutputContent {
+    #[serde(rename = "type")]
+    pub content_type: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponsesUsage {
+    pub output_tokens: usize,
+}
+
+impl From<ChatCompletionsResponse> for ResponsesResponse {
+    fn from(response: ChatCompletionsResponse) -> Self {
+        ResponsesResponse {
+            object: "response".to_string(),
+            status: "completed".to_string(),
+            model: response.model,
+            output: response
+                .choices
+                .into_iter()
+                .map(|choice| ResponsesOutputItem {
+                    item_type: "message".to_string(),
+                    role: ASSISTANT_ROLE.to_string(),
+                    content: vec![ResponsesOutputContent {
+                        content_type: "output_text".to_string(),
+                        text: choice
+                            .message
+                            .content
+                            .map(|content| content.text())
+                            .unwrap_or_default(),
+                    }],
+                })
+                .collect(),
+            usage: response.usage.map(|usage| ResponsesUsage {
+                output_tokens: usage.completion_tokens,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ResponsesRequest, ResponsesResponse};
+    use crate::api::open_ai::{ChatCompletionsRequest, ChatCompletionsResponse};
+
+    #[test]
+    fn test_responses_request_to_chat_completions() {
+        let request: ResponsesRequest = serde_json::from_str(
+            r#"{"model": "gpt-4o", "instructions": "Be brief.", "input": "What is Curve?"}"#,
+        )
+        .unwrap();
+        let chat_request = ChatCompletionsRequest::try_from(request).unwrap();
+        assert_eq!(chat_request.messages.len(), 2);
+        assert_eq!(chat_request.messages[0].role, "system");
+        assert_eq!(
+            chat_request.messages[0].content.as_ref().unwrap().text(),
+            "Be brief."
+        );
+        assert_eq!(chat_request.messages[1].role, "user");
+        assert_eq!(
+            chat_request.messages[1].content.as_ref().unwrap().text(),
+            "What is Curve?"
+        );
+
+        let request: ResponsesRequest = serde_json::from_str(
+            r#"{"input": [
+                {"role": "developer", "content": "Be brief."},
+                {"role": "user", "content": [{"type": "input_text", "text": "What is Curve?"}]}
+            ]}"#,
+        )
+        .unwrap();
+        let chat_request = ChatCompletionsRequest::try_from(request).unwrap();
+        assert_eq!(chat_request.messages[0].role, "system");
+        assert_eq!(
+            chat_request.messages[1].content.as_ref().unwrap().text(),
+            "What is Curve?"
+        );
+
+        let request: ResponsesRequest = serde_json::from_str(
+            r#"{"input": "What is Curve?", "temperature": 0.2, "top_p": 0.9, "max_output_tokens": 64, "store": false}"#,
+        )
+        .unwrap();
+        let chat_request = ChatCompletionsRequest::try_from(request).unwrap();
+        assert_eq!(
+            serde_json::to_value(&chat_request.extra_fields).unwrap(),
+            serde_json::json!({"temperature": 0.2, "top_p": 0.9, "max_tokens": 64})
+        );
+    }
+
+    #[test]
+    fn test_chat_completions_response_to_responses() {
+        let response: ChatCompletionsResponse = serde_json::from_str(
+            r#"{
+                "model": "gpt-4o",
+                "choices": [{"index": 0, "finish_reason": "stop", "message": {"role": "assistant", "content": "An AI gateway."}}],
+                "usage": {"completion_tokens": 4}
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            serde_json::to_value(ResponsesResponse::from(response)).unwrap(),
+            serde_json::json!({
+                "object": "response",
+                "status": "completed",
+                "model": "gpt-4o",
+                "output": [{
+                    "type": "message",
+                    "role": "assistant",
+                    "content": [{"type": "output_text", "text": "An AI gateway."}]
+                }],
+                "usage": {"output_tokens": 4}
+            })
+        );
+    }
+}
@@ -0,0 +1,62 @@
+use crate::access_log::REDACTED;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AuditRecord {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub provider: String,
+    pub model: String,
+    // request body as sent to the llm provider
+    pub request: String,
+    // response body as received from the llm provider, server events are kept as is for streaming responses
+    pub response: String,
+    // the record of a mirrored request, its request_id is the one of the original request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<bool>,
+}
+
+impl AuditRecord {
+    pub fn redact(&mut self, values: &[String]) {
+        for value in values.iter().filter(|value| !value.is_empty()) {
+            self.request = self.request.replace(value.as_str(), REDACTED);
+            self.response = self.response.replace(value.as_str(), REDACTED);
+        }
+    }
+}
+
+pub fn sampled(sampling_rate: Option<f64>) -> bool {
+    match sampling_rate {
+        Some(sampling_rate) => rand::random::<f64>() < sampling_rate,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AuditRecord;
+
+    #[test]
+    fn test_audit_record_redaction() {
+        let mut record = AuditRecord {
+            request: r#"{"messages":[{"role":"user","content":"my ssn is 123-45-6789"}]}"#
+                .to_string(),
+            response: r#"{"content":"noted 123-45-6789"}"#.to_string(),
+            ..Default::default()
+        };
+
+        record.redact(&["123-45-6789".to_string(), "".to_string()]);
+        assert_eq!(
+            record.request,
+            r#"{"messages":[{"role":"user","content":"my ssn is [REDACTED]"}]}"#
+        );
+        assert_eq!(record.response, r#"{"content":"noted [REDACTED]"}"#);
+    }
+
+    #[test]
+    fn test_sampled() {
+        assert!(super::sampled(None));
+        assert!(super::sampled(Some(1.0)));
+        assert!(!super::sampled(Some(0.0)));
+    }
+}
@@ -0,0 +1,142 @@
+use http::StatusCode;
+use serde::de::DeserializeOwned;
+use std::fmt::Debug;
+
+// response of a callout as its handler gets it
+#[derive(Debug)]
+pub struct CalloutResponse {
+    pub status: StatusCode,
+    pub body: Vec<u8>,
+}
+
+impl CalloutResponse {
+    pub fn new(status: StatusCode, body: Vec<u8>) -> Self {
+        CalloutResponse { status, body }
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        serde_json::from_slice(&self.body).map_err(Error::Deserialization)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("callout failed with status {status}")]
+    Status { status: StatusCode, body: Vec<u8> },
+    #[error("could not parse callout response: {0}")]
+    Deserialization(serde_json::Error),
+}
+
+type Handler<C> = Box<dyn FnOnce(&mut C, CalloutResponse)>;
+
+// A callout in flight for the context C. The handler of the response is registered when the
+// callout is dispatched, the context hands every response to the callout it belongs to instead of
+// matching on the kind of the callout, so new callouts don't touch on_http_call_response.
+pub struct Callout<C> {
+    name: &'static str,
+    handler: Handler<C>,
+}
+
+impl<C> Callout<C> {
+    // the handler gets the response as it arrived, whatever its status
+    pub fn new(
+        name: &'static str,
+        handler: impl FnOnce(&mut C, CalloutResponse) + 'static,
+    ) -> Self {
+        Callout {
+            name,
+            handler: Box::new(handler),
+        }
+    }
+
+    // the handler gets the json body of a 2xx response as T, or the reason there is none
+    pub fn json<T: DeserializeOwned + 'static>(
+        name: &'static str,
+        handler: impl FnOnce(&mut C, Result<T, Error>) + 'static,
+    ) -> Self {
+        Callout::new(name, move |context, response| {
+            let result = if response.status.is_success() {
+                response.json()
+            } else {
+                Err(Error::Status {
+                    status: response.status,
+                    body: response.body,
+                })
+            };
+            handler(context, result)
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn handle(self, context: &mut C, response: CalloutResponse) {
+        (self.handler)(context, response)
+    }
+}
+
+impl<C> Debug for Callout<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Callout").field("name", &self.name).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Callout, CalloutResponse, Error};
+    use http::StatusCode;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Verdict {
+        flagged: bool,
+    }
+
+    #[derive(Default)]
+    struct Context {
+        verdicts: Vec<Result<Verdict, String>>,
+    }
+
+    fn verdict_callout() -> Callout<Context> {
+        Callout::json(
+            "moderation",
+            |context: &mut Context, verdict: Result<Verdict, Error>| {
+                context
+                    .verdicts
+                    .push(verdict.map_err(|error| error.to_string()));
+            },
+        )
+    }
+
+    #[test]
+    fn test_json_callout() {
+        let mut context = Context::default();
+        let callout = verdict_callout();
+        assert_eq!(format!("{:?}", callout), "Callout { name: \"moderation\" }");
+
+        callout.handle(
+            &mut context,
+            CalloutResponse::new(StatusCode::OK, br#"{"flagged": true}"#.to_vec()),
+        );
+        verdict_callout().handle(
+            &mut context,
+            CalloutResponse::new(StatusCode::SERVICE_UNAVAILABLE, b"overloaded".to_vec()),
+        );
+        verdict_callout().handle(
+            &mut context,
+            CalloutResponse::new(StatusCode::OK, b"not json".to_vec()),
+        );
+
+        assert_eq!(context.verdicts[0], Ok(Verdict { flagged: true }));
+        assert_eq!(
+            context.verdicts[1],
+            Err(String::from(
+                "callout failed with status 503 Service Unavailable"
+            ))
+        );
+        assert!(context.verdicts[2]
+            .as_ref()
+            .is_err_and(|error| error.starts_with("could not parse callout response")));
+    }
+}
@@ -0,0 +1,153 @@
+use crate::configuration::CalloutLimit;
+use crate::metric_names::{metric_name, Dimension};
+use crate::stats::{Gauge, RecordingMetric};
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
+
+// Concurrent callouts per cluster. Envoy runs a wasm vm per worker thread, so the limits apply per
+// worker.
+#[derive(Debug, Default)]
+pub struct CalloutLimits {
+    clusters: HashMap<String, ClusterLimit>,
+    // cluster of every limited call in flight by token id
+    calls: HashMap<u32, String>,
+}
+
+#[derive(Debug)]
+struct ClusterLimit {
+    max_concurrent_calls: usize,
+    // calls over the limit wait this long for a free slot, they are shed when not set
+    max_wait: Option<Duration>,
+    in_flight: usize,
+    gauge: Option<Gauge>,
+}
+
+impl CalloutLimits {
+    pub fn new(callout_limits: &[CalloutLimit]) -> Self {
+        CalloutLimits {
+            clusters: callout_limits
+                .iter()
+                .map(|limit| {
+                    (
+                        limit.cluster.clone(),
+                        ClusterLimit {
+                            max_concurrent_calls: limit.max_concurrent_calls,
+                            max_wait: limit.max_wait(),
+                            in_flight: 0,
+                            gauge: None,
+                        },
+                    )
+                })
+                .collect(),
+            calls: HashMap::new(),
+        }
+    }
+
+    pub fn is_full(&self, cluster: &str) -> bool {
+        self.clusters
+            .get(cluster)
+            .is_some_and(|limit| limit.in_flight >= limit.max_concurrent_calls)
+    }
+
+    pub fn max_wait(&self, cluster: &str) -> Option<Duration> {
+        self.clusters.get(cluster).and_then(|limit| limit.max_wait)
+    }
+
+    pub fn acquire(&mut self, token_id: u32, cluster: &str) {
+        if let Some(limit) = self.clusters.get_mut(cluster) {
+            limit.in_flight += 1;
+            limit.record();
+            self.calls.insert(token_id, cluster.to_string());
+        }
+    }
+
+    pub fn release(&mut self, token_id: u32) {
+        let cluster = match self.calls.remove(&token_id) {
+            Some(cluster) => cluster,
+            None => return,
+        };
+        if let Some(limit) = self.clusters.get_mut(&cluster) {
+            limit.in_flight = limit.in_flight.saturating_sub(1);
+            limit.record();
+        }
+    }
+}
+
+impl ClusterLimit {
+    fn record(&self) {
+        if let Some(gauge) = self.gauge.as_ref() {
+            gauge.record(self.in_flight as u64);
+        }
+    }
+}
+
+pub fn callout_limits() -> &'static RwLock<CalloutLimits> {
+    static CALLOUT_LIMITS: OnceLock<RwLock<CalloutLimits>> = OnceLock::new();
+    CALLOUT_LIMITS.get_or_init(|| RwLock::new(CalloutLimits::default()))
+}
+
+// applies the callout limits config, calls in flight are no longer counted after a reconfiguration
+pub fn configure(callout_limits_config: Option<&[CalloutLimit]>) {
+    let mut limits = CalloutLimits::new(callout_limits_config.unwrap_or_default());
+    for (cluster, limit) in limits.clusters.iter_mut() {
+        let name = metric_name("callouts_in_flight", &[(Dimension::Cluster, cluster)]);
+        limit.gauge = Some(Gauge::new(name));
+    }
+    *callout_limits().write().unwrap() = limits;
+}
+
+#[cfg(test)]
+mod test {
+    use super::CalloutLimits;
+    use crate::configuration::{CalloutLimit, CalloutOverflow};
+    use std::time::Duration;
+
+    #[test]
+    fn test_callout_limits() {
+        let mut limits = CalloutLimits::new(&[CalloutLimit {
+            cluster: String::from("app_server"),
+            max_concurrent_calls: 2,
+            ..Default::default()
+        }]);
+
+        limits.acquire(1, "app_server");
+        assert!(!limits.is_full("app_server"));
+        limits.acquire(2, "app_server");
+        assert!(limits.is_full("app_server"));
+
+        // clusters without a limit are never full
+        limits.acquire(3, "weather_server");
+        assert!(!limits.is_full("weather_server"));
+
+        limits.release(1);
+        assert!(!limits.is_full("app_server"));
+        // releasing an unknown call changes nothing
+        limits.release(1);
+        limits.release(3);
+        limits.acquire(4, "app_server");
+        assert!(limits.is_full("app_server"));
+    }
+
+    #[test]
+    fn test_max_wait() {
+        let limits = CalloutLimits::new(&[
+            CalloutLimit {
+                cluster: String::from("app_server"),
+                max_concurrent_calls: 2,
+                overflow: Some(CalloutOverflow::Shed),
+                max_wait_ms: Some(100),
+            },
+            CalloutLimit {
+                cluster: String::from("server"),
+                max_concurrent_calls: 2,
+                overflow: Some(CalloutOverflow::Wait),
+                max_wait_ms: None,
+            },
+        ]);
+
+        assert_eq!(limits.max_wait("app_server"), None);
+        assert_eq!(limits.max_wait("server"), Some(Duration::from_millis(1000)));
+        assert_eq!(limits.max_wait("weather_server"), None);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::http::CallArgs;
+use crate::pii::obfuscate_auth_header;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// The callouts of a sampled request and their responses, in the order the responses arrived. A
+// trace is enough to run the request through the gateway again with the same callout responses,
+// e.g. to reproduce a request that was routed to the wrong prompt target.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CaptureTrace {
+    pub request_id: String,
+    // time the request arrived, in nanoseconds since the unix epoch
+    pub timestamp: u64,
+    // request headers as the gateway saw them, with the authorization header obfuscated
+    pub request_headers: Vec<(String, String)>,
+    // request body after it was decompressed
+    pub request_body: String,
+    pub callouts: Vec<CapturedCallout>,
+    // prompt target the request was routed to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_target: Option<String>,
+    // callouts whose response had not arrived when the request was done, by token
+    #[serde(skip)]
+    pending: HashMap<u32, CapturedCallout>,
+}
+
+// the headers of callouts are not captured, they carry the credentials of endpoints
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct CapturedCallout {
+    // cluster the internal listener routed the callout to
+    pub upstream: String,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_body: Option<String>,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: String,
+}
+
+impl CapturedCallout {
+    pub fn new(call_args: &CallArgs) -> Self {
+        CapturedCallout {
+            upstream: call_args.cluster().to_string(),
+            path: call_args.path().to_string(),
+            request_body: call_args
+                .body()
+                .map(|body| String::from_utf8_lossy(body).into_owned()),
+            ..Default::default()
+        }
+    }
+
+    pub fn status(&self) -> Option<&str> {
+        self.response_headers
+            .iter()
+            .find(|(key, _)| key == ":status")
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl CaptureTrace {
+    pub fn new(request_id: &str, now: SystemTime) -> Self {
+        let timestamp = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        CaptureTrace {
+            request_id: request_id.to_string(),
+            timestamp: timestamp.as_nanos() as u64,
+            ..Default::default()
+        }
+    }
+
+    pub fn started_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_nanos(self.timestamp)
+    }
+
+    pub fn record_request(&mut self, mut headers: Vec<(String, String)>, body: &[u8]) {
+        obfuscate_auth_header(&mut headers);
+        self.request_headers = headers;
+        self.request_body = String::from_utf8_lossy(body).into_owned();
+    }
+
+    pub fn record_call(&mut self, token_id: u32, callout: CapturedCallout) {
+        self.pending.insert(token_id, callout);
+    }
+
+    pub fn record_response(&mut self, token_id: u32, headers: Vec<(String, String)>, body: &[u8]) {
+        if let Some(mut callout) = self.pending.remove(&token_id) {
+            callout.response_headers = headers;
+            callout.response_body = String::from_utf8_lossy(body).into_owned();
+            self.callouts.push(callout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CaptureTrace, CapturedCallout};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn callout(path: &str) -> CapturedCallout {
+        CapturedCallout {
+            upstream: "api_server".to_string(),
+            path: path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_callouts_are_kept_in_response_order() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut trace = CaptureTrace::new("req-1", now);
+        trace.record_call(1, callout("/first"));
+        trace.record_call(2, callout("/second"));
+        trace.record_response(2, vec![(":status".into(), "200".into())], b"second");
+        trace.record_response(1, vec![(":status".into(), "503".into())], b"first");
+        trace.record_response(3, vec![], b"unknown");
+
+        let paths: Vec<&str> = trace.callouts.iter().map(|c| c.path.as_str()).collect();
+        assert_eq!(paths, vec!["/second", "/first"]);
+        assert_eq!(trace.callouts[1].status(), Some("503"));
+        assert_eq!(trace.callouts[1].response_body, "first");
+        assert_eq!(trace.started_at(), now);
+    }
+
+    #[test]
+    fn test_authorization_header_is_obfuscated() {
+        let mut trace = CaptureTrace::default();
+        trace.record_request(
+            vec![
+                (":path".into(), "/v1/chat/completions".into()),
+                ("Authorization".into(), "Bearer secret".into()),
+            ],
+            b"{}",
+        );
+        assert_eq!(trace.request_headers[1].1, "Bearer ***");
+
+        let serialized = serde_json::to_string(&trace).unwrap();
+        assert!(!serialized.contains("secret"));
+        let deserialized: CaptureTrace = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.request_body, "{}");
+    }
+}
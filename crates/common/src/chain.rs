@@ -0,0 +1,228 @@
+use crate::configuration::{ChainStep, StepCondition};
+use crate::template::render_template;
+use serde_json::{Map, Value};
+
+// the parameters the step is called with. An input that is a single placeholder keeps the type of
+// the value it refers to, other inputs are rendered as strings. Placeholders that can't be
+// resolved are left untouched.
+pub fn step_inputs(
+    step: &ChainStep,
+    params: &Map<String, Value>,
+    step_responses: &Map<String, Value>,
+) -> Map<String, Value> {
+    let inputs = match step.inputs.as_ref() {
+        Some(inputs) => inputs,
+        None => return params.clone(),
+    };
+
+    inputs
+        .iter()
+        .map(|(name, template)| {
+            let value = template
+                .strip_prefix('{')
+                .and_then(|placeholder| placeholder.strip_suffix('}'))
+                .filter(|placeholder| !placeholder.contains(['{', '}']))
+                .and_then(|placeholder| lookup(placeholder.trim(), params, step_responses))
+                .unwrap_or_else(|| {
+                    Value::String(render_template(template, |name, _| {
+                        lookup(name, params, step_responses).map(|value| match value {
+                            Value::String(value) => value,
+                            value => value.to_string(),
+                        })
+                    }))
+                });
+            (name.clone(), value)
+        })
+        .collect()
+}
+
+pub fn should_run(
+    step: &ChainStep,
+    params: &Map<String, Value>,
+    step_responses: &Map<String, Value>,
+) -> bool {
+    step.when
+        .iter()
+        .flatten()
+        .all(|condition| holds(condition, params, step_responses))
+}
+
+fn holds(
+    condition: &StepCondition,
+    params: &Map<String, Value>,
+    step_responses: &Map<String, Value>,
+) -> bool {
+    let value = match lookup(&reference(&condition.path), params, step_responses) {
+        Some(value) => value,
+        None => return false,
+    };
+    if condition
+        .equals
+        .as_ref()
+        .is_some_and(|equals| *equals != value)
+    {
+        return false;
+    }
+    match (condition.contains.as_ref(), &value) {
+        (None, _) => true,
+        (Some(Value::String(contains)), Value::String(value)) => value.contains(contains.as_str()),
+        (Some(contains), Value::Array(values)) => values.contains(contains),
+        (Some(Value::String(contains)), Value::Object(object)) => object.contains_key(contains),
+        _ => false,
+    }
+}
+
+// $.check_status.interfaces[0].state -> check_status.interfaces.0.state
+fn reference(path: &str) -> String {
+    let path = path.strip_prefix('$').unwrap_or(path);
+    path.replace('[', ".")
+        .replace(']', "")
+        .trim_start_matches('.')
+        .to_string()
+}
+
+// index of the step the chain continues with after the step at index
+pub fn next_step(steps: &[ChainStep], index: usize) -> usize {
+    steps[index]
+        .next
+        .as_ref()
+        .and_then(|next| steps.iter().position(|step| step.name == *next))
+        .unwrap_or(index + 1)
+}
+
+// step.field.0.field refers to a field of the response of a step, anything else to a parameter
+pub fn lookup(
+    reference: &str,
+    params: &Map<String, Value>,
+    step_responses: &Map<String, Value>,
+) -> Option<Value> {
+    let mut segments = reference.split('.');
+    let first = segments.next()?;
+    let mut value = match step_responses.get(first) {
+        Some(step_response) => step_response,
+        None => return params.get(reference).cloned(),
+    };
+    for segment in segments {
+        value = match value {
+            Value::Object(object) => object.get(segment)?,
+            Value::Array(array) => array.get(segment.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value.clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{lookup, next_step, should_run, step_inputs};
+    use crate::configuration::ChainStep;
+    use serde_json::{json, Map, Value};
+
+    fn step(inputs: &str) -> ChainStep {
+        serde_yaml::from_str(&format!(
+            "name: check_status\nendpoint:\n  name: app_server\n{}",
+            inputs
+        ))
+        .unwrap()
+    }
+
+    fn object(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_step_inputs() {
+        let params = object(json!({"device_name": "router-1", "force": true}));
+        let step_responses = object(json!({
+            "lookup_device": {"device_id": 42, "interfaces": [{"name": "eth0"}]}
+        }));
+
+        assert_eq!(
+            step_inputs(&step(""), &params, &step_responses),
+            params.clone()
+        );
+
+        let inputs = step_inputs(
+            &step(
+                "inputs:\n  device_id: '{lookup_device.device_id}'\n  force: '{force}'\n  \
+                 label: '{device_name}/{lookup_device.interfaces.0.name}'\n  \
+                 missing: '{lookup_device.serial}'\n",
+            ),
+            &params,
+            &step_responses,
+        );
+        assert_eq!(
+            Value::Object(inputs),
+            json!({
+                "device_id": 42,
+                "force": true,
+                "label": "router-1/eth0",
+                "missing": "{lookup_device.serial}",
+            })
+        );
+    }
+
+    #[test]
+    fn test_lookup() {
+        let params = object(json!({"device_name": "router-1"}));
+        let step_responses = object(json!({"lookup_device": {"ids": [7, 8]}}));
+
+        assert_eq!(
+            lookup("lookup_device.ids.1", &params, &step_responses),
+            Some(json!(8))
+        );
+        assert_eq!(
+            lookup("lookup_device", &params, &step_responses),
+            Some(json!({"ids": [7, 8]}))
+        );
+        assert_eq!(
+            lookup("lookup_device.ids.2", &params, &step_responses),
+            None
+        );
+        assert_eq!(
+            lookup("device_name", &params, &step_responses),
+            Some(json!("router-1"))
+        );
+        assert_eq!(lookup("unknown", &params, &step_responses), None);
+    }
+    #[test]
+    fn test_should_run() {
+        let params = object(json!({"host_name": "router-1"}));
+        let step_responses = object(json!({
+            "check_status": {"state": "offline", "alarms": ["fan"], "ports": [{"up": false}]}
+        }));
+        let run = |conditions: &str| {
+            let step = step(&format!("when:\n{}", conditions));
+            should_run(&step, &params, &step_responses)
+        };
+
+        assert!(should_run(&step(""), &params, &step_responses));
+        assert!(run("  - path: $.check_status.state\n    equals: offline\n"));
+        assert!(!run("  - path: $.check_status.state\n    equals: online\n"));
+        assert!(run(
+            "  - path: $.check_status.ports[0].up\n    equals: false\n"
+        ));
+        assert!(run("  - path: check_status.alarms\n    contains: fan\n"));
+        assert!(run("  - path: $.host_name\n    contains: router\n"));
+        assert!(run("  - path: $.check_status\n    contains: alarms\n"));
+        assert!(!run("  - path: $.check_status.uptime\n"));
+        assert!(!run(
+            "  - path: $.check_status.state\n    equals: offline\n  - path: $.host_name\n    \
+             equals: router-2\n"
+        ));
+    }
+
+    #[test]
+    fn test_next_step() {
+        let steps: Vec<ChainStep> = serde_yaml::from_str(
+            "- name: check_status\n  endpoint:\n    name: app_server\n  next: reboot\n\
+             - name: escalate\n  endpoint:\n    name: app_server\n\
+             - name: reboot\n  endpoint:\n    name: app_server\n",
+        )
+        .unwrap();
+
+        assert_eq!(next_step(&steps, 0), 2);
+        assert_eq!(next_step(&steps, 1), 2);
+        assert_eq!(next_step(&steps, 2), 3);
+    }
+}
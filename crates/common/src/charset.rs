@@ -0,0 +1,126 @@
+// Bodies of clients and upstreams are decoded with the charset of their content-type. Invalid
+// sequences are replaced instead of failing, a misbehaving upstream can't break the request.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Charset {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    // iso-8859-1 and us-ascii are decoded as windows-1252, like browsers do
+    Windows1252,
+}
+
+// characters of windows-1252 bytes 0x80 to 0x9f, the other bytes are the same in unicode
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+impl Charset {
+    // charset parameter of a content-type header, utf-8 when there is none or it isn't supported
+    pub fn from_content_type(content_type: &str) -> Charset {
+        let charset = content_type.split(';').skip(1).find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            name.trim()
+                .eq_ignore_ascii_case("charset")
+                .then(|| value.trim().trim_matches('"').to_ascii_lowercase())
+        });
+        match charset.as_deref() {
+            Some("utf-16le") => Charset::Utf16Le,
+            // without a byte order mark utf-16 is big endian
+            Some("utf-16be" | "utf-16") => Charset::Utf16Be,
+            Some(
+                "iso-8859-1" | "latin1" | "l1" | "us-ascii" | "ascii" | "windows-1252" | "cp1252",
+            ) => Charset::Windows1252,
+            _ => Charset::Utf8,
+        }
+    }
+}
+
+// text of a body, a byte order mark wins over the charset of the content-type
+pub fn decode(body: &[u8], content_type: Option<&str>) -> String {
+    let (charset, body) = match body {
+        [0xef, 0xbb, 0xbf, rest @ ..] => (Charset::Utf8, rest),
+        [0xff, 0xfe, rest @ ..] => (Charset::Utf16Le, rest),
+        [0xfe, 0xff, rest @ ..] => (Charset::Utf16Be, rest),
+        _ => (
+            content_type.map_or(Charset::Utf8, Charset::from_content_type),
+            body,
+        ),
+    };
+    match charset {
+        Charset::Utf8 => String::from_utf8_lossy(body).into_owned(),
+        Charset::Utf16Le => decode_utf16(body, u16::from_le_bytes),
+        Charset::Utf16Be => decode_utf16(body, u16::from_be_bytes),
+        Charset::Windows1252 => body
+            .iter()
+            .map(|byte| match byte {
+                0x80..=0x9f => WINDOWS_1252_HIGH[usize::from(byte - 0x80)],
+                _ => char::from(*byte),
+            })
+            .collect(),
+    }
+}
+
+fn decode_utf16(body: &[u8], from_bytes: fn([u8; 2]) -> u16) -> String {
+    let units: Vec<u16> = body
+        .chunks(2)
+        .map(|unit| match unit {
+            [first, second] => from_bytes([*first, *second]),
+            // a dangling byte is not a character
+            _ => 0xfffd,
+        })
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, Charset};
+
+    #[test]
+    fn test_charset_of_content_type() {
+        assert_eq!(
+            Charset::from_content_type("application/json"),
+            Charset::Utf8
+        );
+        assert_eq!(
+            Charset::from_content_type("text/plain; Charset=\"ISO-8859-1\""),
+            Charset::Windows1252
+        );
+        assert_eq!(
+            Charset::from_content_type("application/json; charset=utf-16le"),
+            Charset::Utf16Le
+        );
+        assert_eq!(
+            Charset::from_content_type("application/json; charset=koi8-r"),
+            Charset::Utf8
+        );
+    }
+
+    #[test]
+    fn test_invalid_utf8_is_replaced() {
+        assert_eq!(decode(b"caf\xc3\xa9 \xff!", None), "café \u{fffd}!");
+        // a multi byte character cut off at the end of the body
+        assert_eq!(decode(b"caf\xc3", Some("application/json")), "caf\u{fffd}");
+    }
+
+    #[test]
+    fn test_bodies_are_decoded_with_their_charset() {
+        assert_eq!(
+            decode(b"caf\xe9 \x80", Some("text/plain; charset=iso-8859-1")),
+            "café €"
+        );
+        assert_eq!(
+            decode(b"\xff\xfeh\x00i\x00", Some("application/json")),
+            "hi"
+        );
+        assert_eq!(
+            decode(b"\x00h\x00i\x00", Some("text/plain; charset=utf-16")),
+            "hi\u{fffd}"
+        );
+        assert_eq!(decode(b"\xef\xbb\xbf{}", None), "{}");
+    }
+}
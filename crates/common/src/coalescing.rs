@@ -0,0 +1,161 @@
+use crate::ratelimit::Header;
+use crate::routing::stable_hash;
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, SystemTime};
+
+// Identical requests in flight keyed by request_key. The first request is sent to the llm provider,
+// the requests that arrive while it is in flight wait for its response.
+#[derive(Debug, Default)]
+pub struct InFlightRequests {
+    requests: HashMap<u64, InFlightRequest>,
+    // waiting requests by context id with the response they are answered with, None when they
+    // have to be sent to the llm provider themselves
+    ready: Vec<(u32, Option<Vec<u8>>)>,
+}
+
+#[derive(Debug)]
+struct InFlightRequest {
+    // context id of the request sent to the llm provider
+    leader: u32,
+    started_at: SystemTime,
+    waiters: Vec<u32>,
+}
+
+// requests are only coalesced when they go to the same provider with the same body and selector
+pub fn request_key(llm_provider: &str, request_body: &str, selector: Option<&Header>) -> u64 {
+    let selector = selector
+        .map(|selector| format!("{}={}", selector.key, selector.value))
+        .unwrap_or_default();
+    stable_hash(&format!("{}\n{}\n{}", llm_provider, selector, request_body))
+}
+
+impl InFlightRequests {
+    // true when an identical request is in flight, the request then waits for its response
+    pub fn join(&mut self, key: u64, context_id: u32, now: SystemTime) -> bool {
+        match self.requests.get_mut(&key) {
+            Some(request) => {
+                request.waiters.push(context_id);
+                true
+            }
+            None => {
+                self.requests.insert(
+                    key,
+                    InFlightRequest {
+                        leader: context_id,
+                        started_at: now,
+                        waiters: Vec::new(),
+                    },
+                );
+                false
+            }
+        }
+    }
+
+    // hands the response of the first request to the waiting ones, without a response they are
+    // sent to the llm provider themselves. A leader that was given up on doesn't complete the
+    // request that took its key.
+    pub fn complete(&mut self, key: u64, leader: u32, response: Option<&[u8]>) {
+        if self
+            .requests
+            .get(&key)
+            .is_some_and(|request| request.leader == leader)
+        {
+            self.release(key, response);
+        }
+    }
+
+    fn release(&mut self, key: u64, response: Option<&[u8]>) {
+        if let Some(request) = self.requests.remove(&key) {
+            for waiter in request.waiters {
+                self.ready
+                    .push((waiter, response.map(|response| response.to_vec())));
+            }
+        }
+    }
+
+    // waiting requests that can be resumed, requests in flight for longer than the timeout are
+    // given up on
+    pub fn take_ready(
+        &mut self,
+        now: SystemTime,
+        timeout: Duration,
+    ) -> Vec<(u32, Option<Vec<u8>>)> {
+        let expired: Vec<u64> = self
+            .requests
+            .iter()
+            .filter(|(_, request)| {
+                now.duration_since(request.started_at)
+                    .is_ok_and(|age| age >= timeout)
+            })
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            self.release(key, None);
+        }
+        std::mem::take(&mut self.ready)
+    }
+}
+
+pub fn in_flight_requests() -> &'static RwLock<InFlightRequests> {
+    static IN_FLIGHT_REQUESTS: OnceLock<RwLock<InFlightRequests>> = OnceLock::new();
+    IN_FLIGHT_REQUESTS.get_or_init(|| RwLock::new(InFlightRequests::default()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{request_key, InFlightRequests};
+    use crate::ratelimit::Header;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_identical_requests_are_coalesced() {
+        let body = r#"{"model":"gpt-4o","messages":[{"role":"user","content":"hi"}]}"#;
+        let key = request_key("openai", body, None);
+        assert_ne!(key, request_key("mistral", body, None));
+        let selector = Header {
+            key: String::from("x-team-id"),
+            value: String::from("team-1"),
+        };
+        assert_ne!(key, request_key("openai", body, Some(&selector)));
+
+        let mut requests = InFlightRequests::default();
+        let now = UNIX_EPOCH + Duration::from_secs(100);
+        let timeout = Duration::from_secs(30);
+        assert!(!requests.join(key, 1, now));
+        assert!(requests.join(key, 2, now));
+        assert!(requests.join(key, 3, now));
+        assert!(requests.take_ready(now, timeout).is_empty());
+
+        // only the first request completes them
+        requests.complete(key, 2, None);
+        assert!(requests.take_ready(now, timeout).is_empty());
+        requests.complete(key, 1, Some(b"response"));
+        assert_eq!(
+            requests.take_ready(now, timeout),
+            vec![
+                (2, Some(b"response".to_vec())),
+                (3, Some(b"response".to_vec()))
+            ]
+        );
+
+        // once the first request completed the next one is sent to the provider again
+        assert!(!requests.join(key, 4, now));
+        assert!(requests.join(key, 5, now));
+        assert!(requests
+            .take_ready(now + Duration::from_secs(10), timeout)
+            .is_empty());
+        assert_eq!(requests.take_ready(now + timeout, timeout), vec![(5, None)]);
+        assert!(!requests.join(key, 6, now + timeout));
+        assert!(requests.join(key, 7, now + timeout));
+
+        // the request given up on finishes late, the waiters of the one leading now keep waiting
+        requests.complete(key, 4, Some(b"late response"));
+        assert!(requests.take_ready(now + timeout, timeout).is_empty());
+        requests.complete(key, 6, Some(b"response"));
+        assert_eq!(
+            requests.take_ready(now + timeout, timeout),
+            vec![(7, Some(b"response".to_vec()))]
+        );
+    }
+}
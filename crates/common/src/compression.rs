@@ -0,0 +1,186 @@
+use crate::api::open_ai::Message;
+use crate::configuration::Compression;
+use crate::consts::{SYSTEM_ROLE, TOOL_ROLE, TRUNCATED_TOOL_OUTPUT_MARKER};
+use crate::tokenizer;
+use std::collections::{HashMap, HashSet};
+
+// content of messages made of text only, images and other parts are left alone
+fn text_content(message: &Message) -> Option<String> {
+    message
+        .content
+        .as_ref()
+        .filter(|content| !content.has_non_text_parts())
+        .map(|content| content.text())
+}
+
+// removes trailing whitespace, leading and trailing blank lines and runs of blank lines.
+// Indentation is kept, it matters for code.
+pub fn trim_whitespace(text: &str) -> String {
+    let mut lines: Vec<&str> = Vec::new();
+    for line in text.lines().map(str::trim_end) {
+        if line.is_empty() && matches!(lines.last(), None | Some(&"")) {
+            continue;
+        }
+        lines.push(line);
+    }
+    if lines.last().is_some_and(|last| last.is_empty()) {
+        lines.pop();
+    }
+    lines.join("\n")
+}
+
+// drops repeated system messages. Repeated tool outputs are replaced by a reference to the tool
+// call that returned them first, every tool call still needs its response.
+fn deduplicate(messages: &mut Vec<Message>) {
+    let mut system_messages = HashSet::new();
+    let mut tool_outputs: HashMap<String, String> = HashMap::new();
+    messages.retain_mut(|message| {
+        let Some(text) = text_content(message) else {
+            return true;
+        };
+        if message.role == SYSTEM_ROLE {
+            return system_messages.insert(text);
+        }
+        if message.role != TOOL_ROLE {
+            return true;
+        }
+        let Some(tool_call_id) = message.tool_call_id.clone() else {
+            return true;
+        };
+        match tool_outputs.get(&text) {
+            Some(first_tool_call_id) => {
+                let reference = format!("same output as tool call {}", first_tool_call_id);
+                if reference.len() < text.len() {
+                    message.content = Some(reference.into());
+                }
+            }
+            None => {
+                tool_outputs.insert(text, tool_call_id);
+            }
+        }
+        true
+    });
+}
+
+// compresses the messages in place and returns the number of tokens saved. Long tool outputs
+// are cut before they are compared for repeats.
+pub fn compress(compression: &Compression, model: &str, messages: &mut Vec<Message>) -> usize {
+    let tokens_before: usize = tokenizer::message_tokens(model, messages).iter().sum();
+
+    if compression.trim_whitespace() {
+        for message in messages.iter_mut() {
+            if let Some(text) = text_content(message) {
+                let trimmed = trim_whitespace(&text);
+                if trimmed != text {
+                    message.content = Some(trimmed.into());
+                }
+            }
+        }
+    }
+
+    if let Some(max_tokens) = compression.max_tool_output_tokens {
+        for message in messages.iter_mut().filter(|m| m.role == TOOL_ROLE) {
+            let truncated = text_content(message)
+                .and_then(|text| tokenizer::truncate_text(model, &text, max_tokens));
+            if let Some(mut text) = truncated {
+                text.push_str(TRUNCATED_TOOL_OUTPUT_MARKER);
+                message.content = Some(text.into());
+            }
+        }
+    }
+
+    if compression.deduplicate() {
+        deduplicate(messages);
+    }
+
+    let tokens_after: usize = tokenizer::message_tokens(model, messages).iter().sum();
+    tokens_before.saturating_sub(tokens_after)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compress, trim_whitespace};
+    use crate::api::open_ai::Message;
+    use crate::configuration::Compression;
+    use crate::consts::{ASSISTANT_ROLE, SYSTEM_ROLE, TOOL_ROLE, USER_ROLE};
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: Some(content.to_string().into()),
+            model: None,
+            tool_calls: None,
+            tool_call_id: None,
+            name: None,
+        }
+    }
+
+    fn tool_response(tool_call_id: &str, content: &str) -> Message {
+        let mut message = message(TOOL_ROLE, content);
+        message.tool_call_id = Some(tool_call_id.to_string());
+        message
+    }
+
+    fn texts(messages: &[Message]) -> Vec<String> {
+        messages
+            .iter()
+            .map(|m| m.content.as_ref().unwrap().text())
+            .collect()
+    }
+
+    #[test]
+    fn test_trim_whitespace() {
+        assert_eq!(
+            trim_whitespace("\n\nfn main() {  \n    run();\n\n\n\n}\n\n"),
+            "fn main() {\n    run();\n\n}"
+        );
+        assert_eq!(trim_whitespace(" \n \n"), "");
+    }
+
+    #[test]
+    fn test_compress() {
+        let weather = "Seattle: sunny, 21 degrees celsius, light breeze from the north west.";
+        let conversation = vec![
+            message(SYSTEM_ROLE, "You are a helpful assistant.\n\n\n"),
+            message(USER_ROLE, "How is the weather in seattle?   "),
+            tool_response("call_1", weather),
+            message(SYSTEM_ROLE, "You are a helpful assistant."),
+            message(ASSISTANT_ROLE, "It is sunny."),
+            message(USER_ROLE, "Check again please."),
+            tool_response("call_2", weather),
+        ];
+
+        let compression = Compression {
+            trim_whitespace: Some(false),
+            deduplicate: Some(false),
+            max_tool_output_tokens: None,
+        };
+        let mut messages = conversation.clone();
+        assert_eq!(compress(&compression, "gpt-4o", &mut messages), 0);
+        assert_eq!(messages.len(), 7);
+
+        let mut messages = conversation.clone();
+        assert!(compress(&Compression::default(), "gpt-4o", &mut messages) > 0);
+        assert_eq!(
+            texts(&messages),
+            vec![
+                "You are a helpful assistant.",
+                "How is the weather in seattle?",
+                weather,
+                "It is sunny.",
+                "Check again please.",
+                "same output as tool call call_1",
+            ]
+        );
+
+        // tool outputs are compared after they are cut, short ones are kept as they are
+        let compression = Compression {
+            max_tool_output_tokens: Some(2),
+            ..Default::default()
+        };
+        let mut messages = conversation;
+        assert!(compress(&compression, "unknown-model", &mut messages) > 0);
+        assert_eq!(texts(&messages)[2], "Seattle: [truncated]");
+        assert_eq!(texts(&messages)[5], "Seattle: [truncated]");
+    }
+}
@@ -0,0 +1,134 @@
+use crate::configuration::{AllowedHours, Conditions};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, thiserror::Error, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Error {
+    #[error("header {header} is required")]
+    MissingHeader { header: String },
+    #[error("not allowed at hour {hour} (UTC)")]
+    OutsideAllowedHours { hour: u64, start: u64, end: u64 },
+    #[error("none of the user groups is allowed")]
+    UserGroupNotAllowed,
+}
+
+// headers are the request headers the conditions refer to, as captured from the request
+pub fn check(
+    conditions: &Conditions,
+    headers: &HashMap<String, String>,
+    now: SystemTime,
+) -> Result<(), Error> {
+    for header in conditions.required_headers.iter().flatten() {
+        if !headers.contains_key(header) {
+            return Err(Error::MissingHeader {
+                header: header.clone(),
+            });
+        }
+    }
+
+    if let Some(allowed_hours) = conditions.allowed_hours.as_ref() {
+        let hour = now
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs() / 3600 % 24)
+            .unwrap_or_default();
+        if !in_allowed_hours(allowed_hours, hour) {
+            return Err(Error::OutsideAllowedHours {
+                hour,
+                start: allowed_hours.start,
+                end: allowed_hours.end,
+            });
+        }
+    }
+
+    if let Some(user_groups) = conditions.user_groups.as_ref() {
+        // the header holds a comma separated list of the groups of the user
+        let allowed = headers.get(&user_groups.header).is_some_and(|groups| {
+            groups.split(',').any(|group| {
+                user_groups
+                    .allowed
+                    .iter()
+                    .any(|allowed| allowed == group.trim())
+            })
+        });
+        if !allowed {
+            return Err(Error::UserGroupNotAllowed);
+        }
+    }
+
+    Ok(())
+}
+
+// end is excluded, a window with start after end wraps around midnight
+fn in_allowed_hours(allowed_hours: &AllowedHours, hour: u64) -> bool {
+    if allowed_hours.start <= allowed_hours.end {
+        allowed_hours.start <= hour && hour < allowed_hours.end
+    } else {
+        hour >= allowed_hours.start || hour < allowed_hours.end
+    }
+}
+
+// headers the conditions of the prompt targets refer to, captured with the request headers
+pub fn headers<'a>(conditions: impl Iterator<Item = &'a Conditions>) -> Vec<&'a str> {
+    conditions
+        .flat_map(|conditions| {
+            conditions
+                .required_headers
+                .iter()
+                .flatten()
+                .chain(conditions.user_groups.as_ref().map(|groups| &groups.header))
+                .map(|header| header.as_str())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, Error};
+    use crate::configuration::{AllowedHours, Conditions, UserGroups};
+    use std::collections::HashMap;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_conditions() {
+        let conditions = Conditions {
+            required_headers: Some(vec!["x-tenant-id".to_string()]),
+            allowed_hours: Some(AllowedHours { start: 22, end: 6 }),
+            user_groups: Some(UserGroups {
+                header: "x-user-groups".to_string(),
+                allowed: vec!["network-admins".to_string()],
+            }),
+            refusal_message: None,
+        };
+        let headers = HashMap::from([
+            ("x-tenant-id".to_string(), "acme".to_string()),
+            (
+                "x-user-groups".to_string(),
+                "support, network-admins".to_string(),
+            ),
+        ]);
+        let at_hour = |hour: u64| UNIX_EPOCH + Duration::from_secs(86400 * 100 + hour * 3600);
+
+        assert!(check(&conditions, &headers, at_hour(23)).is_ok());
+        assert!(check(&conditions, &headers, at_hour(2)).is_ok());
+        assert!(matches!(
+            check(&conditions, &headers, at_hour(12)),
+            Err(Error::OutsideAllowedHours { hour: 12, .. })
+        ));
+
+        let mut without_tenant = headers.clone();
+        without_tenant.remove("x-tenant-id");
+        assert!(matches!(
+            check(&conditions, &without_tenant, at_hour(23)),
+            Err(Error::MissingHeader { .. })
+        ));
+
+        let mut support_only = headers.clone();
+        support_only.insert("x-user-groups".to_string(), "support".to_string());
+        assert!(matches!(
+            check(&conditions, &support_only, at_hour(23)),
+            Err(Error::UserGroupNotAllowed)
+        ));
+    }
+}
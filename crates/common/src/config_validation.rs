@@ -0,0 +1,1253 @@
+use crate::configuration::{
+    Configuration, EndpointAuth, EndpointDetails, GuardAction, GuardOptions, GuardType,
+    PipelineStage, PromptTarget,
+};
+use crate::consts::{SECRET_ENV_PREFIX, SECRET_FILE_PREFIX};
+use crate::path::validate_path_template;
+use serde_yaml::Value;
+use std::collections::HashSet;
+use std::fmt::Display;
+
+// A problem found in the curve config, located in the yaml document when possible. Line and
+// column are one based.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => {
+                write!(f, "line {}, column {}: {}", line, column, self.message)
+            }
+            _ => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl From<serde_yaml::Error> for ConfigError {
+    fn from(error: serde_yaml::Error) -> Self {
+        let location = error.location();
+        ConfigError {
+            line: location.as_ref().map(|location| location.line()),
+            column: location.as_ref().map(|location| location.column()),
+            message: error.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+type Path = Vec<PathSegment>;
+
+fn key(key: &str) -> PathSegment {
+    PathSegment::Key(key.to_string())
+}
+
+fn path_string(path: &[PathSegment]) -> String {
+    let mut path_str = String::new();
+    for segment in path {
+        match segment {
+            PathSegment::Key(key) if path_str.is_empty() => path_str.push_str(key),
+            PathSegment::Key(key) => path_str.push_str(&format!(".{}", key)),
+            PathSegment::Index(index) => path_str.push_str(&format!("[{}]", index)),
+        }
+    }
+    path_str
+}
+
+// Parses the curve config and checks it for problems that serde can't catch, all problems are
+// reported at once so that a config can be fixed in one go.
+pub fn parse(config_bytes: &[u8]) -> Result<Configuration, Vec<ConfigError>> {
+    let config_str = String::from_utf8_lossy(config_bytes);
+    let config: Configuration = serde_yaml::from_str(&config_str).map_err(|e| vec![e.into()])?;
+    let raw_config: Value = serde_yaml::from_str(&config_str).map_err(|e| vec![e.into()])?;
+
+    let mut problems: Vec<(Path, String)> = Vec::new();
+    match serde_yaml::to_value(&config) {
+        Ok(known_config) => unknown_fields(&raw_config, &known_config, &mut vec![], &mut problems),
+        Err(e) => problems.push((vec![], e.to_string())),
+    }
+    check_references(&config, &mut problems);
+    check_secrets(&config, &mut problems);
+
+    if problems.is_empty() {
+        return Ok(config);
+    }
+
+    Err(config_errors(&config_str, problems))
+}
+
+// Checks a candidate prompt target as if it was added to the config, a prompt target of the same
+// name is replaced. Problems elsewhere in the config are not reported.
+pub fn check_prompt_target(
+    config: &Configuration,
+    prompt_target_bytes: &[u8],
+) -> Result<PromptTarget, Vec<ConfigError>> {
+    let prompt_target_str = String::from_utf8_lossy(prompt_target_bytes);
+    let prompt_target: PromptTarget =
+        serde_yaml::from_str(&prompt_target_str).map_err(|e| vec![e.into()])?;
+    let raw_prompt_target: Value =
+        serde_yaml::from_str(&prompt_target_str).map_err(|e| vec![e.into()])?;
+
+    let mut problems: Vec<(Path, String)> = Vec::new();
+    match serde_yaml::to_value(&prompt_target) {
+        Ok(known_prompt_target) => unknown_fields(
+            &raw_prompt_target,
+            &known_prompt_target,
+            &mut vec![],
+            &mut problems,
+        ),
+        Err(e) => problems.push((vec![], e.to_string())),
+    }
+    check_parameters(&prompt_target, &mut problems);
+
+    let mut config = config.clone();
+    let prompt_targets = config.prompt_targets.get_or_insert_with(Vec::new);
+    prompt_targets.retain(|pt| pt.name != prompt_target.name);
+    prompt_targets.push(prompt_target.clone());
+    let candidate_index = prompt_targets.len() - 1;
+
+    let mut config_problems = Vec::new();
+    check_references(&config, &mut config_problems);
+    for (path, message) in config_problems {
+        if let [PathSegment::Key(field), PathSegment::Index(index), path @ ..] = path.as_slice() {
+            if field == "prompt_targets" && *index == candidate_index {
+                problems.push((path.to_vec(), message));
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(prompt_target);
+    }
+
+    Err(config_errors(&prompt_target_str, problems))
+}
+
+// descriptions are what function calling matches prompts against, short ones match poorly
+const MIN_DESCRIPTION_WORDS: usize = 4;
+
+// things that don't stop a prompt target from loading but are likely to hurt intent matching
+pub fn prompt_target_warnings(prompt_target: &PromptTarget) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if prompt_target.description.split_whitespace().count() < MIN_DESCRIPTION_WORDS {
+        warnings.push(format!(
+            "description has fewer than {} words, prompts may not match it reliably",
+            MIN_DESCRIPTION_WORDS
+        ));
+    }
+    for parameter in prompt_target.parameters.iter().flatten() {
+        if parameter.description.trim().is_empty() {
+            warnings.push(format!("parameter {} has no description", parameter.name));
+        }
+    }
+    warnings
+}
+
+fn config_errors(config_str: &str, problems: Vec<(Path, String)>) -> Vec<ConfigError> {
+    problems
+        .into_iter()
+        .map(|(path, message)| {
+            let location = locate(config_str, &path);
+            ConfigError {
+                line: location.map(|(line, _)| line),
+                column: location.map(|(_, column)| column),
+                message: match path.is_empty() {
+                    true => message,
+                    false => format!("{}: {}", path_string(&path), message),
+                },
+            }
+        })
+        .collect()
+}
+
+// parameter problems are only reported for candidate prompt targets, deployed configs that have
+// them keep loading
+fn check_parameters(prompt_target: &PromptTarget, problems: &mut Vec<(Path, String)>) {
+    let mut parameters = HashSet::new();
+    for (index, parameter) in prompt_target.parameters.iter().flatten().enumerate() {
+        let path = vec![key("parameters"), PathSegment::Index(index)];
+        if !parameters.insert(parameter.name.as_str()) {
+            problems.push((
+                [path.clone(), vec![key("name")]].concat(),
+                format!("duplicate parameter {}", parameter.name),
+            ));
+        }
+        if let (Some(default), Some(enum_values)) =
+            (parameter.default.as_ref(), parameter.enum_values.as_ref())
+        {
+            if !enum_values.contains(default) {
+                problems.push((
+                    [path, vec![key("default")]].concat(),
+                    format!("default {} is not one of the enum values", default),
+                ));
+            }
+        }
+    }
+}
+
+// fields that are in the yaml document but not in the configuration they were deserialized into
+fn unknown_fields(raw: &Value, known: &Value, path: &mut Path, problems: &mut Vec<(Path, String)>) {
+    match (raw, known) {
+        (Value::Mapping(raw), Value::Mapping(known)) => {
+            for (field, raw_value) in raw {
+                let field_str = match field.as_str() {
+                    Some(field_str) => field_str,
+                    None => continue,
+                };
+                path.push(key(field_str));
+                match known.get(field) {
+                    Some(known_value) => unknown_fields(raw_value, known_value, path, problems),
+                    None => problems.push((path.clone(), "unknown field".to_string())),
+                }
+                path.pop();
+            }
+        }
+        (Value::Sequence(raw), Value::Sequence(known)) => {
+            for (index, (raw_value, known_value)) in raw.iter().zip(known.iter()).enumerate() {
+                path.push(PathSegment::Index(index));
+                unknown_fields(raw_value, known_value, path, problems);
+                path.pop();
+            }
+        }
+        (Value::Tagged(raw), known) => unknown_fields(&raw.value, known, path, problems),
+        (raw, Value::Tagged(known)) => unknown_fields(raw, &known.value, path, problems),
+        _ => {}
+    }
+}
+
+// guards of the type among the input and output guards with their path in the config
+fn guards_of_type<'a>(
+    config: &'a Configuration,
+    guard_type: &GuardType,
+) -> Vec<(Path, &'a GuardOptions)> {
+    let Some(prompt_guards) = config.prompt_guards.as_ref() else {
+        return Vec::new();
+    };
+    [
+        ("input_guards", Some(&prompt_guards.input_guards)),
+        ("output_guards", prompt_guards.output_guards.as_ref()),
+    ]
+    .into_iter()
+    .filter_map(|(guards_key, guards)| {
+        let guard = guards?.get(guard_type)?;
+        Some((
+            vec![
+                key("prompt_guards"),
+                key(guards_key),
+                key(&guard_type.to_string()),
+            ],
+            guard,
+        ))
+    })
+    .collect()
+}
+
+fn check_references(config: &Configuration, problems: &mut Vec<(Path, String)>) {
+    if let Some(threshold) = config
+        .overrides
+        .as_ref()
+        .and_then(|overrides| overrides.prompt_target_intent_matching_threshold)
+    {
+        if !(0.0..=1.0).contains(&threshold) {
+            problems.push((
+                vec![
+                    key("overrides"),
+                    key("prompt_target_intent_matching_threshold"),
+                ],
+                format!("threshold {} is not between 0 and 1", threshold),
+            ));
+        }
+    }
+
+    if let Some(sampling_rate) = config.audit.as_ref().and_then(|audit| audit.sampling_rate) {
+        if !(0.0..=1.0).contains(&sampling_rate) {
+            problems.push((
+                vec![key("audit"), key("sampling_rate")],
+                format!("sampling rate {} is not between 0 and 1", sampling_rate),
+            ));
+        }
+    }
+
+    if let Some(sampling_rate) = config
+        .debug_capture
+        .as_ref()
+        .and_then(|debug_capture| debug_capture.sampling_rate)
+    {
+        if !(0.0..=1.0).contains(&sampling_rate) {
+            problems.push((
+                vec![key("debug_capture"), key("sampling_rate")],
+                format!("sampling rate {} is not between 0 and 1", sampling_rate),
+            ));
+        }
+    }
+
+    for (index, fault) in config.fault_injection.iter().flatten().enumerate() {
+        let path = vec![key("fault_injection"), PathSegment::Index(index)];
+        for (field, probability) in [
+            ("abort_probability", fault.abort_probability),
+            ("delay_probability", fault.delay_probability),
+        ] {
+            if let Some(probability) = probability {
+                if !(0.0..=1.0).contains(&probability) {
+                    problems.push((
+                        [path.clone(), vec![key(field)]].concat(),
+                        format!("probability {} is not between 0 and 1", probability),
+                    ));
+                }
+            }
+        }
+        if !(400..=599).contains(&fault.abort_status()) {
+            problems.push((
+                [path.clone(), vec![key("abort_status")]].concat(),
+                format!(
+                    "abort status {} is not an error status",
+                    fault.abort_status()
+                ),
+            ));
+        }
+        if fault.delay_probability.is_some() && fault.delay_ms.is_none() {
+            problems.push((
+                [path, vec![key("delay_ms")]].concat(),
+                "a delay probability requires delay_ms".to_string(),
+            ));
+        }
+    }
+
+    if let Some(mirroring) = config.mirroring.as_ref() {
+        if let Some(sampling_rate) = mirroring.sampling_rate {
+            if !(0.0..=1.0).contains(&sampling_rate) {
+                problems.push((
+                    vec![key("mirroring"), key("sampling_rate")],
+                    format!("sampling rate {} is not between 0 and 1", sampling_rate),
+                ));
+            }
+        }
+        if config.audit.is_none() {
+            problems.push((
+                vec![key("mirroring")],
+                "mirroring requires an audit sink for the responses".to_string(),
+            ));
+        }
+    }
+
+    for (path, guard) in guards_of_type(config, &GuardType::Moderation) {
+        if guard.action == Some(GuardAction::Mask) {
+            problems.push((
+                [path.clone(), vec![key("action")]].concat(),
+                "moderation guard can only block or monitor".to_string(),
+            ));
+        }
+        if guard.llm_provider.is_none() {
+            problems.push((
+                path.clone(),
+                "moderation guard requires an llm_provider".to_string(),
+            ));
+        }
+        for (category, threshold) in guard.thresholds.iter().flatten() {
+            if !(0.0..=1.0).contains(threshold) {
+                problems.push((
+                    [path.clone(), vec![key("thresholds"), key(category)]].concat(),
+                    format!("threshold {} is not between 0 and 1", threshold),
+                ));
+            }
+        }
+    }
+
+    for (path, guard) in guards_of_type(config, &GuardType::DenyList) {
+        let patterns = guard.patterns.iter().flatten();
+        if guard
+            .words
+            .iter()
+            .flatten()
+            .chain(patterns)
+            .next()
+            .is_none()
+        {
+            problems.push((
+                path.clone(),
+                "deny_list guard requires words or patterns".to_string(),
+            ));
+        }
+        for (index, pattern) in guard.patterns.iter().flatten().enumerate() {
+            if let Err(e) = regex::Regex::new(pattern) {
+                problems.push((
+                    [
+                        path.clone(),
+                        vec![key("patterns"), PathSegment::Index(index)],
+                    ]
+                    .concat(),
+                    format!("invalid pattern: {}", e),
+                ));
+            }
+        }
+    }
+
+    let mut llm_providers = HashSet::new();
+    for (index, llm_provider) in config.llm_providers.iter().enumerate() {
+        if !llm_providers.insert(llm_provider.name.as_str()) {
+            problems.push((
+                vec![key("llm_providers"), PathSegment::Index(index), key("name")],
+                format!("duplicate llm provider {}", llm_provider.name),
+            ));
+        }
+    }
+    let mut check_llm_provider = |path: Path, name: &str| {
+        if !llm_providers.contains(name) {
+            problems.push((
+                path,
+                format!("llm provider {} not found in llm_providers", name),
+            ));
+        }
+    };
+
+    if let Some(llm_provider) = config
+        .function_calling
+        .as_ref()
+        .and_then(|function_calling| function_calling.llm_provider.as_ref())
+    {
+        check_llm_provider(
+            vec![key("function_calling"), key("llm_provider")],
+            llm_provider,
+        );
+    }
+    if let Some(embedding_provider) = config.embedding_provider.as_ref() {
+        check_llm_provider(
+            vec![key("embedding_provider"), key("name")],
+            &embedding_provider.name,
+        );
+    }
+    for (experiment_index, experiment) in config.experiments.iter().flatten().enumerate() {
+        for (arm_index, arm) in experiment.arms.iter().enumerate() {
+            check_llm_provider(
+                vec![
+                    key("experiments"),
+                    PathSegment::Index(experiment_index),
+                    key("arms"),
+                    PathSegment::Index(arm_index),
+                    key("llm_provider"),
+                ],
+                &arm.llm_provider,
+            );
+        }
+    }
+    if let Some(model_aliases) = config.model_aliases.as_ref() {
+        for (alias, llm_provider) in model_aliases.aliases.iter() {
+            check_llm_provider(
+                vec![key("model_aliases"), key("aliases"), key(alias)],
+                llm_provider,
+            );
+        }
+    }
+    if let Some(provider_overrides) = config.provider_overrides.as_ref() {
+        for (index, llm_provider) in provider_overrides
+            .llm_providers
+            .iter()
+            .flatten()
+            .enumerate()
+        {
+            check_llm_provider(
+                vec![
+                    key("provider_overrides"),
+                    key("llm_providers"),
+                    PathSegment::Index(index),
+                ],
+                llm_provider,
+            );
+        }
+    }
+    if let Some(session_affinity) = config.session_affinity.as_ref() {
+        for (index, llm_provider) in session_affinity.llm_providers.iter().enumerate() {
+            check_llm_provider(
+                vec![
+                    key("session_affinity"),
+                    key("llm_providers"),
+                    PathSegment::Index(index),
+                ],
+                llm_provider,
+            );
+        }
+    }
+    if let Some(mirroring) = config.mirroring.as_ref() {
+        check_llm_provider(
+            vec![key("mirroring"), key("llm_provider")],
+            &mirroring.llm_provider,
+        );
+    }
+    if let Some(summarization) = config.summarization.as_ref() {
+        check_llm_provider(
+            vec![key("summarization"), key("llm_provider")],
+            &summarization.llm_provider,
+        );
+    }
+    for (path, guard) in guards_of_type(config, &GuardType::Moderation) {
+        if let Some(llm_provider) = guard.llm_provider.as_ref() {
+            check_llm_provider([path, vec![key("llm_provider")]].concat(), llm_provider);
+        }
+    }
+    for (index, prompt_target) in config.prompt_targets.iter().flatten().enumerate() {
+        if let Some(llm_provider) = prompt_target.llm_provider.as_ref() {
+            check_llm_provider(
+                vec![
+                    key("prompt_targets"),
+                    PathSegment::Index(index),
+                    key("llm_provider"),
+                ],
+                llm_provider,
+            );
+        }
+    }
+
+    let mut groups = HashSet::new();
+    for (index, group) in config.prompt_target_groups.iter().flatten().enumerate() {
+        if !groups.insert(group.name.as_str()) {
+            problems.push((
+                vec![
+                    key("prompt_target_groups"),
+                    PathSegment::Index(index),
+                    key("name"),
+                ],
+                format!("duplicate prompt target group {}", group.name),
+            ));
+        }
+    }
+
+    let mut prompt_targets = HashSet::new();
+    for (index, prompt_target) in config.prompt_targets.iter().flatten().enumerate() {
+        let path = vec![key("prompt_targets"), PathSegment::Index(index)];
+        if !prompt_targets.insert(prompt_target.name.as_str()) {
+            problems.push((
+                [path.clone(), vec![key("name")]].concat(),
+                format!("duplicate prompt target {}", prompt_target.name),
+            ));
+        }
+        // aliases are offered to function calling next to the prompt target names
+        for (alias_index, alias) in prompt_target.aliases.iter().flatten().enumerate() {
+            if !prompt_targets.insert(alias.name.as_str()) {
+                problems.push((
+                    [
+                        path.clone(),
+                        vec![key("aliases"), PathSegment::Index(alias_index), key("name")],
+                    ]
+                    .concat(),
+                    format!("duplicate prompt target {}", alias.name),
+                ));
+            }
+        }
+
+        // the cluster and path of a call only come from the config, the arguments of a tool call
+        // can only fill in the params of the path
+        let endpoint_problems = |endpoint_path: Path, endpoint: &EndpointDetails| {
+            let mut problems = Vec::new();
+            let known_endpoint = config
+                .endpoints
+                .as_ref()
+                .is_some_and(|endpoints| endpoints.contains_key(&endpoint.name));
+            if !known_endpoint {
+                problems.push((
+                    [path.clone(), endpoint_path.clone(), vec![key("name")]].concat(),
+                    format!("endpoint {} not found in endpoints", endpoint.name),
+                ));
+            }
+            if let Some(Err(e)) = endpoint.path.as_deref().map(validate_path_template) {
+                problems.push((
+                    [path.clone(), endpoint_path, vec![key("path")]].concat(),
+                    format!("invalid endpoint path: {}", e),
+                ));
+            }
+            problems
+        };
+        if let Some(endpoint) = prompt_target.endpoint.as_ref() {
+            problems.extend(endpoint_problems(vec![key("endpoint")], endpoint));
+        }
+        let mut steps = HashSet::new();
+        for (step_index, step) in prompt_target.steps.iter().flatten().enumerate() {
+            let step_path = vec![key("steps"), PathSegment::Index(step_index)];
+            problems.extend(endpoint_problems(
+                [step_path.clone(), vec![key("endpoint")]].concat(),
+                &step.endpoint,
+            ));
+            if !steps.insert(step.name.as_str()) {
+                problems.push((
+                    [path.clone(), step_path.clone(), vec![key("name")]].concat(),
+                    format!("duplicate step {}", step.name),
+                ));
+            }
+            // chains only go forward, they can't loop
+            if let Some(next) = step.next.as_ref() {
+                let later_steps = prompt_target.steps.iter().flatten().skip(step_index + 1);
+                if !later_steps.map(|step| &step.name).any(|name| name == next) {
+                    problems.push((
+                        [path.clone(), step_path, vec![key("next")]].concat(),
+                        format!("next step {} not found after step {}", next, step.name),
+                    ));
+                }
+            }
+        }
+
+        if prompt_target.shadow.unwrap_or_default() {
+            if prompt_target.endpoint.is_none() {
+                problems.push((
+                    [path.clone(), vec![key("shadow")]].concat(),
+                    "shadow prompt target must have an endpoint".to_string(),
+                ));
+            }
+            if prompt_target.default.unwrap_or_default() {
+                problems.push((
+                    [path.clone(), vec![key("shadow")]].concat(),
+                    "default prompt target can't be a shadow target".to_string(),
+                ));
+            }
+        }
+
+        if let Some(async_operation) = prompt_target.async_operation.as_ref() {
+            let known_status_target = config
+                .prompt_targets
+                .iter()
+                .flatten()
+                .any(|pt| pt.name == async_operation.status_target);
+            if !known_status_target {
+                problems.push((
+                    [
+                        path.clone(),
+                        vec![key("async_operation"), key("status_target")],
+                    ]
+                    .concat(),
+                    format!(
+                        "status target {} not found in prompt_targets",
+                        async_operation.status_target
+                    ),
+                ));
+            }
+        }
+
+        if let Some(allowed_hours) = prompt_target
+            .conditions
+            .as_ref()
+            .and_then(|conditions| conditions.allowed_hours.as_ref())
+        {
+            if allowed_hours.start == allowed_hours.end {
+                problems.push((
+                    [path.clone(), vec![key("conditions"), key("allowed_hours")]].concat(),
+                    "allowed hours start and end must differ".to_string(),
+                ));
+            }
+        }
+
+        if let Some(group) = prompt_target.group.as_ref() {
+            if !groups.contains(group.as_str()) {
+                problems.push((
+                    [path, vec![key("group")]].concat(),
+                    format!(
+                        "prompt target group {} not found in prompt_target_groups",
+                        group
+                    ),
+                ));
+            }
+        }
+    }
+
+    if let Some(access_control) = config.access_control.as_ref() {
+        for (index, client) in access_control.clients.iter().enumerate() {
+            for (target_index, prompt_target) in client.prompt_targets.iter().enumerate() {
+                if !prompt_targets.contains(prompt_target.as_str()) {
+                    problems.push((
+                        vec![
+                            key("access_control"),
+                            key("clients"),
+                            PathSegment::Index(index),
+                            key("prompt_targets"),
+                            PathSegment::Index(target_index),
+                        ],
+                        format!(
+                            "prompt target {} not found in prompt_targets",
+                            prompt_target
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+
+    if let Some(jwt_auth) = config.jwt_auth.as_ref() {
+        let known_endpoint = config
+            .endpoints
+            .as_ref()
+            .is_some_and(|endpoints| endpoints.contains_key(&jwt_auth.jwks.endpoint));
+        if !known_endpoint {
+            problems.push((
+                vec![key("jwt_auth"), key("jwks"), key("endpoint")],
+                format!("endpoint {} not found in endpoints", jwt_auth.jwks.endpoint),
+            ));
+        }
+    }
+
+    if let Some(virtual_keys) = config.virtual_keys.as_ref() {
+        let mut names = HashSet::new();
+        let mut keys = HashSet::new();
+        for (index, virtual_key) in virtual_keys.keys.iter().enumerate() {
+            let path = vec![key("virtual_keys"), key("keys"), PathSegment::Index(index)];
+            if !names.insert(virtual_key.name.as_str()) {
+                problems.push((
+                    [path.clone(), vec![key("name")]].concat(),
+                    format!("duplicate virtual key {}", virtual_key.name),
+                ));
+            }
+            if !keys.insert(virtual_key.key.as_str()) {
+                problems.push((
+                    [path, vec![key("key")]].concat(),
+                    format!("key of virtual key {} is already in use", virtual_key.name),
+                ));
+            }
+        }
+
+        let jwt_on_authorization = config
+            .jwt_auth
+            .as_ref()
+            .is_some_and(|jwt_auth| jwt_auth.header.is_none());
+        if jwt_on_authorization && virtual_keys.header.is_none() {
+            problems.push((
+                vec![key("virtual_keys")],
+                "virtual keys and jwt_auth both read the authorization header, set a header for \
+                 one of them"
+                    .to_string(),
+            ));
+        }
+    }
+
+    if let Some(pipeline) = config.pipeline.as_ref() {
+        let mut last_position = None;
+        for (index, stage) in pipeline.stages.iter().enumerate() {
+            let path = vec![key("pipeline"), key("stages"), PathSegment::Index(index)];
+            let position = PipelineStage::ALL.iter().position(|s| s == stage);
+            if position <= last_position {
+                problems.push((
+                    path,
+                    "stages are repeated or out of order, they run as follow_up, group_matching, \
+                     function_calling"
+                        .to_string(),
+                ));
+            }
+            last_position = last_position.max(position);
+        }
+        if pipeline.stages.contains(&PipelineStage::GroupMatching)
+            && !pipeline.stages.contains(&PipelineStage::FunctionCalling)
+        {
+            problems.push((
+                vec![key("pipeline"), key("stages")],
+                "group_matching needs the function_calling stage".to_string(),
+            ));
+        }
+    }
+
+    let mut hooks = HashSet::new();
+    for (index, hook) in config.hooks.iter().flatten().enumerate() {
+        let path = vec![key("hooks"), PathSegment::Index(index)];
+        if !hooks.insert(hook.name.as_str()) {
+            problems.push((
+                [path.clone(), vec![key("name")]].concat(),
+                format!("duplicate hook {}", hook.name),
+            ));
+        }
+        let known_endpoint = config
+            .endpoints
+            .as_ref()
+            .is_some_and(|endpoints| endpoints.contains_key(&hook.endpoint.name));
+        if !known_endpoint {
+            problems.push((
+                [path, vec![key("endpoint"), key("name")]].concat(),
+                format!("endpoint {} not found in endpoints", hook.endpoint.name),
+            ));
+        }
+    }
+}
+
+// Secrets with an env: or file: source are resolved when the config is rendered. A reference that
+// is still in the config would be sent upstream as the credential itself.
+fn check_secrets(config: &Configuration, problems: &mut Vec<(Path, String)>) {
+    let mut secrets: Vec<(Path, &str)> = Vec::new();
+    for (index, llm_provider) in config.llm_providers.iter().enumerate() {
+        if let Some(access_key) = llm_provider.access_key.as_deref() {
+            let path = vec![
+                key("llm_providers"),
+                PathSegment::Index(index),
+                key("access_key"),
+            ];
+            secrets.push((path, access_key));
+        }
+    }
+    for (index, prompt_target) in config.prompt_targets.iter().flatten().enumerate() {
+        if let Some(endpoint) = prompt_target.endpoint.as_ref() {
+            let path = vec![
+                key("prompt_targets"),
+                PathSegment::Index(index),
+                key("endpoint"),
+            ];
+            secrets.extend(endpoint_secret(endpoint, path));
+        }
+    }
+    if let Some(sink) = config
+        .access_log
+        .as_ref()
+        .and_then(|access_log| access_log.sink.as_ref())
+    {
+        secrets.extend(endpoint_secret(sink, vec![key("access_log"), key("sink")]));
+    }
+    if let Some(audit) = config.audit.as_ref() {
+        let path = vec![key("audit"), key("audit_sink")];
+        secrets.extend(endpoint_secret(&audit.audit_sink, path));
+    }
+    if let Some(debug_capture) = config.debug_capture.as_ref() {
+        let path = vec![key("debug_capture"), key("sink")];
+        secrets.extend(endpoint_secret(&debug_capture.sink, path));
+    }
+    if let Some(notifications) = config.notifications.as_ref() {
+        let path = vec![key("notifications"), key("webhook")];
+        secrets.extend(endpoint_secret(&notifications.webhook, path));
+    }
+    for (index, virtual_key) in config
+        .virtual_keys
+        .iter()
+        .flat_map(|v| v.keys.iter())
+        .enumerate()
+    {
+        let path = vec![
+            key("virtual_keys"),
+            key("keys"),
+            PathSegment::Index(index),
+            key("key"),
+        ];
+        secrets.push((path, virtual_key.key.as_str()));
+    }
+    for (index, hook) in config.hooks.iter().flatten().enumerate() {
+        let path = vec![key("hooks"), PathSegment::Index(index), key("endpoint")];
+        secrets.extend(endpoint_secret(&hook.endpoint, path));
+    }
+
+    for (path, secret) in secrets {
+        if secret.starts_with(SECRET_ENV_PREFIX) || secret.starts_with(SECRET_FILE_PREFIX) {
+            problems.push((
+                path,
+                format!(
+                    "secret {} was not resolved when the config was rendered",
+                    secret
+                ),
+            ));
+        }
+    }
+}
+
+fn endpoint_secret(endpoint: &EndpointDetails, path: Path) -> Option<(Path, &str)> {
+    let (field, secret) = match endpoint.auth.as_ref()? {
+        EndpointAuth::Bearer { token } => ("token", token),
+        EndpointAuth::ApiKey { value, .. } => ("value", value),
+        EndpointAuth::Passthrough { .. } => return None,
+    };
+    Some((
+        [path, vec![key("auth"), key(field)]].concat(),
+        secret.as_str(),
+    ))
+}
+
+// Best effort line and column of a path in the yaml document, only block style mappings and
+// sequences are followed. When a segment can't be found the location of its parent is returned.
+fn locate(config: &str, path: &[PathSegment]) -> Option<(usize, usize)> {
+    let lines: Vec<&str> = config.lines().collect();
+    let mut location = None;
+    // column of the parent node, children are indented further
+    let mut parent_column: Option<usize> = None;
+    let mut start = 0;
+    // the first line of a sequence item also holds the first key of the item
+    let mut in_item = false;
+
+    for segment in path {
+        let mut found = None;
+        let mut child_column = None;
+        let mut item_index = 0;
+
+        for (line_index, line) in lines.iter().enumerate().skip(start) {
+            let content = line.trim_start();
+            if content.is_empty() || content.starts_with('#') {
+                continue;
+            }
+            let column = line.len() - content.len();
+            let item_line = in_item && line_index == start;
+            if !item_line && parent_column.is_some_and(|parent_column| column <= parent_column) {
+                break;
+            }
+
+            match segment {
+                PathSegment::Key(key) => {
+                    // keys of sequence items follow the dash
+                    let key_content = content.trim_start_matches(['-', ' ']);
+                    let key_column = column + content.len() - key_content.len();
+                    if *child_column.get_or_insert(key_column) != key_column {
+                        continue;
+                    }
+                    if key_content.starts_with(&format!("{}:", key))
+                        || key_content.starts_with(&format!("\"{}\":", key))
+                    {
+                        found = Some((line_index, key_column));
+                        break;
+                    }
+                }
+                PathSegment::Index(index) => {
+                    if !content.starts_with('-') {
+                        continue;
+                    }
+                    if *child_column.get_or_insert(column) != column {
+                        continue;
+                    }
+                    if item_index == *index {
+                        found = Some((line_index, column));
+                        break;
+                    }
+                    item_index += 1;
+                }
+            }
+        }
+
+        let (line_index, column) = match found {
+            Some(found) => found,
+            None => break,
+        };
+        location = Some((line_index + 1, column + 1));
+        parent_column = Some(column);
+        in_item = matches!(segment, PathSegment::Index(_));
+        start = match in_item {
+            true => line_index,
+            false => line_index + 1,
+        };
+    }
+
+    location
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_prompt_target, locate, parse, prompt_target_warnings, PathSegment};
+
+    const CONFIG: &str = r#"
+version: v0.1
+
+listener:
+  address: 0.0.0.0
+  port: 10000
+  message_format: huggingface
+
+endpoints:
+  app_server:
+    endpoint: 127.0.0.1:80
+
+llm_providers:
+  - name: gpt-4o
+    provider_interface: openai
+    model: gpt-4o
+
+prompt_targets:
+  - name: weather_forecast
+    description: get the weather forecast
+    endpoint:
+      name: app_server
+"#;
+
+    const INVALID_PROMPT_TARGET: &str = r#"  - name: weather_forecast
+    description: get the weather forecast
+    endpoint:
+      name: weather_server
+    timeout: 5
+"#;
+
+    #[test]
+    fn test_valid_config() {
+        assert!(parse(CONFIG.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn test_all_errors_are_reported() {
+        let config = format!("{}{}", CONFIG, INVALID_PROMPT_TARGET);
+        let errors: Vec<String> = parse(config.as_bytes())
+            .unwrap_err()
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+
+        assert_eq!(
+            errors,
+            vec![
+                "line 27, column 5: prompt_targets[1].timeout: unknown field",
+                "line 23, column 5: prompt_targets[1].name: duplicate prompt target \
+                 weather_forecast",
+                "line 26, column 7: prompt_targets[1].endpoint.name: endpoint weather_server \
+                 not found in endpoints",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unresolved_secrets() {
+        let config = CONFIG.replace(
+            "    model: gpt-4o\n",
+            "    model: gpt-4o\n    access_key: env:OPENAI_API_KEY\n",
+        );
+        let errors: Vec<String> = parse(config.as_bytes())
+            .unwrap_err()
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+
+        assert_eq!(
+            errors,
+            vec![
+                "line 17, column 5: llm_providers[0].access_key: secret env:OPENAI_API_KEY was \
+                 not resolved when the config was rendered"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pipeline_stages() {
+        let config = format!(
+            "{}\npipeline:\n  stages:\n    - function_calling\n    - group_matching\n",
+            CONFIG
+        );
+        let errors: Vec<String> = parse(config.as_bytes())
+            .unwrap_err()
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+
+        assert_eq!(
+            errors,
+            vec![
+                "line 27, column 5: pipeline.stages[1]: stages are repeated or out of order, they \
+                 run as follow_up, group_matching, function_calling"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_moderation_guard() {
+        let config = format!(
+            "{}\nprompt_guards:\n  input_guards:\n    moderation:\n      llm_provider: gpt-4\n      \
+             thresholds:\n        violence: 1.5\n",
+            CONFIG
+        );
+        let errors: Vec<String> = parse(config.as_bytes())
+            .unwrap_err()
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+
+        assert_eq!(
+            errors,
+            vec![
+                "line 29, column 9: prompt_guards.input_guards.moderation.thresholds.violence: \
+                 threshold 1.5 is not between 0 and 1",
+                "line 27, column 7: prompt_guards.input_guards.moderation.llm_provider: llm \
+                 provider gpt-4 not found in llm_providers",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fault_injection() {
+        let config = format!(
+            "{}\nfault_injection:\n  - cluster: app_server\n    abort_probability: 1.5\n    \
+             abort_status: 200\n    delay_probability: 0.5\n",
+            CONFIG
+        );
+        let errors: Vec<String> = parse(config.as_bytes())
+            .unwrap_err()
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+
+        assert_eq!(
+            errors,
+            vec![
+                "line 26, column 5: fault_injection[0].abort_probability: probability 1.5 is not \
+                 between 0 and 1",
+                "line 27, column 5: fault_injection[0].abort_status: abort status 200 is not an \
+                 error status",
+                "line 25, column 3: fault_injection[0].delay_ms: a delay probability requires \
+                 delay_ms",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prompt_target_llm_provider() {
+        let config = format!(
+            "{}    llm_provider: gpt-4o-mini\n    model: gpt-4o-mini\n",
+            CONFIG
+        );
+        let errors: Vec<String> = parse(config.as_bytes())
+            .unwrap_err()
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+
+        assert_eq!(
+            errors,
+            vec![
+                "line 23, column 5: prompt_targets[0].llm_provider: llm provider gpt-4o-mini not \
+                 found in llm_providers",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_chain_steps() {
+        let config = format!(
+            "{}    steps:\n      - name: geocode\n        endpoint:\n          name: geo_server\n      \
+             - name: geocode\n        endpoint:\n          name: app_server\n        next: geocode\n",
+            CONFIG
+        );
+        let errors: Vec<String> = parse(config.as_bytes())
+            .unwrap_err()
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+
+        assert_eq!(
+            errors,
+            vec![
+                "line 26, column 11: prompt_targets[0].steps[0].endpoint.name: endpoint \
+                 geo_server not found in endpoints",
+                "line 27, column 9: prompt_targets[0].steps[1].name: duplicate step geocode",
+                "line 30, column 9: prompt_targets[0].steps[1].next: next step geocode not found \
+                 after step geocode",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_endpoint_paths() {
+        let config = format!(
+            "{}      path: /agent/../admin\n    steps:\n      - name: geocode\n        endpoint:\n          \
+             name: app_server\n          path: http://internal/admin\n",
+            CONFIG
+        );
+        let errors: Vec<String> = parse(config.as_bytes())
+            .unwrap_err()
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+
+        assert_eq!(
+            errors,
+            vec![
+                "line 23, column 7: prompt_targets[0].endpoint.path: invalid endpoint path: path \
+                 must not contain . or .. segments",
+                "line 28, column 11: prompt_targets[0].steps[0].endpoint.path: invalid endpoint \
+                 path: path must start with /",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deny_list_guard() {
+        let config = format!(
+            "{}\nprompt_guards:\n  input_guards:\n    deny_list:\n      words: [darn]\n      \
+             patterns: ['project (falcon']\n  output_guards:\n    deny_list:\n      \
+             action: mask\n",
+            CONFIG
+        );
+        let errors: Vec<String> = parse(config.as_bytes())
+            .unwrap_err()
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with(
+            "line 28, column 7: prompt_guards.input_guards.deny_list.patterns[0]: invalid \
+             pattern: regex parse error"
+        ));
+        assert_eq!(
+            errors[1],
+            "line 30, column 5: prompt_guards.output_guards.deny_list: deny_list guard requires \
+             words or patterns"
+        );
+    }
+
+    #[test]
+    fn test_check_prompt_target() {
+        let config = parse(CONFIG.as_bytes()).unwrap();
+
+        let prompt_target = check_prompt_target(
+            &config,
+            br#"
+name: weather_forecast
+description: get the weather forecast for a city
+endpoint:
+  name: app_server
+parameters:
+  - name: city
+    description: name of the city
+"#,
+        )
+        .unwrap();
+        assert!(prompt_target_warnings(&prompt_target).is_empty());
+
+        let errors: Vec<String> = check_prompt_target(
+            &config,
+            br#"
+name: weather_forecast
+description: weather
+endpoint:
+  name: weather_server
+parameters:
+  - name: unit
+    description: temperature unit
+    enum: [celsius, fahrenheit]
+    default: kelvin
+"#,
+        )
+        .unwrap_err()
+        .iter()
+        .map(|error| error.to_string())
+        .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "line 10, column 5: parameters[0].default: default kelvin is not one of the \
+                 enum values",
+                "line 5, column 3: endpoint.name: endpoint weather_server not found in endpoints",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_yaml_errors_are_located() {
+        let errors = parse(CONFIG.replace("port: 10000", "port: high").as_bytes()).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, Some(6));
+    }
+
+    #[test]
+    fn test_locate() {
+        let path = vec![
+            PathSegment::Key("prompt_targets".to_string()),
+            PathSegment::Index(0),
+            PathSegment::Key("endpoint".to_string()),
+        ];
+        assert_eq!(locate(CONFIG, &path), Some((21, 5)));
+
+        // the location of the closest parent is used for paths that can't be followed
+        let path = vec![
+            PathSegment::Key("listener".to_string()),
+            PathSegment::Key("connect_timeout".to_string()),
+        ];
+        assert_eq!(locate(CONFIG, &path), Some((4, 1)));
+    }
+}
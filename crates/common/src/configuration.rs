@@ -1,10 +1,21 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
 
 use crate::api::open_ai::{
     ChatCompletionTool, FunctionDefinition, FunctionParameter, FunctionParameters, ParameterType,
 };
+use crate::consts::{
+    AUTHORIZATION_HEADER, CURVE_PRIORITY_HEADER, DEFAULT_CALLOUT_MAX_WAIT_MS,
+    DEFAULT_COALESCING_TIMEOUT_SECONDS, DEFAULT_CORS_ALLOWED_HEADERS, DEFAULT_CORS_ALLOWED_METHODS,
+    DEFAULT_FAULT_ABORT_STATUS, DEFAULT_GUARD_MESSAGE, DEFAULT_GUARD_MODEL, DEFAULT_INTENT_MODEL,
+    DEFAULT_JWKS_PATH, DEFAULT_JWKS_TTL_SECONDS, DEFAULT_MAX_DECOMPRESSED_BODY_BYTES,
+    DEFAULT_MAX_QUEUED_REQUESTS, DEFAULT_MAX_RETRY_AFTER_SECONDS, DEFAULT_NOTIFICATION_BATCH_SIZE,
+    DEFAULT_NOTIFICATION_MAX_RETRIES, DEFAULT_OPERATION_ID_FIELD, DEFAULT_OUTPUT_SCHEMA_RETRIES,
+    DEFAULT_QUEUE_TIMEOUT_SECONDS, DEFAULT_REFUSAL_MESSAGE, DEFAULT_SUMMARIZATION_KEEP_MESSAGES,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Configuration {
@@ -16,23 +27,734 @@ pub struct Configuration {
     pub system_prompt: Option<String>,
     pub prompt_guards: Option<PromptGuards>,
     pub prompt_targets: Option<Vec<PromptTarget>>,
+    pub prompt_target_groups: Option<Vec<PromptTargetGroup>>,
     pub error_target: Option<ErrorTargetDetail>,
     pub ratelimits: Option<Vec<Ratelimit>>,
     pub tracing: Option<Tracing>,
     pub mode: Option<GatewayMode>,
+    pub request_limits: Option<RequestLimits>,
+    pub function_calling: Option<FunctionCalling>,
+    pub access_log: Option<AccessLog>,
+    pub audit: Option<Audit>,
+    pub experiments: Option<Vec<Experiment>>,
+    pub costs: Option<Costs>,
+    pub embedding_provider: Option<EmbeddingProviver>,
+    pub model_aliases: Option<ModelAliases>,
+    pub logging: Option<Logging>,
+    pub mirroring: Option<Mirroring>,
+    pub provider_overrides: Option<ProviderOverrides>,
+    pub session_affinity: Option<SessionAffinity>,
+    pub admin: Option<Admin>,
+    pub callout_limits: Option<Vec<CalloutLimit>>,
+    pub request_coalescing: Option<RequestCoalescing>,
+    pub access_control: Option<AccessControl>,
+    pub jwt_auth: Option<JwtAuth>,
+    pub virtual_keys: Option<VirtualKeys>,
+    pub summarization: Option<Summarization>,
+    pub failure_policies: Option<FailurePolicies>,
+    pub pipeline: Option<Pipeline>,
+    pub hooks: Option<Vec<Hook>>,
+    pub provider_backoff: Option<ProviderBackoff>,
+    pub compression: Option<Compression>,
+    pub json_mode: Option<JsonMode>,
+    pub notifications: Option<Notifications>,
+    pub prioritization: Option<Prioritization>,
+    pub cors: Option<Cors>,
+    pub body_encoding: Option<BodyEncoding>,
+    pub debug_capture: Option<DebugCapture>,
+    pub fault_injection: Option<Vec<FaultInjection>>,
+    // models of the model server, the ones of the listener take precedence
+    pub intent_model: Option<String>,
+    pub guard_model: Option<String>,
+}
+
+impl Configuration {
+    // model the model server matches the intent of prompts with
+    pub fn intent_model(&self) -> &str {
+        self.listener
+            .intent_model
+            .as_deref()
+            .or(self.intent_model.as_deref())
+            .unwrap_or(DEFAULT_INTENT_MODEL)
+    }
+
+    // model the model server checks prompts for jailbreaks with
+    pub fn guard_model(&self) -> &str {
+        self.listener
+            .guard_model
+            .as_deref()
+            .or(self.guard_model.as_deref())
+            .unwrap_or(DEFAULT_GUARD_MODEL)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum IntentFallback {
+    // the prompt target the prompt shares the most words with, or else the default prompt target.
+    // Keyword matched prompt targets are called with the default values of their parameters only.
+    #[serde(rename = "keywords")]
+    Keywords,
+    // the default prompt target
+    #[serde(rename = "default_target")]
+    DefaultTarget,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Overrides {
     pub prompt_target_intent_matching_threshold: Option<f64>,
+    // where requests go instead of failing when function calling is unavailable
+    pub intent_fallback: Option<IntentFallback>,
+    // follow ups of at most this many tokens continue with the prompt target of the curve state
+    // instead of being matched against all prompt targets again
+    pub follow_up_max_tokens: Option<usize>,
+    // number of user turns function calling sees for intent matching, the whole conversation when
+    // not set
+    pub intent_history_turns: Option<usize>,
+    // when function calling picks several prompt targets the user is asked which one they meant
+    // instead of the first one being called
+    pub clarify_ambiguous_intent: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Tracing {
     pub sampling_rate: Option<f64>,
+    // percentage of requests traced by envoy
+    pub random_sampling: Option<u32>,
     pub trace_curve _internal: Option<bool>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct AccessLog {
+    // endpoint to POST access log entries to, entries are written to the proxy log when not set
+    pub sink: Option<EndpointDetails>,
+    // names of access log fields whose values are replaced before logging e.g. user_prompt
+    pub redact: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Audit {
+    pub audit_sink: EndpointDetails,
+    // fraction of requests to capture, all requests are captured when not set
+    pub sampling_rate: Option<f64>,
+    // values replaced in captured prompts and completions
+    pub redact: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Costs {
+    pub prices: Vec<ModelPrice>,
+    pub budgets: Option<Vec<Budget>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelPrice {
+    pub model: String,
+    // USD per million tokens
+    pub input_per_million_tokens: f64,
+    pub output_per_million_tokens: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Budget {
+    // same semantics as ratelimit selectors, without a value every header value has its own budget
+    pub selector: Header,
+    // USD per period
+    pub limit: f64,
+    pub period: BudgetPeriod,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BudgetPeriod {
+    #[serde(rename = "day")]
+    Day,
+    #[serde(rename = "month")]
+    Month,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ModelAliases {
+    // virtual model name -> llm provider name
+    pub aliases: HashMap<String, String>,
+    // what to do with models that are not an alias, defaults to routing them as usual
+    pub unknown_model: Option<UnknownModel>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum UnknownModel {
+    #[serde(rename = "default")]
+    #[default]
+    Default,
+    #[serde(rename = "reject")]
+    Reject,
+}
+
+// a sample of chat completions requests is also sent to a secondary llm provider in the background,
+// both responses are written to the audit sink for offline evaluation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mirroring {
+    pub llm_provider: String,
+    // share of requests that are mirrored, all requests when not set
+    pub sampling_rate: Option<f64>,
+}
+
+// the callouts of a sample of requests and their responses are posted to the sink as traces the
+// requests can be replayed from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DebugCapture {
+    pub sink: EndpointDetails,
+    // share of requests that are captured, all requests when not set
+    pub sampling_rate: Option<f64>,
+}
+
+// allowlists for the x-curve-provider and x-curve-model request headers, internal tools use them to
+// pin a request to a provider or model. Overrides that are not listed are rejected with 403.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ProviderOverrides {
+    pub llm_providers: Option<Vec<String>>,
+    pub models: Option<Vec<String>>,
+}
+
+// conversations are kept on one llm provider of the pool, picked by a stable hash of the session
+// header. A session only moves to the next provider of the pool after a provider error.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct SessionAffinity {
+    pub header: String,
+    pub llm_providers: Vec<String>,
+}
+
+// prompt targets each client identity may call, identities that aren't listed may call none
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessControl {
+    // request header carrying the identity of the client
+    pub header: String,
+    pub clients: Vec<ClientAccess>,
+    pub on_unauthorized: Option<OnUnauthorized>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientAccess {
+    pub identity: String,
+    pub prompt_targets: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum OnUnauthorized {
+    // the request is answered with a 403
+    #[serde(rename = "reject")]
+    #[default]
+    Reject,
+    // the request continues as if no prompt target matched
+    #[serde(rename = "llm")]
+    Llm,
+}
+
+// internal /_curve/ routes answered by the prompt gateway, they are only served when this section
+// is set
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Admin {
+    // when set callers have to send it as a bearer token
+    pub token: Option<String>,
+}
+
+// upper bound on the callouts to a cluster that are in flight at once, calls over it are shed and
+// the request fails with 503, or wait for a free slot
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct CalloutLimit {
+    pub cluster: String,
+    pub max_concurrent_calls: usize,
+    pub overflow: Option<CalloutOverflow>,
+    // time a call waits for a free slot before it fails with 503, defaults to 1000
+    pub max_wait_ms: Option<u64>,
+}
+
+impl CalloutLimit {
+    // None when calls over the limit are shed
+    pub fn max_wait(&self) -> Option<Duration> {
+        match self.overflow.unwrap_or_default() {
+            CalloutOverflow::Shed => None,
+            CalloutOverflow::Wait => Some(Duration::from_millis(
+                self.max_wait_ms.unwrap_or(DEFAULT_CALLOUT_MAX_WAIT_MS),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum CalloutOverflow {
+    #[serde(rename = "shed")]
+    #[default]
+    Shed,
+    #[serde(rename = "wait")]
+    Wait,
+}
+
+// faults injected into a share of the callouts to a cluster to test how the gateway copes with it
+// failing or being slow, the callouts go through the fault filter of the internal listener
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct FaultInjection {
+    pub cluster: String,
+    // share of the callouts answered with abort_status without reaching the cluster
+    pub abort_probability: Option<f64>,
+    // defaults to 503
+    pub abort_status: Option<u16>,
+    // share of the callouts held back for delay_ms before they are sent to the cluster
+    pub delay_probability: Option<f64>,
+    pub delay_ms: Option<u64>,
+}
+
+impl FaultInjection {
+    pub fn abort_status(&self) -> u16 {
+        self.abort_status.unwrap_or(DEFAULT_FAULT_ABORT_STATUS)
+    }
+}
+
+// identical non streaming chat completions requests that arrive while the first one is in flight
+// wait for its response instead of being sent to the llm provider again
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct RequestCoalescing {
+    // waiting requests are sent to the llm provider themselves after this long, defaults to 30
+    pub timeout_seconds: Option<u64>,
+}
+
+impl RequestCoalescing {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.timeout_seconds
+                .unwrap_or(DEFAULT_COALESCING_TIMEOUT_SECONDS),
+        )
+    }
+}
+
+// requests hitting a ratelimit, or a provider backing off, are shed or queued by their priority
+// class, taken from the virtual key of the request, the priority header or the default
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Prioritization {
+    // defaults to x-curve -priority
+    pub header: Option<String>,
+    // priority of requests without one, defaults to normal
+    pub default: Option<Priority>,
+    // priorities rejected right away when a limit is hit, the others wait in the queue. Defaults
+    // to low.
+    pub shed: Option<Vec<Priority>>,
+    // queued requests are rejected after this long, defaults to 10
+    pub queue_timeout_seconds: Option<u64>,
+    // requests past it are rejected instead of queued, defaults to 100
+    pub max_queued: Option<usize>,
+}
+
+impl Prioritization {
+    pub fn header(&self) -> &str {
+        self.header.as_deref().unwrap_or(CURVE_PRIORITY_HEADER)
+    }
+
+    pub fn is_shed(&self, priority: Priority) -> bool {
+        match self.shed.as_ref() {
+            Some(shed) => shed.contains(&priority),
+            None => priority == Priority::Low,
+        }
+    }
+
+    pub fn queue_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.queue_timeout_seconds
+                .unwrap_or(DEFAULT_QUEUE_TIMEOUT_SECONDS),
+        )
+    }
+
+    pub fn max_queued(&self) -> usize {
+        self.max_queued.unwrap_or(DEFAULT_MAX_QUEUED_REQUESTS)
+    }
+}
+
+// browser clients calling the gateway from another origin, preflight requests are answered by
+// the gateway and responses carry the cors headers of the origin
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Cors {
+    // origins allowed to call the gateway, * allows any origin
+    pub allowed_origins: Vec<String>,
+    // defaults to authorization and content-type
+    pub allowed_headers: Option<Vec<String>>,
+    // defaults to GET, POST and OPTIONS
+    pub allowed_methods: Option<Vec<String>>,
+    // how long browsers may cache a preflight response, they pick their own default when not set
+    pub max_age_seconds: Option<u64>,
+}
+
+impl Cors {
+    pub fn allowed_headers(&self) -> String {
+        match self.allowed_headers.as_ref() {
+            Some(headers) => headers.join(", "),
+            None => DEFAULT_CORS_ALLOWED_HEADERS.join(", "),
+        }
+    }
+
+    pub fn allowed_methods(&self) -> String {
+        match self.allowed_methods.as_ref() {
+            Some(methods) => methods.join(", "),
+            None => DEFAULT_CORS_ALLOWED_METHODS.join(", "),
+        }
+    }
+}
+
+// gzip and deflate request bodies are decompressed before they are parsed, whether or not this
+// section is set
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct BodyEncoding {
+    // requests decompressing to more are rejected with a 413, defaults to 10485760
+    pub max_decompressed_bytes: Option<usize>,
+}
+
+impl BodyEncoding {
+    pub fn max_decompressed_bytes(&self) -> usize {
+        self.max_decompressed_bytes
+            .unwrap_or(DEFAULT_MAX_DECOMPRESSED_BODY_BYTES)
+    }
+}
+
+// queued requests are admitted high first
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+)]
+pub enum Priority {
+    #[serde(rename = "high")]
+    High,
+    #[default]
+    #[serde(rename = "normal")]
+    Normal,
+    #[serde(rename = "low")]
+    Low,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "high" => Ok(Priority::High),
+            "normal" => Ok(Priority::Normal),
+            "low" => Ok(Priority::Low),
+            _ => Err(format!("unknown priority: {}", s)),
+        }
+    }
+}
+
+// callers have to present a JWT signed with one of the keys of the JWKS, requests without a valid
+// token are rejected before any callout is made
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JwtAuth {
+    pub jwks: Jwks,
+    // expected iss claim, not checked when not set
+    pub issuer: Option<String>,
+    // the aud claim has to contain one of these, not checked when not set
+    pub audiences: Option<Vec<String>>,
+    // request header carrying the token, defaults to the bearer token of the authorization header
+    pub header: Option<String>,
+    // claim -> request header it is forwarded in, so that ratelimit selectors, access control and
+    // prompt target conditions can refer to it. Values sent by the client are replaced.
+    pub claim_headers: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Jwks {
+    // name of the endpoint in endpoints serving the JWKS document
+    pub endpoint: String,
+    // defaults to /.well-known/jwks.json
+    pub path: Option<String>,
+    // the keys are fetched again once they are this old, defaults to 300
+    pub ttl_seconds: Option<u64>,
+}
+
+impl Jwks {
+    pub fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or(DEFAULT_JWKS_PATH)
+    }
+
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_seconds.unwrap_or(DEFAULT_JWKS_TTL_SECONDS))
+    }
+}
+
+// clients authenticate with keys issued by the gateway instead of provider keys, the credentials
+// of the llm providers never leave the gateway
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VirtualKeys {
+    // request header carrying the key, defaults to the bearer token of the authorization header
+    pub header: Option<String>,
+    pub keys: Vec<VirtualKey>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VirtualKey {
+    // ratelimits, budgets and logs refer to the key by name
+    pub name: String,
+    pub key: String,
+    // models the key may call, matched against the model sent to the llm provider. All models
+    // when not set.
+    pub models: Option<Vec<String>>,
+    // token limit of the key on each model it calls
+    pub ratelimit: Option<Limit>,
+    pub budget: Option<VirtualKeyBudget>,
+    // priority of the requests of the key, the priority header is not used for them
+    pub priority: Option<Priority>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VirtualKeyBudget {
+    // USD per period, spend is tracked with the prices of the costs config
+    pub limit: f64,
+    pub period: BudgetPeriod,
+}
+
+// what happens to a request when an internal dependency of the gateway fails
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct FailurePolicies {
+    // function calling on the model server or the function calling llm provider
+    pub function_calling: Option<FailurePolicy>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum FailurePolicy {
+    // the request is answered with the error
+    #[serde(rename = "fail_closed")]
+    #[default]
+    FailClosed,
+    // the prompt continues as if no prompt target matched, to the default prompt target or the
+    // default llm provider
+    #[serde(rename = "fail_open")]
+    FailOpen,
+}
+
+// stages of prompt target resolution, they run in the order of PipelineStage::ALL and the stages
+// left out are skipped. Without a stage resolving a prompt target the request goes to the llm as is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pipeline {
+    pub stages: Vec<PipelineStage>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum PipelineStage {
+    // follow ups continue with the prompt target of the previous turn
+    #[serde(rename = "follow_up")]
+    FollowUp,
+    // a prompt target group is matched before the prompt targets within it
+    #[serde(rename = "group_matching")]
+    GroupMatching,
+    #[serde(rename = "function_calling")]
+    FunctionCalling,
+}
+
+impl PipelineStage {
+    pub const ALL: [PipelineStage; 3] = [
+        PipelineStage::FollowUp,
+        PipelineStage::GroupMatching,
+        PipelineStage::FunctionCalling,
+    ];
+}
+
+// operator endpoints called at a point of the prompt flow, they can rewrite the messages or answer
+// the request themselves. Hooks of the same point run in the order they are configured.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hook {
+    pub name: String,
+    pub point: HookPoint,
+    pub endpoint: EndpointDetails,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum HookPoint {
+    // the request of the client, before the prompt target is resolved and the guards run on it
+    #[serde(rename = "pre_guard")]
+    PreGuard,
+    // a prompt target was resolved and is about to be called
+    #[serde(rename = "post_intent")]
+    PostIntent,
+    // the messages are about to be sent to the llm provider
+    #[serde(rename = "pre_provider")]
+    PreProvider,
+    // the llm response is about to go back to the client, streamed responses skip these hooks
+    #[serde(rename = "post_response")]
+    PostResponse,
+}
+
+// llm providers answering 429 with a retry-after are skipped by default routing until it passed,
+// the client gets the retry-after in a structured error
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderBackoff {
+    // longer retry-afters are capped to this
+    pub max_retry_after_seconds: Option<u64>,
+}
+
+impl ProviderBackoff {
+    pub fn max_retry_after_seconds(&self) -> u64 {
+        self.max_retry_after_seconds
+            .unwrap_or(DEFAULT_MAX_RETRY_AFTER_SECONDS)
+    }
+}
+
+// messages are compressed in the gateway before they are sent to the llm provider
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Compression {
+    // trailing whitespace and runs of blank lines are removed, defaults to true
+    pub trim_whitespace: Option<bool>,
+    // repeated system messages are dropped and repeated tool outputs refer to the first one,
+    // defaults to true
+    pub deduplicate: Option<bool>,
+    // longer tool outputs are cut to this many tokens
+    pub max_tool_output_tokens: Option<usize>,
+}
+
+impl Compression {
+    pub fn trim_whitespace(&self) -> bool {
+        self.trim_whitespace.unwrap_or(true)
+    }
+
+    pub fn deduplicate(&self) -> bool {
+        self.deduplicate.unwrap_or(true)
+    }
+}
+
+// non streamed responses to requests with a json response_format are checked before they are
+// returned to the client
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct JsonMode {
+    pub on_invalid: Option<OnInvalidJson>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum OnInvalidJson {
+    // the llm provider is asked once to correct its response, the client gets an error if it
+    // still isn't valid
+    #[serde(rename = "repair")]
+    #[default]
+    Repair,
+    // the client gets an error right away
+    #[serde(rename = "error")]
+    Error,
+}
+
+// gateway events are posted in batches to a webhook, e.g. to alert on them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notifications {
+    pub webhook: EndpointDetails,
+    // events posted to the webhook, all events when not set
+    pub events: Option<Vec<NotificationEvent>>,
+    // most events posted in one request, defaults to 50
+    pub batch_size: Option<usize>,
+    // times a batch the webhook didn't accept is posted again, defaults to 3
+    pub max_retries: Option<u32>,
+}
+
+impl Notifications {
+    pub fn notifies(&self, event: NotificationEvent) -> bool {
+        self.events
+            .as_ref()
+            .is_none_or(|events| events.contains(&event))
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+            .unwrap_or(DEFAULT_NOTIFICATION_BATCH_SIZE)
+            .max(1)
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries.unwrap_or(DEFAULT_NOTIFICATION_MAX_RETRIES)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum NotificationEvent {
+    // a request was rejected by a rate limit
+    #[serde(rename = "ratelimit_breach")]
+    RatelimitBreach,
+    // a request was routed to another llm provider as its provider is backing off
+    #[serde(rename = "provider_failover")]
+    ProviderFailover,
+    // an llm provider started backing off after a 429
+    #[serde(rename = "circuit_breaker_open")]
+    CircuitBreakerOpen,
+    #[serde(rename = "guard_detection")]
+    GuardDetection,
+}
+
+// conversations over max_tokens have their older turns replaced by a summary before they are sent
+// to the llm provider
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Summarization {
+    // llm provider the older turns are summarized with, a small model is enough
+    pub llm_provider: String,
+    pub max_tokens: usize,
+    // most recent messages kept as they are, defaults to 4
+    pub keep_messages: Option<usize>,
+}
+
+impl Summarization {
+    pub fn keep_messages(&self) -> usize {
+        self.keep_messages
+            .unwrap_or(DEFAULT_SUMMARIZATION_KEEP_MESSAGES)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Logging {
+    // defaults to trace
+    pub level: Option<LogLevel>,
+    // module name (e.g. stream_context, filter_context, ratelimit) -> level
+    pub modules: Option<HashMap<String, LogLevel>>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Experiment {
+    pub name: String,
+    // request header whose value is hashed to pick an arm, a given value always lands on the same arm
+    pub header: String,
+    pub arms: Vec<ExperimentArm>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExperimentArm {
+    pub llm_provider: String,
+    // share of the traffic relative to the other arms of the experiment
+    pub weight: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct FunctionCalling {
+    // name of the llm provider used to resolve prompt targets, Curve-FC on the model server is used when not set
+    pub llm_provider: Option<String>,
+    // ask the resolver model for a streamed response, parameter collection questions are then
+    // forwarded to streaming clients as the resolver sent them
+    pub stream: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct RequestLimits {
+    pub max_body_bytes: Option<usize>,
+    pub max_messages: Option<usize>,
+    pub max_tokens: Option<usize>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Default)]
 pub enum GatewayMode {
     #[serde(rename = "llm")]
@@ -45,6 +767,32 @@ pub enum GatewayMode {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ErrorTargetDetail {
     pub endpoint: Option<EndpointDetails>,
+    // errors reported to the error target besides the ones that ask for it
+    pub forward: Option<Vec<ErrorEvent>>,
+    // returned to the client instead of the error target response, supports {error_type},
+    // {error_message} and {request_id}
+    pub message: Option<String>,
+}
+
+impl ErrorTargetDetail {
+    pub fn forwards(&self, event: ErrorEvent) -> bool {
+        self.forward
+            .iter()
+            .flatten()
+            .any(|forward| *forward == event)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum ErrorEvent {
+    // errors of the gateway, e.g. failed calls to prompt target endpoints
+    #[serde(rename = "server_error")]
+    ServerError,
+    // 5xx responses of the llm provider
+    #[serde(rename = "provider_failure")]
+    ProviderFailure,
+    #[serde(rename = "guard_violation")]
+    GuardViolation,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -52,7 +800,11 @@ pub struct Listener {
     pub address: String,
     pub port: u16,
     pub message_format: MessageFormat,
-    // pub connect_timeout: Option<DurationString>,
+    pub client_tools: Option<ClientToolsMode>,
+    // used by envoy, e.g. 0.005s
+    pub connect_timeout: Option<String>,
+    pub intent_model: Option<String>,
+    pub guard_model: Option<String>,
 }
 
 impl Default for Listener {
@@ -61,7 +813,38 @@ impl Default for Listener {
             address: "".to_string(),
             port: 0,
             message_format: MessageFormat::default(),
-            // connect_timeout: None,
+            client_tools: None,
+            connect_timeout: None,
+            intent_model: None,
+            guard_model: None,
+        }
+    }
+}
+
+// what to do with tools that the client sends in its chat completions request
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum ClientToolsMode {
+    // client tools are replaced by prompt targets
+    #[default]
+    #[serde(rename = "override")]
+    Override,
+    // client tools are resolved together with prompt targets and kept on the llm request
+    #[serde(rename = "merge")]
+    Merge,
+    // prompt target resolution is skipped and the request is forwarded as is
+    #[serde(rename = "passthrough")]
+    Passthrough,
+}
+
+impl FromStr for ClientToolsMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "override" => Ok(ClientToolsMode::Override),
+            "merge" => Ok(ClientToolsMode::Merge),
+            "passthrough" => Ok(ClientToolsMode::Passthrough),
+            _ => Err(format!("unknown client tools mode: {}", s)),
         }
     }
 }
@@ -76,9 +859,19 @@ pub enum MessageFormat {
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct PromptGuards {
     pub input_guards: HashMap<GuardType, GuardOptions>,
+    // checked on the responses of the llm providers
+    pub output_guards: Option<HashMap<GuardType, GuardOptions>>,
 }
 
 impl PromptGuards {
+    pub fn input_guard(&self, guard_type: &GuardType) -> Option<&GuardOptions> {
+        self.input_guards.get(guard_type)
+    }
+
+    pub fn output_guard(&self, guard_type: &GuardType) -> Option<&GuardOptions> {
+        self.output_guards.as_ref()?.get(guard_type)
+    }
+
     pub fn jailbreak_on_exception_message(&self) -> Option<&str> {
         self.input_guards
             .get(&GuardType::Jailbreak)?
@@ -95,11 +888,68 @@ impl PromptGuards {
 pub enum GuardType {
     #[serde(rename = "jailbreak")]
     Jailbreak,
+    #[serde(rename = "moderation")]
+    Moderation,
+    // api keys, passwords and connection strings in user messages, found without a callout
+    #[serde(rename = "secrets")]
+    Secrets,
+    // words and patterns of the config, found without a callout
+    #[serde(rename = "deny_list")]
+    DenyList,
+}
+
+impl Display for GuardType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GuardType::Jailbreak => write!(f, "jailbreak"),
+            GuardType::Moderation => write!(f, "moderation"),
+            GuardType::Secrets => write!(f, "secrets"),
+            GuardType::DenyList => write!(f, "deny_list"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub enum GuardAction {
+    #[serde(rename = "block")]
+    #[default]
+    Block,
+    // violations are only logged and counted
+    #[serde(rename = "monitor")]
+    Monitor,
+    // the offending content is replaced and the request goes on, secrets and deny_list guards only
+    #[serde(rename = "mask")]
+    Mask,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GuardOptions {
     pub on_exception: Option<OnExceptionDetails>,
+    pub action: Option<GuardAction>,
+    // moderation: llm provider whose moderations api is called
+    pub llm_provider: Option<String>,
+    // moderation: defaults to /v1/moderations
+    pub path: Option<String>,
+    // moderation: model of the moderations api, e.g. omni-moderation-latest
+    pub model: Option<String>,
+    // moderation: category -> score from which it is a violation, categories without a threshold
+    // are violations when the provider flags them
+    pub thresholds: Option<HashMap<String, f64>>,
+    // deny_list: words matched as whole words
+    pub words: Option<Vec<String>>,
+    // deny_list: regular expressions
+    pub patterns: Option<Vec<String>>,
+    // deny_list: words and patterns match regardless of case by default
+    pub case_sensitive: Option<bool>,
+}
+
+impl GuardOptions {
+    pub fn message(&self) -> &str {
+        self.on_exception
+            .as_ref()
+            .and_then(|on_exception| on_exception.message.as_deref())
+            .unwrap_or(DEFAULT_GUARD_MESSAGE)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -131,12 +981,34 @@ pub struct Ratelimit {
     pub model: String,
     pub selector: Header,
     pub limit: Limit,
+    // how the limit is enforced, token_bucket when not set
+    pub algorithm: Option<RatelimitAlgorithm>,
+    // streaming responses a selector value may have open at the same time, requests opening one
+    // more are rejected
+    pub max_concurrent_streams: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Limit {
     pub tokens: u32,
     pub unit: TimeUnit,
+    // tokens a token bucket holds when it is full, the limit tokens when not set. The bucket still
+    // fills up at the limit tokens per unit.
+    pub burst: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum RatelimitAlgorithm {
+    // tokens are taken from a bucket that fills up over the unit
+    #[default]
+    #[serde(rename = "token_bucket")]
+    TokenBucket,
+    // tokens used in the last unit are counted from a log of the requests
+    #[serde(rename = "sliding_window")]
+    SlidingWindow,
+    // the limit tokens are the requests in flight at the same time, the unit is not used
+    #[serde(rename = "concurrency")]
+    Concurrency,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -158,6 +1030,7 @@ pub struct RatelimitHeader {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 //TODO: use enum for model, but if there is a new model, we need to update the code
 pub struct EmbeddingProviver {
+    // name of the llm provider that serves /v1/embeddings requests
     pub name: String,
     pub model: String,
 }
@@ -191,6 +1064,39 @@ pub struct LlmProvider {
     pub endpoint: Option<String>,
     pub port: Option<u16>,
     pub rate_limits: Option<LlmRatelimit>,
+    // response bodies are passed to the client without being inspected, no output token counts,
+    // costs or audited completions are recorded for the provider
+    pub passthrough_response: Option<bool>,
+    pub context_window: Option<ContextWindow>,
+}
+
+// tokens the model accepts, requests over it are rejected by the gateway instead of failing at the
+// llm provider
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContextWindow {
+    pub max_tokens: usize,
+    pub on_overflow: Option<ContextOverflow>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum ContextOverflow {
+    // the request is answered with a 400 naming the limit
+    #[serde(rename = "reject")]
+    #[default]
+    Reject,
+    // the oldest messages after the system messages are dropped until the request fits
+    #[serde(rename = "truncate")]
+    Truncate,
+}
+
+impl LlmProvider {
+    // providers without an endpoint are served by the cluster named after their interface
+    pub fn cluster_name(&self) -> String {
+        match self.endpoint {
+            Some(_) => self.name.clone(),
+            None => self.provider_interface.to_string(),
+        }
+    }
 }
 
 impl Display for LlmProvider {
@@ -202,6 +1108,9 @@ impl Display for LlmProvider {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Endpoint {
     pub endpoint: Option<String>,
+    // used by envoy for the cluster of the endpoint
+    pub connect_timeout: Option<String>,
+    pub protocol: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -236,12 +1145,50 @@ impl Display for HttpMethod {
     }
 }
 
+impl From<&HttpMethod> for http::Method {
+    fn from(method: &HttpMethod) -> Self {
+        match method {
+            HttpMethod::Get => http::Method::GET,
+            HttpMethod::Post => http::Method::POST,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EndpointDetails {
     pub name: String,
     pub path: Option<String>,
     #[serde(rename = "http_method")]
     pub method: Option<HttpMethod>,
+    pub auth: Option<EndpointAuth>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum EndpointAuth {
+    // static bearer token, use env:NAME or file:/path in config to have it resolved when the config
+    // is rendered
+    #[serde(rename = "bearer")]
+    Bearer { token: String },
+    #[serde(rename = "api_key")]
+    ApiKey { header: String, value: String },
+    // forward the value of a header from the inbound request
+    #[serde(rename = "passthrough")]
+    Passthrough { header: String },
+}
+
+impl EndpointAuth {
+    // header for auth types that don't depend on the inbound request
+    pub fn static_header(&self) -> Option<(String, String)> {
+        match self {
+            EndpointAuth::Bearer { token } => Some((
+                AUTHORIZATION_HEADER.to_string(),
+                format!("Bearer {}", token),
+            )),
+            EndpointAuth::ApiKey { header, value } => Some((header.clone(), value.clone())),
+            EndpointAuth::Passthrough { .. } => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -249,10 +1196,253 @@ pub struct PromptTarget {
     pub name: String,
     pub default: Option<bool>,
     pub description: String,
+    // other names function calling can pick the prompt target by
+    pub aliases: Option<Vec<PromptTargetAlias>>,
     pub endpoint: Option<EndpointDetails>,
     pub parameters: Option<Vec<Parameter>>,
     pub system_prompt: Option<String>,
     pub auto_llm_dispatch_on_response: Option<bool>,
+    pub ratelimits: Option<PromptTargetRatelimits>,
+    // name of the prompt target group this target belongs to
+    pub group: Option<String>,
+    pub cache: Option<PromptTargetCache>,
+    pub parameter_collection: Option<ParameterCollection>,
+    // the endpoint is called with the resolved parameters but its response is discarded and the
+    // request continues as if no prompt target matched
+    pub shadow: Option<bool>,
+    pub async_operation: Option<AsyncOperation>,
+    pub conditions: Option<Conditions>,
+    // json schema the final llm response must follow, sent as the response_format of the llm
+    // request unless the client asked for one
+    pub output_schema: Option<serde_json::Value>,
+    // times the llm is asked to correct a response that doesn't follow the output schema,
+    // defaults to 2
+    pub output_schema_retries: Option<usize>,
+    // the llm provider and model the final llm call of this target is made with instead of the
+    // globally routed ones, the model applies only when the request ends up on that provider
+    pub llm_provider: Option<String>,
+    pub model: Option<String>,
+    pub response_mode: Option<ResponseMode>,
+    // endpoints called one after the other instead of the endpoint, the llm gets the responses of
+    // all the steps
+    pub steps: Option<Vec<ChainStep>>,
+    pub tool_response: Option<ToolResponse>,
+}
+
+impl PromptTarget {
+    pub fn has_alias(&self, name: &str) -> bool {
+        self.aliases
+            .iter()
+            .flatten()
+            .any(|alias| alias.name == name)
+    }
+
+    pub fn output_schema_retries(&self) -> usize {
+        self.output_schema_retries
+            .unwrap_or(DEFAULT_OUTPUT_SCHEMA_RETRIES)
+    }
+
+    // response_format of the llm request for the output schema
+    pub fn response_format(&self) -> Option<serde_json::Value> {
+        let schema = self.output_schema.as_ref()?;
+        Some(serde_json::json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": self.name,
+                "schema": schema,
+            }
+        }))
+    }
+
+    // the endpoint and the endpoints of the steps
+    pub fn endpoints(&self) -> impl Iterator<Item = &EndpointDetails> {
+        self.endpoint
+            .iter()
+            .chain(self.steps.iter().flatten().map(|step| &step.endpoint))
+    }
+
+    // the prompt target and each of its aliases offered to function calling, an alias takes the
+    // description of the prompt target unless it has its own
+    pub fn tools(&self) -> Vec<ChatCompletionTool> {
+        let tool: ChatCompletionTool = self.into();
+        let mut tools = vec![tool.clone()];
+        for alias in self.aliases.iter().flatten() {
+            let mut alias_tool = tool.clone();
+            alias_tool.function.name = alias.name.clone();
+            if let Some(description) = alias.description.as_ref() {
+                alias_tool.function.description = description.clone();
+            }
+            tools.push(alias_tool);
+        }
+        tools
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainStep {
+    pub name: String,
+    pub endpoint: EndpointDetails,
+    // parameter -> template of its value, {parameter} is a parameter of the prompt target and
+    // {step.field} a field of the response of an earlier step. The step gets the parameters of the
+    // prompt target when not set.
+    pub inputs: Option<HashMap<String, String>>,
+    pub on_error: Option<OnStepError>,
+    // the step is skipped unless all the conditions hold
+    pub when: Option<Vec<StepCondition>>,
+    // name of a later step the chain continues with, the steps in between are skipped
+    pub next: Option<String>,
+}
+
+// path is a json path like $.check_status.state into the parameters and the responses of earlier
+// steps, a condition without equals or contains holds when the path exists
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StepCondition {
+    pub path: String,
+    pub equals: Option<serde_json::Value>,
+    // a substring of a string, an element of an array or a key of an object
+    pub contains: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum OnStepError {
+    // the request fails with the error of the step
+    #[serde(rename = "abort")]
+    #[default]
+    Abort,
+    // the error is recorded as the response of the step and the next step is called
+    #[serde(rename = "continue")]
+    Continue,
+}
+
+// how the response of the endpoint gets to the client
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum ResponseMode {
+    // the llm answers the prompt with the endpoint response as context
+    #[serde(rename = "llm")]
+    #[default]
+    Llm,
+    // the endpoint response is returned as is
+    #[serde(rename = "direct")]
+    Direct,
+    // the endpoint response is returned as the message of a chat completions response
+    #[serde(rename = "direct_chat")]
+    DirectChat,
+}
+
+// How the endpoint response is handed to the llm. The response comes from outside the gateway, a
+// compromised endpoint could slip instructions for the llm into it.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ToolResponse {
+    pub role: Option<ToolResponseRole>,
+    // the response is checked by the jailbreak guard of the model server before the llm gets it
+    pub guard: Option<bool>,
+    // returned to the client when the guard flags the response
+    pub on_exception_message: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum ToolResponseRole {
+    // the response is added to the user prompt as context, as is done for responses of tools the
+    // llm never called
+    #[serde(rename = "user")]
+    User,
+    // the response answers the tool call in a tool message, after a system note that its content
+    // is data and not instructions
+    #[serde(rename = "tool")]
+    #[default]
+    Tool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptTargetAlias {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+// evaluated before the endpoint is called, when a condition fails the user gets the refusal
+// message instead
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Conditions {
+    pub required_headers: Option<Vec<String>>,
+    pub allowed_hours: Option<AllowedHours>,
+    pub user_groups: Option<UserGroups>,
+    pub refusal_message: Option<String>,
+}
+
+impl Conditions {
+    pub fn refusal_message(&self) -> &str {
+        self.refusal_message
+            .as_deref()
+            .unwrap_or(DEFAULT_REFUSAL_MESSAGE)
+    }
+}
+
+// hours of the day in UTC, end excluded. A window with start after end wraps around midnight.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AllowedHours {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserGroups {
+    // request header with the comma separated groups of the user
+    pub header: String,
+    pub allowed: Vec<String>,
+}
+
+// endpoints of long running actions answer 202 with an operation id, the user is told that the
+// operation is in progress and later turns can check on it through the status prompt target
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AsyncOperation {
+    // prompt target called with the operation id to report the status of the operation
+    pub status_target: String,
+    // field of the 202 response body holding the operation id, defaults to operation_id
+    pub operation_id_field: Option<String>,
+}
+
+impl AsyncOperation {
+    pub fn operation_id_field(&self) -> &str {
+        self.operation_id_field
+            .as_deref()
+            .unwrap_or(DEFAULT_OPERATION_ID_FIELD)
+    }
+}
+
+// limits on the dialog Curve FC has with the user to collect missing parameters
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ParameterCollection {
+    // number of follow up questions after which the dialog is abandoned
+    pub max_turns: Option<usize>,
+    // seconds since the first follow up question after which the dialog is abandoned
+    pub timeout_seconds: Option<u64>,
+    // send the conversation to the error target instead of returning an error
+    pub forward_to_error_target: Option<bool>,
+}
+
+// responses of the prompt target endpoint are cached per tool call arguments
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptTargetCache {
+    pub ttl_seconds: u64,
+}
+
+// prompt targets can be grouped so that intent matching first picks a group and then a target
+// within that group, targets without a group are candidates in every group
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptTargetGroup {
+    pub name: String,
+    pub description: String,
+    // used for the targets of the group that don't have their own system prompt
+    pub system_prompt: Option<String>,
+}
+
+// limits applied to calls dispatched to a prompt target endpoint, independent of llm ratelimits
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct PromptTargetRatelimits {
+    // number of endpoint calls allowed per unit
+    pub requests: Option<Limit>,
+    // number of tokens in the endpoint request body allowed per unit
+    pub tokens: Option<Limit>,
 }
 
 // convert PromptTarget to ChatCompletionTool
@@ -290,6 +1480,22 @@ impl From<&PromptTarget> for ChatCompletionTool {
     }
 }
 
+// groups are offered to function calling as tools without parameters
+impl From<&PromptTargetGroup> for ChatCompletionTool {
+    fn from(val: &PromptTargetGroup) -> Self {
+        ChatCompletionTool {
+            tool_type: crate::api::open_ai::ToolType::Function,
+            function: FunctionDefinition {
+                name: val.name.clone(),
+                description: val.description.clone(),
+                parameters: FunctionParameters {
+                    properties: HashMap::new(),
+                },
+            },
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use pretty_assertions::assert_eq;
@@ -306,6 +1512,11 @@ mod test {
 
         let config: super::Configuration = serde_yaml::from_str(&ref_config).unwrap();
         assert_eq!(config.version, "v0.1");
+        assert_eq!(config.intent_model(), "Curve-Intent");
+        assert_eq!(config.guard_model(), "Curve-Guard");
+        let ratelimits = config.ratelimits.as_ref().unwrap();
+        assert_eq!(ratelimits[0].selector.value, None);
+        assert_eq!(ratelimits[0].max_concurrent_streams, Some(3));
 
         let prompt_guards = config.prompt_guards.as_ref().unwrap();
         let input_guards = &prompt_guards.input_guards;
@@ -322,9 +1533,15 @@ mod test {
             jailbreak_guard.on_exception.as_ref().unwrap().error_handler,
             None
         );
+        let moderation_guard = prompt_guards.output_guard(&GuardType::Moderation).unwrap();
+        assert_eq!(moderation_guard.action, Some(super::GuardAction::Monitor));
+        assert_eq!(
+            moderation_guard.message(),
+            "This content was blocked by a content guard."
+        );
 
         let prompt_targets = &config.prompt_targets;
-        assert_eq!(prompt_targets.as_ref().unwrap().len(), 2);
+        assert_eq!(prompt_targets.as_ref().unwrap().len(), 5);
         let prompt_target = prompt_targets
             .as_ref()
             .unwrap()
@@ -333,6 +1550,34 @@ mod test {
             .unwrap();
         assert_eq!(prompt_target.name, "reboot_network_device");
         assert_eq!(prompt_target.default, None);
+        assert_eq!(
+            prompt_target.endpoint.as_ref().unwrap().auth,
+            Some(super::EndpointAuth::Bearer {
+                token: "env:APP_SERVER_TOKEN".to_string()
+            })
+        );
+        assert_eq!(prompt_target.group, Some("network_operations".to_string()));
+        assert_eq!(prompt_target.shadow, None);
+        let async_operation = prompt_target.async_operation.as_ref().unwrap();
+        assert_eq!(async_operation.status_target, "network_operation_status");
+        assert_eq!(async_operation.operation_id_field(), "operation_id");
+        assert_eq!(
+            prompt_target.response_format().unwrap()["json_schema"]["name"],
+            "reboot_network_device"
+        );
+        assert_eq!(prompt_target.output_schema_retries(), 1);
+
+        let shadow_prompt_target = prompt_targets
+            .as_ref()
+            .unwrap()
+            .iter()
+            .find(|p| p.name == "reboot_network_device_v2")
+            .unwrap();
+        assert_eq!(shadow_prompt_target.shadow, Some(true));
+
+        let prompt_target_groups = config.prompt_target_groups.as_ref().unwrap();
+        assert_eq!(prompt_target_groups.len(), 1);
+        assert_eq!(prompt_target_groups[0].name, "network_operations");
 
         let prompt_target = prompt_targets
             .as_ref()
@@ -342,6 +1587,40 @@ mod test {
             .unwrap();
         assert_eq!(prompt_target.name, "information_extraction");
         assert_eq!(prompt_target.default, Some(true));
+        assert_eq!(prompt_target.llm_provider, Some("OpenAI".to_string()));
+        assert_eq!(prompt_target.model, Some("gpt-4o-mini".to_string()));
+        assert_eq!(prompt_target.response_mode, None);
+        let tool_response = prompt_target.tool_response.as_ref().unwrap();
+        assert_eq!(tool_response.role, Some(super::ToolResponseRole::Tool));
+        assert_eq!(tool_response.guard, Some(true));
+
+        let status_prompt_target = prompt_targets
+            .as_ref()
+            .unwrap()
+            .iter()
+            .find(|p| p.name == "network_operation_status")
+            .unwrap();
+        assert_eq!(
+            status_prompt_target.response_mode,
+            Some(super::ResponseMode::DirectChat)
+        );
+
+        let chain_prompt_target = prompt_targets
+            .as_ref()
+            .unwrap()
+            .iter()
+            .find(|p| p.name == "reboot_device_by_name")
+            .unwrap();
+        let steps = chain_prompt_target.steps.as_ref().unwrap();
+        assert_eq!(steps.len(), 5);
+        assert_eq!(steps[0].inputs, None);
+        assert_eq!(steps[1].on_error, Some(super::OnStepError::Continue));
+        assert_eq!(
+            steps[2].when.as_ref().unwrap()[0].equals,
+            Some(serde_json::json!("offline"))
+        );
+        assert_eq!(steps[2].next, Some("notify".to_string()));
+        assert_eq!(chain_prompt_target.endpoints().count(), 5);
         assert_eq!(
             prompt_target.endpoint.as_ref().unwrap().name,
             "app_server".to_string()
@@ -352,6 +1631,8 @@ mod test {
         );
 
         let error_target = config.error_target.as_ref().unwrap();
+        assert!(error_target.forwards(super::ErrorEvent::GuardViolation));
+        assert!(!error_target.forwards(super::ErrorEvent::ServerError));
         assert_eq!(
             error_target.endpoint.as_ref().unwrap().name,
             "error_target_1".to_string()
@@ -364,6 +1645,44 @@ mod test {
         let tracing = config.tracing.as_ref().unwrap();
         assert_eq!(tracing.sampling_rate.unwrap(), 0.1);
 
+        let overrides = config.overrides.as_ref().unwrap();
+        assert_eq!(
+            overrides.intent_fallback,
+            Some(super::IntentFallback::Keywords)
+        );
+
+        let hook_points: Vec<super::HookPoint> = config
+            .hooks
+            .iter()
+            .flatten()
+            .map(|hook| hook.point)
+            .collect();
+        assert_eq!(
+            hook_points,
+            vec![
+                super::HookPoint::PreProvider,
+                super::HookPoint::PreGuard,
+                super::HookPoint::PostResponse
+            ]
+        );
+
+        let notifications = config.notifications.as_ref().unwrap();
+        assert_eq!(notifications.webhook.name, "app_server");
+        assert!(notifications.notifies(super::NotificationEvent::CircuitBreakerOpen));
+        assert!(!notifications.notifies(super::NotificationEvent::ProviderFailover));
+        assert_eq!(notifications.batch_size(), 20);
+        assert_eq!(notifications.max_retries(), 3);
+
+        let function_calling = config.function_calling.as_ref().unwrap();
+        assert_eq!(function_calling.llm_provider, Some("OpenAI".to_string()));
+
+        let callout_limits = config.callout_limits.as_ref().unwrap();
+        assert_eq!(callout_limits[0].max_wait(), None);
+        assert_eq!(
+            callout_limits[1].max_wait(),
+            Some(std::time::Duration::from_millis(250))
+        );
+
         let mode = config.mode.as_ref().unwrap_or(&super::GatewayMode::Prompt);
         assert_eq!(*mode, super::GatewayMode::Prompt);
     }
@@ -438,5 +1757,15 @@ mod test {
                 .parameter_type,
             crate::api::open_ai::ParameterType::Bool
         );
+
+        assert!(prompt_target.has_alias("restart_router"));
+        let tools = prompt_target.tools();
+        assert_eq!(tools.len(), 2);
+        assert_eq!(tools[1].function.name, "restart_router");
+        assert_eq!(
+            tools[1].function.description,
+            "Restart a router that is unresponsive or slow"
+        );
+        assert_eq!(tools[1].function.parameters.properties.len(), 2);
     }
 }
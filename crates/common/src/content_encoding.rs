@@ -0,0 +1,128 @@
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use std::io::Read;
+
+pub const CONTENT_ENCODING_HEADER: &str = "content-encoding";
+pub const ACCEPT_ENCODING_HEADER: &str = "accept-encoding";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentEncoding {
+    Gzip,
+    Deflate,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("content encoding {0} not supported")]
+    Unsupported(String),
+    #[error("decompressed body exceeds {0} bytes")]
+    TooLarge(usize),
+    #[error("body could not be decompressed: {0}")]
+    Invalid(#[from] std::io::Error),
+}
+
+impl ContentEncoding {
+    // value of a content-encoding header, None when the body isn't encoded
+    pub fn parse(value: &str) -> Result<Option<Self>, Error> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "identity" => Ok(None),
+            "gzip" | "x-gzip" => Ok(Some(ContentEncoding::Gzip)),
+            "deflate" => Ok(Some(ContentEncoding::Deflate)),
+            _ => Err(Error::Unsupported(value.to_string())),
+        }
+    }
+}
+
+// Decompresses the body, decompressing stops past max_bytes so a small body can't blow up into
+// more memory than the filter has.
+pub fn decode(encoding: ContentEncoding, body: &[u8], max_bytes: usize) -> Result<Vec<u8>, Error> {
+    let reader: Box<dyn Read + '_> = match encoding {
+        ContentEncoding::Gzip => Box::new(GzDecoder::new(body)),
+        // deflate is meant to be zlib wrapped, some clients send raw deflate data
+        ContentEncoding::Deflate if is_zlib(body) => Box::new(ZlibDecoder::new(body)),
+        ContentEncoding::Deflate => Box::new(DeflateDecoder::new(body)),
+    };
+    let mut decoded = Vec::new();
+    reader
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut decoded)?;
+    if decoded.len() > max_bytes {
+        return Err(Error::TooLarge(max_bytes));
+    }
+    Ok(decoded)
+}
+
+fn is_zlib(body: &[u8]) -> bool {
+    match body {
+        [cmf, flg, ..] => cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, ContentEncoding, Error};
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+    use std::io::Write;
+
+    fn encode(encoding: ContentEncoding, body: &[u8]) -> Vec<u8> {
+        let compression = flate2::Compression::default();
+        match encoding {
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), compression);
+                encoder.write_all(body).unwrap();
+                encoder.finish().unwrap()
+            }
+            ContentEncoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), compression);
+                encoder.write_all(body).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+
+    #[test]
+    fn test_bodies_are_decoded() {
+        let body = br#"{"messages":[{"role":"user","content":"hello"}]}"#;
+        for encoding in [ContentEncoding::Gzip, ContentEncoding::Deflate] {
+            let encoded = encode(encoding, body);
+            assert_ne!(encoded, body);
+            assert_eq!(decode(encoding, &encoded, 1024).unwrap(), body);
+        }
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(body).unwrap();
+        let raw_deflate = encoder.finish().unwrap();
+        assert_eq!(
+            decode(ContentEncoding::Deflate, &raw_deflate, 1024).unwrap(),
+            body
+        );
+
+        assert!(matches!(
+            decode(ContentEncoding::Gzip, b"not gzip", 1024),
+            Err(Error::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_decoding_stops_at_max_bytes() {
+        let bomb = encode(ContentEncoding::Gzip, &vec![b'a'; 1024 * 1024]);
+        assert!(bomb.len() < 4096);
+        assert!(matches!(
+            decode(ContentEncoding::Gzip, &bomb, 64 * 1024),
+            Err(Error::TooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_content_encoding_header() {
+        assert_eq!(ContentEncoding::parse("identity").unwrap(), None);
+        assert_eq!(
+            ContentEncoding::parse("GZIP").unwrap(),
+            Some(ContentEncoding::Gzip)
+        );
+        assert!(matches!(
+            ContentEncoding::parse("br"),
+            Err(Error::Unsupported(_))
+        ));
+    }
+}
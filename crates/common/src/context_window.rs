@@ -0,0 +1,130 @@
+use crate::api::open_ai::Message;
+use crate::configuration::ContextWindow;
+use crate::consts::TOOL_ROLE;
+use crate::summarization::leading_system_messages;
+use crate::tokenizer;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("request of {input_tokens} tokens exceeds the context window of {max_tokens} tokens")]
+pub struct Error {
+    pub input_tokens: usize,
+    pub max_tokens: usize,
+}
+
+pub fn check(
+    context_window: &ContextWindow,
+    model: &str,
+    messages: &[Message],
+) -> Result<(), Error> {
+    let input_tokens: usize = tokenizer::message_tokens(model, messages).iter().sum();
+    if input_tokens > context_window.max_tokens {
+        return Err(Error {
+            input_tokens,
+            max_tokens: context_window.max_tokens,
+        });
+    }
+    Ok(())
+}
+
+// drops the oldest messages after the leading system messages until the request fits, tool
+// responses go with the tool call they answer. The last message is never dropped. Returns the
+// number of dropped messages.
+pub fn truncate(
+    context_window: &ContextWindow,
+    model: &str,
+    messages: &mut Vec<Message>,
+) -> Result<usize, Error> {
+    let first = leading_system_messages(messages);
+    let mut tokens = tokenizer::message_tokens(model, messages);
+    let mut dropped = 0;
+    loop {
+        let input_tokens: usize = tokens.iter().sum();
+        if input_tokens <= context_window.max_tokens {
+            return Ok(dropped);
+        }
+        if messages.len() - first <= 1 {
+            return Err(Error {
+                input_tokens,
+                max_tokens: context_window.max_tokens,
+            });
+        }
+        messages.remove(first);
+        tokens.remove(first);
+        dropped += 1;
+        while messages.len() - first > 1 && messages[first].role == TOOL_ROLE {
+            messages.remove(first);
+            tokens.remove(first);
+            dropped += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, truncate, Error};
+    use crate::api::open_ai::{FunctionCallDetail, Message, ToolCall, ToolType};
+    use crate::configuration::ContextWindow;
+    use crate::consts::{ASSISTANT_ROLE, SYSTEM_ROLE, TOOL_ROLE, USER_ROLE};
+    use std::collections::HashMap;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: Some(content.to_string().into()),
+            model: None,
+            tool_calls: None,
+            tool_call_id: None,
+            name: None,
+        }
+    }
+
+    #[test]
+    fn test_context_window() {
+        let mut tool_call = message(ASSISTANT_ROLE, "");
+        tool_call.content = None;
+        tool_call.tool_calls = Some(vec![ToolCall {
+            id: "call_1".to_string(),
+            tool_type: ToolType::Function,
+            function: FunctionCallDetail {
+                name: "get_weather".to_string(),
+                arguments: HashMap::new(),
+            },
+        }]);
+        let mut messages = vec![
+            message(SYSTEM_ROLE, "You are a helpful assistant."),
+            message(USER_ROLE, "How is the weather in seattle today?"),
+            tool_call,
+            message(TOOL_ROLE, "It is sunny and warm, with a light breeze."),
+            message(USER_ROLE, "And tomorrow?"),
+        ];
+
+        let context_window = ContextWindow {
+            max_tokens: 100,
+            on_overflow: None,
+        };
+        assert_eq!(check(&context_window, "gpt-4o", &messages), Ok(()));
+
+        let context_window = ContextWindow {
+            max_tokens: 12,
+            on_overflow: None,
+        };
+        assert!(check(&context_window, "unknown-model", &messages).is_err());
+
+        // the tool call is dropped with its response
+        assert_eq!(truncate(&context_window, "gpt-4o", &mut messages), Ok(3));
+        let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, vec![SYSTEM_ROLE, USER_ROLE]);
+
+        let context_window = ContextWindow {
+            max_tokens: 2,
+            on_overflow: None,
+        };
+        assert_eq!(
+            truncate(&context_window, "gpt-4o", &mut messages),
+            Err(Error {
+                input_tokens: 9,
+                max_tokens: 2
+            })
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use crate::configuration::Cors;
+
+pub const ORIGIN_HEADER: &str = "origin";
+pub const ACCESS_CONTROL_REQUEST_METHOD_HEADER: &str = "access-control-request-method";
+
+// value of access-control-allow-origin for the origin, None when it isn't allowed. With * any
+// origin is allowed and the origin is still echoed, browsers don't accept * for requests sending
+// credentials.
+pub fn allowed_origin(cors: &Cors, origin: &str) -> Option<String> {
+    cors.allowed_origins
+        .iter()
+        .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+        .then(|| origin.to_string())
+}
+
+// headers answering a preflight request of an allowed origin
+pub fn preflight_headers(cors: &Cors, origin: &str) -> Option<Vec<(&'static str, String)>> {
+    let mut headers = response_headers(cors, origin)?;
+    headers.push(("access-control-allow-methods", cors.allowed_methods()));
+    headers.push(("access-control-allow-headers", cors.allowed_headers()));
+    if let Some(max_age_seconds) = cors.max_age_seconds {
+        headers.push(("access-control-max-age", max_age_seconds.to_string()));
+    }
+    Some(headers)
+}
+
+// headers added to the responses of an allowed origin
+pub fn response_headers(cors: &Cors, origin: &str) -> Option<Vec<(&'static str, String)>> {
+    let allowed_origin = allowed_origin(cors, origin)?;
+    Some(vec![
+        ("access-control-allow-origin", allowed_origin),
+        // the response depends on the origin, caches must not serve it to other origins
+        ("vary", ORIGIN_HEADER.to_string()),
+    ])
+}
+
+#[cfg(test)]
+mod test {
+    use super::{allowed_origin, preflight_headers};
+    use crate::configuration::Cors;
+
+    #[test]
+    fn test_allowed_origins() {
+        let cors = Cors {
+            allowed_origins: vec![String::from("https://app.example.com")],
+            ..Default::default()
+        };
+        assert_eq!(
+            allowed_origin(&cors, "https://APP.example.com").as_deref(),
+            Some("https://APP.example.com")
+        );
+        assert!(allowed_origin(&cors, "https://evil.example.com").is_none());
+
+        let cors = Cors {
+            allowed_origins: vec![String::from("*")],
+            ..Default::default()
+        };
+        assert!(allowed_origin(&cors, "https://evil.example.com").is_some());
+    }
+
+    #[test]
+    fn test_preflight_headers() {
+        let cors = Cors {
+            allowed_origins: vec![String::from("https://app.example.com")],
+            allowed_methods: Some(vec![String::from("POST")]),
+            max_age_seconds: Some(600),
+            ..Default::default()
+        };
+        let headers = preflight_headers(&cors, "https://app.example.com").unwrap();
+        assert_eq!(
+            headers,
+            vec![
+                (
+                    "access-control-allow-origin",
+                    String::from("https://app.example.com")
+                ),
+                ("vary", String::from("origin")),
+                ("access-control-allow-methods", String::from("POST")),
+                (
+                    "access-control-allow-headers",
+                    String::from("authorization, content-type")
+                ),
+                ("access-control-max-age", String::from("600")),
+            ]
+        );
+        assert!(preflight_headers(&cors, "https://evil.example.com").is_none());
+    }
+}
@@ -0,0 +1,211 @@
+use crate::configuration::{Budget, BudgetPeriod, Costs, ModelPrice};
+use crate::ratelimit::Header;
+use crate::template::format_date;
+use log::debug;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+use std::time::SystemTime;
+
+pub type CostData = RwLock<CostMap>;
+
+// the prices and budgets of a new config replace the old ones, spend starts over with them
+pub fn costs(costs_config: Option<Costs>) -> &'static CostData {
+    static COST_DATA: OnceLock<CostData> = OnceLock::new();
+    let cost_data = COST_DATA.get_or_init(|| RwLock::new(CostMap::new(Costs::default())));
+    if let Some(costs_config) = costs_config {
+        *cost_data.write().unwrap() = CostMap::new(costs_config);
+    }
+    cost_data
+}
+
+#[derive(Debug, thiserror::Error, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Error {
+    #[error("budget exhausted selector={selector}, spent={spent}, limit={limit}, period={period}")]
+    BudgetExceeded {
+        selector: String,
+        spent: f64,
+        limit: f64,
+        period: String,
+    },
+}
+
+// Spend is tracked per budget and per selector value. Budgets configured with a header value have a
+// single entry keyed by the empty string, budgets configured without one have an entry per value.
+// Entries are reset when the period they were recorded in is over.
+pub struct CostMap {
+    prices: HashMap<String, ModelPrice>,
+    budgets: Vec<Budget>,
+    spent: HashMap<(usize, String), (String, f64)>,
+}
+
+impl CostMap {
+    // n.b new is private so that the only access to the costs can be done via the static
+    // reference inside a RwLock via cost::costs().
+    fn new(costs_config: Costs) -> Self {
+        CostMap {
+            prices: costs_config
+                .prices
+                .into_iter()
+                .map(|price| (price.model.clone(), price))
+                .collect(),
+            budgets: costs_config.budgets.unwrap_or_default(),
+            spent: HashMap::new(),
+        }
+    }
+
+    // cost in USD of a request, None if the model has no price configured
+    pub fn cost(&self, model: &str, input_tokens: usize, output_tokens: usize) -> Option<f64> {
+        self.prices.get(model).map(|price| {
+            (input_tokens as f64 * price.input_per_million_tokens
+                + output_tokens as f64 * price.output_per_million_tokens)
+                / 1_000_000.0
+        })
+    }
+
+    pub fn check_budget(&self, selector: &Header, now: SystemTime) -> Result<(), Error> {
+        for (index, budget) in self.matching_budgets(selector) {
+            let period = period_window(&budget.period, now);
+            let spent = match self.spent.get(&(index, budget_key(budget, selector))) {
+                Some((spent_period, spent)) if *spent_period == period => *spent,
+                _ => 0.0,
+            };
+
+            if spent >= budget.limit {
+                return Err(Error::BudgetExceeded {
+                    selector: selector.to_string(),
+                    spent,
+                    limit: budget.limit,
+                    period,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn record(&mut self, selector: &Header, cost: f64, now: SystemTime) {
+        let entries: Vec<((usize, String), String)> = self
+            .matching_budgets(selector)
+            .map(|(index, budget)| {
+                (
+                    (index, budget_key(budget, selector)),
+                    period_window(&budget.period, now),
+                )
+            })
+            .collect();
+
+        for (key, period) in entries {
+            let entry = self.spent.entry(key).or_insert((period.clone(), 0.0));
+            if entry.0 != period {
+                *entry = (period, 0.0);
+            }
+            entry.1 += cost;
+            debug!(
+                "recorded cost {} for selector={}, spent={}",
+                cost, selector, entry.1
+            );
+        }
+    }
+
+    fn matching_budgets<'a>(
+        &'a self,
+        selector: &'a Header,
+    ) -> impl Iterator<Item = (usize, &'a Budget)> + 'a {
+        self.budgets.iter().enumerate().filter(move |(_, budget)| {
+            match budget.selector.value.as_ref() {
+                Some(value) => budget.selector.key == selector.key && *value == selector.value,
+                None => budget.selector.key == selector.key,
+            }
+        })
+    }
+}
+
+fn budget_key(budget: &Budget, selector: &Header) -> String {
+    match budget.selector.value {
+        Some(_) => String::from(""),
+        None => selector.value.clone(),
+    }
+}
+
+fn period_window(period: &BudgetPeriod, now: SystemTime) -> String {
+    let date = format_date(now);
+    match period {
+        BudgetPeriod::Day => date,
+        BudgetPeriod::Month => date[..7].to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CostMap;
+    use crate::configuration::{self, Budget, BudgetPeriod, Costs, ModelPrice};
+    use crate::ratelimit::Header;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn costs_config(selector_value: Option<&str>, period: BudgetPeriod) -> Costs {
+        Costs {
+            prices: vec![ModelPrice {
+                model: String::from("gpt-4o"),
+                input_per_million_tokens: 2.5,
+                output_per_million_tokens: 10.0,
+            }],
+            budgets: Some(vec![Budget {
+                selector: configuration::Header {
+                    key: String::from("x-team"),
+                    value: selector_value.map(String::from),
+                },
+                limit: 1.0,
+                period,
+            }]),
+        }
+    }
+
+    fn selector(value: &str) -> Header {
+        Header {
+            key: String::from("x-team"),
+            value: String::from(value),
+        }
+    }
+
+    #[test]
+    fn test_cost() {
+        let costs = CostMap::new(costs_config(None, BudgetPeriod::Day));
+        assert_eq!(costs.cost("gpt-4o", 1_000_000, 100_000), Some(3.5));
+        assert_eq!(costs.cost("unknown-model", 1_000_000, 100_000), None);
+    }
+
+    #[test]
+    fn test_budget_per_selector_value() {
+        let mut costs = CostMap::new(costs_config(None, BudgetPeriod::Day));
+        let now = UNIX_EPOCH + Duration::from_secs(1709210096);
+
+        assert!(costs.check_budget(&selector("search"), now).is_ok());
+        costs.record(&selector("search"), 1.5, now);
+        assert!(costs.check_budget(&selector("search"), now).is_err());
+
+        // every selector value has its own budget
+        assert!(costs.check_budget(&selector("support"), now).is_ok());
+
+        // budgets are reset on the next period
+        let tomorrow = now + Duration::from_secs(86400);
+        assert!(costs.check_budget(&selector("search"), tomorrow).is_ok());
+    }
+
+    #[test]
+    fn test_budget_specific_selector_value() {
+        let mut costs = CostMap::new(costs_config(Some("search"), BudgetPeriod::Month));
+        let now = UNIX_EPOCH + Duration::from_secs(1709210096);
+
+        costs.record(&selector("support"), 5.0, now);
+        assert!(costs.check_budget(&selector("support"), now).is_ok());
+
+        costs.record(&selector("search"), 0.6, now);
+        assert!(costs.check_budget(&selector("search"), now).is_ok());
+        costs.record(&selector("search"), 0.6, now);
+        assert!(costs.check_budget(&selector("search"), now).is_err());
+        assert!(costs
+            .check_budget(&selector("search"), now + Duration::from_secs(86400))
+            .is_ok());
+    }
+}
@@ -0,0 +1,91 @@
+use log::warn;
+use proxy_wasm::hostcalls;
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+
+// Streams waiting for callouts they made, by context id with the number of callouts in flight.
+// Streams still waiting when the vm shuts down are answered with a 503 instead of being dropped.
+#[derive(Debug, Default)]
+pub struct PausedStreams {
+    callouts: HashMap<u32, usize>,
+}
+
+impl PausedStreams {
+    pub fn add(&mut self, context_id: u32) {
+        *self.callouts.entry(context_id).or_default() += 1;
+    }
+
+    pub fn remove(&mut self, context_id: u32) {
+        if let Some(callouts) = self.callouts.get_mut(&context_id) {
+            *callouts -= 1;
+            if *callouts == 0 {
+                self.callouts.remove(&context_id);
+            }
+        }
+    }
+
+    // callouts of a stream that is done are not waited for anymore
+    pub fn done(&mut self, context_id: u32) {
+        self.callouts.remove(&context_id);
+    }
+
+    pub fn take(&mut self) -> Vec<u32> {
+        let mut context_ids: Vec<u32> = self.callouts.drain().map(|(id, _)| id).collect();
+        context_ids.sort();
+        context_ids
+    }
+}
+
+pub fn paused_streams() -> &'static RwLock<PausedStreams> {
+    static PAUSED_STREAMS: OnceLock<RwLock<PausedStreams>> = OnceLock::new();
+    PAUSED_STREAMS.get_or_init(|| RwLock::new(PausedStreams::default()))
+}
+
+// Answers the streams with a 503 the client can retry on another instance, returns how many of
+// them were answered. Switches the effective context to the streams.
+pub fn abort_streams(context_ids: &[u32]) -> usize {
+    let body = serde_json::json!({
+        "error": {
+            "type": "shutting_down",
+            "message": "the gateway is shutting down, retry the request",
+        }
+    })
+    .to_string();
+
+    let mut aborted = 0;
+    for context_id in context_ids {
+        let result = hostcalls::set_effective_context(*context_id).and_then(|_| {
+            hostcalls::send_http_response(
+                503,
+                vec![("content-type", "application/json")],
+                Some(body.as_bytes()),
+            )
+        });
+        match result {
+            Ok(()) => aborted += 1,
+            Err(e) => warn!("could not abort stream {}: {:?}", context_id, e),
+        }
+    }
+    aborted
+}
+
+#[cfg(test)]
+mod test {
+    use super::PausedStreams;
+
+    #[test]
+    fn test_streams_are_paused_while_their_callouts_are_in_flight() {
+        let mut paused_streams = PausedStreams::default();
+        paused_streams.add(2);
+        paused_streams.add(2);
+        paused_streams.add(7);
+        paused_streams.remove(7);
+        paused_streams.add(5);
+        paused_streams.done(5);
+        paused_streams.remove(2);
+        // callouts of other contexts are not counted
+        paused_streams.remove(9);
+        assert_eq!(paused_streams.take(), vec![2]);
+        assert!(paused_streams.take().is_empty());
+    }
+}
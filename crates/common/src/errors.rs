@@ -1,6 +1,6 @@
 use proxy_wasm::types::Status;
 
-use crate::{api::open_ai::ChatCompletionChunkResponseError, ratelimit};
+use crate::{api::open_ai::ChatCompletionChunkResponseError, content_encoding, ratelimit};
 
 #[derive(thiserror::Error, Debug)]
 pub enum ClientError {
@@ -10,6 +10,8 @@ pub enum ClientError {
         path: String,
         internal_status: Status,
     },
+    #[error("too many calls in flight to `{cluster}`")]
+    CalloutLimitExceeded { cluster: String },
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -37,6 +39,14 @@ pub enum ServerError {
     ExceededRatelimit(ratelimit::Error),
     #[error("{why}")]
     BadRequest { why: String },
+    #[error("request exceeds {limit} limit: {actual} > {max}")]
+    RequestLimitExceeded {
+        limit: String,
+        actual: usize,
+        max: usize,
+    },
     #[error("error in streaming response")]
     Streaming(#[from] ChatCompletionChunkResponseError),
+    #[error(transparent)]
+    ContentEncoding(#[from] content_encoding::Error),
 }
@@ -0,0 +1,114 @@
+use crate::configuration::FaultInjection;
+use crate::consts::{FAULT_ABORT_HEADER, FAULT_DELAY_HEADER};
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+
+// Faults injected into the callouts by cluster. The filter only decides which callouts fail, the
+// fault filter of the internal listener aborts or delays the callouts carrying the fault headers,
+// so the gateway sees the same responses and timeouts as from a failing cluster.
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    clusters: HashMap<String, FaultInjection>,
+}
+
+impl FaultInjector {
+    pub fn new(fault_injection: &[FaultInjection]) -> Self {
+        FaultInjector {
+            clusters: fault_injection
+                .iter()
+                .map(|fault| (fault.cluster.clone(), fault.clone()))
+                .collect(),
+        }
+    }
+
+    // headers of the faults injected into a callout to the cluster, roll draws the random numbers
+    // between 0 and 1 the probabilities of the faults are checked against
+    pub fn fault_headers(
+        &self,
+        cluster: &str,
+        mut roll: impl FnMut() -> f64,
+    ) -> Vec<(&'static str, String)> {
+        let fault = match self.clusters.get(cluster) {
+            Some(fault) => fault,
+            None => return Vec::new(),
+        };
+        let mut headers = Vec::new();
+        if fault
+            .abort_probability
+            .is_some_and(|probability| roll() < probability)
+        {
+            headers.push((FAULT_ABORT_HEADER, fault.abort_status().to_string()));
+        }
+        if let Some(delay_ms) = fault.delay_ms {
+            if fault
+                .delay_probability
+                .is_some_and(|probability| roll() < probability)
+            {
+                headers.push((FAULT_DELAY_HEADER, delay_ms.to_string()));
+            }
+        }
+        headers
+    }
+}
+
+pub fn fault_injector() -> &'static RwLock<FaultInjector> {
+    static FAULT_INJECTOR: OnceLock<RwLock<FaultInjector>> = OnceLock::new();
+    FAULT_INJECTOR.get_or_init(|| RwLock::new(FaultInjector::default()))
+}
+
+pub fn configure(fault_injection: Option<&[FaultInjection]>) {
+    *fault_injector().write().unwrap() = FaultInjector::new(fault_injection.unwrap_or_default());
+}
+
+#[cfg(test)]
+mod test {
+    use super::FaultInjector;
+    use crate::configuration::FaultInjection;
+    use crate::consts::{FAULT_ABORT_HEADER, FAULT_DELAY_HEADER};
+
+    fn rolls(rolls: &[f64]) -> impl FnMut() -> f64 + '_ {
+        let mut rolls = rolls.iter();
+        move || *rolls.next().unwrap()
+    }
+
+    #[test]
+    fn test_fault_headers() {
+        let injector = FaultInjector::new(&[
+            FaultInjection {
+                cluster: String::from("model_server"),
+                abort_probability: Some(0.1),
+                delay_probability: Some(0.5),
+                delay_ms: Some(2000),
+                ..Default::default()
+            },
+            FaultInjection {
+                cluster: String::from("app_server"),
+                abort_probability: Some(1.0),
+                abort_status: Some(500),
+                // no delay without a duration
+                delay_probability: Some(1.0),
+                ..Default::default()
+            },
+        ]);
+
+        assert!(injector
+            .fault_headers("model_server", rolls(&[0.5, 0.5]))
+            .is_empty());
+        assert_eq!(
+            injector.fault_headers("model_server", rolls(&[0.05, 0.9])),
+            vec![(FAULT_ABORT_HEADER, String::from("503"))]
+        );
+        assert_eq!(
+            injector.fault_headers("model_server", rolls(&[0.9, 0.2])),
+            vec![(FAULT_DELAY_HEADER, String::from("2000"))]
+        );
+        assert_eq!(
+            injector.fault_headers("app_server", rolls(&[0.99, 0.0])),
+            vec![(FAULT_ABORT_HEADER, String::from("500"))]
+        );
+        // clusters without faults are left alone
+        assert!(injector
+            .fault_headers("weather_server", rolls(&[0.0, 0.0]))
+            .is_empty());
+    }
+}
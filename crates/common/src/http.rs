@@ -1,42 +1,209 @@
 use crate::{
+    callout::CalloutResponse,
+    callout_limits::callout_limits,
+    capture::{CaptureTrace, CapturedCallout},
+    charset,
+    consts::{
+        CALLOUT_WAIT_PATH, CALLOUT_WAIT_POLL_INTERVAL_MS, CURVE_INTERNAL_CLUSTER_NAME,
+        CURVE_UPSTREAM_HOST_HEADER, DEFAULT_CALLOUT_TIMEOUT_SECONDS, DEFAULT_FAULT_ABORT_STATUS,
+        FAULT_ABORT_HEADER, FAULT_DELAY_HEADER,
+    },
+    drain::paused_streams,
     errors::ClientError,
-    stats::{Gauge, IncrementingMetric},
+    fault_injection::fault_injector,
+    metric_names::Dimension,
+    stats::{CounterFamily, Gauge, IncrementingMetric},
 };
 use derivative::Derivative;
-use log::trace;
+use http::{Method, StatusCode};
+use log::{debug, trace, warn};
 use proxy_wasm::traits::Context;
-use serde::Serialize;
-use std::{cell::RefCell, collections::HashMap, fmt::Debug, time::Duration};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Debug,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
 
-#[derive(Derivative, Serialize)]
+// A callout of the gateway. Calls made with internal go through the internal listener, which routes
+// them to the cluster of the x-curve-upstream header so they get the tls, retries and faults of the
+// cluster, calls made with new go to the upstream itself.
+#[derive(Derivative)]
 #[derivative(Debug)]
 pub struct CallArgs<'a> {
     upstream: &'a str,
+    cluster: Option<&'a str>,
+    method: Method,
     path: &'a str,
     headers: Vec<(&'a str, &'a str)>,
     #[derivative(Debug = "ignore")]
     body: Option<&'a [u8]>,
-    trailers: Vec<(&'a str, &'a str)>,
     timeout: Duration,
 }
 
 impl<'a> CallArgs<'a> {
-    pub fn new(
-        upstream: &'a str,
-        path: &'a str,
-        headers: Vec<(&'a str, &'a str)>,
-        body: Option<&'a [u8]>,
-        trailers: Vec<(&'a str, &'a str)>,
-        timeout: Duration,
-    ) -> Self {
+    pub fn new(upstream: &'a str, method: Method, path: &'a str) -> Self {
         CallArgs {
             upstream,
+            cluster: None,
+            method,
             path,
-            headers,
-            body,
-            trailers,
-            timeout,
+            headers: Vec::new(),
+            body: None,
+            timeout: Duration::from_secs(DEFAULT_CALLOUT_TIMEOUT_SECONDS),
+        }
+    }
+
+    pub fn internal(cluster: &'a str, method: Method, path: &'a str) -> Self {
+        CallArgs {
+            cluster: Some(cluster),
+            ..CallArgs::new(CURVE_INTERNAL_CLUSTER_NAME, method, path)
+        }
+    }
+
+    pub fn with_header(mut self, key: &'a str, value: &'a str) -> Self {
+        self.headers.push((key, value));
+        self
+    }
+
+    pub fn with_body(mut self, body: &'a [u8]) -> Self {
+        self.body = Some(body);
+        self
+    }
+
+    pub fn with_json_body(self, body: &'a [u8]) -> Self {
+        self.with_header("content-type", "application/json")
+            .with_body(body)
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn upstream(&self) -> &'a str {
+        self.upstream
+    }
+
+    // cluster the call ends up at, the callout limits and faults are configured by it
+    pub fn cluster(&self) -> &'a str {
+        self.cluster.unwrap_or(self.upstream)
+    }
+
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    pub fn path(&self) -> &'a str {
+        self.path
+    }
+
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    }
+
+    pub fn body(&self) -> Option<&'a [u8]> {
+        self.body
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    // the pseudo headers and the routing header come first, then the headers added to the call
+    fn dispatch_headers(&self) -> Vec<(&str, &str)> {
+        let mut headers = Vec::with_capacity(self.headers.len() + 4);
+        if let Some(cluster) = self.cluster {
+            headers.push((CURVE_UPSTREAM_HOST_HEADER, cluster));
         }
+        headers.push((":method", self.method.as_str()));
+        headers.push((":path", self.path));
+        headers.push((":authority", self.cluster()));
+        headers.extend(self.headers.iter().copied());
+        headers
+    }
+}
+
+// A callout over the limit of its cluster waiting for a free slot. The client checks for one each
+// time the fault filter of the internal listener answers the wake up call it holds back.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct WaitingCall<C> {
+    upstream: String,
+    cluster: Option<String>,
+    method: Method,
+    path: String,
+    headers: Vec<(String, String)>,
+    #[derivative(Debug = "ignore")]
+    body: Option<Vec<u8>>,
+    timeout: Duration,
+    deadline: SystemTime,
+    call_context: C,
+}
+
+impl<C> WaitingCall<C> {
+    fn new(call_args: &CallArgs, call_context: C, deadline: SystemTime) -> Self {
+        WaitingCall {
+            upstream: call_args.upstream.to_string(),
+            cluster: call_args.cluster.map(String::from),
+            method: call_args.method.clone(),
+            path: call_args.path.to_string(),
+            headers: call_args
+                .headers
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            body: call_args.body.map(<[u8]>::to_vec),
+            timeout: call_args.timeout,
+            deadline,
+            call_context,
+        }
+    }
+
+    fn call_args(&self) -> CallArgs<'_> {
+        CallArgs {
+            upstream: &self.upstream,
+            cluster: self.cluster.as_deref(),
+            method: self.method.clone(),
+            path: &self.path,
+            headers: self
+                .headers
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect(),
+            body: self.body.as_deref(),
+            timeout: self.timeout,
+        }
+    }
+}
+
+// metrics every client keeps of its callouts
+#[derive(Debug)]
+pub struct CalloutMetrics {
+    pub active_http_calls: Gauge,
+    // dispatched callouts by cluster
+    pub callouts: CounterFamily,
+    // callouts that could not be dispatched by cluster, shed callouts included
+    pub callout_failures: CounterFamily,
+}
+
+impl CalloutMetrics {
+    pub fn new() -> CalloutMetrics {
+        CalloutMetrics {
+            active_http_calls: Gauge::new(String::from("active_http_calls")),
+            callouts: CounterFamily::new("callouts"),
+            callout_failures: CounterFamily::new("callout_failures"),
+        }
+    }
+}
+
+impl Default for CalloutMetrics {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -54,34 +221,292 @@ pub trait Client: Context {
             call_context
         );
 
+        let cluster = call_args.cluster();
+        if callout_limits().read().unwrap().is_full(cluster) {
+            let max_wait = callout_limits().read().unwrap().max_wait(cluster);
+            if let (Some(max_wait), Some(waiting_calls)) = (max_wait, self.waiting_calls()) {
+                let deadline = self.get_current_time() + max_wait;
+                let waiting_call = WaitingCall::new(&call_args, call_context, deadline);
+                let id = self.wait_for_slot(&waiting_call)?;
+                waiting_calls.borrow_mut().insert(id, waiting_call);
+                return Ok(id);
+            }
+            self.callout_metrics()
+                .callout_failures
+                .with(&[(Dimension::Cluster, cluster)])
+                .increment(1);
+            return Err(ClientError::CalloutLimitExceeded {
+                cluster: cluster.to_string(),
+            });
+        }
+
+        let id = self.dispatch_call(&call_args)?;
+        self.add_call_context(id, call_context);
+        Ok(id)
+    }
+
+    // dispatches the callout, it counts against the limit of its cluster until its response
+    fn dispatch_call(&self, call_args: &CallArgs) -> Result<u32, ClientError> {
+        let cluster = call_args.cluster();
+        let labels = [(Dimension::Cluster, cluster)];
+        let captured = self.capture().map(|_| CapturedCallout::new(&call_args));
+
+        let fault_headers = fault_injector()
+            .read()
+            .unwrap()
+            .fault_headers(cluster, rand::random::<f64>);
+        let mut headers = call_args.dispatch_headers();
+        if !fault_headers.is_empty() {
+            debug!(
+                "injecting faults into call to {}: {:?}",
+                cluster, fault_headers
+            );
+            headers.extend(
+                fault_headers
+                    .iter()
+                    .map(|(key, value)| (*key, value.as_str())),
+            );
+        }
+
         match self.dispatch_http_call(
             call_args.upstream,
-            call_args.headers,
+            headers,
             call_args.body,
-            call_args.trailers,
+            vec![],
             call_args.timeout,
         ) {
             Ok(id) => {
-                self.add_call_context(id, call_context);
+                self.callout_metrics().callouts.with(&labels).increment(1);
+                callout_limits().write().unwrap().acquire(id, cluster);
+                if let (Some(capture), Some(callout)) = (self.capture(), captured) {
+                    capture.borrow_mut().record_call(id, callout);
+                }
                 Ok(id)
             }
-            Err(status) => Err(ClientError::DispatchError {
-                upstream_name: String::from(call_args.upstream),
-                path: String::from(call_args.path),
+            Err(status) => {
+                self.callout_metrics()
+                    .callout_failures
+                    .with(&labels)
+                    .increment(1);
+                Err(ClientError::DispatchError {
+                    upstream_name: String::from(call_args.upstream),
+                    path: String::from(call_args.path),
+                    internal_status: status,
+                })
+            }
+        }
+    }
+
+    // the wake up call is aborted by the fault filter after the poll interval, or once the deadline
+    // of the waiting call passed
+    fn wait_for_slot(
+        &self,
+        waiting_call: &WaitingCall<Self::CallContext>,
+    ) -> Result<u32, ClientError> {
+        let delay = waiting_call
+            .deadline
+            .duration_since(self.get_current_time())
+            .unwrap_or_default()
+            .min(Duration::from_millis(CALLOUT_WAIT_POLL_INTERVAL_MS));
+        let delay_ms = delay.as_millis().to_string();
+        let abort_status = DEFAULT_FAULT_ABORT_STATUS.to_string();
+        let headers = vec![
+            (":method", "GET"),
+            (":path", CALLOUT_WAIT_PATH),
+            (":authority", CURVE_INTERNAL_CLUSTER_NAME),
+            (FAULT_DELAY_HEADER, delay_ms.as_str()),
+            (FAULT_ABORT_HEADER, abort_status.as_str()),
+        ];
+        // the listener answers at once when it has no fault filter, the call then polls
+        let timeout = delay + Duration::from_secs(1);
+        let id = self
+            .dispatch_http_call(CURVE_INTERNAL_CLUSTER_NAME, headers, None, vec![], timeout)
+            .map_err(|status| ClientError::DispatchError {
+                upstream_name: String::from(CURVE_INTERNAL_CLUSTER_NAME),
+                path: String::from(CALLOUT_WAIT_PATH),
                 internal_status: status,
-            }),
+            })?;
+        debug!(
+            "call to {} waits for a free slot, wake up call id={}",
+            waiting_call.call_args().cluster(),
+            id
+        );
+        if let Some(context_id) = self.paused_stream_id() {
+            paused_streams().write().unwrap().add(context_id);
         }
+        Ok(id)
+    }
+
+    // the waiting call is dispatched once its cluster has a free slot. Its context is returned when
+    // it can no longer wait, the response of the wake up call, a 503, is then its response.
+    fn resume_waiting_call(
+        &self,
+        waiting_call: WaitingCall<Self::CallContext>,
+    ) -> Option<Self::CallContext> {
+        let cluster = waiting_call.call_args().cluster().to_string();
+        if !callout_limits().read().unwrap().is_full(&cluster) {
+            return match self.dispatch_call(&waiting_call.call_args()) {
+                Ok(id) => {
+                    self.add_call_context(id, waiting_call.call_context);
+                    None
+                }
+                Err(e) => {
+                    warn!("could not dispatch waiting call to {}: {}", cluster, e);
+                    Some(waiting_call.call_context)
+                }
+            };
+        }
+
+        if self.get_current_time() < waiting_call.deadline {
+            match self.wait_for_slot(&waiting_call) {
+                Ok(id) => {
+                    if let Some(waiting_calls) = self.waiting_calls() {
+                        waiting_calls.borrow_mut().insert(id, waiting_call);
+                    }
+                    return None;
+                }
+                Err(e) => warn!("could not keep waiting for a slot of {}: {}", cluster, e),
+            }
+        } else {
+            debug!("call to {} waited too long for a free slot", cluster);
+        }
+        self.callout_metrics()
+            .callout_failures
+            .with(&[(Dimension::Cluster, &cluster)])
+            .increment(1);
+        Some(waiting_call.call_context)
     }
 
     fn add_call_context(&self, id: u32, call_context: Self::CallContext) {
         let callouts = self.callouts();
         if callouts.borrow_mut().insert(id, call_context).is_some() {
-            panic!("Duplicate http call with id={}", id);
+            // the host reused the token of a call in flight, the context of the first call is lost
+            warn!("duplicate http call with id={}", id);
+            return;
+        }
+        self.callout_metrics().active_http_calls.increment(1);
+        if let Some(context_id) = self.paused_stream_id() {
+            paused_streams().write().unwrap().add(context_id);
+        }
+    }
+
+    // None when no call waits for the response, also when a waiting call was dispatched or keeps
+    // waiting
+    fn remove_call_context(&self, id: u32) -> Option<Self::CallContext> {
+        let waiting_call = self
+            .waiting_calls()
+            .and_then(|waiting_calls| waiting_calls.borrow_mut().remove(&id));
+        if let Some(waiting_call) = waiting_call {
+            if let Some(context_id) = self.paused_stream_id() {
+                paused_streams().write().unwrap().remove(context_id);
+            }
+            return self.resume_waiting_call(waiting_call);
+        }
+
+        let call_context = self.callouts().borrow_mut().remove(&id)?;
+        self.callout_metrics().active_http_calls.increment(-1);
+        callout_limits().write().unwrap().release(id);
+        if let Some(context_id) = self.paused_stream_id() {
+            paused_streams().write().unwrap().remove(context_id);
+        }
+        Some(call_context)
+    }
+
+    // the response of the callout being answered, a response without status is taken as 200
+    fn call_response(&self, body: Vec<u8>) -> CalloutResponse {
+        let status = match self.get_http_call_response_header(":status") {
+            Some(status) => StatusCode::from_str(&status).unwrap_or(StatusCode::BAD_GATEWAY),
+            None => StatusCode::OK,
+        };
+        CalloutResponse::new(status, body)
+    }
+
+    // body of the callout response as text, decoded with the charset of its content-type
+    fn call_response_text(&self, body: &[u8]) -> String {
+        let content_type = self.get_http_call_response_header("content-type");
+        charset::decode(body, content_type.as_deref())
+    }
+
+    // the response of a callout is added to the trace of a captured request
+    fn capture_call_response(&self, token_id: u32, body: &[u8]) {
+        if let Some(capture) = self.capture() {
+            let headers = self.get_http_call_response_headers();
+            capture
+                .borrow_mut()
+                .record_response(token_id, headers, body);
         }
-        self.active_http_calls().increment(1);
     }
 
     fn callouts(&self) -> &RefCell<HashMap<u32, Self::CallContext>>;
 
-    fn active_http_calls(&self) -> &Gauge;
+    fn callout_metrics(&self) -> &CalloutMetrics;
+
+    // calls waiting for a free slot by the id of their wake up call, None for clients whose calls
+    // over the limit are always shed
+    fn waiting_calls(&self) -> Option<&RefCell<HashMap<u32, WaitingCall<Self::CallContext>>>> {
+        None
+    }
+
+    // the stream waiting for the callouts, None for callouts no stream waits for
+    fn paused_stream_id(&self) -> Option<u32> {
+        None
+    }
+
+    // the trace the callouts are recorded into, None unless the request was sampled for capture
+    fn capture(&self) -> Option<&RefCell<CaptureTrace>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CallArgs;
+    use crate::consts::{
+        CURVE_INTERNAL_CLUSTER_NAME, CURVE_UPSTREAM_HOST_HEADER, DEFAULT_CALLOUT_TIMEOUT_SECONDS,
+    };
+    use http::{Method, StatusCode};
+    use std::time::Duration;
+
+    #[test]
+    fn test_internal_call_headers() {
+        let call_args = CallArgs::internal("api_server", Method::POST, "/weather")
+            .with_json_body(b"{}")
+            .with_header("x-request-id", "req-1");
+
+        assert_eq!(call_args.upstream(), CURVE_INTERNAL_CLUSTER_NAME);
+        assert_eq!(call_args.cluster(), "api_server");
+        assert_eq!(
+            call_args.timeout(),
+            Duration::from_secs(DEFAULT_CALLOUT_TIMEOUT_SECONDS)
+        );
+        assert_eq!(
+            call_args.dispatch_headers(),
+            vec![
+                (CURVE_UPSTREAM_HOST_HEADER, "api_server"),
+                (":method", "POST"),
+                (":path", "/weather"),
+                (":authority", "api_server"),
+                ("content-type", "application/json"),
+                ("x-request-id", "req-1"),
+            ]
+        );
+        assert_eq!(call_args.header("Content-Type"), Some("application/json"));
+    }
+
+    #[test]
+    fn test_direct_call_headers() {
+        let call_args = CallArgs::new("opentelemetry_collector_http", Method::GET, "/health")
+            .with_timeout(Duration::from_secs(5));
+
+        assert_eq!(call_args.cluster(), "opentelemetry_collector_http");
+        assert_eq!(call_args.body(), None);
+        assert_eq!(
+            call_args.dispatch_headers(),
+            vec![
+                (":method", "GET"),
+                (":path", "/health"),
+                (":authority", "opentelemetry_collector_http"),
+            ]
+        );
+    }
 }
@@ -0,0 +1,157 @@
+use crate::configuration::PromptTarget;
+use serde_yaml::Value;
+use std::collections::{HashMap, HashSet};
+
+// a prompt target is only picked when it shares at least this many words with the prompt
+const MIN_SHARED_WORDS: usize = 2;
+
+// words too common to tell prompt targets apart
+const STOP_WORDS: &[&str] = &[
+    "about", "all", "and", "any", "are", "can", "could", "did", "does", "for", "from", "get",
+    "give", "has", "have", "how", "into", "its", "like", "need", "not", "now", "our", "please",
+    "should", "some", "tell", "that", "the", "this", "want", "was", "were", "what", "when",
+    "where", "which", "who", "will", "with", "would", "you", "your",
+];
+
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .map(str::to_lowercase)
+        .filter(|word| word.len() > 2 && !STOP_WORDS.contains(&word.as_str()))
+        // plurals match the singular
+        .map(|word| match word.strip_suffix('s') {
+            Some(stem) if stem.len() > 2 && !stem.ends_with('s') => stem.to_string(),
+            _ => word,
+        })
+        .collect()
+}
+
+fn prompt_target_words(prompt_target: &PromptTarget) -> HashSet<String> {
+    let mut text = format!("{} {}", prompt_target.name, prompt_target.description);
+    for parameter in prompt_target.parameters.iter().flatten() {
+        text.push_str(&format!(" {} {}", parameter.name, parameter.description));
+    }
+    words(&text)
+}
+
+// targets that can be called without parameters extracted from the prompt, every required
+// parameter has a default
+pub fn callable_without_arguments(prompt_target: &PromptTarget) -> bool {
+    (prompt_target.endpoint.is_some() || prompt_target.steps.is_some())
+        && prompt_target
+            .parameters
+            .iter()
+            .flatten()
+            .all(|parameter| !parameter.required.unwrap_or_default() || parameter.default.is_some())
+}
+
+// coarse intent matching for when function calling is unavailable: the prompt target sharing the
+// most words with the prompt, None when none shares enough of them or the best ones tie
+pub fn best_match<'a>(
+    prompt: &str,
+    prompt_targets: impl Iterator<Item = &'a PromptTarget>,
+) -> Option<&'a PromptTarget> {
+    let prompt_words = words(prompt);
+    let mut best: Option<(&PromptTarget, usize)> = None;
+    let mut tied = false;
+    for prompt_target in prompt_targets {
+        let shared = prompt_target_words(prompt_target)
+            .intersection(&prompt_words)
+            .count();
+        if shared < MIN_SHARED_WORDS {
+            continue;
+        }
+        match best {
+            Some((_, best_shared)) if shared < best_shared => {}
+            Some((_, best_shared)) if shared == best_shared => tied = true,
+            _ => {
+                best = Some((prompt_target, shared));
+                tied = false;
+            }
+        }
+    }
+    best.filter(|_| !tied)
+        .map(|(prompt_target, _)| prompt_target)
+}
+
+// the arguments a matched prompt target is called with, the defaults of its parameters
+pub fn default_arguments(prompt_target: &PromptTarget) -> HashMap<String, Value> {
+    prompt_target
+        .parameters
+        .iter()
+        .flatten()
+        .filter_map(|parameter| {
+            let default = parameter.default.as_ref()?;
+            Some((parameter.name.clone(), Value::String(default.clone())))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{best_match, callable_without_arguments, default_arguments};
+    use crate::configuration::PromptTarget;
+
+    fn prompt_targets() -> Vec<PromptTarget> {
+        serde_yaml::from_str(
+            r#"
+- name: reboot_devices
+  description: Reboot network devices like routers and switches
+  endpoint:
+    name: api_server
+    path: /reboot
+- name: weather_forecast
+  description: Realtime weather forecast for a city
+  parameters:
+    - name: city
+      description: The city of the forecast
+      required: true
+      default: seattle
+  endpoint:
+    name: api_server
+    path: /weather
+- name: billing_history
+  description: Billing history and invoices of the account
+  parameters:
+    - name: account
+      description: The account number
+      required: true
+  endpoint:
+    name: api_server
+    path: /billing
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_best_match() {
+        let prompt_targets = prompt_targets();
+        let best = |prompt: &str| best_match(prompt, prompt_targets.iter()).map(|pt| &pt.name);
+
+        assert_eq!(
+            best("please reboot the routers").map(String::as_str),
+            Some("reboot_devices")
+        );
+        assert_eq!(
+            best("what is the weather forecast for tomorrow?").map(String::as_str),
+            Some("weather_forecast")
+        );
+        // a single shared word is not enough
+        assert_eq!(best("what is the weather like"), None);
+        assert_eq!(best("tell me a joke"), None);
+    }
+
+    #[test]
+    fn test_callable_without_arguments() {
+        let prompt_targets = prompt_targets();
+        assert!(callable_without_arguments(&prompt_targets[0]));
+        assert!(callable_without_arguments(&prompt_targets[1]));
+        assert!(!callable_without_arguments(&prompt_targets[2]));
+        assert_eq!(
+            default_arguments(&prompt_targets[1])
+                .get("city")
+                .and_then(|city| city.as_str()),
+            Some("seattle")
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use crate::api::open_ai::{ChatCompletionsRequest, Message};
+use crate::consts::{ASSISTANT_ROLE, JSON_REPAIR_PROMPT, USER_ROLE};
+use serde_json::Value;
+
+// response_format of a chat completions request that asks for json
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResponseFormat {
+    JsonObject,
+    JsonSchema(Value),
+}
+
+impl ResponseFormat {
+    pub fn from_request(request: &ChatCompletionsRequest) -> Option<Self> {
+        let response_format = request.extra_fields.get("response_format")?;
+        match response_format.get("type")?.as_str()? {
+            "json_object" => Some(ResponseFormat::JsonObject),
+            "json_schema" => {
+                let schema = response_format.get("json_schema")?.get("schema")?;
+                Some(ResponseFormat::JsonSchema(schema.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    // the json of the response content or what is wrong with it. Models sometimes wrap the json
+    // in a markdown code block, it is taken out of it.
+    pub fn validate(&self, content: &str) -> Result<String, String> {
+        let json = strip_code_block(content.trim());
+        let value: Value =
+            serde_json::from_str(json).map_err(|e| format!("invalid json: {}", e))?;
+        match self {
+            ResponseFormat::JsonObject if !value.is_object() => {
+                return Err("expected a json object".to_string());
+            }
+            ResponseFormat::JsonObject => {}
+            ResponseFormat::JsonSchema(schema) => validate_schema(schema, &value, "$")?,
+        }
+        Ok(json.to_string())
+    }
+}
+
+fn strip_code_block(text: &str) -> &str {
+    let Some(code) = text
+        .strip_prefix("```")
+        .and_then(|text| text.strip_suffix("```"))
+    else {
+        return text;
+    };
+    // the language of the code block, e.g. json, ends the first line
+    match code.split_once('\n') {
+        Some((language, rest)) if !language.trim_start().starts_with(['{', '[']) => rest.trim(),
+        _ => code.trim(),
+    }
+}
+
+// checks the part of json schema used for structured outputs: type, enum, const, properties,
+// required, additionalProperties and items. Other keywords are not checked.
+fn validate_schema(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let types: Vec<&str> = match schema.get("type") {
+        Some(Value::String(schema_type)) => vec![schema_type.as_str()],
+        Some(Value::Array(types)) => types.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    if !types.is_empty() && !types.iter().any(|schema_type| has_type(value, schema_type)) {
+        return Err(format!("{}: expected {}", path, types.join(" or ")));
+    }
+    if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+        if !values.contains(value) {
+            return Err(format!(
+                "{}: {} is not one of the allowed values",
+                path, value
+            ));
+        }
+    }
+    if schema
+        .get("const")
+        .is_some_and(|expected| expected != value)
+    {
+        return Err(format!("{}: {} is not the allowed value", path, value));
+    }
+
+    if let Some(object) = value.as_object() {
+        let required = schema.get("required").and_then(Value::as_array);
+        for name in required.into_iter().flatten().filter_map(Value::as_str) {
+            if !object.contains_key(name) {
+                return Err(format!("{}: missing property {}", path, name));
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        let additional_properties = schema.get("additionalProperties");
+        for (name, property) in object {
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(property_schema) => {
+                    validate_schema(property_schema, property, &format!("{}.{}", path, name))?
+                }
+                None if additional_properties == Some(&Value::Bool(false)) => {
+                    return Err(format!("{}: unexpected property {}", path, name));
+                }
+                None => {}
+            }
+        }
+    }
+
+    if let (Some(items), Some(array)) = (schema.get("items"), value.as_array()) {
+        for (index, item) in array.iter().enumerate() {
+            validate_schema(items, item, &format!("{}[{}]", path, index))?;
+        }
+    }
+    Ok(())
+}
+
+fn has_type(value: &Value, schema_type: &str) -> bool {
+    match schema_type {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.as_f64().is_some_and(|number| number.fract() == 0.0),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+// the request followed by the invalid response and what is wrong with it, for the llm provider
+// to correct its response
+pub fn repair_request(
+    request: &ChatCompletionsRequest,
+    content: &str,
+    error: &str,
+) -> ChatCompletionsRequest {
+    let mut repair_request = request.clone();
+    repair_request.stream = false;
+    repair_request.stream_options = None;
+    repair_request.extra_fields.remove("n");
+    for (role, content) in [
+        (ASSISTANT_ROLE, content.to_string()),
+        (USER_ROLE, format!("{}{}", JSON_REPAIR_PROMPT, error)),
+    ] {
+        repair_request.messages.push(Message {
+            role: role.to_string(),
+            content: Some(content.into()),
+            model: None,
+            tool_calls: None,
+            tool_call_id: None,
+            name: None,
+        });
+    }
+    repair_request
+}
+
+#[cfg(test)]
+mod test {
+    use super::{repair_request, ResponseFormat};
+    use crate::api::open_ai::ChatCompletionsRequest;
+    use crate::consts::{ASSISTANT_ROLE, USER_ROLE};
+
+    fn request(response_format: &str) -> ChatCompletionsRequest {
+        serde_json::from_str(&format!(
+            r#"{{
+                "model": "gpt-4o",
+                "messages": [{{"role": "user", "content": "weather in seattle as json"}}],
+                "n": 2,
+                "response_format": {}
+            }}"#,
+            response_format
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_validate_json_object() {
+        let json_object = request(r#"{"type": "json_object"}"#);
+        let response_format = ResponseFormat::from_request(&json_object).unwrap();
+        assert_eq!(response_format, ResponseFormat::JsonObject);
+
+        assert_eq!(
+            response_format.validate("```json\n{\"city\": \"seattle\"}\n```"),
+            Ok("{\"city\": \"seattle\"}".to_string())
+        );
+        assert_eq!(
+            response_format.validate("```{\"city\": \"seattle\"}```"),
+            Ok("{\"city\": \"seattle\"}".to_string())
+        );
+        assert_eq!(
+            response_format.validate("[1, 2]"),
+            Err("expected a json object".to_string())
+        );
+        assert!(response_format
+            .validate("Sure! {\"city\": \"seattle\"}")
+            .unwrap_err()
+            .starts_with("invalid json"));
+
+        assert!(ResponseFormat::from_request(&request(r#"{"type": "text"}"#)).is_none());
+    }
+
+    #[test]
+    fn test_validate_json_schema() {
+        let json_schema = request(
+            r#"{
+                "type": "json_schema",
+                "json_schema": {
+                    "name": "forecast",
+                    "schema": {
+                        "type": "object",
+                        "properties": {
+                            "city": {"type": "string"},
+                            "days": {
+                                "type": "array",
+                                "items": {
+                                    "type": "object",
+                                    "properties": {
+                                        "temperature": {"type": "integer"},
+                                        "sky": {"enum": ["sunny", "cloudy"]}
+                                    },
+                                    "required": ["temperature"]
+                                }
+                            }
+                        },
+                        "required": ["city", "days"],
+                        "additionalProperties": false
+                    }
+                }
+            }"#,
+        );
+        let response_format = ResponseFormat::from_request(&json_schema).unwrap();
+
+        let valid = r#"{"city": "seattle", "days": [{"temperature": 21, "sky": "sunny"}]}"#;
+        assert!(response_format.validate(valid).is_ok());
+        for (content, error) in [
+            (r#"{"city": "seattle"}"#, "$: missing property days"),
+            (
+                r#"{"city": "seattle", "days": [], "unit": "c"}"#,
+                "$: unexpected property unit",
+            ),
+            (
+                r#"{"city": "seattle", "days": [{"temperature": 21.5}]}"#,
+                "$.days[0].temperature: expected integer",
+            ),
+            (
+                r#"{"city": "seattle", "days": [{"temperature": 21, "sky": "rainy"}]}"#,
+                "$.days[0].sky: \"rainy\" is not one of the allowed values",
+            ),
+        ] {
+            assert_eq!(response_format.validate(content), Err(error.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_repair_request() {
+        let repair_request = repair_request(
+            &request(r#"{"type": "json_object"}"#),
+            "{\"city\": ",
+            "invalid json",
+        );
+
+        assert!(!repair_request.extra_fields.contains_key("n"));
+        assert!(repair_request.extra_fields.contains_key("response_format"));
+        assert_eq!(repair_request.messages.len(), 3);
+        assert_eq!(repair_request.messages[1].role, ASSISTANT_ROLE);
+        assert_eq!(repair_request.messages[2].role, USER_ROLE);
+        assert!(repair_request.messages[2]
+            .content
+            .as_ref()
+            .unwrap()
+            .text()
+            .ends_with("The problem is: invalid json"));
+    }
+}
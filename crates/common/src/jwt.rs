@@ -0,0 +1,357 @@
+use crate::configuration::JwtAuth;
+use crate::consts::{JWKS_FETCH_TIMEOUT_SECONDS, JWT_CLOCK_SKEW_SECONDS};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rsa::{BigUint, Pkcs1v15Sign, RsaPublicKey};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub type Claims = Map<String, Value>;
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum Error {
+    #[error("token missing")]
+    MissingToken,
+    #[error("token malformed")]
+    Malformed,
+    #[error("algorithm {0} not supported")]
+    UnsupportedAlgorithm(String),
+    #[error("no signing key for the token")]
+    UnknownKey,
+    #[error("signature invalid")]
+    InvalidSignature,
+    #[error("token expired")]
+    Expired,
+    #[error("token not valid yet")]
+    NotYetValid,
+    #[error("issuer not accepted")]
+    InvalidIssuer,
+    #[error("audience not accepted")]
+    InvalidAudience,
+    #[error("signing keys not loaded yet")]
+    KeysUnavailable,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+// RSA keys of a JWKS document by key id, keys of other types are skipped
+#[derive(Debug, Default)]
+pub struct SigningKeys {
+    keys: Vec<(Option<String>, RsaPublicKey)>,
+}
+
+impl SigningKeys {
+    pub fn from_jwks(jwks: &[u8]) -> Result<Self, serde_json::Error> {
+        let jwk_set: JwkSet = serde_json::from_slice(jwks)?;
+        let keys = jwk_set
+            .keys
+            .into_iter()
+            .filter(|jwk| jwk.kty == "RSA")
+            .filter_map(|jwk| {
+                let n = URL_SAFE_NO_PAD.decode(jwk.n?).ok()?;
+                let e = URL_SAFE_NO_PAD.decode(jwk.e?).ok()?;
+                let key = RsaPublicKey::new(BigUint::from_bytes_be(&n), BigUint::from_bytes_be(&e))
+                    .ok()?;
+                Some((jwk.kid, key))
+            })
+            .collect();
+        Ok(SigningKeys { keys })
+    }
+
+    // tokens without a kid can only be checked when the set has a single key
+    fn get(&self, kid: Option<&str>) -> Option<&RsaPublicKey> {
+        match kid {
+            Some(kid) => self
+                .keys
+                .iter()
+                .find(|(key_id, _)| key_id.as_deref() == Some(kid)),
+            None if self.keys.len() == 1 => self.keys.first(),
+            None => None,
+        }
+        .map(|(_, key)| key)
+    }
+}
+
+// Signing keys of the JWKS endpoint. They are fetched by the filter on tick and shared with the
+// streams of the worker.
+#[derive(Debug, Default)]
+pub struct JwksCache {
+    keys: Option<SigningKeys>,
+    fetched_at: Option<SystemTime>,
+    requested_at: Option<SystemTime>,
+}
+
+impl JwksCache {
+    // keys older than the ttl are fetched again, a fetch that got no usable response is retried
+    // once it timed out. Stale keys keep being used until the fetch succeeds.
+    pub fn should_fetch(&self, now: SystemTime, ttl: Duration) -> bool {
+        let elapsed = |since: SystemTime| now.duration_since(since).unwrap_or_default();
+        let fresh = self
+            .fetched_at
+            .is_some_and(|fetched_at| elapsed(fetched_at) < ttl);
+        let pending = self.requested_at.is_some_and(|requested_at| {
+            elapsed(requested_at) < Duration::from_secs(JWKS_FETCH_TIMEOUT_SECONDS)
+        });
+        !fresh && !pending
+    }
+
+    pub fn fetching(&mut self, now: SystemTime) {
+        self.requested_at = Some(now);
+    }
+
+    pub fn update(&mut self, jwks: &[u8], now: SystemTime) -> Result<(), serde_json::Error> {
+        self.keys = Some(SigningKeys::from_jwks(jwks)?);
+        self.fetched_at = Some(now);
+        self.requested_at = None;
+        Ok(())
+    }
+}
+
+pub fn jwks_cache() -> &'static RwLock<JwksCache> {
+    static JWKS_CACHE: OnceLock<RwLock<JwksCache>> = OnceLock::new();
+    JWKS_CACHE.get_or_init(|| RwLock::new(JwksCache::default()))
+}
+
+// header_value is the value of the configured token header, by default the authorization header
+// with a bearer token
+pub fn authenticate(
+    jwt_auth: &JwtAuth,
+    header_value: Option<&str>,
+    now: SystemTime,
+) -> Result<Claims, Error> {
+    let token = match jwt_auth.header {
+        Some(_) => header_value,
+        None => header_value.and_then(|value| value.strip_prefix("Bearer ")),
+    }
+    .ok_or(Error::MissingToken)?;
+
+    let jwks_cache = jwks_cache().read().unwrap();
+    let keys = jwks_cache.keys.as_ref().ok_or(Error::KeysUnavailable)?;
+    validate(token.trim(), keys, jwt_auth, now)
+}
+
+// only RS256 signed tokens are accepted
+pub fn validate(
+    token: &str,
+    keys: &SigningKeys,
+    jwt_auth: &JwtAuth,
+    now: SystemTime,
+) -> Result<Claims, Error> {
+    let (signed, signature) = token.rsplit_once('.').ok_or(Error::Malformed)?;
+    let (header, claims) = signed.split_once('.').ok_or(Error::Malformed)?;
+
+    let header: JwtHeader = decode(header)?;
+    if header.alg != "RS256" {
+        return Err(Error::UnsupportedAlgorithm(header.alg));
+    }
+    let key = keys.get(header.kid.as_deref()).ok_or(Error::UnknownKey)?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| Error::Malformed)?;
+    let hash = Sha256::digest(signed.as_bytes());
+    key.verify(Pkcs1v15Sign::new::<Sha256>(), &hash, &signature)
+        .map_err(|_| Error::InvalidSignature)?;
+
+    let claims: Claims = decode(claims)?;
+    check_claims(&claims, jwt_auth, now)?;
+    Ok(claims)
+}
+
+fn decode<T: for<'de> Deserialize<'de>>(part: &str) -> Result<T, Error> {
+    let json = URL_SAFE_NO_PAD.decode(part).map_err(|_| Error::Malformed)?;
+    serde_json::from_slice(&json).map_err(|_| Error::Malformed)
+}
+
+fn check_claims(claims: &Claims, jwt_auth: &JwtAuth, now: SystemTime) -> Result<(), Error> {
+    let now = now
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default();
+    let claim = |name: &str| claims.get(name).and_then(Value::as_u64);
+
+    if claim("exp").is_some_and(|exp| now > exp + JWT_CLOCK_SKEW_SECONDS) {
+        return Err(Error::Expired);
+    }
+    if claim("nbf").is_some_and(|nbf| now + JWT_CLOCK_SKEW_SECONDS < nbf) {
+        return Err(Error::NotYetValid);
+    }
+
+    if let Some(issuer) = jwt_auth.issuer.as_ref() {
+        if claims.get("iss").and_then(Value::as_str) != Some(issuer.as_str()) {
+            return Err(Error::InvalidIssuer);
+        }
+    }
+
+    if let Some(audiences) = jwt_auth.audiences.as_ref() {
+        let accepted = |aud: &str| audiences.iter().any(|audience| audience == aud);
+        let accepted = match claims.get("aud") {
+            Some(Value::String(aud)) => accepted(aud),
+            Some(Value::Array(auds)) => auds.iter().filter_map(Value::as_str).any(accepted),
+            _ => false,
+        };
+        if !accepted {
+            return Err(Error::InvalidAudience);
+        }
+    }
+
+    Ok(())
+}
+
+// request headers set from the claims, None removes the header when the claim is missing so that
+// clients can't set it themselves. Arrays are joined with commas.
+pub fn claim_headers<'a>(jwt_auth: &'a JwtAuth, claims: &Claims) -> Vec<(&'a str, Option<String>)> {
+    jwt_auth
+        .claim_headers
+        .iter()
+        .flatten()
+        .map(|(claim, header)| {
+            let value = claims.get(claim).and_then(|value| match value {
+                Value::Array(values) => Some(
+                    values
+                        .iter()
+                        .filter_map(claim_value)
+                        .collect::<Vec<_>>()
+                        .join(","),
+                ),
+                value => claim_value(value),
+            });
+            (header.as_str(), value)
+        })
+        .collect()
+}
+
+fn claim_value(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Null | Value::Array(_) | Value::Object(_) => None,
+        value => Some(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{claim_headers, validate, Error, SigningKeys};
+    use crate::configuration::{Jwks, JwtAuth};
+    use std::collections::HashMap;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const JWKS: &str = concat!(
+        r#"{"keys":[{"kty":"RSA","kid":"test-key","alg":"RS256","use":"sig","e":"AQAB","n":""#,
+        "us6er-V-3AexsJYi0DV6Qase3VCLcBTYmi0RPeP5RzRnSSxB1hQVripcMYZwW39eYuXIiZ8LcWYqtxKRd",
+        "vY3wjKAtcYtzqchX5SfZjXiqHm0SyL1n5J47Ge_T9Ly2F8_4f6AejqRfH9tTzFIDMJpTzBqzYNXhKcaWj3qhJHs70s",
+        r#""}]}"#
+    );
+
+    // claims: iss https://auth.example.com, aud [curve], sub support-bot,
+    // groups [support, network-admins], nbf 1700000000, exp 4102444800
+    const HEADER: &str = "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCIsImtpZCI6InRlc3Qta2V5In0";
+    const CLAIMS: &str = concat!(
+        "eyJpc3MiOiJodHRwczovL2F1dGguZXhhbXBsZS5jb20iLCJhdWQiOlsiY3VydmUiXSwic3ViIjoic3VwcG9ydC1",
+        "ib3QiLCJncm91cHMiOlsic3VwcG9ydCIsIm5ldHdvcmstYWRtaW5zIl0sIm5iZiI6MTcwMDAwMDAwMCwiZXhwIjo0",
+        "MTAyNDQ0ODAwfQ"
+    );
+    const SIGNATURE: &str = concat!(
+        "RMhZ8Pb1wuFJu62bmdO_Q4UE1YPZvEo8lGkn5sY8YZOS67EPwVIe7p3zknVlDbMThRrbJJWE2S2Joaei628D-j92f",
+        "_o92g7lc7A_vss5t3JmmpVcT8SK65Db3qJ6XxleqmARYkeG3b3-azE8k-Kg6nQiZpl03fcHSu4axcchpI8"
+    );
+
+    fn jwt_auth() -> JwtAuth {
+        JwtAuth {
+            jwks: Jwks {
+                endpoint: "auth_server".to_string(),
+                path: None,
+                ttl_seconds: None,
+            },
+            issuer: Some("https://auth.example.com".to_string()),
+            audiences: Some(vec!["curve".to_string()]),
+            header: None,
+            claim_headers: Some(HashMap::from([
+                ("sub".to_string(), "x-client-id".to_string()),
+                ("groups".to_string(), "x-user-groups".to_string()),
+                ("tenant".to_string(), "x-tenant-id".to_string()),
+            ])),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let keys = SigningKeys::from_jwks(JWKS.as_bytes()).unwrap();
+        let token = format!("{}.{}.{}", HEADER, CLAIMS, SIGNATURE);
+        let at = |secs: u64| UNIX_EPOCH + Duration::from_secs(secs);
+        let jwt_auth = jwt_auth();
+
+        let claims = validate(&token, &keys, &jwt_auth, at(1800000000)).unwrap();
+        let mut headers = claim_headers(&jwt_auth, &claims);
+        headers.sort();
+        assert_eq!(
+            headers,
+            vec![
+                ("x-client-id", Some("support-bot".to_string())),
+                ("x-tenant-id", None),
+                ("x-user-groups", Some("support,network-admins".to_string())),
+            ]
+        );
+
+        assert_eq!(
+            validate(&token, &keys, &jwt_auth, at(4102444800 + 3600)),
+            Err(Error::Expired)
+        );
+        assert_eq!(
+            validate(&token, &keys, &jwt_auth, at(1600000000)),
+            Err(Error::NotYetValid)
+        );
+
+        let tampered = format!("{}.{}.{}", HEADER, &CLAIMS[4..], SIGNATURE);
+        assert_eq!(
+            validate(&tampered, &keys, &jwt_auth, at(1800000000)),
+            Err(Error::InvalidSignature)
+        );
+        let forged = format!("{}.{}.S{}", HEADER, CLAIMS, &SIGNATURE[1..]);
+        assert_eq!(
+            validate(&forged, &keys, &jwt_auth, at(1800000000)),
+            Err(Error::InvalidSignature)
+        );
+
+        let other_issuer = JwtAuth {
+            issuer: Some("https://login.example.com".to_string()),
+            ..jwt_auth.clone()
+        };
+        assert_eq!(
+            validate(&token, &keys, &other_issuer, at(1800000000)),
+            Err(Error::InvalidIssuer)
+        );
+        let other_audience = JwtAuth {
+            audiences: Some(vec!["billing".to_string()]),
+            ..jwt_auth.clone()
+        };
+        assert_eq!(
+            validate(&token, &keys, &other_audience, at(1800000000)),
+            Err(Error::InvalidAudience)
+        );
+
+        assert_eq!(
+            validate("not-a-token", &keys, &jwt_auth, at(1800000000)),
+            Err(Error::Malformed)
+        );
+    }
+}
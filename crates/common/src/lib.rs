@@ -1,13 +1,44 @@
+pub mod access_control;
+pub mod access_log;
 pub mod api;
+pub mod audit;
+pub mod callout;
+pub mod callout_limits;
+pub mod capture;
+pub mod chain;
+pub mod charset;
+pub mod coalescing;
+pub mod compression;
+pub mod conditions;
+pub mod config_validation;
 pub mod configuration;
 pub mod consts;
+pub mod content_encoding;
+pub mod context_window;
+pub mod cors;
+pub mod cost;
+pub mod drain;
 pub mod errors;
+pub mod fault_injection;
+pub mod guards;
 pub mod http;
+pub mod intent_fallback;
+pub mod json_mode;
+pub mod jwt;
 pub mod llm_providers;
+pub mod logging;
+pub mod metric_names;
+pub mod notifications;
+pub mod parameter_collection;
 pub mod path;
 pub mod pii;
+pub mod priority;
 pub mod ratelimit;
+pub mod response_cache;
 pub mod routing;
 pub mod stats;
+pub mod summarization;
+pub mod template;
 pub mod tokenizer;
 pub mod tracing;
+pub mod virtual_keys;
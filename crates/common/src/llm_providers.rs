@@ -1,7 +1,25 @@
-use crate::configuration::LlmProvider;
+use crate::api::open_ai::ChatCompletionsRequest;
+use crate::configuration::{LlmProvider, LlmProviderType};
 use std::collections::HashMap;
 use std::rc::Rc;
 
+// request parameters accepted by the mistral chat completions api besides the ones modelled in
+// ChatCompletionsRequest, mistral rejects requests with unknown fields
+const MISTRAL_PARAMETERS: [&str; 12] = [
+    "temperature",
+    "top_p",
+    "max_tokens",
+    "stop",
+    "random_seed",
+    "response_format",
+    "tool_choice",
+    "presence_penalty",
+    "frequency_penalty",
+    "n",
+    "parallel_tool_calls",
+    "safe_prompt",
+];
+
 #[derive(Debug)]
 pub struct LlmProviders {
     providers: HashMap<String, Rc<LlmProvider>>,
@@ -67,3 +85,70 @@ impl TryFrom<Vec<LlmProvider>> for LlmProviders {
         Ok(llm_providers)
     }
 }
+
+// Translates the tuning parameters the client sent to the names the provider expects, parameters
+// the provider doesn't know are dropped for providers that reject them.
+pub fn adapt_request(request: &mut ChatCompletionsRequest, provider_interface: &LlmProviderType) {
+    let extra_fields = &mut request.extra_fields;
+    match provider_interface {
+        LlmProviderType::OpenAI => {
+            rename_field(extra_fields, "random_seed", "seed");
+        }
+        LlmProviderType::Mistral => {
+            rename_field(extra_fields, "max_completion_tokens", "max_tokens");
+            rename_field(extra_fields, "seed", "random_seed");
+            extra_fields.retain(|name, _| MISTRAL_PARAMETERS.contains(&name.as_str()));
+        }
+    }
+}
+
+// a value already present under the new name wins
+fn rename_field(fields: &mut HashMap<String, serde_json::Value>, from: &str, to: &str) {
+    if let Some(value) = fields.remove(from) {
+        fields.entry(to.to_string()).or_insert(value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::adapt_request;
+    use crate::api::open_ai::ChatCompletionsRequest;
+    use crate::configuration::LlmProviderType;
+    use serde_json::json;
+
+    fn request() -> ChatCompletionsRequest {
+        serde_json::from_value(json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "hi"}],
+            "temperature": 0.2,
+            "max_completion_tokens": 100,
+            "seed": 7,
+            "logit_bias": {"50256": -100}
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_adapt_request_openai() {
+        let mut request = request();
+        adapt_request(&mut request, &LlmProviderType::OpenAI);
+        let request_json = serde_json::to_value(&request).unwrap();
+        assert_eq!(request_json["temperature"], json!(0.2));
+        assert_eq!(request_json["max_completion_tokens"], json!(100));
+        assert_eq!(request_json["seed"], json!(7));
+        assert_eq!(request_json["logit_bias"], json!({"50256": -100}));
+    }
+
+    #[test]
+    fn test_adapt_request_mistral() {
+        let mut request = request();
+        adapt_request(&mut request, &LlmProviderType::Mistral);
+        let request_json = serde_json::to_value(&request).unwrap();
+        assert_eq!(request_json["temperature"], json!(0.2));
+        assert_eq!(request_json["max_tokens"], json!(100));
+        assert_eq!(request_json["random_seed"], json!(7));
+        assert!(request_json.get("max_completion_tokens").is_none());
+        assert!(request_json.get("seed").is_none());
+        assert!(request_json.get("logit_bias").is_none());
+    }
+}
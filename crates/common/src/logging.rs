@@ -0,0 +1,150 @@
+use crate::configuration::{LogLevel, Logging};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use proxy_wasm::hostcalls;
+use proxy_wasm::types;
+use std::collections::HashMap;
+use std::panic;
+use std::sync::{OnceLock, RwLock};
+
+// Levels of the proxy log. proxy_wasm::set_log_level only knows a single global level, the filter
+// installs this logger instead so that modules can be given their own level from the config.
+#[derive(Debug)]
+struct LogLevels {
+    level: LogLevel,
+    modules: HashMap<String, LogLevel>,
+}
+
+impl Default for LogLevels {
+    fn default() -> Self {
+        LogLevels {
+            level: LogLevel::Trace,
+            modules: HashMap::new(),
+        }
+    }
+}
+
+impl LogLevels {
+    fn new(logging: &Logging) -> Self {
+        LogLevels {
+            level: logging.level.unwrap_or(LogLevel::Trace),
+            modules: logging.modules.clone().unwrap_or_default(),
+        }
+    }
+
+    // the target of a record is its module path, e.g. prompt_gateway::stream_context. A module
+    // override matches the full path, its last segment or a parent path.
+    fn level(&self, target: &str) -> LogLevel {
+        self.modules
+            .iter()
+            .find(|(module, _)| {
+                target == module.as_str()
+                    || target.ends_with(&format!("::{}", module))
+                    || target.starts_with(&format!("{}::", module))
+            })
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level)
+    }
+
+    fn max_level(&self) -> LogLevel {
+        self.modules
+            .values()
+            .copied()
+            .chain([self.level])
+            .max()
+            .unwrap_or(self.level)
+    }
+}
+
+fn log_levels() -> &'static RwLock<LogLevels> {
+    static LOG_LEVELS: OnceLock<RwLock<LogLevels>> = OnceLock::new();
+    LOG_LEVELS.get_or_init(|| RwLock::new(LogLevels::default()))
+}
+
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let level = log_levels().read().unwrap().level(metadata.target());
+        metadata.level() <= LevelFilter::from(level)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let level = match record.level() {
+            Level::Trace => types::LogLevel::Trace,
+            Level::Debug => types::LogLevel::Debug,
+            Level::Info => types::LogLevel::Info,
+            Level::Warn => types::LogLevel::Warn,
+            Level::Error => types::LogLevel::Error,
+        };
+        let _ = hostcalls::log(level, &record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+// installs the logger with everything logged at trace until the config is applied, takes the
+// place of proxy_wasm::set_log_level including its panic hook
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        panic::set_hook(Box::new(|panic_info| {
+            let _ = hostcalls::log(types::LogLevel::Critical, &panic_info.to_string());
+        }));
+    }
+    log::set_max_level(LevelFilter::from(log_levels().read().unwrap().max_level()));
+}
+
+// applies the logging config, called on every (re)configuration of the filter
+pub fn configure(logging: Option<&Logging>) {
+    let levels = logging.map(LogLevels::new).unwrap_or_default();
+    log::set_max_level(LevelFilter::from(levels.max_level()));
+    *log_levels().write().unwrap() = levels;
+}
+
+#[cfg(test)]
+mod test {
+    use super::LogLevels;
+    use crate::configuration::{LogLevel, Logging};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_module_levels() {
+        let levels = LogLevels::new(&Logging {
+            level: Some(LogLevel::Info),
+            modules: Some(HashMap::from([
+                ("stream_context".to_string(), LogLevel::Debug),
+                ("common".to_string(), LogLevel::Warn),
+            ])),
+        });
+
+        assert_eq!(
+            levels.level("prompt_gateway::stream_context"),
+            LogLevel::Debug
+        );
+        assert_eq!(levels.level("common::ratelimit"), LogLevel::Warn);
+        assert_eq!(levels.level("llm_gateway::filter_context"), LogLevel::Info);
+        assert_eq!(levels.max_level(), LogLevel::Debug);
+
+        assert_eq!(
+            LogLevels::default().level("common::ratelimit"),
+            LogLevel::Trace
+        );
+    }
+}
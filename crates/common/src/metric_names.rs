@@ -0,0 +1,101 @@
+// Metric names carry their dimensions as .<dimension>.<value> segments after the name of the
+// metric, in the order of Dimension, e.g. llm_responses.provider.openai.model.gpt-4o.status.200.
+// The stats_tags of envoy.template.yaml turn the segments into labels, prometheus scrapes the
+// series above as wasmcustom_llm_responses{provider="openai",model="gpt-4o",status="200"}.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Dimension {
+    Provider,
+    Model,
+    Target,
+    Status,
+    Experiment,
+    Cluster,
+    Priority,
+}
+
+impl Dimension {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Dimension::Provider => "provider",
+            Dimension::Model => "model",
+            Dimension::Target => "target",
+            Dimension::Status => "status",
+            Dimension::Experiment => "experiment",
+            Dimension::Cluster => "cluster",
+            Dimension::Priority => "priority",
+        }
+    }
+}
+
+pub const OTHER_LABEL_VALUE: &str = "other";
+const UNKNOWN_LABEL_VALUE: &str = "unknown";
+
+pub fn metric_name(name: &str, labels: &[(Dimension, &str)]) -> String {
+    let mut labels = labels.to_vec();
+    labels.sort_by_key(|(dimension, _)| *dimension);
+    labels.dedup_by_key(|(dimension, _)| *dimension);
+
+    let mut metric_name = name.to_string();
+    for (dimension, value) in labels {
+        metric_name.push('.');
+        metric_name.push_str(dimension.as_str());
+        metric_name.push('.');
+        metric_name.push_str(&label_value(value));
+    }
+    metric_name
+}
+
+// dots separate the segments of a name, label values keep letters, digits, - and _ and the rest
+// becomes _, e.g. gpt-3.5-turbo -> gpt-3_5-turbo
+pub fn label_value(value: &str) -> String {
+    if value.is_empty() {
+        return UNKNOWN_LABEL_VALUE.to_string();
+    }
+    value
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{label_value, metric_name, Dimension};
+
+    #[test]
+    fn test_metric_name() {
+        assert_eq!(metric_name("active_http_calls", &[]), "active_http_calls");
+        assert_eq!(
+            metric_name(
+                "llm_responses",
+                &[
+                    (Dimension::Status, "429"),
+                    (Dimension::Provider, "open-ai-gpt-4"),
+                    (Dimension::Model, "gpt-3.5-turbo"),
+                ]
+            ),
+            "llm_responses.provider.open-ai-gpt-4.model.gpt-3_5-turbo.status.429"
+        );
+        assert_eq!(
+            metric_name(
+                "ratelimited_rq",
+                &[(Dimension::Target, "weather"), (Dimension::Target, "other")]
+            ),
+            "ratelimited_rq.target.weather"
+        );
+    }
+
+    #[test]
+    fn test_label_value() {
+        assert_eq!(label_value("Mistral8x7b"), "Mistral8x7b");
+        assert_eq!(label_value("app server"), "app_server");
+        assert_eq!(
+            label_value("models/gemini-1.5:pro"),
+            "models_gemini-1_5_pro"
+        );
+        assert_eq!(label_value(""), "unknown");
+    }
+}
@@ -0,0 +1,211 @@
+use crate::configuration::{EndpointDetails, NotificationEvent, Notifications};
+use crate::consts::MAX_QUEUED_NOTIFICATIONS;
+use log::warn;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::{OnceLock, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub event: NotificationEvent,
+    // unix seconds
+    pub timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub details: Value,
+}
+
+// notifications posted to the webhook in one request, a json array
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch {
+    pub body: String,
+    // times the batch was posted before
+    pub attempts: u32,
+}
+
+// notifications waiting to be posted. Envoy runs a wasm vm per worker thread, every worker posts
+// the notifications of its own requests.
+#[derive(Debug, Default)]
+pub struct Notifier {
+    config: Option<Notifications>,
+    queue: VecDeque<Notification>,
+    retries: VecDeque<Batch>,
+}
+
+impl Notifier {
+    pub fn new(config: Option<&Notifications>) -> Self {
+        Notifier {
+            config: config.cloned(),
+            ..Default::default()
+        }
+    }
+
+    pub fn webhook(&self) -> Option<&EndpointDetails> {
+        self.config.as_ref().map(|config| &config.webhook)
+    }
+
+    pub fn push(&mut self, notification: Notification) {
+        match self.config.as_ref() {
+            Some(config) if config.notifies(notification.event) => {}
+            _ => return,
+        }
+        if self.queue.len() >= MAX_QUEUED_NOTIFICATIONS {
+            warn!("notification queue is full, dropping the oldest notification");
+            self.queue.pop_front();
+        }
+        self.queue.push_back(notification);
+    }
+
+    // batches to post, the ones to post again first
+    pub fn batches(&mut self) -> Vec<Batch> {
+        let batch_size = match self.config.as_ref() {
+            Some(config) => config.batch_size(),
+            None => return Vec::new(),
+        };
+        let mut batches: Vec<Batch> = self.retries.drain(..).collect();
+        while !self.queue.is_empty() {
+            let notifications: Vec<Notification> = self
+                .queue
+                .drain(..batch_size.min(self.queue.len()))
+                .collect();
+            match serde_json::to_string(&notifications) {
+                Ok(body) => batches.push(Batch { body, attempts: 0 }),
+                Err(e) => warn!("failed to serialize notifications: {}", e),
+            }
+        }
+        batches
+    }
+
+    // a batch the webhook didn't accept is posted again on the next tick until it ran out of
+    // retries
+    pub fn retry(&mut self, batch: Batch) {
+        let max_retries = match self.config.as_ref() {
+            Some(config) => config.max_retries(),
+            None => return,
+        };
+        if batch.attempts >= max_retries {
+            warn!(
+                "dropping {} bytes of notifications after {} retries",
+                batch.body.len(),
+                batch.attempts
+            );
+            return;
+        }
+        self.retries.push_back(Batch {
+            body: batch.body,
+            attempts: batch.attempts + 1,
+        });
+    }
+}
+
+pub fn notifier() -> &'static RwLock<Notifier> {
+    static NOTIFIER: OnceLock<RwLock<Notifier>> = OnceLock::new();
+    NOTIFIER.get_or_init(|| RwLock::new(Notifier::default()))
+}
+
+// applies the notifications config, notifications not posted yet are dropped
+pub fn configure(notifications: Option<&Notifications>) {
+    *notifier().write().unwrap() = Notifier::new(notifications);
+}
+
+pub fn notify(event: NotificationEvent, request_id: &str, details: Value, now: SystemTime) {
+    notifier().write().unwrap().push(Notification {
+        event,
+        timestamp: now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        request_id: (!request_id.is_empty()).then(|| request_id.to_string()),
+        details,
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Batch, Notification, Notifier};
+    use crate::configuration::{NotificationEvent, Notifications};
+    use serde_json::{json, Value};
+
+    fn notification(event: NotificationEvent) -> Notification {
+        Notification {
+            event,
+            timestamp: 1700000000,
+            request_id: Some(String::from("req-1")),
+            details: json!({"llm_provider": "open-ai-gpt-4"}),
+        }
+    }
+
+    #[test]
+    fn test_batches() {
+        let config: Notifications = serde_yaml::from_str(
+            "webhook:\n  name: alerts\n  path: /events\nevents:\n  - ratelimit_breach\n  - \
+             guard_detection\nbatch_size: 2\n",
+        )
+        .unwrap();
+        let mut notifier = Notifier::new(Some(&config));
+
+        for event in [
+            NotificationEvent::RatelimitBreach,
+            NotificationEvent::ProviderFailover,
+            NotificationEvent::GuardDetection,
+            NotificationEvent::RatelimitBreach,
+        ] {
+            notifier.push(notification(event));
+        }
+
+        let batches = notifier.batches();
+        assert_eq!(batches.len(), 2);
+        let first: Value = serde_json::from_str(&batches[0].body).unwrap();
+        assert_eq!(
+            first,
+            json!([
+                {
+                    "event": "ratelimit_breach",
+                    "timestamp": 1700000000,
+                    "request_id": "req-1",
+                    "details": {"llm_provider": "open-ai-gpt-4"}
+                },
+                {
+                    "event": "guard_detection",
+                    "timestamp": 1700000000,
+                    "request_id": "req-1",
+                    "details": {"llm_provider": "open-ai-gpt-4"}
+                }
+            ])
+        );
+        assert_eq!(batches[1].attempts, 0);
+        assert!(notifier.batches().is_empty());
+    }
+
+    #[test]
+    fn test_retry() {
+        let config: Notifications =
+            serde_yaml::from_str("webhook:\n  name: alerts\nmax_retries: 1\n").unwrap();
+        let mut notifier = Notifier::new(Some(&config));
+        notifier.push(notification(NotificationEvent::CircuitBreakerOpen));
+
+        let batch = notifier.batches().remove(0);
+        notifier.retry(batch.clone());
+        notifier.push(notification(NotificationEvent::ProviderFailover));
+        let batches = notifier.batches();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(
+            batches[0],
+            Batch {
+                body: batch.body,
+                attempts: 1,
+            }
+        );
+
+        // out of retries
+        notifier.retry(batches[0].clone());
+        assert!(notifier.batches().is_empty());
+    }
+
+    #[test]
+    fn test_without_config() {
+        let mut notifier = Notifier::new(None);
+        notifier.push(notification(NotificationEvent::GuardDetection));
+        assert!(notifier.webhook().is_none());
+        assert!(notifier.batches().is_empty());
+    }
+}
@@ -0,0 +1,180 @@
+use crate::api::open_ai::Message;
+use crate::configuration::ParameterCollection;
+use crate::consts::{ASSISTANT_ROLE, CURVE_MODEL_PREFIX};
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, thiserror::Error, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Error {
+    #[error("could not complete parameter collection within {max_turns} turns")]
+    TurnLimitExceeded { turns: usize, max_turns: usize },
+    #[error("could not complete parameter collection within {timeout_seconds} seconds")]
+    DeadlineExceeded {
+        elapsed_seconds: u64,
+        timeout_seconds: u64,
+    },
+}
+
+// Number of consecutive follow up questions Curve FC asked since the last assistant message that
+// didn't come from Curve FC, clients send assistant messages back with the model that produced them.
+pub fn collection_turns(messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .rev()
+        .filter(|message| message.role == ASSISTANT_ROLE)
+        .take_while(|message| {
+            let curve _model = message
+                .model
+                .as_ref()
+                .is_some_and(|model| model.starts_with(CURVE_MODEL_PREFIX));
+            let tool_call = message
+                .tool_calls
+                .as_ref()
+                .is_some_and(|tool_calls| !tool_calls.is_empty());
+            curve _model && !tool_call
+        })
+        .count()
+}
+
+// the prompt target isn't known while parameters are being collected, so the strictest limits of
+// the candidate prompt targets apply
+pub fn strictest<'a>(
+    limits: impl Iterator<Item = &'a ParameterCollection>,
+) -> Option<ParameterCollection> {
+    limits.fold(None, |strictest, limits| {
+        let strictest = match strictest {
+            Some(strictest) => strictest,
+            None => return Some(limits.clone()),
+        };
+        Some(ParameterCollection {
+            max_turns: min(strictest.max_turns, limits.max_turns),
+            timeout_seconds: min(strictest.timeout_seconds, limits.timeout_seconds),
+            forward_to_error_target: match (
+                strictest.forward_to_error_target,
+                limits.forward_to_error_target,
+            ) {
+                (Some(a), Some(b)) => Some(a || b),
+                (a, b) => a.or(b),
+            },
+        })
+    })
+}
+
+fn min<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+// started_at is the unix time in seconds of the first follow up question of the dialog
+pub fn check_limits(
+    limits: &ParameterCollection,
+    turns: usize,
+    started_at: Option<u64>,
+    now: SystemTime,
+) -> Result<(), Error> {
+    if let Some(max_turns) = limits.max_turns {
+        if turns >= max_turns {
+            return Err(Error::TurnLimitExceeded { turns, max_turns });
+        }
+    }
+
+    if let (Some(timeout_seconds), Some(started_at)) = (limits.timeout_seconds, started_at) {
+        let elapsed_seconds = unix_seconds(now).saturating_sub(started_at);
+        if elapsed_seconds >= timeout_seconds {
+            return Err(Error::DeadlineExceeded {
+                elapsed_seconds,
+                timeout_seconds,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+pub fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_limits, collection_turns, strictest, Error};
+    use crate::api::open_ai::Message;
+    use crate::configuration::ParameterCollection;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn message(role: &str, model: Option<&str>) -> Message {
+        Message {
+            role: String::from(role),
+            content: Some(String::from("content").into()),
+            model: model.map(String::from),
+            tool_calls: None,
+            tool_call_id: None,
+            name: None,
+        }
+    }
+
+    #[test]
+    fn test_collection_turns() {
+        let mut messages = vec![
+            message("user", None),
+            message("assistant", Some("gpt-4o")),
+            message("user", None),
+        ];
+        assert_eq!(collection_turns(&messages), 0);
+
+        messages.push(message("assistant", Some("Curve-Function-1.5B")));
+        messages.push(message("user", None));
+        messages.push(message("assistant", Some("Curve-Function-1.5B")));
+        messages.push(message("user", None));
+        assert_eq!(collection_turns(&messages), 2);
+    }
+
+    #[test]
+    fn test_strictest_limits() {
+        let limits = [
+            ParameterCollection {
+                max_turns: Some(5),
+                timeout_seconds: None,
+                forward_to_error_target: None,
+            },
+            ParameterCollection {
+                max_turns: Some(3),
+                timeout_seconds: Some(300),
+                forward_to_error_target: Some(true),
+            },
+        ];
+        let strictest = strictest(limits.iter()).unwrap();
+        assert_eq!(strictest.max_turns, Some(3));
+        assert_eq!(strictest.timeout_seconds, Some(300));
+        assert_eq!(strictest.forward_to_error_target, Some(true));
+
+        assert!(super::strictest([].iter()).is_none());
+    }
+
+    #[test]
+    fn test_check_limits() {
+        let limits = ParameterCollection {
+            max_turns: Some(3),
+            timeout_seconds: Some(60),
+            forward_to_error_target: None,
+        };
+        let started_at = 1709210096;
+        let now = UNIX_EPOCH + Duration::from_secs(started_at + 30);
+
+        assert!(check_limits(&limits, 2, Some(started_at), now).is_ok());
+        assert!(check_limits(&limits, 2, None, now).is_ok());
+        assert!(matches!(
+            check_limits(&limits, 3, Some(started_at), now),
+            Err(Error::TurnLimitExceeded { .. })
+        ));
+        assert!(matches!(
+            check_limits(&limits, 2, Some(started_at), now + Duration::from_secs(30)),
+            Err(Error::DeadlineExceeded { .. })
+        ));
+    }
+}
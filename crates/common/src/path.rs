@@ -1,5 +1,9 @@
+use serde_yaml::Value;
 use std::collections::HashMap;
 
+// The params are encoded into the segments of the path they are in, so a value can't add segments
+// or a query to the path. Values that would make the segment point elsewhere, like .. or an
+// empty segment, are rejected.
 pub fn replace_params_in_path(
     path: &str,
     params: &HashMap<String, String>,
@@ -15,7 +19,10 @@ pub fn replace_params_in_path(
             in_param = false;
             let param_name = current_param.clone();
             if let Some(value) = params.get(&param_name) {
-                result.push_str(value);
+                if matches!(value.trim(), "" | "." | "..") {
+                    return Err(format!("Invalid value for parameter `{}`", param_name));
+                }
+                result.push_str(&encode_component(value));
             } else {
                 return Err(format!("Missing value for parameter `{}`", param_name));
             }
@@ -30,6 +37,90 @@ pub fn replace_params_in_path(
     Ok(result)
 }
 
+// Paths of endpoints are configured, the params in them are filled in from the arguments of the
+// tool calls. A path has to be absolute and stay on the endpoint it is configured for, so it
+// can't name another host or go up with .. segments.
+pub fn validate_path_template(path: &str) -> Result<(), String> {
+    if !path.starts_with('/') {
+        return Err(String::from("path must start with /"));
+    }
+    if path.contains("://") || path.starts_with("//") || path.contains('#') {
+        return Err(String::from("path must not point to another host"));
+    }
+    let path = path.split('?').next().unwrap_or_default();
+    for segment in path.split('/') {
+        if segment == "." || segment == ".." {
+            return Err(String::from("path must not contain . or .. segments"));
+        }
+    }
+    Ok(())
+}
+
+// characters other than the unreserved ones of RFC 3986 are percent encoded, for values that go
+// into a path segment or a query param
+pub fn encode_component(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// the text of a scalar argument, None for null, arrays and objects
+pub fn param_value(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Tagged(tagged) => param_value(&tagged.value),
+        Value::Null | Value::Sequence(_) | Value::Mapping(_) => None,
+    }
+}
+
+// Query string of the arguments of a GET request, ordered by name. Arrays are repeated params,
+// objects are sent as json and null arguments are left out.
+pub fn query_string<'a>(params: impl IntoIterator<Item = (&'a String, &'a Value)>) -> String {
+    let mut params: Vec<(&String, &Value)> = params.into_iter().collect();
+    params.sort_by_key(|(name, _)| *name);
+
+    let mut pairs = Vec::new();
+    for (name, value) in params {
+        let values: Vec<String> = match value {
+            Value::Sequence(items) => items.iter().filter_map(query_value).collect(),
+            value => query_value(value).into_iter().collect(),
+        };
+        for value in values {
+            pairs.push(format!(
+                "{}={}",
+                encode_component(name),
+                encode_component(&value)
+            ));
+        }
+    }
+    pairs.join("&")
+}
+
+fn query_value(value: &Value) -> Option<String> {
+    match value {
+        Value::Mapping(_) => serde_json::to_string(value).ok(),
+        value => param_value(value),
+    }
+}
+
+// the query is appended to the query the path may already have
+pub fn with_query(path: &str, query: &str) -> String {
+    match (query.is_empty(), path.contains('?')) {
+        (true, _) => path.to_string(),
+        (false, true) => format!("{}&{}", path, query),
+        (false, false) => format!("{}?{}", path, query),
+    }
+}
+
 #[cfg(test)]
 mod test {
     #[test]
@@ -80,4 +171,74 @@ mod test {
             Err("Missing value for parameter `qux`".to_string())
         );
     }
+
+    #[test]
+    fn test_replace_path_encodes_values() {
+        let path = "/v1/devices/{device_id}/status";
+        let params = vec![("device_id".to_string(), "../../admin?x=1".to_string())]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            super::replace_params_in_path(path, &params),
+            Ok("/v1/devices/..%2F..%2Fadmin%3Fx%3D1/status".to_string())
+        );
+
+        let path = "/v1/latest?symbols={symbols}";
+        let params = vec![("symbols".to_string(), "EUR&base=GBP".to_string())]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            super::replace_params_in_path(path, &params),
+            Ok("/v1/latest?symbols=EUR%26base%3DGBP".to_string())
+        );
+
+        for value in ["..", ".", ""] {
+            let params = vec![("device_id".to_string(), value.to_string())]
+                .into_iter()
+                .collect();
+            assert_eq!(
+                super::replace_params_in_path("/v1/devices/{device_id}", &params),
+                Err("Invalid value for parameter `device_id`".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_path_template() {
+        assert!(super::validate_path_template("/agent/device_summary").is_ok());
+        assert!(super::validate_path_template("/v1/latest?base=USD&symbols={symbol}").is_ok());
+        assert!(super::validate_path_template("/v1/devices/{id}.json").is_ok());
+        assert!(super::validate_path_template("agent/summary").is_err());
+        assert!(super::validate_path_template("//internal/admin").is_err());
+        assert!(super::validate_path_template("/redirect?to=http://internal").is_err());
+        assert!(super::validate_path_template("/agent/../admin").is_err());
+        assert!(super::validate_path_template("/agent/./summary#x").is_err());
+    }
+
+    #[test]
+    fn test_query_string() {
+        let params: std::collections::HashMap<String, serde_yaml::Value> = serde_yaml::from_str(
+            r#"
+city: San Francisco
+days: 3
+metric: true
+conditions: [rain, "wind & snow"]
+location: { lat: 37.7, lon: -122.4 }
+unit: null
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            super::query_string(&params),
+            "city=San%20Francisco&conditions=rain&conditions=wind%20%26%20snow&days=3\
+             &location=%7B%22lat%22%3A37.7%2C%22lon%22%3A-122.4%7D&metric=true"
+        );
+        assert_eq!(
+            super::with_query("/weather?v=2", "city=Paris"),
+            "/weather?v=2&city=Paris"
+        );
+        assert_eq!(super::with_query("/weather", ""), "/weather");
+        assert_eq!(super::encode_component("a/b?c=d~"), "a%2Fb%3Fc%3Dd~");
+    }
 }
@@ -0,0 +1,164 @@
+use crate::configuration::Priority;
+use crate::ratelimit::{Header, InFlight};
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, SystemTime};
+
+// Requests waiting for a limit they hit. They are admitted by priority, the oldest first within a
+// priority.
+#[derive(Debug, Default)]
+pub struct RequestQueue {
+    queued: Vec<QueuedRequest>,
+    // admitted requests by context id with the concurrency limit they count against until they
+    // are done
+    admitted: HashMap<u32, InFlight>,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueuedRequest {
+    pub context_id: u32,
+    pub priority: Priority,
+    pub queued_at: SystemTime,
+    // the provider has to be done backing off before the request is admitted
+    pub llm_provider: String,
+    pub model: String,
+    // the ratelimit the request waits for, None when it was already let through by it
+    pub selector: Option<Header>,
+    pub tokens: NonZeroU32,
+}
+
+pub enum Admission {
+    // the request goes on, counting against a concurrency limit when there is an in flight
+    Admit(Option<InFlight>),
+    Wait,
+}
+
+impl RequestQueue {
+    // false when the queue is full, the request is then rejected
+    pub fn enqueue(&mut self, request: QueuedRequest, max_queued: usize) -> bool {
+        if self.queued.len() >= max_queued {
+            return false;
+        }
+        self.queued.push(request);
+        true
+    }
+
+    // Queued requests that are admitted, true, or have waited for longer than the timeout, false.
+    // Requests still waiting stay in the queue.
+    pub fn take_ready(
+        &mut self,
+        now: SystemTime,
+        timeout: Duration,
+        mut admit: impl FnMut(&QueuedRequest) -> Admission,
+    ) -> Vec<(QueuedRequest, bool)> {
+        let mut queued = std::mem::take(&mut self.queued);
+        // the sort is stable, requests of a priority stay in the order they were queued in
+        queued.sort_by_key(|request| request.priority);
+
+        let mut ready = Vec::new();
+        for request in queued {
+            if now
+                .duration_since(request.queued_at)
+                .is_ok_and(|waited| waited >= timeout)
+            {
+                ready.push((request, false));
+                continue;
+            }
+            match admit(&request) {
+                Admission::Admit(in_flight) => {
+                    if let Some(in_flight) = in_flight {
+                        self.admitted.insert(request.context_id, in_flight);
+                    }
+                    ready.push((request, true));
+                }
+                Admission::Wait => self.queued.push(request),
+            }
+        }
+        ready
+    }
+
+    // Takes the request out of the queue once it is done, it is returned as in flight when it was
+    // admitted by a concurrency limit.
+    pub fn remove(&mut self, context_id: u32) -> Option<InFlight> {
+        self.queued
+            .retain(|request| request.context_id != context_id);
+        self.admitted.remove(&context_id)
+    }
+}
+
+pub fn request_queue() -> &'static RwLock<RequestQueue> {
+    static REQUEST_QUEUE: OnceLock<RwLock<RequestQueue>> = OnceLock::new();
+    REQUEST_QUEUE.get_or_init(|| RwLock::new(RequestQueue::default()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Admission, QueuedRequest, RequestQueue};
+    use crate::configuration::Priority;
+    use std::num::NonZeroU32;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn queued_request(context_id: u32, priority: Priority, queued_at: u64) -> QueuedRequest {
+        QueuedRequest {
+            context_id,
+            priority,
+            queued_at: UNIX_EPOCH + Duration::from_secs(queued_at),
+            llm_provider: String::from("openai"),
+            model: String::from("gpt-4o"),
+            selector: None,
+            tokens: NonZeroU32::MIN,
+        }
+    }
+
+    #[test]
+    fn test_requests_are_admitted_by_priority() {
+        let mut queue = RequestQueue::default();
+        assert!(queue.enqueue(queued_request(1, Priority::Low, 100), 3));
+        assert!(queue.enqueue(queued_request(2, Priority::Normal, 101), 3));
+        assert!(queue.enqueue(queued_request(3, Priority::High, 102), 3));
+        assert!(!queue.enqueue(queued_request(4, Priority::High, 102), 3));
+
+        let now = UNIX_EPOCH + Duration::from_secs(105);
+        let timeout = Duration::from_secs(10);
+
+        // one request fits, the others keep waiting
+        let mut capacity = 1;
+        let ready = queue.take_ready(now, timeout, |_| {
+            if capacity == 0 {
+                return Admission::Wait;
+            }
+            capacity -= 1;
+            Admission::Admit(None)
+        });
+        let ready: Vec<(u32, bool)> = ready
+            .iter()
+            .map(|(request, admitted)| (request.context_id, *admitted))
+            .collect();
+        assert_eq!(ready, vec![(3, true)]);
+
+        let ready: Vec<u32> = queue
+            .take_ready(now, timeout, |_| Admission::Admit(None))
+            .iter()
+            .map(|(request, _)| request.context_id)
+            .collect();
+        assert_eq!(ready, vec![2, 1]);
+    }
+
+    #[test]
+    fn test_queued_requests_time_out() {
+        let mut queue = RequestQueue::default();
+        assert!(queue.enqueue(queued_request(1, Priority::Normal, 100), 10));
+        assert!(queue.enqueue(queued_request(2, Priority::Normal, 105), 10));
+        assert!(queue.enqueue(queued_request(3, Priority::Normal, 105), 10));
+        assert!(queue.remove(3).is_none());
+
+        let now = UNIX_EPOCH + Duration::from_secs(110);
+        let ready: Vec<(u32, bool)> = queue
+            .take_ready(now, Duration::from_secs(10), |_| Admission::Wait)
+            .iter()
+            .map(|(request, admitted)| (request.context_id, *admitted))
+            .collect();
+        assert_eq!(ready, vec![(1, false)]);
+    }
+}
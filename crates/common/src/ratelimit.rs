@@ -1,31 +1,257 @@
 use crate::configuration;
-use configuration::{Limit, Ratelimit, TimeUnit};
-use governor::{DefaultKeyedRateLimiter, InsufficientCapacity, Quota};
-use log::debug;
+use crate::consts::{IN_FLIGHT_LEASE_SECONDS, MAX_RATELIMIT_CAS_ATTEMPTS};
+use configuration::{Limit, PromptTarget, Ratelimit, RatelimitAlgorithm, TimeUnit};
+use log::{debug, warn};
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::Status;
 use std::fmt::Display;
-use std::num::{NonZero, NonZeroU32};
+#[cfg(test)]
+use std::num::NonZero;
+use std::num::NonZeroU32;
 use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{collections::HashMap, sync::OnceLock};
 
 pub type RatelimitData = RwLock<RatelimitMap>;
 
+// the limits are replaced when a config is passed. The buckets of the limits are kept in the
+// shared data of the vm, so tokens used are shared by the worker threads and survive config
+// changes and vm restarts.
 pub fn ratelimits(ratelimits_config: Option<Vec<Ratelimit>>) -> &'static RatelimitData {
     static RATELIMIT_DATA: OnceLock<RatelimitData> = OnceLock::new();
-    RATELIMIT_DATA.get_or_init(|| {
-        RwLock::new(RatelimitMap::new(
-            ratelimits_config.expect("The initialization call has to have passed a config"),
-        ))
+    let ratelimit_data = RATELIMIT_DATA
+        .get_or_init(|| RwLock::new(RatelimitMap::new(Vec::new(), Box::new(SharedData))));
+    if let Some(ratelimits_config) = ratelimits_config {
+        *ratelimit_data.write().unwrap() =
+            RatelimitMap::new(ratelimits_config, Box::new(SharedData));
+    }
+    ratelimit_data
+}
+
+// Where the token buckets are kept, shared data in the proxy. Updates are compare-and-swap: a
+// write fails with CasMismatch when the bucket changed since it was read.
+pub trait BucketStore: Send + Sync {
+    fn get(&self, key: &str) -> (Option<Vec<u8>>, Option<u32>);
+    fn set(&self, key: &str, value: &[u8], cas: Option<u32>) -> Result<(), Status>;
+}
+
+pub struct SharedData;
+
+impl BucketStore for SharedData {
+    fn get(&self, key: &str) -> (Option<Vec<u8>>, Option<u32>) {
+        hostcalls::get_shared_data(key).unwrap_or_default()
+    }
+
+    fn set(&self, key: &str, value: &[u8], cas: Option<u32>) -> Result<(), Status> {
+        hostcalls::set_shared_data(key, Some(value), cas)
+    }
+}
+
+// tokens left in a bucket and when they were counted, stored as 16 little endian bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Bucket {
+    tokens: f64,
+    updated_at_ms: u64,
+}
+
+impl Bucket {
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let tokens = f64::from_le_bytes(bytes.get(..8)?.try_into().ok()?);
+        let updated_at_ms = u64::from_le_bytes(bytes.get(8..16)?.try_into().ok()?);
+        Some(Bucket {
+            tokens,
+            updated_at_ms,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        [self.tokens.to_le_bytes(), self.updated_at_ms.to_le_bytes()].concat()
+    }
+
+    // the bucket fills up with the tokens of the limit over its time unit, up to the burst of the
+    // limit. A limit lowered by a config change caps the tokens left.
+    fn refill(self, limit: &Limit, now_ms: u64) -> Self {
+        let elapsed_ms = now_ms.saturating_sub(self.updated_at_ms);
+        Bucket {
+            tokens: (self.tokens
+                + elapsed_ms as f64 * limit.tokens as f64 / unit_millis(&limit.unit) as f64)
+                .min(capacity(limit)),
+            updated_at_ms: self.updated_at_ms.max(now_ms),
+        }
+    }
+}
+
+fn capacity(limit: &Limit) -> f64 {
+    limit.burst.unwrap_or(limit.tokens) as f64
+}
+
+fn unit_millis(unit: &TimeUnit) -> u64 {
+    match unit {
+        TimeUnit::Second => 1000,
+        TimeUnit::Minute => 60 * 1000,
+        TimeUnit::Hour => 60 * 60 * 1000,
+    }
+}
+
+fn millis_since_epoch(now: SystemTime) -> u64 {
+    now.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// Replaces the value of key with the one returned by next_value, None when the limit is exceeded.
+// Values that can't be updated, e.g. as other workers keep changing them, let the request through.
+fn update(
+    store: &dyn BucketStore,
+    key: &str,
+    next_value: impl Fn(Option<&[u8]>) -> Option<Vec<u8>>,
+) -> bool {
+    for _ in 0..MAX_RATELIMIT_CAS_ATTEMPTS {
+        let (bytes, cas) = store.get(key);
+        let value = match next_value(bytes.as_deref()) {
+            Some(value) => value,
+            None => return false,
+        };
+        match store.set(key, &value, cas) {
+            Ok(()) => return true,
+            Err(Status::CasMismatch) => continue,
+            Err(status) => {
+                warn!("failed to update ratelimit bucket {}: {:?}", key, status);
+                return true;
+            }
+        }
+    }
+    warn!(
+        "ratelimit bucket {} changed on every one of {} attempts, letting the request through",
+        key, MAX_RATELIMIT_CAS_ATTEMPTS
+    );
+    true
+}
+
+// Takes used tokens from the bucket when it has enough of them.
+fn take(
+    store: &dyn BucketStore,
+    key: &str,
+    limit: &Limit,
+    used: NonZeroU32,
+    now: SystemTime,
+) -> bool {
+    let now_ms = millis_since_epoch(now);
+    update(store, key, |bytes| {
+        let bucket = match bytes.and_then(Bucket::decode) {
+            Some(bucket) => bucket.refill(limit, now_ms),
+            None => Bucket {
+                tokens: capacity(limit),
+                updated_at_ms: now_ms,
+            },
+        };
+        if used.get() as f64 > bucket.tokens {
+            return None;
+        }
+        Some(
+            Bucket {
+                tokens: bucket.tokens - used.get() as f64,
+                ..bucket
+            }
+            .encode(),
+        )
     })
 }
 
+// Adds used tokens to the log of the window when the tokens logged over the last unit leave room
+// for them. Each entry of the log is the time in ms and the tokens used, 12 little endian bytes.
+fn log_in_window(
+    store: &dyn BucketStore,
+    key: &str,
+    limit: &Limit,
+    used: NonZeroU32,
+    now: SystemTime,
+) -> bool {
+    let now_ms = millis_since_epoch(now);
+    let window_start_ms = now_ms.saturating_sub(unit_millis(&limit.unit));
+    update(store, key, |bytes| {
+        let mut log: Vec<(u64, u32)> = bytes
+            .unwrap_or_default()
+            .chunks_exact(12)
+            .map(|entry| {
+                (
+                    u64::from_le_bytes(entry[..8].try_into().unwrap()),
+                    u32::from_le_bytes(entry[8..].try_into().unwrap()),
+                )
+            })
+            .filter(|(logged_at_ms, _)| *logged_at_ms > window_start_ms)
+            .collect();
+        let logged: u64 = log.iter().map(|(_, tokens)| *tokens as u64).sum();
+        if logged + used.get() as u64 > limit.tokens as u64 {
+            return None;
+        }
+        log.push((now_ms, used.get()));
+        Some(
+            log.iter()
+                .flat_map(|(logged_at_ms, tokens)| {
+                    [&logged_at_ms.to_le_bytes()[..], &tokens.to_le_bytes()[..]].concat()
+                })
+                .collect(),
+        )
+    })
+}
+
+// Requests in flight are kept as the times their leases expire in ms, 8 little endian bytes each.
+// A lease outlives the request only when it was never released, e.g. its vm was torn down.
+fn decode_leases(bytes: Option<&[u8]>) -> Vec<u64> {
+    bytes
+        .unwrap_or_default()
+        .chunks_exact(8)
+        .map(|lease| u64::from_le_bytes(lease.try_into().unwrap()))
+        .collect()
+}
+
+fn encode_leases(leases: &[u64]) -> Vec<u8> {
+    leases
+        .iter()
+        .flat_map(|lease| lease.to_le_bytes())
+        .collect()
+}
+
+// Counts one more request in flight when there are less than limit of them, the expired leases
+// don't count. Returns when the lease of the request expires.
+fn acquire(store: &dyn BucketStore, key: &str, limit: u32, now: SystemTime) -> Option<u64> {
+    let now_ms = millis_since_epoch(now);
+    let expires_at_ms = now_ms + IN_FLIGHT_LEASE_SECONDS * 1000;
+    let acquired = update(store, key, |bytes| {
+        let mut leases = decode_leases(bytes);
+        leases.retain(|expires_at_ms| *expires_at_ms > now_ms);
+        if leases.len() >= limit as usize {
+            return None;
+        }
+        leases.push(expires_at_ms);
+        Some(encode_leases(&leases))
+    });
+    acquired.then_some(expires_at_ms)
+}
+
+// A request counted by a concurrency or concurrent stream limit, it has to be released once the
+// request is done.
+#[derive(Debug)]
+pub struct InFlight {
+    key: String,
+    expires_at_ms: u64,
+}
+
 // The Data Structure is laid out in the following way:
 // Provider -> Hash { Header -> Limit }.
 // If the Header used to configure the given Limit:
-//   a) Has None value, then there will be N Limit keyed by the Header value.
-//   b) Has Some() value, then there will be 1 Limit keyed by the empty string.
-// It would have been nicer to use a non-keyed limit for b). However, the type system made that option a nightmare.
+//   a) Has None value, then every Header value has its own bucket.
+//   b) Has Some() value, then there is 1 bucket for the Header.
 pub struct RatelimitMap {
-    datastore: HashMap<String, HashMap<configuration::Header, DefaultKeyedRateLimiter<String>>>,
+    datastore: HashMap<String, HashMap<configuration::Header, SelectorLimit>>,
+    store: Box<dyn BucketStore>,
+}
+
+struct SelectorLimit {
+    limit: Limit,
+    algorithm: RatelimitAlgorithm,
+    max_concurrent_streams: Option<u32>,
 }
 
 // This version of Header demands that the user passes a header value to match on.
@@ -58,31 +284,56 @@ pub enum Error {
         selector: Header,
         tokens_used: NonZeroU32,
     },
+    #[error("exceeded {limit} concurrent streams provider={provider}, selector={selector}")]
+    ExceededStreamLimit {
+        provider: String,
+        selector: Header,
+        limit: u32,
+    },
+    #[error("exceeded {limit} limit for prompt_target={prompt_target}, used={used}")]
+    ExceededPromptTargetLimit {
+        prompt_target: String,
+        limit: String,
+        used: NonZeroU32,
+    },
 }
 
 impl RatelimitMap {
     // n.b new is private so that the only access to the Ratelimits can be done via the static
     // reference inside a RwLock via ratelimit::ratelimits().
-    fn new(ratelimits_config: Vec<Ratelimit>) -> Self {
+    fn new(ratelimits_config: Vec<Ratelimit>, store: Box<dyn BucketStore>) -> Self {
         let mut new_ratelimit_map = RatelimitMap {
             datastore: HashMap::new(),
+            store,
         };
         for ratelimit_config in ratelimits_config {
-            let limit = DefaultKeyedRateLimiter::keyed(get_quota(ratelimit_config.limit));
-
             match new_ratelimit_map.datastore.get_mut(&ratelimit_config.model) {
                 Some(limits) => match limits.get_mut(&ratelimit_config.selector) {
                     Some(_) => {
                         panic!("repeated selector. Selectors per provider must be unique")
                     }
                     None => {
-                        limits.insert(ratelimit_config.selector, limit);
+                        limits.insert(
+                            ratelimit_config.selector,
+                            SelectorLimit {
+                                limit: ratelimit_config.limit,
+                                algorithm: ratelimit_config.algorithm.unwrap_or_default(),
+                                max_concurrent_streams: ratelimit_config.max_concurrent_streams,
+                            },
+                        );
                     }
                 },
                 None => {
                     // The provider has not been seen before.
                     // Insert the provider and a new HashMap with the specified limit
-                    let new_hash_map = HashMap::from([(ratelimit_config.selector, limit)]);
+                    let new_hash_map = HashMap::from([(
+                        ratelimit_config.selector,
+                        SelectorLimit {
+                            limit: ratelimit_config.limit,
+                            algorithm: ratelimit_config.algorithm.unwrap_or_default(),
+                            max_concurrent_streams: ratelimit_config.max_concurrent_streams,
+                        },
+                    )]);
                     new_ratelimit_map
                         .datastore
                         .insert(ratelimit_config.model, new_hash_map);
@@ -92,69 +343,268 @@ impl RatelimitMap {
         new_ratelimit_map
     }
 
+    // A request let through by a concurrency limit is returned as in flight until it is released.
     #[allow(unused)]
     pub fn check_limit(
         &self,
         provider: String,
         selector: Header,
         tokens_used: NonZeroU32,
-    ) -> Result<(), Error> {
+        now: SystemTime,
+    ) -> Result<Option<InFlight>, Error> {
         debug!(
             "Checking limit for provider={}, with selector={:?}, consuming tokens={:?}",
             provider, selector, tokens_used
         );
 
-        let provider_limits = match self.datastore.get(&provider) {
-            None => {
-                // No limit configured for this provider, hence ok.
-                return Ok(());
-            }
-            Some(limit) => limit,
+        let (selector_limit, bucket_key) = match self.selector_limit(&provider, &selector) {
+            Some(selector_limit) => selector_limit,
+            // No limit configured for this provider and selector, hence ok.
+            None => return Ok(None),
         };
 
-        let mut config_selector = configuration::Header::from(selector.clone());
-
-        let (limit, limit_key) = match provider_limits.get(&config_selector) {
-            // This is a specific limit, i.e one that was configured with both key, and value.
-            // Therefore, the key for the internal limit does not matter, and hence the empty string is always returned.
-            Some(limit) => (limit, String::from("")),
-            None => {
-                // Unwrap is ok here because we _know_ the value exists.
-                let header_key = config_selector.value.take().unwrap();
-                // Securve  for less specific limit, i.e, one that was configured without a value, therefore every Header
-                // value has its own key in the internal limit.
-                match provider_limits.get(&config_selector) {
-                    Some(limit) => (limit, header_key),
-                    // No limit for that header key, value pair exists within that provider limits.
-                    None => {
-                        return Ok(());
-                    }
+        // each algorithm keeps its own value, so a config changing the algorithm starts over
+        let store = self.store.as_ref();
+        let limit = &selector_limit.limit;
+        let (allowed, in_flight) = match selector_limit.algorithm {
+            RatelimitAlgorithm::TokenBucket => {
+                (take(store, &bucket_key, limit, tokens_used, now), None)
+            }
+            RatelimitAlgorithm::SlidingWindow => {
+                let key = format!("{}/sliding_window", bucket_key);
+                (log_in_window(store, &key, limit, tokens_used, now), None)
+            }
+            RatelimitAlgorithm::Concurrency => {
+                let key = format!("{}/concurrency", bucket_key);
+                match acquire(store, &key, limit.tokens, now) {
+                    Some(expires_at_ms) => (true, Some(InFlight { key, expires_at_ms })),
+                    None => (false, None),
                 }
             }
         };
 
-        match limit.check_key_n(&limit_key, tokens_used) {
-            Ok(Ok(())) => Ok(()),
-            Ok(Err(_)) | Err(InsufficientCapacity(_)) => Err(Error::ExceededLimit {
+        if allowed {
+            Ok(in_flight)
+        } else {
+            Err(Error::ExceededLimit {
                 provider,
                 selector,
                 tokens_used,
+            })
+        }
+    }
+
+    // A streaming request is counted as an open stream of its selector until it is released, None
+    // when no stream limit applies to the selector.
+    pub fn open_stream(
+        &self,
+        provider: String,
+        selector: Header,
+        now: SystemTime,
+    ) -> Result<Option<InFlight>, Error> {
+        let (max_streams, bucket_key) = match self.selector_limit(&provider, &selector) {
+            Some((selector_limit, bucket_key)) => match selector_limit.max_concurrent_streams {
+                Some(max_streams) => (max_streams, bucket_key),
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+
+        let key = format!("{}/streams", bucket_key);
+        match acquire(self.store.as_ref(), &key, max_streams, now) {
+            Some(expires_at_ms) => Ok(Some(InFlight { key, expires_at_ms })),
+            None => Err(Error::ExceededStreamLimit {
+                provider,
+                selector,
+                limit: max_streams,
             }),
         }
     }
+
+    // The limit of the provider the selector falls under, with the key of its bucket.
+    fn selector_limit(
+        &self,
+        provider: &str,
+        selector: &Header,
+    ) -> Option<(&SelectorLimit, String)> {
+        let provider_limits = self.datastore.get(provider)?;
+        let mut config_selector = configuration::Header::from(selector.clone());
+
+        match provider_limits.get(&config_selector) {
+            // This is a specific limit, i.e one that was configured with both key, and value.
+            // Therefore all requests with the header value share its bucket.
+            Some(limit) => Some((
+                limit,
+                format!("ratelimit/{}/{}={}", provider, selector.key, selector.value),
+            )),
+            None => {
+                config_selector.value = None;
+                // Securve  for less specific limit, i.e, one that was configured without a value, therefore every Header
+                // value has its own bucket.
+                provider_limits.get(&config_selector).map(|limit| {
+                    (
+                        limit,
+                        format!("ratelimit/{}/{}/{}", provider, selector.key, selector.value),
+                    )
+                })
+            }
+        }
+    }
+
+    // The request no longer counts against the concurrency limit it was let through by.
+    pub fn release(&self, in_flight: InFlight) {
+        update(self.store.as_ref(), &in_flight.key, |bytes| {
+            let mut leases = decode_leases(bytes);
+            // an expired lease may have been dropped already
+            if let Some(index) = leases
+                .iter()
+                .position(|expires_at_ms| *expires_at_ms == in_flight.expires_at_ms)
+            {
+                leases.remove(index);
+            }
+            Some(encode_leases(&leases))
+        });
+    }
+}
+
+pub type PromptTargetRatelimitData = RwLock<PromptTargetRatelimitMap>;
+
+// as with ratelimits() the limits are replaced when prompt targets are passed
+pub fn prompt_target_ratelimits(
+    prompt_targets: Option<&[PromptTarget]>,
+) -> &'static PromptTargetRatelimitData {
+    static PROMPT_TARGET_RATELIMIT_DATA: OnceLock<PromptTargetRatelimitData> = OnceLock::new();
+    let ratelimit_data = PROMPT_TARGET_RATELIMIT_DATA
+        .get_or_init(|| RwLock::new(PromptTargetRatelimitMap::new(&[], Box::new(SharedData))));
+    if let Some(prompt_targets) = prompt_targets {
+        *ratelimit_data.write().unwrap() =
+            PromptTargetRatelimitMap::new(prompt_targets, Box::new(SharedData));
+    }
+    ratelimit_data
 }
 
-fn get_quota(limit: Limit) -> Quota {
-    let tokens = NonZero::new(limit.tokens).expect("Limit's tokens must be positive");
-    match limit.unit {
-        TimeUnit::Second => Quota::per_second(tokens),
-        TimeUnit::Minute => Quota::per_minute(tokens),
-        TimeUnit::Hour => Quota::per_hour(tokens),
+// Prompt target -> limits, each limit has one bucket.
+pub struct PromptTargetRatelimitMap {
+    datastore: HashMap<String, PromptTargetLimits>,
+    store: Box<dyn BucketStore>,
+}
+
+struct PromptTargetLimits {
+    requests: Option<Limit>,
+    tokens: Option<Limit>,
+}
+
+impl PromptTargetRatelimitMap {
+    fn new(prompt_targets: &[PromptTarget], store: Box<dyn BucketStore>) -> Self {
+        let datastore = prompt_targets
+            .iter()
+            .filter_map(|prompt_target| {
+                let ratelimits = prompt_target.ratelimits.clone()?;
+                Some((
+                    prompt_target.name.clone(),
+                    PromptTargetLimits {
+                        requests: ratelimits.requests,
+                        tokens: ratelimits.tokens,
+                    },
+                ))
+            })
+            .collect();
+        PromptTargetRatelimitMap { datastore, store }
+    }
+
+    // Consumes one request and tokens_used tokens from the limits of the prompt target.
+    pub fn check_limit(
+        &self,
+        prompt_target: &str,
+        tokens_used: NonZeroU32,
+        now: SystemTime,
+    ) -> Result<(), Error> {
+        debug!(
+            "Checking limit for prompt_target={}, consuming tokens={:?}",
+            prompt_target, tokens_used
+        );
+
+        let limits = match self.datastore.get(prompt_target) {
+            // No limit configured for this prompt target, hence ok.
+            None => return Ok(()),
+            Some(limits) => limits,
+        };
+
+        let checks = [
+            ("requests", &limits.requests, NonZeroU32::MIN),
+            ("tokens", &limits.tokens, tokens_used),
+        ];
+        for (limit_name, limit, used) in checks {
+            let limit = match limit {
+                Some(limit) => limit,
+                None => continue,
+            };
+            let bucket_key = format!("ratelimit/prompt_target/{}/{}", prompt_target, limit_name);
+            if !take(self.store.as_ref(), &bucket_key, limit, used, now) {
+                return Err(Error::ExceededPromptTargetLimit {
+                    prompt_target: prompt_target.to_string(),
+                    limit: limit_name.to_string(),
+                    used,
+                });
+            }
+        }
+        Ok(())
     }
 }
 
 // The following tests are inside the ratelimit module in order to access RatelimitMap::new() in order to provide
 // different configuration values per test.
+#[cfg(test)]
+#[derive(Default)]
+struct MemoryStore {
+    // key -> value, cas
+    data: std::sync::Mutex<HashMap<String, (Vec<u8>, u32)>>,
+}
+
+#[cfg(test)]
+impl BucketStore for MemoryStore {
+    fn get(&self, key: &str) -> (Option<Vec<u8>>, Option<u32>) {
+        match self.data.lock().unwrap().get(key) {
+            Some((value, cas)) => (Some(value.clone()), Some(*cas)),
+            None => (None, None),
+        }
+    }
+
+    fn set(&self, key: &str, value: &[u8], cas: Option<u32>) -> Result<(), Status> {
+        let mut data = self.data.lock().unwrap();
+        let current_cas = data.get(key).map(|(_, cas)| *cas);
+        if cas.is_some() && cas != current_cas {
+            return Err(Status::CasMismatch);
+        }
+        data.insert(
+            key.to_string(),
+            (value.to_vec(), current_cas.unwrap_or_default() + 1),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl BucketStore for std::sync::Arc<MemoryStore> {
+    fn get(&self, key: &str) -> (Option<Vec<u8>>, Option<u32>) {
+        self.as_ref().get(key)
+    }
+
+    fn set(&self, key: &str, value: &[u8], cas: Option<u32>) -> Result<(), Status> {
+        self.as_ref().set(key, value, cas)
+    }
+}
+
+#[cfg(test)]
+fn memory_store() -> Box<dyn BucketStore> {
+    Box::new(MemoryStore::default())
+}
+
+#[cfg(test)]
+fn now() -> SystemTime {
+    UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000)
+}
+
 #[test]
 fn non_existent_provider_is_ok() {
     let ratelimits_config = vec![Ratelimit {
@@ -166,10 +616,13 @@ fn non_existent_provider_is_ok() {
         limit: Limit {
             tokens: 100,
             unit: TimeUnit::Minute,
+            burst: None,
         },
+        algorithm: None,
+        max_concurrent_streams: None,
     }];
 
-    let ratelimits = RatelimitMap::new(ratelimits_config);
+    let ratelimits = RatelimitMap::new(ratelimits_config, memory_store());
 
     assert!(ratelimits
         .check_limit(
@@ -179,6 +632,7 @@ fn non_existent_provider_is_ok() {
                 value: String::from("value"),
             },
             NonZero::new(5000).unwrap(),
+            now(),
         )
         .is_ok())
 }
@@ -194,10 +648,13 @@ fn non_existent_key_is_ok() {
         limit: Limit {
             tokens: 100,
             unit: TimeUnit::Minute,
+            burst: None,
         },
+        algorithm: None,
+        max_concurrent_streams: None,
     }];
 
-    let ratelimits = RatelimitMap::new(ratelimits_config);
+    let ratelimits = RatelimitMap::new(ratelimits_config, memory_store());
 
     assert!(ratelimits
         .check_limit(
@@ -207,6 +664,7 @@ fn non_existent_key_is_ok() {
                 value: String::from("value"),
             },
             NonZero::new(5000).unwrap(),
+            now(),
         )
         .is_ok())
 }
@@ -222,10 +680,13 @@ fn specific_limit_does_not_catch_non_specific_value() {
         limit: Limit {
             tokens: 200,
             unit: TimeUnit::Second,
+            burst: None,
         },
+        algorithm: None,
+        max_concurrent_streams: None,
     }];
 
-    let ratelimits = RatelimitMap::new(ratelimits_config);
+    let ratelimits = RatelimitMap::new(ratelimits_config, memory_store());
 
     assert!(ratelimits
         .check_limit(
@@ -235,6 +696,7 @@ fn specific_limit_does_not_catch_non_specific_value() {
                 value: String::from("not-the-correct-value"),
             },
             NonZero::new(5000).unwrap(),
+            now(),
         )
         .is_ok())
 }
@@ -250,10 +712,13 @@ fn specific_limit_is_hit() {
         limit: Limit {
             tokens: 200,
             unit: TimeUnit::Hour,
+            burst: None,
         },
+        algorithm: None,
+        max_concurrent_streams: None,
     }];
 
-    let ratelimits = RatelimitMap::new(ratelimits_config);
+    let ratelimits = RatelimitMap::new(ratelimits_config, memory_store());
 
     assert!(ratelimits
         .check_limit(
@@ -263,6 +728,7 @@ fn specific_limit_is_hit() {
                 value: String::from("value"),
             },
             NonZero::new(5000).unwrap(),
+            now(),
         )
         .is_err())
 }
@@ -278,10 +744,13 @@ fn non_specific_key_has_different_limits_for_different_values() {
         limit: Limit {
             tokens: 100,
             unit: TimeUnit::Hour,
+            burst: None,
         },
+        algorithm: None,
+        max_concurrent_streams: None,
     }];
 
-    let ratelimits = RatelimitMap::new(ratelimits_config);
+    let ratelimits = RatelimitMap::new(ratelimits_config, memory_store());
 
     // Value1 takes 50.
     assert!(ratelimits
@@ -292,6 +761,7 @@ fn non_specific_key_has_different_limits_for_different_values() {
                 value: String::from("value1"),
             },
             NonZero::new(50).unwrap(),
+            now(),
         )
         .is_ok());
 
@@ -304,6 +774,7 @@ fn non_specific_key_has_different_limits_for_different_values() {
                 value: String::from("value2"),
             },
             NonZero::new(60).unwrap(),
+            now(),
         )
         .is_ok());
 
@@ -316,6 +787,7 @@ fn non_specific_key_has_different_limits_for_different_values() {
                 value: String::from("value1"),
             },
             NonZero::new(70).unwrap(),
+            now(),
         )
         .is_err())
 }
@@ -332,7 +804,10 @@ fn different_provider_can_have_different_limits_with_the_same_keys() {
             limit: Limit {
                 tokens: 100,
                 unit: TimeUnit::Hour,
+                burst: None,
             },
+            algorithm: None,
+            max_concurrent_streams: None,
         },
         Ratelimit {
             model: String::from("second_provider"),
@@ -343,11 +818,14 @@ fn different_provider_can_have_different_limits_with_the_same_keys() {
             limit: Limit {
                 tokens: 200,
                 unit: TimeUnit::Hour,
+                burst: None,
             },
+            algorithm: None,
+            max_concurrent_streams: None,
         },
     ];
 
-    let ratelimits = RatelimitMap::new(ratelimits_config);
+    let ratelimits = RatelimitMap::new(ratelimits_config, memory_store());
 
     assert!(ratelimits
         .check_limit(
@@ -357,6 +835,7 @@ fn different_provider_can_have_different_limits_with_the_same_keys() {
                 value: String::from("value"),
             },
             NonZero::new(100).unwrap(),
+            now(),
         )
         .is_ok());
 
@@ -368,6 +847,7 @@ fn different_provider_can_have_different_limits_with_the_same_keys() {
                 value: String::from("value"),
             },
             NonZero::new(200).unwrap(),
+            now(),
         )
         .is_ok());
 
@@ -379,6 +859,7 @@ fn different_provider_can_have_different_limits_with_the_same_keys() {
                 value: String::from("value"),
             },
             NonZero::new(1).unwrap(),
+            now(),
         )
         .is_err());
 
@@ -390,6 +871,422 @@ fn different_provider_can_have_different_limits_with_the_same_keys() {
                 value: String::from("value"),
             },
             NonZero::new(1).unwrap(),
+            now(),
+        )
+        .is_err());
+}
+
+#[cfg(test)]
+fn prompt_target_with_ratelimits(
+    name: &str,
+    ratelimits: Option<configuration::PromptTargetRatelimits>,
+) -> PromptTarget {
+    PromptTarget {
+        name: String::from(name),
+        default: None,
+        description: String::from("description"),
+        aliases: None,
+        endpoint: None,
+        parameters: None,
+        system_prompt: None,
+        auto_llm_dispatch_on_response: None,
+        ratelimits,
+        group: None,
+        cache: None,
+        parameter_collection: None,
+        shadow: None,
+        async_operation: None,
+        conditions: None,
+        output_schema: None,
+        output_schema_retries: None,
+        llm_provider: None,
+        model: None,
+        response_mode: None,
+        steps: None,
+        tool_response: None,
+    }
+}
+
+#[test]
+fn prompt_target_without_ratelimits_is_ok() {
+    let prompt_targets = vec![prompt_target_with_ratelimits("get_weather", None)];
+
+    let ratelimits = PromptTargetRatelimitMap::new(&prompt_targets, memory_store());
+
+    assert!(ratelimits
+        .check_limit("get_weather", NonZero::new(5000).unwrap(), now())
+        .is_ok());
+    assert!(ratelimits
+        .check_limit(
+            "non-existent-prompt-target",
+            NonZero::new(5000).unwrap(),
+            now()
+        )
+        .is_ok());
+}
+
+#[test]
+fn prompt_target_requests_limit_is_hit() {
+    let prompt_targets = vec![prompt_target_with_ratelimits(
+        "reboot_devices",
+        Some(configuration::PromptTargetRatelimits {
+            requests: Some(Limit {
+                tokens: 2,
+                unit: TimeUnit::Minute,
+                burst: None,
+            }),
+            tokens: None,
+        }),
+    )];
+
+    let ratelimits = PromptTargetRatelimitMap::new(&prompt_targets, memory_store());
+
+    for _ in 0..2 {
+        assert!(ratelimits
+            .check_limit("reboot_devices", NonZero::new(5000).unwrap(), now())
+            .is_ok());
+    }
+    assert!(ratelimits
+        .check_limit("reboot_devices", NonZero::new(1).unwrap(), now())
+        .is_err());
+}
+
+#[test]
+fn prompt_target_tokens_limit_is_hit() {
+    let prompt_targets = vec![prompt_target_with_ratelimits(
+        "reboot_devices",
+        Some(configuration::PromptTargetRatelimits {
+            requests: Some(Limit {
+                tokens: 100,
+                unit: TimeUnit::Minute,
+                burst: None,
+            }),
+            tokens: Some(Limit {
+                tokens: 100,
+                unit: TimeUnit::Minute,
+                burst: None,
+            }),
+        }),
+    )];
+
+    let ratelimits = PromptTargetRatelimitMap::new(&prompt_targets, memory_store());
+
+    assert!(ratelimits
+        .check_limit("reboot_devices", NonZero::new(60).unwrap(), now())
+        .is_ok());
+    assert!(ratelimits
+        .check_limit("reboot_devices", NonZero::new(60).unwrap(), now())
+        .is_err());
+}
+
+#[test]
+fn tokens_are_refilled_over_time() {
+    let ratelimits_config = vec![Ratelimit {
+        model: String::from("provider"),
+        selector: configuration::Header {
+            key: String::from("key"),
+            value: None,
+        },
+        limit: Limit {
+            tokens: 100,
+            unit: TimeUnit::Minute,
+            burst: None,
+        },
+        algorithm: None,
+        max_concurrent_streams: None,
+    }];
+    let ratelimits = RatelimitMap::new(ratelimits_config, memory_store());
+    let check = |tokens: u32, seconds: u64| {
+        ratelimits.check_limit(
+            String::from("provider"),
+            Header {
+                key: String::from("key"),
+                value: String::from("value"),
+            },
+            NonZero::new(tokens).unwrap(),
+            now() + std::time::Duration::from_secs(seconds),
+        )
+    };
+
+    assert!(check(100, 0).is_ok());
+    assert!(check(1, 0).is_err());
+    // half of the limit is back after half a minute
+    assert!(check(50, 30).is_ok());
+    assert!(check(1, 30).is_err());
+    // the bucket holds no more than the limit
+    assert!(check(101, 600).is_err());
+    assert!(check(100, 600).is_ok());
+}
+
+#[test]
+fn token_bucket_allows_a_burst() {
+    let ratelimits_config = vec![Ratelimit {
+        model: String::from("provider"),
+        selector: configuration::Header {
+            key: String::from("key"),
+            value: None,
+        },
+        limit: Limit {
+            tokens: 100,
+            unit: TimeUnit::Minute,
+            burst: Some(300),
+        },
+        algorithm: Some(RatelimitAlgorithm::TokenBucket),
+        max_concurrent_streams: None,
+    }];
+    let ratelimits = RatelimitMap::new(ratelimits_config, memory_store());
+    let check = |tokens: u32, seconds: u64| {
+        ratelimits.check_limit(
+            String::from("provider"),
+            Header {
+                key: String::from("key"),
+                value: String::from("value"),
+            },
+            NonZero::new(tokens).unwrap(),
+            now() + std::time::Duration::from_secs(seconds),
+        )
+    };
+
+    assert!(check(300, 0).is_ok());
+    assert!(check(1, 0).is_err());
+    // the bucket still fills up at the limit
+    assert!(check(100, 60).is_ok());
+    assert!(check(1, 60).is_err());
+}
+
+#[test]
+fn sliding_window_counts_tokens_of_the_last_unit() {
+    let ratelimits_config = vec![Ratelimit {
+        model: String::from("provider"),
+        selector: configuration::Header {
+            key: String::from("key"),
+            value: None,
+        },
+        limit: Limit {
+            tokens: 100,
+            unit: TimeUnit::Minute,
+            burst: None,
+        },
+        algorithm: Some(RatelimitAlgorithm::SlidingWindow),
+        max_concurrent_streams: None,
+    }];
+    let ratelimits = RatelimitMap::new(ratelimits_config, memory_store());
+    let check = |tokens: u32, seconds: u64| {
+        ratelimits.check_limit(
+            String::from("provider"),
+            Header {
+                key: String::from("key"),
+                value: String::from("value"),
+            },
+            NonZero::new(tokens).unwrap(),
+            now() + std::time::Duration::from_secs(seconds),
+        )
+    };
+
+    assert!(check(60, 0).is_ok());
+    assert!(check(40, 30).is_ok());
+    // nothing is given back before the tokens leave the window
+    assert!(check(1, 59).is_err());
+    // the 60 tokens are out of the window, the 40 are not
+    assert!(check(60, 61).is_ok());
+    assert!(check(1, 61).is_err());
+}
+
+#[test]
+fn concurrency_limits_requests_in_flight() {
+    let ratelimits_config = vec![Ratelimit {
+        model: String::from("provider"),
+        selector: configuration::Header {
+            key: String::from("key"),
+            value: Some(String::from("value")),
+        },
+        limit: Limit {
+            tokens: 2,
+            unit: TimeUnit::Second,
+            burst: None,
+        },
+        algorithm: Some(RatelimitAlgorithm::Concurrency),
+        max_concurrent_streams: None,
+    }];
+    let ratelimits = RatelimitMap::new(ratelimits_config, memory_store());
+    let check = || {
+        ratelimits.check_limit(
+            String::from("provider"),
+            Header {
+                key: String::from("key"),
+                value: String::from("value"),
+            },
+            NonZero::new(5000).unwrap(),
+            now(),
+        )
+    };
+
+    let first = check().unwrap().unwrap();
+    let _second = check().unwrap().unwrap();
+    assert!(check().is_err());
+
+    ratelimits.release(first);
+    assert!(check().unwrap().is_some());
+    assert!(check().is_err());
+}
+
+#[test]
+fn concurrent_streams_are_limited_per_selector_value() {
+    let ratelimits_config = vec![Ratelimit {
+        model: String::from("provider"),
+        selector: configuration::Header {
+            key: String::from("x-user-id"),
+            value: None,
+        },
+        limit: Limit {
+            tokens: 100000,
+            unit: TimeUnit::Minute,
+            burst: None,
+        },
+        algorithm: None,
+        max_concurrent_streams: Some(1),
+    }];
+    let ratelimits = RatelimitMap::new(ratelimits_config, memory_store());
+    let open_stream = |user: &str| {
+        ratelimits.open_stream(
+            String::from("provider"),
+            Header {
+                key: String::from("x-user-id"),
+                value: String::from(user),
+            },
+            now(),
+        )
+    };
+
+    let stream = open_stream("alice").unwrap().unwrap();
+    assert!(matches!(
+        open_stream("alice"),
+        Err(Error::ExceededStreamLimit { limit: 1, .. })
+    ));
+    // every user has streams of their own
+    assert!(open_stream("bob").unwrap().is_some());
+
+    ratelimits.release(stream);
+    assert!(open_stream("alice").unwrap().is_some());
+    // streams don't count against the token limit of the selector
+    assert!(ratelimits
+        .check_limit(
+            String::from("provider"),
+            Header {
+                key: String::from("x-user-id"),
+                value: String::from("alice"),
+            },
+            NonZero::new(5000).unwrap(),
+            now(),
+        )
+        .unwrap()
+        .is_none());
+    // nor are there stream limits for providers without them
+    assert!(ratelimits
+        .open_stream(
+            String::from("other"),
+            Header {
+                key: String::from("x-user-id"),
+                value: String::from("alice"),
+            },
+            now(),
+        )
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn requests_never_released_stop_counting_once_their_lease_expires() {
+    let ratelimits_config = vec![Ratelimit {
+        model: String::from("provider"),
+        selector: configuration::Header {
+            key: String::from("key"),
+            value: Some(String::from("value")),
+        },
+        limit: Limit {
+            tokens: 1,
+            unit: TimeUnit::Second,
+            burst: None,
+        },
+        algorithm: Some(RatelimitAlgorithm::Concurrency),
+        max_concurrent_streams: Some(1),
+    }];
+    let ratelimits = RatelimitMap::new(ratelimits_config, memory_store());
+    let selector = Header {
+        key: String::from("key"),
+        value: String::from("value"),
+    };
+    let check = |now: SystemTime| {
+        ratelimits.check_limit(
+            String::from("provider"),
+            selector.clone(),
+            NonZero::new(1).unwrap(),
+            now,
+        )
+    };
+    let open_stream =
+        |now: SystemTime| ratelimits.open_stream(String::from("provider"), selector.clone(), now);
+
+    // e.g. the vm of the requests was torn down before they were done
+    let first = check(now()).unwrap().unwrap();
+    let stream = open_stream(now()).unwrap().unwrap();
+    let lease = std::time::Duration::from_secs(IN_FLIGHT_LEASE_SECONDS);
+    assert!(check(now() + lease - std::time::Duration::from_secs(1)).is_err());
+    assert!(open_stream(now() + lease - std::time::Duration::from_secs(1)).is_err());
+
+    let second = check(now() + lease).unwrap().unwrap();
+    assert!(open_stream(now() + lease).unwrap().is_some());
+    assert!(check(now() + lease).is_err());
+
+    // releasing an expired lease doesn't release the ones taken after it
+    ratelimits.release(first);
+    ratelimits.release(stream);
+    assert!(check(now() + lease).is_err());
+    assert!(open_stream(now() + lease).is_err());
+    ratelimits.release(second);
+    assert!(check(now() + lease).unwrap().is_some());
+}
+
+#[test]
+fn used_tokens_survive_new_limits() {
+    let ratelimits_config = vec![Ratelimit {
+        model: String::from("provider"),
+        selector: configuration::Header {
+            key: String::from("key"),
+            value: Some(String::from("value")),
+        },
+        limit: Limit {
+            tokens: 100,
+            unit: TimeUnit::Hour,
+            burst: None,
+        },
+        algorithm: None,
+        max_concurrent_streams: None,
+    }];
+    let store = std::sync::Arc::new(MemoryStore::default());
+    let selector = Header {
+        key: String::from("key"),
+        value: String::from("value"),
+    };
+
+    let ratelimits = RatelimitMap::new(ratelimits_config.clone(), Box::new(store.clone()));
+    assert!(ratelimits
+        .check_limit(
+            String::from("provider"),
+            selector.clone(),
+            NonZero::new(100).unwrap(),
+            now(),
+        )
+        .is_ok());
+
+    // e.g. a config change or a restarted vm
+    let ratelimits = RatelimitMap::new(ratelimits_config, Box::new(store));
+    assert!(ratelimits
+        .check_limit(
+            String::from("provider"),
+            selector,
+            NonZero::new(1).unwrap(),
+            now(),
         )
         .is_err());
 }
@@ -402,19 +1299,13 @@ mod test {
 
     use super::ratelimits;
     use configuration::{Limit, Ratelimit, TimeUnit};
-    use std::num::NonZero;
     use std::thread;
 
     #[test]
-    fn make_ratelimits_optional() {
-        let ratelimits_config = Vec::new();
-
+    fn different_threads_have_same_ratelimit_data_structure() {
         // Initialize in the main thread.
-        ratelimits(Some(ratelimits_config));
-    }
+        ratelimits(Some(Vec::new()));
 
-    #[test]
-    fn different_threads_have_same_ratelimit_data_structure() {
         let ratelimits_config = Some(vec![Ratelimit {
             model: String::from("provider"),
             selector: configuration::Header {
@@ -424,10 +1315,13 @@ mod test {
             limit: Limit {
                 tokens: 200,
                 unit: TimeUnit::Hour,
+                burst: None,
             },
+            algorithm: None,
+            max_concurrent_streams: None,
         }]);
 
-        // Initialize in the main thread.
+        // A new config replaces the limits.
         ratelimits(ratelimits_config);
 
         // Use the singleton in a different thread.
@@ -437,15 +1331,10 @@ mod test {
             assert!(ratelimits
                 .read()
                 .unwrap()
-                .check_limit(
-                    String::from("provider"),
-                    super::Header {
-                        key: String::from("key"),
-                        value: String::from("value"),
-                    },
-                    NonZero::new(5000).unwrap(),
-                )
-                .is_err())
-        });
+                .datastore
+                .contains_key("provider"))
+        })
+        .join()
+        .unwrap();
     }
 }
@@ -0,0 +1,81 @@
+use crate::routing::stable_hash;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const CACHE_KEY_PREFIX: &str = "curve.fc_cache";
+
+// Arguments are sorted by name before hashing so that the same tool call always maps to the same
+// key, regardless of the order in which function calling returned the arguments.
+pub fn cache_key(prompt_target: &str, arguments: &HashMap<String, serde_yaml::Value>) -> String {
+    let arguments: BTreeMap<&String, &serde_yaml::Value> = arguments.iter().collect();
+    let arguments_str = serde_json::to_string(&arguments).unwrap_or_default();
+    format!(
+        "{}.{}.{:016x}",
+        CACHE_KEY_PREFIX,
+        prompt_target,
+        stable_hash(&arguments_str)
+    )
+}
+
+// value stored in the proxy shared data for a cached prompt target response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    // seconds since the unix epoch
+    pub expires_at: u64,
+    pub response: String,
+}
+
+impl CacheEntry {
+    pub fn new(response: String, ttl: Duration, now: SystemTime) -> Self {
+        CacheEntry {
+            expires_at: unix_seconds(now + ttl),
+            response,
+        }
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        unix_seconds(now) >= self.expires_at
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cache_key, CacheEntry};
+    use std::collections::HashMap;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_cache_key() {
+        let mut arguments = HashMap::new();
+        arguments.insert(String::from("city"), serde_yaml::Value::from("seattle"));
+        arguments.insert(String::from("days"), serde_yaml::Value::from(7));
+        let key = cache_key("weather_forecast", &arguments);
+        assert!(key.starts_with("curve.fc_cache.weather_forecast."));
+
+        // insertion order doesn't matter
+        let mut reordered = HashMap::new();
+        reordered.insert(String::from("days"), serde_yaml::Value::from(7));
+        reordered.insert(String::from("city"), serde_yaml::Value::from("seattle"));
+        assert_eq!(cache_key("weather_forecast", &reordered), key);
+
+        arguments.insert(String::from("days"), serde_yaml::Value::from(3));
+        assert_ne!(cache_key("weather_forecast", &arguments), key);
+        assert_ne!(cache_key("air_quality", &reordered), key);
+    }
+
+    #[test]
+    fn test_cache_entry_expiry() {
+        let now = UNIX_EPOCH + Duration::from_secs(1709210096);
+        let entry = CacheEntry::new(String::from("{}"), Duration::from_secs(60), now);
+        assert!(!entry.is_expired(now));
+        assert!(!entry.is_expired(now + Duration::from_secs(59)));
+        assert!(entry.is_expired(now + Duration::from_secs(60)));
+    }
+}
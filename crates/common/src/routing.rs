@@ -1,7 +1,9 @@
+use std::collections::HashMap;
 use std::rc::Rc;
+use std::sync::{OnceLock, RwLock};
 
 use crate::{configuration, llm_providers::LlmProviders};
-use configuration::LlmProvider;
+use configuration::{Experiment, ExperimentArm, LlmProvider, SessionAffinity};
 use log::debug;
 use rand::{seq::IteratorRandom, thread_rng};
 
@@ -48,3 +50,246 @@ pub fn get_llm_provider(
         .1
         .clone()
 }
+
+// picks the arm of the experiment for the given header value, the choice only depends on the
+// experiment name and the value so repeated requests from the same client hit the same arm
+pub fn get_experiment_arm<'a>(experiment: &'a Experiment, key: &str) -> Option<&'a ExperimentArm> {
+    let total_weight: u64 = experiment.arms.iter().map(|arm| arm.weight as u64).sum();
+    if total_weight == 0 {
+        return None;
+    }
+
+    let mut bucket = stable_hash(&format!("{}:{}", experiment.name, key)) % total_weight;
+    for arm in experiment.arms.iter() {
+        if bucket < arm.weight as u64 {
+            return Some(arm);
+        }
+        bucket -= arm.weight as u64;
+    }
+    None
+}
+
+// sessions are forgotten once this many have failed over, they fall back to their hashed provider
+const MAX_SESSION_FAILOVERS: usize = 10000;
+
+// number of failovers per session
+fn session_failovers() -> &'static RwLock<HashMap<String, usize>> {
+    static SESSION_FAILOVERS: OnceLock<RwLock<HashMap<String, usize>>> = OnceLock::new();
+    SESSION_FAILOVERS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+// picks the llm provider of the pool for the given session, the choice only depends on the session
+// and the failovers it had so a conversation stays on the same provider
+pub fn get_session_provider<'a>(
+    session_affinity: &'a SessionAffinity,
+    session: &str,
+) -> Option<&'a String> {
+    let llm_providers = &session_affinity.llm_providers;
+    if llm_providers.is_empty() {
+        return None;
+    }
+
+    let failovers = session_failovers()
+        .read()
+        .unwrap()
+        .get(session)
+        .copied()
+        .unwrap_or(0);
+    let index = (stable_hash(session) % llm_providers.len() as u64) as usize + failovers;
+    llm_providers.get(index % llm_providers.len())
+}
+
+// moves the session on to the next llm provider of the pool after a provider error
+pub fn fail_over_session(session: &str) {
+    let mut failovers = session_failovers().write().unwrap();
+    if failovers.len() >= MAX_SESSION_FAILOVERS && !failovers.contains_key(session) {
+        failovers.clear();
+    }
+    *failovers.entry(session.to_string()).or_default() += 1;
+}
+
+// providers that answered 429 with a retry-after and the unix seconds until which they are skipped
+fn provider_cooldowns() -> &'static RwLock<HashMap<String, u64>> {
+    static PROVIDER_COOLDOWNS: OnceLock<RwLock<HashMap<String, u64>>> = OnceLock::new();
+    PROVIDER_COOLDOWNS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+// only the delay-seconds form of retry-after is supported, http dates are ignored
+pub fn parse_retry_after(value: &str) -> Option<u64> {
+    value.trim().parse().ok()
+}
+
+pub fn cool_down_provider(llm_provider: &str, now: u64, retry_after: u64) {
+    let mut cooldowns = provider_cooldowns().write().unwrap();
+    cooldowns.retain(|_, until| *until > now);
+    let until = cooldowns.entry(llm_provider.to_string()).or_default();
+    *until = (*until).max(now + retry_after);
+}
+
+pub fn is_cooling_down(llm_provider: &str, now: u64) -> bool {
+    provider_cooldowns()
+        .read()
+        .unwrap()
+        .get(llm_provider)
+        .is_some_and(|until| *until > now)
+}
+
+// the llm provider itself unless it is cooling down and another provider isn't, used for requests
+// that didn't name their provider
+pub fn avoid_cooling_down(
+    llm_providers: &LlmProviders,
+    llm_provider: Rc<LlmProvider>,
+    now: u64,
+) -> Rc<LlmProvider> {
+    if !is_cooling_down(&llm_provider.name, now) {
+        return llm_provider;
+    }
+    llm_providers
+        .iter()
+        .map(|(_, llm_provider)| llm_provider)
+        .filter(|llm_provider| !is_cooling_down(&llm_provider.name, now))
+        .min_by(|a, b| a.name.cmp(&b.name))
+        .cloned()
+        .unwrap_or(llm_provider)
+}
+
+// fnv-1a, unlike the std hashers its output is guaranteed to be the same across builds
+pub(crate) fn stable_hash(value: &str) -> u64 {
+    value.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::configuration::{Experiment, ExperimentArm, LlmProvider, SessionAffinity};
+    use crate::llm_providers::LlmProviders;
+
+    fn experiment(weights: &[(&str, u32)]) -> Experiment {
+        Experiment {
+            name: String::from("gpt4o_vs_mistral"),
+            header: String::from("x-user-id"),
+            arms: weights
+                .iter()
+                .map(|(llm_provider, weight)| ExperimentArm {
+                    llm_provider: llm_provider.to_string(),
+                    weight: *weight,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_experiment_arm_is_stable() {
+        let experiment = experiment(&[("gpt-4o", 50), ("mistral", 50)]);
+        for user in ["user-1", "user-2", "user-3"] {
+            let arm = super::get_experiment_arm(&experiment, user).unwrap();
+            for _ in 0..10 {
+                assert_eq!(
+                    super::get_experiment_arm(&experiment, user)
+                        .unwrap()
+                        .llm_provider,
+                    arm.llm_provider
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_experiment_arm_split() {
+        let experiment = experiment(&[("gpt-4o", 90), ("mistral", 10)]);
+        let mistral_count = (0..1000)
+            .filter(|user| {
+                super::get_experiment_arm(&experiment, &format!("user-{}", user))
+                    .unwrap()
+                    .llm_provider
+                    == "mistral"
+            })
+            .count();
+        assert!((50..150).contains(&mistral_count));
+    }
+
+    #[test]
+    fn test_experiment_arm_zero_weight() {
+        let no_mistral = experiment(&[("gpt-4o", 100), ("mistral", 0)]);
+        for user in ["user-1", "user-2", "user-3"] {
+            assert_eq!(
+                super::get_experiment_arm(&no_mistral, user)
+                    .unwrap()
+                    .llm_provider,
+                "gpt-4o"
+            );
+        }
+
+        let no_traffic = experiment(&[("gpt-4o", 0)]);
+        assert!(super::get_experiment_arm(&no_traffic, "user-1").is_none());
+    }
+
+    #[test]
+    fn test_provider_cooldown() {
+        let llm_providers: Vec<LlmProvider> = serde_yaml::from_str(
+            r#"
+- name: gpt-4o
+  provider_interface: openai
+  model: gpt-4o
+  default: true
+- name: mistral
+  provider_interface: mistral
+  model: mistral-large
+"#,
+        )
+        .unwrap();
+        let llm_providers = LlmProviders::try_from(llm_providers).unwrap();
+        let default = llm_providers.default().unwrap();
+
+        assert_eq!(super::parse_retry_after(" 30 "), Some(30));
+        assert_eq!(
+            super::parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            None
+        );
+
+        super::cool_down_provider("gpt-4o", 1000, 30);
+        assert!(super::is_cooling_down("gpt-4o", 1029));
+        assert!(!super::is_cooling_down("gpt-4o", 1030));
+        let llm_provider = super::avoid_cooling_down(&llm_providers, default.clone(), 1010);
+        assert_eq!(llm_provider.name, "mistral");
+
+        // with every provider cooling down the request stays on its provider
+        super::cool_down_provider("mistral", 1000, 60);
+        let llm_provider = super::avoid_cooling_down(&llm_providers, default.clone(), 1010);
+        assert_eq!(llm_provider.name, "gpt-4o");
+        let llm_provider = super::avoid_cooling_down(&llm_providers, default, 1040);
+        assert_eq!(llm_provider.name, "gpt-4o");
+    }
+
+    #[test]
+    fn test_session_provider_fails_over() {
+        let session_affinity = SessionAffinity {
+            header: String::from("x-session-id"),
+            llm_providers: vec![String::from("gpt-4o"), String::from("mistral")],
+        };
+
+        let provider = super::get_session_provider(&session_affinity, "session-1").unwrap();
+        for _ in 0..10 {
+            assert_eq!(
+                super::get_session_provider(&session_affinity, "session-1").unwrap(),
+                provider
+            );
+        }
+
+        super::fail_over_session("session-1");
+        let failover_provider =
+            super::get_session_provider(&session_affinity, "session-1").unwrap();
+        assert_ne!(failover_provider, provider);
+        assert_eq!(
+            super::get_session_provider(&session_affinity, "session-1").unwrap(),
+            failover_provider
+        );
+
+        let no_providers = SessionAffinity {
+            header: String::from("x-session-id"),
+            llm_providers: vec![],
+        };
+        assert!(super::get_session_provider(&no_providers, "session-1").is_none());
+    }
+}
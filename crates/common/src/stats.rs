@@ -1,6 +1,10 @@
+use crate::consts::MAX_METRIC_SERIES;
+use crate::metric_names::{metric_name, Dimension, OTHER_LABEL_VALUE};
 use log::error;
 use proxy_wasm::hostcalls;
 use proxy_wasm::types::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
 
 pub trait Metric {
     fn id(&self) -> u32;
@@ -44,6 +48,12 @@ impl Counter {
     }
 }
 
+impl DefinedMetric for Counter {
+    fn define(name: String) -> Self {
+        Counter::new(name)
+    }
+}
+
 impl Metric for Counter {
     fn id(&self) -> u32 {
         self.id
@@ -65,6 +75,12 @@ impl Gauge {
     }
 }
 
+impl DefinedMetric for Gauge {
+    fn define(name: String) -> Self {
+        Gauge::new(name)
+    }
+}
+
 impl Metric for Gauge {
     fn id(&self) -> u32 {
         self.id
@@ -89,6 +105,12 @@ impl Histogram {
     }
 }
 
+impl DefinedMetric for Histogram {
+    fn define(name: String) -> Self {
+        Histogram::new(name)
+    }
+}
+
 impl Metric for Histogram {
     fn id(&self) -> u32 {
         self.id
@@ -96,3 +118,48 @@ impl Metric for Histogram {
 }
 
 impl RecordingMetric for Histogram {}
+
+pub trait DefinedMetric: Metric + Copy {
+    fn define(name: String) -> Self;
+}
+
+// a metric with dimensions, see metric_names. A series is defined the first time its labels are
+// used, once a metric has MAX_METRIC_SERIES series new label values are counted as other.
+#[derive(Debug)]
+pub struct MetricFamily<M> {
+    name: &'static str,
+    series: RefCell<HashMap<String, M>>,
+}
+
+impl<M: DefinedMetric> MetricFamily<M> {
+    pub fn new(name: &'static str) -> Self {
+        MetricFamily {
+            name,
+            series: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn with(&self, labels: &[(Dimension, &str)]) -> M {
+        let name = metric_name(self.name, labels);
+        if let Some(metric) = self.series.borrow().get(&name) {
+            return *metric;
+        }
+
+        let mut series = self.series.borrow_mut();
+        let name = if series.len() < MAX_METRIC_SERIES {
+            name
+        } else {
+            let other: Vec<(Dimension, &str)> = labels
+                .iter()
+                .map(|(dimension, _)| (*dimension, OTHER_LABEL_VALUE))
+                .collect();
+            metric_name(self.name, &other)
+        };
+        *series
+            .entry(name)
+            .or_insert_with_key(|name| M::define(name.clone()))
+    }
+}
+
+pub type CounterFamily = MetricFamily<Counter>;
+pub type HistogramFamily = MetricFamily<Histogram>;
@@ -0,0 +1,191 @@
+use crate::api::open_ai::{ChatCompletionsRequest, Message};
+use crate::configuration::Summarization;
+use crate::consts::{SUMMARIZATION_PROMPT, SYSTEM_ROLE, TOOL_ROLE, USER_ROLE};
+use crate::tokenizer;
+use std::collections::HashMap;
+
+fn message(role: &str, content: String) -> Message {
+    Message {
+        role: role.to_string(),
+        content: Some(content.into()),
+        model: None,
+        tool_calls: None,
+        tool_call_id: None,
+        name: None,
+    }
+}
+
+pub fn leading_system_messages(messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .take_while(|message| message.role == SYSTEM_ROLE)
+        .count()
+}
+
+// index of the first message kept as is, None when there is nothing older to summarize. Leading
+// system messages are always kept and the kept messages don't start with a tool response, which
+// would be cut off from its tool call.
+pub fn split_index(messages: &[Message], keep_messages: usize) -> Option<usize> {
+    let first = leading_system_messages(messages);
+    let mut split = messages.len().saturating_sub(keep_messages).max(first);
+    while split > first && messages.get(split).is_some_and(|m| m.role == TOOL_ROLE) {
+        split -= 1;
+    }
+    (split > first).then_some(split)
+}
+
+// the split index of a conversation over the token budget, None when it fits
+pub fn needs_summary(
+    summarization: &Summarization,
+    model: &str,
+    messages: &[Message],
+) -> Option<usize> {
+    let tokens: usize = tokenizer::message_tokens(model, messages).iter().sum();
+    if tokens <= summarization.max_tokens {
+        return None;
+    }
+    split_index(messages, summarization.keep_messages())
+}
+
+// request for the summarization provider, the messages before the split index are sent as a
+// transcript
+pub fn summary_request(
+    model: &str,
+    messages: &[Message],
+    split_index: usize,
+) -> ChatCompletionsRequest {
+    let first = leading_system_messages(messages);
+    let transcript = messages[first..split_index]
+        .iter()
+        .map(|message| {
+            let mut text = message
+                .content
+                .as_ref()
+                .map(|content| content.text())
+                .unwrap_or_default();
+            for tool_call in message.tool_calls.iter().flatten() {
+                let arguments =
+                    serde_json::to_string(&tool_call.function.arguments).unwrap_or_default();
+                text.push_str(&format!(
+                    " [called {} with {}]",
+                    tool_call.function.name, arguments
+                ));
+            }
+            format!("{}: {}", message.role, text.trim())
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    ChatCompletionsRequest {
+        model: model.to_string(),
+        messages: vec![
+            message(SYSTEM_ROLE, SUMMARIZATION_PROMPT.to_string()),
+            message(USER_ROLE, transcript),
+        ],
+        tools: None,
+        stream: false,
+        stream_options: None,
+        metadata: None,
+        extra_fields: HashMap::new(),
+    }
+}
+
+// replaces the messages before the split index, except the leading system messages, with the
+// summary
+pub fn apply_summary(messages: &mut Vec<Message>, split_index: usize, summary: &str) {
+    let first = leading_system_messages(messages);
+    let summary = message(
+        SYSTEM_ROLE,
+        format!("Summary of the earlier conversation: {}", summary.trim()),
+    );
+    messages.splice(first..split_index, [summary]);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply_summary, message, needs_summary, split_index, summary_request};
+    use crate::api::open_ai::{FunctionCallDetail, Message, ToolCall, ToolType};
+    use crate::configuration::Summarization;
+    use crate::consts::{ASSISTANT_ROLE, SYSTEM_ROLE, TOOL_ROLE, USER_ROLE};
+    use std::collections::HashMap;
+
+    fn conversation() -> Vec<Message> {
+        let mut tool_call = message(ASSISTANT_ROLE, String::new());
+        tool_call.content = None;
+        tool_call.tool_calls = Some(vec![ToolCall {
+            id: "call_1".to_string(),
+            tool_type: ToolType::Function,
+            function: FunctionCallDetail {
+                name: "get_weather".to_string(),
+                arguments: HashMap::from([("city".to_string(), "seattle".into())]),
+            },
+        }]);
+        let mut tool_response = message(TOOL_ROLE, "sunny".to_string());
+        tool_response.tool_call_id = Some("call_1".to_string());
+
+        vec![
+            message(SYSTEM_ROLE, "You are a helpful assistant.".to_string()),
+            message(USER_ROLE, "I am planning a trip to seattle.".to_string()),
+            message(ASSISTANT_ROLE, "When are you leaving?".to_string()),
+            message(USER_ROLE, "Tomorrow, how is the weather?".to_string()),
+            tool_call,
+            tool_response,
+            message(ASSISTANT_ROLE, "It will be sunny.".to_string()),
+        ]
+    }
+
+    #[test]
+    fn test_split_index() {
+        let messages = conversation();
+        assert_eq!(split_index(&messages, 1), Some(6));
+        // the tool response stays with its tool call
+        assert_eq!(split_index(&messages, 2), Some(4));
+        assert_eq!(split_index(&messages, 0), Some(7));
+        assert_eq!(split_index(&messages, 6), None);
+        assert_eq!(split_index(&messages[..1], 0), None);
+    }
+
+    #[test]
+    fn test_summarize() {
+        let mut summarization = Summarization {
+            llm_provider: "gpt-4o-mini".to_string(),
+            max_tokens: 1000,
+            keep_messages: Some(3),
+        };
+        let mut messages = conversation();
+        assert_eq!(needs_summary(&summarization, "gpt-4o", &messages), None);
+        summarization.max_tokens = 10;
+        let split = needs_summary(&summarization, "unknown-model", &messages).unwrap();
+        assert_eq!(split, 4);
+
+        let request = summary_request("gpt-4o-mini", &messages, split);
+        assert_eq!(request.model, "gpt-4o-mini");
+        assert_eq!(
+            request.messages[1].content.as_ref().unwrap().text(),
+            "user: I am planning a trip to seattle.\n\
+             assistant: When are you leaving?\n\
+             user: Tomorrow, how is the weather?"
+        );
+
+        apply_summary(
+            &mut messages,
+            split,
+            "The user travels to seattle tomorrow.\n",
+        );
+        let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(
+            roles,
+            vec![
+                SYSTEM_ROLE,
+                SYSTEM_ROLE,
+                ASSISTANT_ROLE,
+                TOOL_ROLE,
+                ASSISTANT_ROLE
+            ]
+        );
+        assert_eq!(
+            messages[1].content.as_ref().unwrap().text(),
+            "Summary of the earlier conversation: The user travels to seattle tomorrow."
+        );
+    }
+}
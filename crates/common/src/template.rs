@@ -0,0 +1,118 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// replaces {variable} and {variable:arg} placeholders using the resolver, placeholders that the
+// resolver does not know about are left untouched so literal braces in prompts survive rendering
+pub fn render_template<F>(template: &str, resolve: F) -> String
+where
+    F: Fn(&str, Option<&str>) -> Option<String>,
+{
+    let mut result = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let after_brace = &rest[start + 1..];
+        let end = match after_brace.find(['{', '}']) {
+            Some(end) if after_brace.as_bytes()[end] == b'}' => end,
+            _ => {
+                result.push('{');
+                rest = after_brace;
+                continue;
+            }
+        };
+
+        let placeholder = &after_brace[..end];
+        let (name, arg) = match placeholder.split_once(':') {
+            Some((name, arg)) => (name.trim(), Some(arg.trim())),
+            None => (placeholder.trim(), None),
+        };
+
+        match resolve(name, arg) {
+            Some(value) => result.push_str(&value),
+            None => {
+                result.push('{');
+                result.push_str(placeholder);
+                result.push('}');
+            }
+        }
+        rest = &after_brace[end + 1..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+// formats time as YYYY-MM-DD in UTC
+pub fn format_date(time: SystemTime) -> String {
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() / 86400)
+        .unwrap_or(0) as i64;
+
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn resolve(name: &str, arg: Option<&str>) -> Option<String> {
+        match (name, arg) {
+            ("prompt_target_name", None) => Some("get_weather".to_string()),
+            ("user_header", Some("x-user-id")) => Some("user-1".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_render_template() {
+        assert_eq!(
+            super::render_template("You are serving {prompt_target_name}.", resolve),
+            "You are serving get_weather."
+        );
+        assert_eq!(
+            super::render_template("user: {user_header:x-user-id}", resolve),
+            "user: user-1"
+        );
+        assert_eq!(
+            super::render_template("no variables here", resolve),
+            "no variables here"
+        );
+    }
+
+    #[test]
+    fn test_render_template_unknown_placeholders() {
+        assert_eq!(
+            super::render_template("respond with {\"key\": {unknown}}", resolve),
+            "respond with {\"key\": {unknown}}"
+        );
+        assert_eq!(
+            super::render_template("dangling { brace", resolve),
+            "dangling { brace"
+        );
+        assert_eq!(
+            super::render_template("missing {user_header:x-tenant}", resolve),
+            "missing {user_header:x-tenant}"
+        );
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(super::format_date(UNIX_EPOCH), "1970-01-01");
+        assert_eq!(
+            super::format_date(UNIX_EPOCH + Duration::from_secs(1709210096)),
+            "2024-02-29"
+        );
+    }
+}
@@ -1,4 +1,6 @@
+use crate::api::open_ai::Message;
 use log::debug;
+use tiktoken_rs::CoreBPE;
 
 #[derive(thiserror::Error, Debug, PartialEq, Eq)]
 #[allow(dead_code)]
@@ -17,6 +19,41 @@ pub fn token_count(model_name: &str, text: &str) -> Result<usize, Error> {
     Ok(bpe.encode_ordinary(text).len())
 }
 
+// models unknown to the tokenizer are counted like gpt-4
+fn bpe_or_default(model_name: &str) -> Option<CoreBPE> {
+    tiktoken_rs::get_bpe_from_model(model_name)
+        .or_else(|_| tiktoken_rs::get_bpe_from_model("gpt-4"))
+        .ok()
+}
+
+// tokens of the content of each message
+pub fn message_tokens(model_name: &str, messages: &[Message]) -> Vec<usize> {
+    let Some(bpe) = bpe_or_default(model_name) else {
+        return vec![0; messages.len()];
+    };
+    messages
+        .iter()
+        .map(|message| match message.content.as_ref() {
+            Some(content) => bpe.encode_ordinary(&content.text()).len(),
+            None => 0,
+        })
+        .collect()
+}
+
+// the text cut to its first max_tokens tokens, None when it is not longer than that
+pub fn truncate_text(model_name: &str, text: &str, max_tokens: usize) -> Option<String> {
+    let bpe = bpe_or_default(model_name)?;
+    let tokens = bpe.encode_ordinary(text);
+    if tokens.len() <= max_tokens {
+        return None;
+    }
+    // a cut in the middle of a multi-byte character doesn't decode, cut before it instead
+    (0..4).find_map(|back| {
+        let end = max_tokens.saturating_sub(back);
+        bpe.decode(tokens[..end].to_vec()).ok()
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -40,4 +77,14 @@ mod test {
             token_count("unknown", "").expect_err("unknown model")
         )
     }
+
+    #[test]
+    fn truncate() {
+        let text = "How many tokens does this sentence have?";
+        assert_eq!(truncate_text("gpt-3.5-turbo", text, 8), None);
+        assert_eq!(
+            truncate_text("unknown", text, 3),
+            Some("How many tokens".to_string())
+        );
+    }
 }
@@ -102,6 +102,15 @@ impl Span {
     }
 }
 
+// request id used to correlate logs and callouts of a request that came without an x-request-id
+pub fn random_request_id() -> String {
+    let mut rng = rand::thread_rng();
+    let mut random_bytes = [0u8; 16];
+    rng.fill_bytes(&mut random_bytes);
+
+    hex::encode(random_bytes)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Event {
     #[serde(rename = "timeUnixNano")]
@@ -0,0 +1,161 @@
+use crate::configuration::{self, Budget, Ratelimit, VirtualKey, VirtualKeys};
+use crate::consts::CURVE_VIRTUAL_KEY_HEADER;
+use crate::ratelimit::Header;
+
+// header_value is the value of the configured key header, by default the authorization header with
+// a bearer token
+pub fn find<'a>(
+    virtual_keys: &'a VirtualKeys,
+    header_value: Option<&str>,
+) -> Option<&'a VirtualKey> {
+    let key = match virtual_keys.header {
+        Some(_) => header_value,
+        None => header_value.and_then(|value| value.strip_prefix("Bearer ")),
+    }?;
+    virtual_keys
+        .keys
+        .iter()
+        .find(|virtual_key| virtual_key.key == key.trim())
+}
+
+pub fn is_model_allowed(virtual_key: &VirtualKey, model: &str) -> bool {
+    match virtual_key.models.as_ref() {
+        Some(models) => models.iter().any(|allowed| allowed == model),
+        None => true,
+    }
+}
+
+// the ratelimits and budget of a key are tracked under this selector, it takes the place of the
+// selector sent by the client
+pub fn selector(virtual_key: &VirtualKey) -> Header {
+    Header {
+        key: CURVE_VIRTUAL_KEY_HEADER.to_string(),
+        value: virtual_key.name.clone(),
+    }
+}
+
+fn config_selector(virtual_key: &VirtualKey) -> configuration::Header {
+    configuration::Header::from(selector(virtual_key))
+}
+
+// the limit of a key applies to each of its models, keys without models are limited on all the
+// models of the llm providers
+pub fn ratelimits(virtual_keys: &VirtualKeys, provider_models: &[String]) -> Vec<Ratelimit> {
+    let mut ratelimits = Vec::new();
+    for virtual_key in virtual_keys.keys.iter() {
+        let limit = match virtual_key.ratelimit.as_ref() {
+            Some(limit) => limit,
+            None => continue,
+        };
+        let mut models = virtual_key
+            .models
+            .clone()
+            .unwrap_or_else(|| provider_models.to_vec());
+        models.sort();
+        models.dedup();
+        for model in models {
+            ratelimits.push(Ratelimit {
+                model,
+                selector: config_selector(virtual_key),
+                limit: limit.clone(),
+                algorithm: None,
+                max_concurrent_streams: None,
+            });
+        }
+    }
+    ratelimits
+}
+
+pub fn budgets(virtual_keys: &VirtualKeys) -> Vec<Budget> {
+    virtual_keys
+        .keys
+        .iter()
+        .filter_map(|virtual_key| {
+            virtual_key.budget.as_ref().map(|budget| Budget {
+                selector: config_selector(virtual_key),
+                limit: budget.limit,
+                period: budget.period.clone(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{budgets, find, is_model_allowed, ratelimits};
+    use crate::configuration::{
+        BudgetPeriod, Limit, TimeUnit, VirtualKey, VirtualKeyBudget, VirtualKeys,
+    };
+
+    #[test]
+    fn test_virtual_keys() {
+        let virtual_keys = VirtualKeys {
+            header: None,
+            keys: vec![
+                VirtualKey {
+                    name: "team-a".to_string(),
+                    key: "sk-curve-team-a".to_string(),
+                    models: Some(vec!["gpt-4o-mini".to_string()]),
+                    ratelimit: Some(Limit {
+                        tokens: 1000,
+                        unit: TimeUnit::Minute,
+                        burst: None,
+                    }),
+                    budget: Some(VirtualKeyBudget {
+                        limit: 10.0,
+                        period: BudgetPeriod::Day,
+                    }),
+                    priority: None,
+                },
+                VirtualKey {
+                    name: "team-b".to_string(),
+                    key: "sk-curve-team-b".to_string(),
+                    models: None,
+                    ratelimit: Some(Limit {
+                        tokens: 500,
+                        unit: TimeUnit::Minute,
+                        burst: None,
+                    }),
+                    budget: None,
+                    priority: None,
+                },
+            ],
+        };
+
+        let team_a = find(&virtual_keys, Some("Bearer sk-curve-team-a")).unwrap();
+        assert_eq!(team_a.name, "team-a");
+        assert!(is_model_allowed(team_a, "gpt-4o-mini"));
+        assert!(!is_model_allowed(team_a, "gpt-4o"));
+        assert!(find(&virtual_keys, Some("sk-curve-team-a")).is_none());
+        assert!(find(&virtual_keys, Some("Bearer sk-openai")).is_none());
+        assert!(find(&virtual_keys, None).is_none());
+
+        let provider_models = vec![
+            "gpt-4o".to_string(),
+            "gpt-4o-mini".to_string(),
+            "gpt-4o".to_string(),
+        ];
+        let ratelimits = ratelimits(&virtual_keys, &provider_models);
+        let limited: Vec<(&str, Option<&str>)> = ratelimits
+            .iter()
+            .map(|ratelimit| {
+                (
+                    ratelimit.model.as_str(),
+                    ratelimit.selector.value.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            limited,
+            vec![
+                ("gpt-4o-mini", Some("team-a")),
+                ("gpt-4o", Some("team-b")),
+                ("gpt-4o-mini", Some("team-b")),
+            ]
+        );
+
+        let budgets = budgets(&virtual_keys);
+        assert_eq!(budgets.len(), 1);
+        assert_eq!(budgets[0].selector.value.as_deref(), Some("team-a"));
+    }
+}
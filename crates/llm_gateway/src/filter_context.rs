@@ -1,28 +1,58 @@
 use crate::metrics::Metrics;
 use crate::stream_context::StreamContext;
-use common::configuration::Configuration;
+use common::audit::AuditRecord;
+use common::callout_limits;
+use common::config_validation;
+use common::configuration::{
+    AccessLog, Audit, BodyEncoding, Compression, Cors, EmbeddingProviver, EndpointDetails,
+    Experiment, JsonMode, JwtAuth, Mirroring, ModelAliases, Prioritization, PromptGuards,
+    PromptTarget, ProviderBackoff, ProviderOverrides, RequestCoalescing, SessionAffinity,
+    Summarization, VirtualKeys,
+};
+use common::consts::AUTHORIZATION_HEADER;
+use common::consts::CHAT_COMPLETIONS_PATH;
+use common::consts::CURVE_COALESCED_HEADER;
+use common::consts::JWKS_FETCH_TIMEOUT_SECONDS;
+use common::consts::NOTIFICATION_TIMEOUT_SECONDS;
 use common::consts::OTEL_COLLECTOR_HTTP;
 use common::consts::OTEL_POST_PATH;
+use common::consts::REQUEST_ID_HEADER;
+use common::fault_injection;
 use common::http::CallArgs;
+use common::http::CalloutMetrics;
 use common::http::Client;
 use common::llm_providers::LlmProviders;
-use common::ratelimit;
-use common::stats::Gauge;
+use common::logging;
+use common::metric_names::{metric_name, Dimension};
+use common::notifications::{self, Batch};
+use common::priority::{self, Admission};
+use common::stats::{Counter, IncrementingMetric};
 use common::tracing::TraceData;
+use common::{coalescing, cost, drain, guards, jwt, ratelimit, routing, virtual_keys};
+use http::{Method, StatusCode};
 use log::debug;
+use log::error;
 use log::warn;
+use proxy_wasm::hostcalls;
 use proxy_wasm::traits::*;
 use proxy_wasm::types::*;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::collections::VecDeque;
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, UNIX_EPOCH};
 
 use std::sync::{Arc, Mutex};
 
-#[derive(Debug)]
-pub struct CallContext {}
+#[derive(Debug, Default)]
+pub struct CallContext {
+    // audit record of a mirrored request, completed with the response of the mirror provider
+    mirror_record: Option<AuditRecord>,
+    // response carries the JWKS of jwt_auth
+    jwks: bool,
+    // notifications posted to the webhook, posted again when the webhook doesn't accept them
+    notification_batch: Option<Batch>,
+}
 
 #[derive(Debug)]
 pub struct FilterContext {
@@ -31,6 +61,33 @@ pub struct FilterContext {
     callouts: RefCell<HashMap<u32, CallContext>>,
     llm_providers: Option<Rc<LlmProviders>>,
     traces_queue: Arc<Mutex<VecDeque<TraceData>>>,
+    access_log: Rc<Option<AccessLog>>,
+    access_log_queue: Arc<Mutex<VecDeque<String>>>,
+    audit: Rc<Option<Audit>>,
+    audit_queue: Arc<Mutex<VecDeque<String>>>,
+    experiments: Rc<Vec<Experiment>>,
+    // request counter per experiment arm keyed by <experiment>.<llm_provider>
+    experiment_metrics: Rc<HashMap<String, Counter>>,
+    embedding_provider: Rc<Option<EmbeddingProviver>>,
+    model_aliases: Rc<Option<ModelAliases>>,
+    mirroring: Rc<Option<Mirroring>>,
+    mirror_queue: Arc<Mutex<VecDeque<AuditRecord>>>,
+    provider_overrides: Rc<Option<ProviderOverrides>>,
+    session_affinity: Rc<Option<SessionAffinity>>,
+    request_coalescing: Rc<Option<RequestCoalescing>>,
+    jwt_auth: Rc<Option<JwtAuth>>,
+    virtual_keys: Rc<Option<VirtualKeys>>,
+    summarization: Rc<Option<Summarization>>,
+    provider_backoff: Rc<Option<ProviderBackoff>>,
+    compression: Rc<Option<Compression>>,
+    prompt_guards: Rc<Option<PromptGuards>>,
+    json_mode: Rc<Option<JsonMode>>,
+    prompt_targets: Rc<HashMap<String, PromptTarget>>,
+    prioritization: Rc<Option<Prioritization>>,
+    cors: Rc<Option<Cors>>,
+    body_encoding: Rc<Option<BodyEncoding>>,
+    // the vm is shutting down, it is done once the callouts flushing the queues returned
+    draining: bool,
 }
 
 impl FilterContext {
@@ -40,6 +97,345 @@ impl FilterContext {
             metrics: Rc::new(Metrics::new()),
             llm_providers: None,
             traces_queue: Arc::new(Mutex::new(VecDeque::new())),
+            access_log: Rc::new(None),
+            access_log_queue: Arc::new(Mutex::new(VecDeque::new())),
+            audit: Rc::new(None),
+            audit_queue: Arc::new(Mutex::new(VecDeque::new())),
+            experiments: Rc::new(Vec::new()),
+            experiment_metrics: Rc::new(HashMap::new()),
+            embedding_provider: Rc::new(None),
+            model_aliases: Rc::new(None),
+            mirroring: Rc::new(None),
+            mirror_queue: Arc::new(Mutex::new(VecDeque::new())),
+            provider_overrides: Rc::new(None),
+            session_affinity: Rc::new(None),
+            request_coalescing: Rc::new(None),
+            jwt_auth: Rc::new(None),
+            virtual_keys: Rc::new(None),
+            summarization: Rc::new(None),
+            provider_backoff: Rc::new(None),
+            compression: Rc::new(None),
+            prompt_guards: Rc::new(None),
+            json_mode: Rc::new(None),
+            prompt_targets: Rc::new(HashMap::new()),
+            prioritization: Rc::new(None),
+            cors: Rc::new(None),
+            body_encoding: Rc::new(None),
+            draining: false,
+        }
+    }
+}
+
+impl FilterContext {
+    // post every queued entry to the endpoint, routed through the internal listener
+    fn flush_to_endpoint(&self, endpoint: &EndpointDetails, queue: &Mutex<VecDeque<String>>) {
+        let path = endpoint.path.clone().unwrap_or(String::from("/"));
+        let auth_header = endpoint.auth.as_ref().and_then(|auth| auth.static_header());
+
+        let _ = queue.try_lock().map(|mut queue| {
+            while let Some(entry) = queue.pop_front() {
+                let mut call_args = CallArgs::internal(&endpoint.name, Method::POST, &path)
+                    .with_json_body(entry.as_bytes());
+                if let Some((key, value)) = auth_header.as_ref() {
+                    call_args = call_args.with_header(key, value);
+                }
+                if let Err(error) = self.http_call(call_args, CallContext::default()) {
+                    warn!(
+                        "failed to schedule http call to {}: {:?}",
+                        endpoint.name, error
+                    );
+                }
+            }
+        });
+    }
+
+    // post the queued notifications in batches to the notifications webhook
+    fn send_notifications(&self) {
+        let (webhook, batches) = {
+            let mut notifier = notifications::notifier().write().unwrap();
+            match notifier.webhook().cloned() {
+                Some(webhook) => (webhook, notifier.batches()),
+                None => return,
+            }
+        };
+        let path = webhook.path.clone().unwrap_or(String::from("/"));
+        let auth_header = webhook.auth.as_ref().and_then(|auth| auth.static_header());
+
+        for batch in batches {
+            let mut call_args = CallArgs::internal(&webhook.name, Method::POST, &path)
+                .with_json_body(batch.body.as_bytes())
+                .with_timeout(Duration::from_secs(NOTIFICATION_TIMEOUT_SECONDS));
+            if let Some((key, value)) = auth_header.as_ref() {
+                call_args = call_args.with_header(key, value);
+            }
+            let call_context = CallContext {
+                notification_batch: Some(batch.clone()),
+                ..Default::default()
+            };
+            if let Err(error) = self.http_call(call_args, call_context) {
+                warn!(
+                    "failed to schedule notifications to {}: {:?}",
+                    webhook.name, error
+                );
+                notifications::notifier().write().unwrap().retry(batch);
+            }
+        }
+    }
+}
+
+impl FilterContext {
+    // requests that waited for an identical request in flight are answered with its response, or
+    // sent to the llm provider when it failed
+    fn resume_coalesced_requests(&self) {
+        let timeout = match Option::as_ref(&self.request_coalescing) {
+            Some(request_coalescing) => request_coalescing.timeout(),
+            None => return,
+        };
+        let ready = coalescing::in_flight_requests()
+            .write()
+            .unwrap()
+            .take_ready(self.get_current_time(), timeout);
+
+        for (context_id, response) in ready {
+            if let Err(e) = hostcalls::set_effective_context(context_id) {
+                warn!("could not resume coalesced request {}: {:?}", context_id, e);
+                continue;
+            }
+            let result = match response {
+                Some(body) => hostcalls::send_http_response(
+                    StatusCode::OK.as_u16() as u32,
+                    vec![
+                        ("content-type", "application/json"),
+                        (CURVE_COALESCED_HEADER, "true"),
+                    ],
+                    Some(&body),
+                ),
+                None => hostcalls::resume_http_request(),
+            };
+            if let Err(e) = result {
+                warn!("could not resume coalesced request {}: {:?}", context_id, e);
+            }
+        }
+    }
+
+    // queued requests are admitted by priority once the provider they go to is done backing off
+    // and the ratelimit they hit lets them through, the ones waiting too long are rejected
+    fn resume_queued_requests(&self) {
+        let prioritization = match Option::as_ref(&self.prioritization) {
+            Some(prioritization) => prioritization,
+            None => return,
+        };
+        let now = self.get_current_time();
+        let unix_seconds = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let ready = priority::request_queue().write().unwrap().take_ready(
+            now,
+            prioritization.queue_timeout(),
+            |request| {
+                if routing::is_cooling_down(&request.llm_provider, unix_seconds) {
+                    return Admission::Wait;
+                }
+                let selector = match request.selector.as_ref() {
+                    Some(selector) => selector.clone(),
+                    None => return Admission::Admit(None),
+                };
+                match ratelimit::ratelimits(None).read().unwrap().check_limit(
+                    request.model.clone(),
+                    selector,
+                    request.tokens,
+                    now,
+                ) {
+                    Ok(in_flight) => Admission::Admit(in_flight),
+                    Err(_) => Admission::Wait,
+                }
+            },
+        );
+
+        for (request, admitted) in ready {
+            if let Err(e) = hostcalls::set_effective_context(request.context_id) {
+                warn!(
+                    "could not resume queued request {}: {:?}",
+                    request.context_id, e
+                );
+                continue;
+            }
+            let result = if admitted {
+                hostcalls::resume_http_request()
+            } else {
+                self.metrics
+                    .queue_timeouts
+                    .with(&[(Dimension::Priority, request.priority.as_str())])
+                    .increment(1);
+                let body = serde_json::json!({
+                    "error": {
+                        "type": "queue_timeout",
+                        "message": format!(
+                            "{} priority request was queued for longer than {} seconds",
+                            request.priority.as_str(),
+                            prioritization.queue_timeout().as_secs()
+                        ),
+                    }
+                })
+                .to_string();
+                hostcalls::send_http_response(
+                    StatusCode::TOO_MANY_REQUESTS.as_u16() as u32,
+                    vec![("content-type", "application/json")],
+                    Some(body.as_bytes()),
+                )
+            };
+            if let Err(e) = result {
+                warn!(
+                    "could not resume queued request {}: {:?}",
+                    request.context_id, e
+                );
+            }
+        }
+    }
+
+    // traces, access log entries, audit records and notifications are posted on every tick and
+    // once more when the vm shuts down
+    fn flush_queues(&self) {
+        let _ = self.traces_queue.try_lock().map(|mut traces_queue| {
+            while let Some(trace) = traces_queue.pop_front() {
+                debug!("trace received: {:?}", trace);
+
+                let trace_str = match serde_json::to_string(&trace) {
+                    Ok(trace_str) => trace_str,
+                    Err(error) => {
+                        warn!("failed to serialize trace: {}", error);
+                        continue;
+                    }
+                };
+                debug!("trace: {}", trace_str);
+                let call_args = CallArgs::new(OTEL_COLLECTOR_HTTP, Method::POST, OTEL_POST_PATH)
+                    .with_json_body(trace_str.as_bytes());
+                if let Err(error) = self.http_call(call_args, CallContext::default()) {
+                    warn!(
+                        "failed to schedule http call to otel-collector: {:?}",
+                        error
+                    );
+                }
+            }
+        });
+
+        if let Some(sink) = self
+            .access_log
+            .as_ref()
+            .as_ref()
+            .and_then(|access_log| access_log.sink.as_ref())
+        {
+            self.flush_to_endpoint(sink, &self.access_log_queue);
+        }
+
+        if let Some(audit) = self.audit.as_ref() {
+            self.flush_to_endpoint(&audit.audit_sink, &self.audit_queue);
+        }
+
+        self.send_notifications();
+    }
+
+    // mirrored requests go to the mirror provider through the internal listener, the responses are
+    // written to the audit sink
+    fn send_mirror_requests(&self) {
+        let llm_providers = match self.llm_providers.as_ref() {
+            Some(llm_providers) => llm_providers,
+            None => return,
+        };
+
+        let _ = self.mirror_queue.try_lock().map(|mut mirror_queue| {
+            while let Some(mirror_record) = mirror_queue.pop_front() {
+                let llm_provider = match llm_providers.get(&mirror_record.provider) {
+                    Some(llm_provider) => llm_provider,
+                    None => continue,
+                };
+                let upstream_host = llm_provider.cluster_name();
+                let authorization_header = llm_provider
+                    .access_key
+                    .as_ref()
+                    .map(|access_key| format!("Bearer {}", access_key));
+                let request_id = mirror_record.request_id.clone().unwrap_or_default();
+                let body = mirror_record.request.clone();
+
+                let mut call_args =
+                    CallArgs::internal(&upstream_host, Method::POST, CHAT_COMPLETIONS_PATH)
+                        .with_json_body(body.as_bytes())
+                        .with_header(REQUEST_ID_HEADER, &request_id);
+                if let Some(authorization_header) = authorization_header.as_ref() {
+                    call_args = call_args.with_header(AUTHORIZATION_HEADER, authorization_header);
+                }
+                let call_context = CallContext {
+                    mirror_record: Some(mirror_record),
+                    ..Default::default()
+                };
+                if let Err(error) = self.http_call(call_args, call_context) {
+                    warn!(
+                        "failed to schedule mirror request to {}: {:?}",
+                        llm_provider.name, error
+                    );
+                }
+            }
+        });
+    }
+
+    // the signing keys of jwt_auth are fetched through the internal listener once they are stale
+    fn fetch_jwks(&self) {
+        let jwks = match Option::as_ref(&self.jwt_auth) {
+            Some(jwt_auth) => &jwt_auth.jwks,
+            None => return,
+        };
+        let now = self.get_current_time();
+        let jwks_cache = jwt::jwks_cache();
+        if !jwks_cache.read().unwrap().should_fetch(now, jwks.ttl()) {
+            return;
+        }
+
+        let call_args = CallArgs::internal(&jwks.endpoint, Method::GET, jwks.path())
+            .with_timeout(Duration::from_secs(JWKS_FETCH_TIMEOUT_SECONDS));
+        let call_context = CallContext {
+            jwks: true,
+            ..Default::default()
+        };
+        match self.http_call(call_args, call_context) {
+            Ok(_) => jwks_cache.write().unwrap().fetching(now),
+            Err(error) => warn!(
+                "failed to schedule jwks request to {}: {:?}",
+                jwks.endpoint, error
+            ),
+        }
+    }
+
+    fn store_jwks(&self, body_size: usize) {
+        let status = self.get_http_call_response_header(":status");
+        if status.as_deref() != Some("200") {
+            warn!("jwks request failed with status {:?}", status);
+            return;
+        }
+        let body = self
+            .get_http_call_response_body(0, body_size)
+            .unwrap_or_default();
+        let now = self.get_current_time();
+        if let Err(e) = jwt::jwks_cache().write().unwrap().update(&body, now) {
+            warn!("could not parse jwks: {}", e);
+        }
+    }
+
+    fn write_mirror_record(&self, mut mirror_record: AuditRecord, body_size: usize) {
+        let body = self
+            .get_http_call_response_body(0, body_size)
+            .unwrap_or_default();
+        mirror_record.response = self.call_response_text(&body);
+
+        if let Some(redact) = self
+            .audit
+            .as_ref()
+            .as_ref()
+            .and_then(|audit| audit.redact.as_ref())
+        {
+            mirror_record.redact(redact);
+        }
+
+        match serde_json::to_string(&mirror_record) {
+            Ok(record_str) => self.audit_queue.lock().unwrap().push_back(record_str),
+            Err(e) => warn!("could not serialize mirror audit record: {}", e),
         }
     }
 }
@@ -51,29 +447,108 @@ impl Client for FilterContext {
         &self.callouts
     }
 
-    fn active_http_calls(&self) -> &Gauge {
-        &self.metrics.active_http_calls
+    fn callout_metrics(&self) -> &CalloutMetrics {
+        &self.metrics.callouts
     }
 }
 
 // RootContext allows the Rust code to reach into the Envoy Config
 impl RootContext for FilterContext {
     fn on_configure(&mut self, _: usize) -> bool {
-        let config_bytes = self
-            .get_plugin_configuration()
-            .expect("Curve config cannot be empty");
+        let config_bytes = match self.get_plugin_configuration() {
+            Some(config_bytes) => config_bytes,
+            None => {
+                error!("invalid config: config cannot be empty");
+                return false;
+            }
+        };
 
-        let config: Configuration = match serde_yaml::from_slice(&config_bytes) {
+        let config = match config_validation::parse(&config_bytes) {
             Ok(config) => config,
-            Err(err) => panic!("Invalid curve  config \"{:?}\"", err),
+            Err(errors) => {
+                for error in errors {
+                    error!("invalid config: {}", error);
+                }
+                return false;
+            }
         };
 
-        ratelimit::ratelimits(Some(config.ratelimits.unwrap_or_default()));
+        logging::configure(config.logging.as_ref());
+        callout_limits::configure(config.callout_limits.as_deref());
+        fault_injection::configure(config.fault_injection.as_deref());
+        guards::configure(config.prompt_guards.as_ref());
+        notifications::configure(config.notifications.as_ref());
 
-        match config.llm_providers.try_into() {
-            Ok(llm_providers) => self.llm_providers = Some(Rc::new(llm_providers)),
-            Err(err) => panic!("{err}"),
+        let mut ratelimits = config.ratelimits.unwrap_or_default();
+        let mut costs = config.costs.unwrap_or_default();
+        if let Some(virtual_keys) = config.virtual_keys.as_ref() {
+            let provider_models: Vec<String> = config
+                .llm_providers
+                .iter()
+                .map(|llm_provider| llm_provider.model.clone())
+                .collect();
+            ratelimits.extend(virtual_keys::ratelimits(virtual_keys, &provider_models));
+            costs
+                .budgets
+                .get_or_insert_with(Vec::new)
+                .extend(virtual_keys::budgets(virtual_keys));
         }
+        ratelimit::ratelimits(Some(ratelimits));
+        cost::costs(Some(costs));
+
+        let llm_providers: LlmProviders = match config.llm_providers.try_into() {
+            Ok(llm_providers) => llm_providers,
+            Err(err) => {
+                error!("invalid config: {}", err);
+                return false;
+            }
+        };
+
+        let experiments = config.experiments.unwrap_or_default();
+        let mut experiment_metrics = HashMap::new();
+        for experiment in experiments.iter() {
+            for arm in experiment.arms.iter() {
+                let experiment_arm = format!("{}.{}", experiment.name, arm.llm_provider);
+                let name = metric_name(
+                    "experiment_rq",
+                    &[
+                        (Dimension::Experiment, &experiment.name),
+                        (Dimension::Provider, &arm.llm_provider),
+                    ],
+                );
+                experiment_metrics.insert(experiment_arm, Counter::new(name));
+            }
+        }
+        self.experiments = Rc::new(experiments);
+        self.model_aliases = Rc::new(config.model_aliases);
+        self.provider_overrides = Rc::new(config.provider_overrides);
+        self.session_affinity = Rc::new(config.session_affinity);
+        self.request_coalescing = Rc::new(config.request_coalescing);
+        self.jwt_auth = Rc::new(config.jwt_auth);
+        self.virtual_keys = Rc::new(config.virtual_keys);
+        self.summarization = Rc::new(config.summarization);
+        self.provider_backoff = Rc::new(config.provider_backoff);
+        self.compression = Rc::new(config.compression);
+        self.prompt_guards = Rc::new(config.prompt_guards);
+        self.json_mode = Rc::new(config.json_mode);
+        self.prioritization = Rc::new(config.prioritization);
+        self.cors = Rc::new(config.cors);
+        self.body_encoding = Rc::new(config.body_encoding);
+        self.prompt_targets = Rc::new(
+            config
+                .prompt_targets
+                .into_iter()
+                .flatten()
+                .map(|prompt_target| (prompt_target.name.clone(), prompt_target))
+                .collect(),
+        );
+        self.embedding_provider = Rc::new(config.embedding_provider);
+        self.experiment_metrics = Rc::new(experiment_metrics);
+        self.llm_providers = Some(Rc::new(llm_providers));
+
+        self.access_log = Rc::new(config.access_log);
+        self.audit = Rc::new(config.audit);
+        self.mirroring = Rc::new(config.mirroring);
 
         true
     }
@@ -93,6 +568,30 @@ impl RootContext for FilterContext {
                     .expect("LLM Providers must exist when Streams are being created"),
             ),
             Arc::clone(&self.traces_queue),
+            Rc::clone(&self.access_log),
+            Arc::clone(&self.access_log_queue),
+            Rc::clone(&self.audit),
+            Arc::clone(&self.audit_queue),
+            Rc::clone(&self.experiments),
+            Rc::clone(&self.experiment_metrics),
+            Rc::clone(&self.embedding_provider),
+            Rc::clone(&self.model_aliases),
+            Rc::clone(&self.mirroring),
+            Arc::clone(&self.mirror_queue),
+            Rc::clone(&self.provider_overrides),
+            Rc::clone(&self.session_affinity),
+            Rc::clone(&self.request_coalescing),
+            Rc::clone(&self.jwt_auth),
+            Rc::clone(&self.virtual_keys),
+            Rc::clone(&self.summarization),
+            Rc::clone(&self.provider_backoff),
+            Rc::clone(&self.compression),
+            Rc::clone(&self.prompt_guards),
+            Rc::clone(&self.json_mode),
+            Rc::clone(&self.prompt_targets),
+            Rc::clone(&self.prioritization),
+            Rc::clone(&self.cors),
+            Rc::clone(&self.body_encoding),
         )))
     }
 
@@ -106,33 +605,48 @@ impl RootContext for FilterContext {
     }
 
     fn on_tick(&mut self) {
-        let _ = self.traces_queue.try_lock().map(|mut traces_queue| {
-            while let Some(trace) = traces_queue.pop_front() {
-                debug!("trace received: {:?}", trace);
+        self.fetch_jwks();
+        self.flush_queues();
+        self.send_mirror_requests();
 
-                let trace_str = serde_json::to_string(&trace).unwrap();
-                debug!("trace: {}", trace_str);
-                let call_args = CallArgs::new(
-                    OTEL_COLLECTOR_HTTP,
-                    OTEL_POST_PATH,
-                    vec![
-                        (":method", http::Method::POST.as_str()),
-                        (":path", OTEL_POST_PATH),
-                        (":authority", OTEL_COLLECTOR_HTTP),
-                        ("content-type", "application/json"),
-                    ],
-                    Some(trace_str.as_bytes()),
-                    vec![],
-                    Duration::from_secs(60),
-                );
-                if let Err(error) = self.http_call(call_args, CallContext {}) {
-                    warn!(
-                        "failed to schedule http call to otel-collector: {:?}",
-                        error
-                    );
-                }
-            }
-        });
+        // last as they switch the effective context to the resumed requests
+        self.resume_queued_requests();
+        self.resume_coalesced_requests();
+    }
+
+    // Streams still waiting, on callouts, identical requests or the request queue, are answered
+    // with a 503 and the queues are flushed. The vm is done once the flushing callouts returned.
+    fn on_done(&mut self) -> bool {
+        let now = self.get_current_time();
+        let mut context_ids = drain::paused_streams().write().unwrap().take();
+        context_ids.extend(
+            coalescing::in_flight_requests()
+                .write()
+                .unwrap()
+                .take_ready(now, Duration::ZERO)
+                .into_iter()
+                .map(|(context_id, _)| context_id),
+        );
+        context_ids.extend(
+            priority::request_queue()
+                .write()
+                .unwrap()
+                .take_ready(now, Duration::ZERO, |_| Admission::Wait)
+                .into_iter()
+                .map(|(request, _)| request.context_id),
+        );
+        context_ids.sort();
+        context_ids.dedup();
+
+        let aborted = drain::abort_streams(&context_ids);
+        if aborted > 0 {
+            warn!("vm shutting down, aborted {} requests", aborted);
+            self.metrics.aborted_rq.increment(aborted as i64);
+        }
+
+        self.flush_queues();
+        self.draining = true;
+        self.callouts.borrow().is_empty()
     }
 }
 
@@ -141,7 +655,7 @@ impl Context for FilterContext {
         &mut self,
         token_id: u32,
         _num_headers: usize,
-        _body_size: usize,
+        body_size: usize,
         _num_trailers: usize,
     ) {
         debug!(
@@ -149,14 +663,39 @@ impl Context for FilterContext {
             token_id
         );
 
-        let _callout_data = self
-            .callouts
-            .borrow_mut()
-            .remove(&token_id)
-            .expect("invalid token_id");
+        let call_context = match self.remove_call_context(token_id) {
+            Some(call_context) => call_context,
+            None => {
+                warn!("http call response for unknown token_id: {}", token_id);
+                return;
+            }
+        };
 
-        if let Some(status) = self.get_http_call_response_header(":status") {
+        let status = self.get_http_call_response_header(":status");
+        if let Some(status) = status.as_ref() {
             debug!("trace response status: {:?}", status);
         };
+
+        if let Some(mirror_record) = call_context.mirror_record {
+            self.write_mirror_record(mirror_record, body_size);
+        }
+
+        if call_context.jwks {
+            self.store_jwks(body_size);
+        }
+
+        if let Some(batch) = call_context.notification_batch {
+            if !status
+                .as_ref()
+                .is_some_and(|status| status.starts_with('2'))
+            {
+                warn!("notification webhook responded with status {:?}", status);
+                notifications::notifier().write().unwrap().retry(batch);
+            }
+        }
+
+        if self.draining && self.callouts.borrow().is_empty() {
+            self.done();
+        }
     }
 }
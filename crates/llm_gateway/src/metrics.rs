@@ -1,28 +1,75 @@
-use common::stats::{Counter, Gauge, Histogram};
+use common::http::CalloutMetrics;
+use common::stats::{Counter, CounterFamily, HistogramFamily};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Debug)]
 pub struct Metrics {
-    pub active_http_calls: Gauge,
-    pub ratelimited_rq: Counter,
-    pub time_to_first_token: Histogram,
-    pub time_per_output_token: Histogram,
-    pub tokens_per_second: Histogram,
-    pub request_latency: Histogram,
-    pub output_sequence_length: Histogram,
-    pub input_sequence_length: Histogram,
+    pub callouts: CalloutMetrics,
+    pub ratelimited_rq: CounterFamily,
+    pub time_to_first_token: HistogramFamily,
+    pub time_per_output_token: HistogramFamily,
+    pub tokens_per_second: HistogramFamily,
+    pub request_latency: HistogramFamily,
+    pub output_sequence_length: HistogramFamily,
+    pub input_sequence_length: HistogramFamily,
+    pub embeddings_rq: Counter,
+    pub mirrored_rq: Counter,
+    pub session_failovers: Counter,
+    pub coalesced_rq: Counter,
+    pub jwt_rejections: Counter,
+    pub virtual_key_rejections: Counter,
+    pub summarized_rq: Counter,
+    pub summarization_failures: Counter,
+    pub context_window_rejections: Counter,
+    pub context_window_truncations: Counter,
+    pub provider_cooldowns: CounterFamily,
+    pub compressed_rq: Counter,
+    pub compression_saved_tokens: Counter,
+    pub guard_violations: Counter,
+    pub guard_failures: Counter,
+    pub json_repairs: Counter,
+    pub invalid_json_responses: Counter,
+    pub json_validation_failures: Counter,
+    pub llm_responses: CounterFamily,
+    pub queued_rq: CounterFamily,
+    pub shed_rq: CounterFamily,
+    pub queue_timeouts: CounterFamily,
+    pub aborted_rq: Counter,
 }
 
 impl Metrics {
     pub fn new() -> Metrics {
         Metrics {
-            active_http_calls: Gauge::new(String::from("active_http_calls")),
-            ratelimited_rq: Counter::new(String::from("ratelimited_rq")),
-            time_to_first_token: Histogram::new(String::from("time_to_first_token")),
-            time_per_output_token: Histogram::new(String::from("time_per_output_token")),
-            tokens_per_second: Histogram::new(String::from("tokens_per_second")),
-            request_latency: Histogram::new(String::from("request_latency")),
-            output_sequence_length: Histogram::new(String::from("output_sequence_length")),
-            input_sequence_length: Histogram::new(String::from("input_sequence_length")),
+            callouts: CalloutMetrics::new(),
+            ratelimited_rq: CounterFamily::new("ratelimited_rq"),
+            time_to_first_token: HistogramFamily::new("time_to_first_token"),
+            time_per_output_token: HistogramFamily::new("time_per_output_token"),
+            tokens_per_second: HistogramFamily::new("tokens_per_second"),
+            request_latency: HistogramFamily::new("request_latency"),
+            output_sequence_length: HistogramFamily::new("output_sequence_length"),
+            input_sequence_length: HistogramFamily::new("input_sequence_length"),
+            embeddings_rq: Counter::new(String::from("embeddings_rq")),
+            mirrored_rq: Counter::new(String::from("mirrored_rq")),
+            session_failovers: Counter::new(String::from("session_failovers")),
+            coalesced_rq: Counter::new(String::from("coalesced_rq")),
+            jwt_rejections: Counter::new(String::from("jwt_rejections")),
+            virtual_key_rejections: Counter::new(String::from("virtual_key_rejections")),
+            summarized_rq: Counter::new(String::from("summarized_rq")),
+            summarization_failures: Counter::new(String::from("summarization_failures")),
+            context_window_rejections: Counter::new(String::from("context_window_rejections")),
+            context_window_truncations: Counter::new(String::from("context_window_truncations")),
+            provider_cooldowns: CounterFamily::new("provider_cooldowns"),
+            compressed_rq: Counter::new(String::from("compressed_rq")),
+            compression_saved_tokens: Counter::new(String::from("compression_saved_tokens")),
+            guard_violations: Counter::new(String::from("guard_violations")),
+            guard_failures: Counter::new(String::from("guard_failures")),
+            json_repairs: Counter::new(String::from("json_repairs")),
+            invalid_json_responses: Counter::new(String::from("invalid_json_responses")),
+            json_validation_failures: Counter::new(String::from("json_validation_failures")),
+            llm_responses: CounterFamily::new("llm_responses"),
+            queued_rq: CounterFamily::new("queued_rq"),
+            shed_rq: CounterFamily::new("shed_rq"),
+            queue_timeouts: CounterFamily::new("queue_timeouts"),
+            aborted_rq: Counter::new(String::from("aborted_rq")),
         }
     }
 }
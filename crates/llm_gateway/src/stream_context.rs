@@ -1,74 +1,253 @@
 use crate::metrics::Metrics;
+use common::access_log::AccessLogEntry;
+use common::api::completions::{CompletionsRequest, CompletionsResponse};
+use common::api::moderation::{ModerationRequest, ModerationResponse, ModerationResult};
 use common::api::open_ai::{
     ChatCompletionStreamResponseServerEvents, ChatCompletionsRequest, ChatCompletionsResponse,
-    Message, StreamOptions,
+    Choice, CurveMetadataEvent, EmbeddingsRequest, Message, StreamOptions,
+};
+use common::api::responses::{ResponsesRequest, ResponsesResponse};
+use common::audit::{self, AuditRecord};
+use common::callout::{self, Callout};
+use common::configuration::{
+    AccessLog, Audit, BodyEncoding, Compression, ContextOverflow, Cors, EmbeddingProviver,
+    Experiment, GuardAction, GuardOptions, GuardType, JsonMode, JwtAuth, LlmProvider, Mirroring,
+    ModelAliases, NotificationEvent, OnInvalidJson, Prioritization, Priority, PromptGuards,
+    PromptTarget, ProviderBackoff, ProviderOverrides, RequestCoalescing, SessionAffinity,
+    Summarization, UnknownModel, VirtualKey, VirtualKeys,
 };
-use common::configuration::LlmProvider;
 use common::consts::{
-    CURVE_PROVIDER_HINT_HEADER, CURVE_ROUTING_HEADER, CHAT_COMPLETIONS_PATH,
-    RATELIMIT_SELECTOR_HEADER_KEY, REQUEST_ID_HEADER, TRACE_PARENT_HEADER,
+    AUTHORIZATION_HEADER, CHAT_COMPLETIONS_PATH, COMPLETIONS_PATH, CURVE_EXPERIMENT_HEADER,
+    CURVE_INCLUDE_METADATA_HEADER, CURVE_METADATA_OBJECT, CURVE_MODEL_OVERRIDE_HEADER,
+    CURVE_PROMPT_TARGET_HEADER, CURVE_PROVIDER_HINT_HEADER, CURVE_PROVIDER_OVERRIDE_HEADER,
+    CURVE_REQUEST_ID_HEADER, CURVE_ROUTING_HEADER, EMBEDDINGS_PATH, JSON_REPAIR_TIMEOUT_SECONDS,
+    MODERATIONS_PATH, MODERATION_TIMEOUT_SECONDS, RATELIMIT_SELECTOR_HEADER_KEY, REQUEST_ID_HEADER,
+    RESPONSES_PATH, RETRY_AFTER_HEADER, SUMMARIZATION_TIMEOUT_SECONDS, TRACE_PARENT_HEADER,
+};
+use common::content_encoding::{
+    self, ContentEncoding, ACCEPT_ENCODING_HEADER, CONTENT_ENCODING_HEADER,
 };
-use common::errors::ServerError;
-use common::llm_providers::LlmProviders;
+use common::errors::{ClientError, ServerError};
+use common::http::{CallArgs, CalloutMetrics, Client, WaitingCall};
+use common::json_mode::{self, ResponseFormat};
+use common::llm_providers::{self, LlmProviders};
+use common::metric_names::Dimension;
 use common::pii::obfuscate_auth_header;
-use common::ratelimit::Header;
-use common::stats::{IncrementingMetric, RecordingMetric};
-use common::tracing::{Event, Span, TraceData, Traceparent};
-use common::{ratelimit, routing, tokenizer};
-use http::StatusCode;
-use log::{debug, trace, warn};
+use common::priority::QueuedRequest;
+use common::ratelimit::{Header, InFlight};
+use common::routing::ProviderHint;
+use common::stats::{Counter, IncrementingMetric, RecordingMetric};
+use common::tracing::{self, Event, Span, TraceData, Traceparent};
+use common::{
+    charset, coalescing, compression, context_window, cors, cost, drain, guards, jwt,
+    notifications, priority, ratelimit, routing, summarization, tokenizer, virtual_keys,
+};
+use http::{Method, StatusCode};
+use log::{debug, info, trace, warn};
 use proxy_wasm::hostcalls::get_current_time;
 use proxy_wasm::traits::*;
 use proxy_wasm::types::*;
-use std::collections::VecDeque;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
 use std::num::NonZero;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+// request shapes accepted from clients, anything other than chat completions is translated to chat
+// completions for the upstream llm and the response is translated back
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RequestApi {
+    ChatCompletions,
+    Completions,
+    Responses,
+}
+
 pub struct StreamContext {
     context_id: u32,
     metrics: Rc<Metrics>,
     ratelimit_selector: Option<Header>,
+    // counted by a concurrency limit until the request is done
+    ratelimit_in_flight: Option<InFlight>,
+    // counted as an open stream of the ratelimit selector until the response is done
+    stream_in_flight: Option<InFlight>,
     streaming_response: bool,
     response_tokens: usize,
     is_chat_completions_request: bool,
+    request_api: RequestApi,
     llm_providers: Rc<LlmProviders>,
     llm_provider: Option<Rc<LlmProvider>>,
-    request_id: Option<String>,
+    request_id: String,
     start_time: SystemTime,
     ttft_duration: Option<Duration>,
     ttft_time: Option<u128>,
+    // tokens of the chunk the first token came in, left out of the streaming rate
+    ttft_tokens: usize,
     traceparent: Option<String>,
     request_body_sent_time: Option<u128>,
     user_message: Option<Message>,
     traces_queue: Arc<Mutex<VecDeque<TraceData>>>,
+    access_log: Rc<Option<AccessLog>>,
+    access_log_queue: Arc<Mutex<VecDeque<String>>>,
+    prompt_target: Option<String>,
+    input_tokens: usize,
+    audit: Rc<Option<Audit>>,
+    audit_queue: Arc<Mutex<VecDeque<String>>>,
+    audit_record: Option<AuditRecord>,
+    experiments: Rc<Vec<Experiment>>,
+    experiment_metrics: Rc<HashMap<String, Counter>>,
+    experiment_arm: Option<String>,
+    cost: Option<f64>,
+    // the cost and the access log entry of the response are written once, at the end of the stream
+    response_finished: bool,
+    embedding_provider: Rc<Option<EmbeddingProviver>>,
+    is_embeddings_request: bool,
+    model_aliases: Rc<Option<ModelAliases>>,
+    routing_deferred: bool,
+    include_metadata: bool,
+    mirroring: Rc<Option<Mirroring>>,
+    mirror_queue: Arc<Mutex<VecDeque<AuditRecord>>>,
+    provider_overrides: Rc<Option<ProviderOverrides>>,
+    provider_override: Option<String>,
+    model_override: Option<String>,
+    session_affinity: Rc<Option<SessionAffinity>>,
+    session: Option<String>,
+    request_coalescing: Rc<Option<RequestCoalescing>>,
+    // set on the first of identical requests, the ones waiting for it are answered with its response
+    coalescing_key: Option<u64>,
+    jwt_auth: Rc<Option<JwtAuth>>,
+    virtual_keys: Rc<Option<VirtualKeys>>,
+    // the virtual key the client authenticated with
+    virtual_key: Option<VirtualKey>,
+    summarization: Rc<Option<Summarization>>,
+    callouts: RefCell<HashMap<u32, Callout<StreamContext>>>,
+    waiting_calls: RefCell<HashMap<u32, WaitingCall<Callout<StreamContext>>>>,
+    provider_backoff: Rc<Option<ProviderBackoff>>,
+    // retry-after of a provider 429, its body is replaced by a structured error
+    retry_after: Option<u64>,
+    compression: Rc<Option<Compression>>,
+    prompt_guards: Rc<Option<PromptGuards>>,
+    json_mode: Rc<Option<JsonMode>>,
+    // json response_format of a non streamed request, its response is validated against it
+    response_format: Option<ResponseFormat>,
+    // the request sent upstream, kept to ask the llm provider to repair an invalid response
+    json_repair_request: Option<ChatCompletionsRequest>,
+    // times the llm provider can still be asked to repair the response
+    json_repairs_left: usize,
+    prompt_targets: Rc<HashMap<String, PromptTarget>>,
+    prioritization: Rc<Option<Prioritization>>,
+    priority: Priority,
+    // the request waits in the request queue for a limit it hit
+    queued: bool,
+    cors: Rc<Option<Cors>>,
+    // origin of a browser request, the response carries the cors headers it is allowed
+    cors_origin: Option<String>,
+    body_encoding: Rc<Option<BodyEncoding>>,
+    // how the client compressed the request body
+    request_encoding: Option<ContentEncoding>,
 }
 
 impl StreamContext {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         context_id: u32,
         metrics: Rc<Metrics>,
         llm_providers: Rc<LlmProviders>,
         traces_queue: Arc<Mutex<VecDeque<TraceData>>>,
+        access_log: Rc<Option<AccessLog>>,
+        access_log_queue: Arc<Mutex<VecDeque<String>>>,
+        audit: Rc<Option<Audit>>,
+        audit_queue: Arc<Mutex<VecDeque<String>>>,
+        experiments: Rc<Vec<Experiment>>,
+        experiment_metrics: Rc<HashMap<String, Counter>>,
+        embedding_provider: Rc<Option<EmbeddingProviver>>,
+        model_aliases: Rc<Option<ModelAliases>>,
+        mirroring: Rc<Option<Mirroring>>,
+        mirror_queue: Arc<Mutex<VecDeque<AuditRecord>>>,
+        provider_overrides: Rc<Option<ProviderOverrides>>,
+        session_affinity: Rc<Option<SessionAffinity>>,
+        request_coalescing: Rc<Option<RequestCoalescing>>,
+        jwt_auth: Rc<Option<JwtAuth>>,
+        virtual_keys: Rc<Option<VirtualKeys>>,
+        summarization: Rc<Option<Summarization>>,
+        provider_backoff: Rc<Option<ProviderBackoff>>,
+        compression: Rc<Option<Compression>>,
+        prompt_guards: Rc<Option<PromptGuards>>,
+        json_mode: Rc<Option<JsonMode>>,
+        prompt_targets: Rc<HashMap<String, PromptTarget>>,
+        prioritization: Rc<Option<Prioritization>>,
+        cors: Rc<Option<Cors>>,
+        body_encoding: Rc<Option<BodyEncoding>>,
     ) -> Self {
         StreamContext {
             context_id,
             metrics,
             ratelimit_selector: None,
+            ratelimit_in_flight: None,
+            stream_in_flight: None,
             streaming_response: false,
             response_tokens: 0,
             is_chat_completions_request: false,
+            request_api: RequestApi::ChatCompletions,
             llm_providers,
             llm_provider: None,
-            request_id: None,
+            request_id: String::new(),
             start_time: SystemTime::now(),
             ttft_duration: None,
             traceparent: None,
             ttft_time: None,
+            ttft_tokens: 0,
             user_message: None,
             traces_queue,
             request_body_sent_time: None,
+            access_log,
+            access_log_queue,
+            prompt_target: None,
+            input_tokens: 0,
+            audit,
+            audit_queue,
+            audit_record: None,
+            experiments,
+            experiment_metrics,
+            experiment_arm: None,
+            cost: None,
+            response_finished: false,
+            embedding_provider,
+            is_embeddings_request: false,
+            model_aliases,
+            routing_deferred: false,
+            include_metadata: false,
+            mirroring,
+            mirror_queue,
+            provider_overrides,
+            provider_override: None,
+            model_override: None,
+            session_affinity,
+            session: None,
+            request_coalescing,
+            coalescing_key: None,
+            jwt_auth,
+            virtual_keys,
+            virtual_key: None,
+            summarization,
+            callouts: RefCell::new(HashMap::new()),
+            waiting_calls: RefCell::new(HashMap::new()),
+            provider_backoff,
+            retry_after: None,
+            compression,
+            prompt_guards,
+            json_mode,
+            response_format: None,
+            json_repair_request: None,
+            json_repairs_left: 0,
+            prompt_targets,
+            prioritization,
+            priority: Priority::default(),
+            queued: false,
+            cors,
+            cors_origin: None,
+            body_encoding,
+            request_encoding: None,
         }
     }
     fn llm_provider(&self) -> &LlmProvider {
@@ -77,17 +256,215 @@ impl StreamContext {
             .expect("the provider should be set when asked for it")
     }
 
-    fn select_llm_provider(&mut self) {
-        let provider_hint = self
-            .get_http_request_header(CURVE_PROVIDER_HINT_HEADER)
-            .map(|llm_name| llm_name.into());
+    // the model sent upstream, the provider's model unless the request or its prompt target
+    // overrides it
+    fn model(&self) -> &str {
+        self.model_override
+            .as_deref()
+            .or_else(|| self.prompt_target_model())
+            .unwrap_or(&self.llm_provider().model)
+    }
+
+    // the config of the prompt target the prompt gateway made the request for
+    fn prompt_target_config(&self) -> Option<&PromptTarget> {
+        self.prompt_targets.get(self.prompt_target.as_ref()?)
+    }
+
+    // the model of the prompt target is meant for its own llm provider, it is not used when the
+    // request was routed elsewhere e.g. by a sanctioned provider override
+    fn prompt_target_model(&self) -> Option<&str> {
+        let prompt_target = self.prompt_target_config()?;
+        match prompt_target.llm_provider.as_ref() {
+            Some(llm_provider) if *llm_provider != self.llm_provider().name => None,
+            _ => prompt_target.model.as_deref(),
+        }
+    }
+
+    // reads the x-curve-provider and x-curve-model headers, only overrides listed in the
+    // provider_overrides config are accepted
+    fn read_overrides(&mut self) -> Result<(), ServerError> {
+        let provider_overrides = Rc::clone(&self.provider_overrides);
+        let provider_overrides = Option::as_ref(&provider_overrides);
+
+        if let Some(llm_provider) = self.get_http_request_header(CURVE_PROVIDER_OVERRIDE_HEADER) {
+            let allowed = provider_overrides
+                .and_then(|overrides| overrides.llm_providers.as_ref())
+                .is_some_and(|llm_providers| llm_providers.contains(&llm_provider));
+            if !allowed {
+                return Err(ServerError::BadRequest {
+                    why: format!("llm provider override {:?} is not allowed", llm_provider),
+                });
+            }
+            self.provider_override = Some(llm_provider);
+        }
+
+        if let Some(model) = self.get_http_request_header(CURVE_MODEL_OVERRIDE_HEADER) {
+            let allowed = provider_overrides
+                .and_then(|overrides| overrides.models.as_ref())
+                .is_some_and(|models| models.contains(&model));
+            if !allowed {
+                return Err(ServerError::BadRequest {
+                    why: format!("model override {:?} is not allowed", model),
+                });
+            }
+            self.model_override = Some(model);
+        }
+        Ok(())
+    }
+
+    fn select_llm_provider(&mut self, model: Option<&str>) -> Result<(), ServerError> {
+        if let Some(embedding_provider) = self.embedding_provider.as_ref() {
+            if self.get_http_request_header(":path").unwrap_or_default() == EMBEDDINGS_PATH {
+                self.is_embeddings_request = true;
+                self.llm_provider = self.llm_providers.get(&embedding_provider.name);
+                debug!(
+                    "[R={}] selected embedding provider: {}",
+                    self.request_id, embedding_provider.name
+                );
+                return Ok(());
+            }
+        }
+
+        // a sanctioned override takes precedence over the provider of the prompt target, which
+        // takes precedence over the provider hint, an explicit provider hint over model aliases and
+        // experiments
+        let target_llm_provider = self
+            .prompt_target_config()
+            .and_then(|prompt_target| prompt_target.llm_provider.clone());
+        let provider_hint = match self.provider_override.clone().or(target_llm_provider) {
+            Some(llm_name) => Some(ProviderHint::Name(llm_name)),
+            None => match self.get_http_request_header(CURVE_PROVIDER_HINT_HEADER) {
+                Some(llm_name) => Some(llm_name.into()),
+                None => match self.model_alias_hint(model)? {
+                    Some(provider_hint) => Some(provider_hint),
+                    None => self
+                        .session_affinity_hint()
+                        .or_else(|| self.select_experiment_arm()),
+                },
+            },
+        };
+
+        debug!(
+            "[R={}] llm provider hint: {:?}",
+            self.request_id, provider_hint
+        );
+        // requests that didn't name their provider are kept off providers backing off
+        let named_provider = matches!(provider_hint, Some(ProviderHint::Name(_)));
+        let mut llm_provider = routing::get_llm_provider(&self.llm_providers, provider_hint);
+        if self.provider_backoff.is_some() && !named_provider {
+            let preferred_provider = Rc::clone(&llm_provider);
+            llm_provider =
+                routing::avoid_cooling_down(&self.llm_providers, llm_provider, self.unix_seconds());
+            if llm_provider.name != preferred_provider.name {
+                self.notify(
+                    NotificationEvent::ProviderFailover,
+                    serde_json::json!({
+                        "from": preferred_provider.name,
+                        "to": llm_provider.name,
+                    }),
+                );
+            }
+        }
+        self.llm_provider = Some(llm_provider);
+        debug!(
+            "[R={}] selected llm: {}",
+            self.request_id,
+            self.llm_provider.as_ref().unwrap().name
+        );
+        Ok(())
+    }
+
+    fn model_alias_hint(&self, model: Option<&str>) -> Result<Option<ProviderHint>, ServerError> {
+        let model_aliases = match self.model_aliases.as_ref() {
+            Some(model_aliases) => model_aliases,
+            None => return Ok(None),
+        };
+
+        match model.and_then(|model| model_aliases.aliases.get(model)) {
+            Some(llm_provider) => {
+                debug!(
+                    "[R={}] model alias {:?} resolved to {}",
+                    self.request_id, model, llm_provider
+                );
+                Ok(Some(ProviderHint::Name(llm_provider.clone())))
+            }
+            None if model_aliases.unknown_model.unwrap_or_default() == UnknownModel::Reject => {
+                Err(ServerError::BadRequest {
+                    why: format!("unknown model {:?}", model.unwrap_or_default()),
+                })
+            }
+            None => Ok(None),
+        }
+    }
+
+    // the model in the request body is needed to resolve aliases, which is only known once the
+    // body has been read. Envoy lets the headers be modified until the body is let through.
+    fn select_llm_provider_from_body(&mut self, body_size: usize) -> Result<(), ServerError> {
+        let model = self
+            .get_http_request_body(0, body_size)
+            .and_then(|body_bytes| serde_json::from_slice::<serde_json::Value>(&body_bytes).ok())
+            .and_then(|body| body.get("model")?.as_str().map(String::from));
+
+        self.select_llm_provider(model.as_deref())?;
+        self.route_to_llm_provider();
+        Ok(())
+    }
+
+    fn route_to_llm_provider(&mut self) {
+        // if endpoint is not set then use provider name as routing header so envoy can resolve the cluster name
+        if self.llm_provider().endpoint.is_none() {
+            self.add_http_request_header(
+                CURVE_ROUTING_HEADER,
+                &self.llm_provider().provider_interface.to_string(),
+            );
+        } else {
+            self.add_http_request_header(CURVE_ROUTING_HEADER, &self.llm_provider().name);
+        }
+
+        if let Err(error) = self.modify_auth_headers() {
+            // ensure that the provider has an endpoint if the access key is missing else return a bad request
+            if self.llm_provider.as_ref().unwrap().endpoint.is_none() {
+                self.send_server_error(error, Some(StatusCode::BAD_REQUEST));
+            }
+        }
+    }
+
+    fn session_affinity_hint(&mut self) -> Option<ProviderHint> {
+        let session_affinity = Rc::clone(&self.session_affinity);
+        let session_affinity = Option::as_ref(&session_affinity)?;
+        let session = self.get_http_request_header(&session_affinity.header)?;
+
+        let llm_provider = routing::get_session_provider(session_affinity, &session)?;
+        debug!(
+            "[R={}] session {} pinned to llm provider: {}",
+            self.request_id, session, llm_provider
+        );
+        self.session = Some(session);
+        Some(ProviderHint::Name(llm_provider.clone()))
+    }
+
+    fn select_experiment_arm(&mut self) -> Option<ProviderHint> {
+        let experiments = Rc::clone(&self.experiments);
+        for experiment in experiments.iter() {
+            let key = match self.get_http_request_header(&experiment.header) {
+                Some(key) => key,
+                None => continue,
+            };
 
-        debug!("llm provider hint: {:?}", provider_hint);
-        self.llm_provider = Some(routing::get_llm_provider(
-            &self.llm_providers,
-            provider_hint,
-        ));
-        debug!("selected llm: {}", self.llm_provider.as_ref().unwrap().name);
+            if let Some(arm) = routing::get_experiment_arm(experiment, &key) {
+                let experiment_arm = format!("{}.{}", experiment.name, arm.llm_provider);
+                debug!(
+                    "[R={}] experiment arm selected: {}",
+                    self.request_id, experiment_arm
+                );
+                if let Some(counter) = self.experiment_metrics.get(&experiment_arm) {
+                    counter.increment(1);
+                }
+                self.experiment_arm = Some(experiment_arm);
+                return Some(ProviderHint::Name(arm.llm_provider.clone()));
+            }
+        }
+        None
     }
 
     fn modify_auth_headers(&mut self) -> Result<(), ServerError> {
@@ -102,69 +479,1633 @@ impl StreamContext {
                     ),
                 })?;
 
-        let authorization_header_value = format!("Bearer {}", llm_provider_api_key_value);
+        let authorization_header_value = format!("Bearer {}", llm_provider_api_key_value);
+
+        self.set_http_request_header("Authorization", Some(&authorization_header_value));
+
+        Ok(())
+    }
+
+    fn delete_content_length_header(&mut self) {
+        // Remove the Content-Length header because further body manipulations in the gateway logic will invalidate it.
+        // Server's generally throw away requests whose body length do not match the Content-Length header.
+        // However, a missing Content-Length header is not grounds for bad requests given that intermediary hops could
+        // manipulate the body in benign ways e.g., compression.
+        self.set_http_request_header("content-length", None);
+    }
+
+    // the priority of a virtual key can't be raised with the priority header
+    fn save_priority(&mut self) {
+        let prioritization = Rc::clone(&self.prioritization);
+        let prioritization = match prioritization.as_ref() {
+            Some(prioritization) => prioritization,
+            None => return,
+        };
+        self.priority = self
+            .virtual_key
+            .as_ref()
+            .and_then(|virtual_key| virtual_key.priority)
+            .or_else(|| {
+                self.get_http_request_header(prioritization.header())
+                    .and_then(|priority| priority.parse().ok())
+            })
+            .or(prioritization.default)
+            .unwrap_or_default();
+    }
+
+    fn save_ratelimit_header(&mut self) {
+        // the ratelimits and budget of a virtual key can't be traded for another selector
+        if let Some(virtual_key) = self.virtual_key.as_ref() {
+            self.ratelimit_selector = Some(virtual_keys::selector(virtual_key));
+            return;
+        }
+        self.ratelimit_selector = self
+            .get_http_request_header(RATELIMIT_SELECTOR_HEADER_KEY)
+            .and_then(|key| {
+                self.get_http_request_header(&key)
+                    .map(|value| Header { key, value })
+            });
+    }
+
+    fn write_access_log(&self, current_time: SystemTime) {
+        let access_log = match self.access_log.as_ref() {
+            Some(access_log) => access_log,
+            None => return,
+        };
+
+        let entry = AccessLogEntry {
+            request_id: Some(self.request_id.clone()),
+            prompt_target: self.prompt_target.clone(),
+            provider: Some(self.llm_provider().name.clone()),
+            model: Some(self.model().to_string()),
+            experiment_arm: self.experiment_arm.clone(),
+            user_prompt: self
+                .user_message
+                .as_ref()
+                .and_then(|message| message.content.as_ref())
+                .map(|content| content.text()),
+            input_tokens: Some(self.input_tokens),
+            output_tokens: Some(self.response_tokens),
+            cost: self.cost,
+            request_latency_ms: current_time
+                .duration_since(self.start_time)
+                .ok()
+                .map(|duration| duration.as_millis() as u64),
+            time_to_first_token_ms: self
+                .ttft_duration
+                .map(|duration| duration.as_millis() as u64),
+        };
+        let entry_str = entry.to_json(access_log.redact.as_deref().unwrap_or_default());
+
+        match access_log.sink {
+            Some(_) => self.access_log_queue.lock().unwrap().push_back(entry_str),
+            None => info!("access_log: {}", entry_str),
+        }
+    }
+
+    // adds the metadata event in front of [DONE] when the client asked for it
+    fn include_metadata_event(&mut self, chunk: &str, body_size: usize) {
+        if !self.include_metadata {
+            return;
+        }
+        let event = CurveMetadataEvent {
+            object: CURVE_METADATA_OBJECT.to_string(),
+            request_id: self.request_id.clone(),
+            prompt_target: self.prompt_target.clone(),
+            llm_provider: self.llm_provider().name.clone(),
+            model: self.model().to_string(),
+            experiment_arm: self.experiment_arm.clone(),
+            input_tokens: self.input_tokens,
+            output_tokens: self.response_tokens,
+        };
+        if let Some(chunk) = event.insert_before_done(chunk) {
+            self.set_http_response_body(0, body_size, chunk.as_bytes());
+        }
+    }
+
+    // queues a copy of the request for the mirror provider, the filter context sends it off the
+    // request path. Returns whether the request was mirrored.
+    fn mirror_request(&self, request: &ChatCompletionsRequest) -> bool {
+        let mirroring = match self.mirroring.as_ref() {
+            Some(mirroring) => mirroring,
+            None => return false,
+        };
+        if self.audit.is_none() || !audit::sampled(mirroring.sampling_rate) {
+            return false;
+        }
+        let llm_provider = match self.llm_providers.get(&mirroring.llm_provider) {
+            Some(llm_provider) => llm_provider,
+            None => return false,
+        };
+        if llm_provider.name == self.llm_provider().name {
+            return false;
+        }
+
+        let mut mirror_request = request.clone();
+        mirror_request.model.clone_from(&llm_provider.model);
+        // the mirrored response is read in one piece
+        mirror_request.stream = false;
+        mirror_request.stream_options = None;
+        llm_providers::adapt_request(&mut mirror_request, &llm_provider.provider_interface);
+
+        let mirror_request_str = match serde_json::to_string(&mirror_request) {
+            Ok(mirror_request_str) => mirror_request_str,
+            Err(e) => {
+                warn!("could not serialize mirror request: {}", e);
+                return false;
+            }
+        };
+        debug!(
+            "[R={}] mirroring request to {}",
+            self.request_id, llm_provider.name
+        );
+        self.mirror_queue.lock().unwrap().push_back(AuditRecord {
+            request_id: Some(self.request_id.clone()),
+            provider: llm_provider.name.clone(),
+            model: mirror_request.model,
+            request: mirror_request_str,
+            response: String::new(),
+            mirror: Some(true),
+        });
+        self.metrics.mirrored_rq.increment(1);
+        true
+    }
+
+    fn write_audit_record(&mut self) {
+        let mut audit_record = match self.audit_record.take() {
+            Some(audit_record) => audit_record,
+            None => return,
+        };
+
+        if let Some(redact) = self
+            .audit
+            .as_ref()
+            .as_ref()
+            .and_then(|audit| audit.redact.as_ref())
+        {
+            audit_record.redact(redact);
+        }
+
+        match serde_json::to_string(&audit_record) {
+            Ok(audit_record_str) => self.audit_queue.lock().unwrap().push_back(audit_record_str),
+            Err(e) => warn!("could not serialize audit record: {}", e),
+        }
+    }
+
+    fn send_server_error(&self, error: ServerError, override_status_code: Option<StatusCode>) {
+        debug!("[R={}] server error occurred: {}", self.request_id, error);
+        self.send_http_response(
+            override_status_code
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+                .as_u16()
+                .into(),
+            vec![],
+            Some(format!("{error}").as_bytes()),
+        );
+    }
+
+    // Answers cors preflight requests, true when the request was one. The origin of other requests
+    // is kept for the cors headers of the response.
+    fn handle_cors(&mut self) -> bool {
+        let cors = Rc::clone(&self.cors);
+        let Some(cors) = Option::as_ref(&cors) else {
+            return false;
+        };
+        let Some(origin) = self.get_http_request_header(cors::ORIGIN_HEADER) else {
+            return false;
+        };
+        let is_preflight = self.get_http_request_header(":method").as_deref() == Some("OPTIONS")
+            && self
+                .get_http_request_header(cors::ACCESS_CONTROL_REQUEST_METHOD_HEADER)
+                .is_some();
+        if !is_preflight {
+            self.cors_origin = Some(origin);
+            return false;
+        }
+        match cors::preflight_headers(cors, &origin) {
+            Some(headers) => {
+                let headers = headers
+                    .iter()
+                    .map(|(name, value)| (*name, value.as_str()))
+                    .collect();
+                self.send_http_response(StatusCode::NO_CONTENT.as_u16().into(), headers, None);
+            }
+            None => {
+                debug!("cors preflight from origin {} not allowed", origin);
+                self.send_http_response(StatusCode::FORBIDDEN.as_u16().into(), vec![], None);
+            }
+        }
+        true
+    }
+
+    fn add_cors_headers(&self) {
+        if let (Some(cors), Some(origin)) = (Option::as_ref(&self.cors), self.cors_origin.as_ref())
+        {
+            for (name, value) in cors::response_headers(cors, origin).unwrap_or_default() {
+                self.set_http_response_header(name, Some(&value));
+            }
+        }
+    }
+
+    // Reads how the request body is compressed. The gateway reads the responses of the upstream,
+    // they are asked for without compression, envoy's compressor compresses them for the client.
+    fn save_content_encoding(&mut self) -> Result<(), content_encoding::Error> {
+        if self
+            .get_http_request_header(ACCEPT_ENCODING_HEADER)
+            .is_some()
+        {
+            self.set_http_request_header(ACCEPT_ENCODING_HEADER, None);
+        }
+        if let Some(encoding) = self.get_http_request_header(CONTENT_ENCODING_HEADER) {
+            self.request_encoding = ContentEncoding::parse(&encoding)?;
+            // the body goes upstream decompressed
+            self.set_http_request_header(CONTENT_ENCODING_HEADER, None);
+        }
+        Ok(())
+    }
+
+    // replaces a compressed request body by the decompressed one, returns the size of the body
+    fn decode_request_body(&mut self, body_size: usize) -> Result<usize, content_encoding::Error> {
+        let Some(encoding) = self.request_encoding.take() else {
+            return Ok(body_size);
+        };
+        let max_bytes = Option::as_ref(&self.body_encoding)
+            .map(BodyEncoding::max_decompressed_bytes)
+            .unwrap_or_else(|| BodyEncoding::default().max_decompressed_bytes());
+        let body = self.get_http_request_body(0, body_size).unwrap_or_default();
+        let decoded = content_encoding::decode(encoding, &body, max_bytes)?;
+        self.set_http_request_body(0, body_size, &decoded);
+        Ok(decoded.len())
+    }
+
+    fn send_content_encoding_error(&self, error: content_encoding::Error) {
+        let status_code = match error {
+            content_encoding::Error::Unsupported(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            content_encoding::Error::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            content_encoding::Error::Invalid(_) => StatusCode::BAD_REQUEST,
+        };
+        self.send_server_error(ServerError::ContentEncoding(error), Some(status_code));
+    }
+
+    // validates the token of the request, the claims are forwarded as the configured headers
+    fn authenticate(&self) -> Result<(), jwt::Error> {
+        let jwt_auth = match Option::as_ref(&self.jwt_auth) {
+            Some(jwt_auth) => jwt_auth,
+            None => return Ok(()),
+        };
+        let header = jwt_auth.header.as_deref().unwrap_or(AUTHORIZATION_HEADER);
+        let token = self.get_http_request_header(header);
+        let claims = jwt::authenticate(jwt_auth, token.as_deref(), self.get_current_time())?;
+        for (header, value) in jwt::claim_headers(jwt_auth, &claims) {
+            self.set_http_request_header(header, value.as_deref());
+        }
+        Ok(())
+    }
+
+    // with virtual keys configured every request has to carry one of them. The key is removed from
+    // the request, the llm provider is called with the credentials of the gateway.
+    fn read_virtual_key(&mut self) -> bool {
+        let virtual_keys = Rc::clone(&self.virtual_keys);
+        let virtual_keys = match Option::as_ref(&virtual_keys) {
+            Some(virtual_keys) => virtual_keys,
+            None => return true,
+        };
+        let header = virtual_keys
+            .header
+            .as_deref()
+            .unwrap_or(AUTHORIZATION_HEADER);
+        let header_value = self.get_http_request_header(header);
+        self.set_http_request_header(header, None);
+        self.virtual_key = virtual_keys::find(virtual_keys, header_value.as_deref()).cloned();
+        self.virtual_key.is_some()
+    }
+
+    fn send_virtual_key_rejection(&self, status_code: StatusCode, error: serde_json::Value) {
+        debug!("[R={}] virtual key rejected: {}", self.request_id, error);
+        self.metrics.virtual_key_rejections.increment(1);
+        let body = serde_json::json!({ "error": error }).to_string();
+        self.send_http_response(
+            status_code.as_u16().into(),
+            vec![("content-type", "application/json")],
+            Some(body.as_bytes()),
+        );
+    }
+
+    // the request is rejected when its virtual key may not call the model, before any callout is
+    // made for it. Returns whether the model is allowed.
+    fn check_virtual_key_model(&self, model: &str) -> bool {
+        let Some(virtual_key) = self.virtual_key.as_ref() else {
+            return true;
+        };
+        if virtual_keys::is_model_allowed(virtual_key, model) {
+            return true;
+        }
+        self.send_virtual_key_rejection(
+            StatusCode::FORBIDDEN,
+            serde_json::json!({
+                "type": "model_not_allowed",
+                "virtual_key": virtual_key.name,
+                "model": model,
+            }),
+        );
+        false
+    }
+
+    fn send_budget_exceeded(&self, error: cost::Error) {
+        debug!("[R={}] budget exhausted: {}", self.request_id, error);
+        let body = serde_json::json!({ "error": error }).to_string();
+        self.send_http_response(
+            StatusCode::PAYMENT_REQUIRED.as_u16().into(),
+            vec![("content-type", "application/json")],
+            Some(body.as_bytes()),
+        );
+    }
+
+    // compressed messages are what the summarization and the context window see
+    fn compress_messages(&self, request: &mut ChatCompletionsRequest) {
+        let compression = match self.compression.as_ref() {
+            Some(compression) => compression,
+            None => return,
+        };
+        let saved_tokens =
+            compression::compress(compression, &request.model, &mut request.messages);
+        if saved_tokens > 0 {
+            debug!(
+                "[R={}] compression saved {} tokens",
+                self.request_id, saved_tokens
+            );
+            self.metrics.compressed_rq.increment(1);
+            self.metrics
+                .compression_saved_tokens
+                .increment(saved_tokens as i64);
+        }
+    }
+
+    // requests over the context window of the model are rejected, or have their oldest messages
+    // dropped when the llm provider truncates
+    fn fit_context_window(
+        &self,
+        request: &mut ChatCompletionsRequest,
+    ) -> Result<(), context_window::Error> {
+        let context_window = match self.llm_provider().context_window.as_ref() {
+            Some(context_window) => context_window,
+            None => return Ok(()),
+        };
+        match context_window.on_overflow.unwrap_or_default() {
+            ContextOverflow::Reject => {
+                context_window::check(context_window, &request.model, &request.messages)
+            }
+            ContextOverflow::Truncate => {
+                let dropped = context_window::truncate(
+                    context_window,
+                    &request.model,
+                    &mut request.messages,
+                )?;
+                if dropped > 0 {
+                    debug!(
+                        "[R={}] dropped {} messages to fit the context window",
+                        self.request_id, dropped
+                    );
+                    self.metrics.context_window_truncations.increment(1);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn send_context_window_exceeded(&self, error: context_window::Error, model: &str) {
+        debug!("[R={}] {}", self.request_id, error);
+        self.metrics.context_window_rejections.increment(1);
+        let body = serde_json::json!({
+            "error": {
+                "type": "context_window_exceeded",
+                "message": error.to_string(),
+                "llm_provider": self.llm_provider().name,
+                "model": model,
+                "max_tokens": error.max_tokens,
+                "input_tokens": error.input_tokens,
+            }
+        })
+        .to_string();
+        self.send_http_response(
+            StatusCode::BAD_REQUEST.as_u16().into(),
+            vec![("content-type", "application/json")],
+            Some(body.as_bytes()),
+        );
+    }
+
+    // provider and model dimensions of the metrics of the response
+    fn metric_labels(&self) -> Vec<(Dimension, &str)> {
+        match self.llm_provider.as_ref() {
+            Some(llm_provider) => vec![
+                (Dimension::Provider, llm_provider.name.as_str()),
+                (Dimension::Model, self.model()),
+            ],
+            None => Vec::new(),
+        }
+    }
+
+    // the rate the provider streams tokens at once the first one arrived, the time to the first
+    // token is measured on its own. None when the response came in a single chunk.
+    fn tokens_per_second(&self, duration: Duration) -> Option<u64> {
+        let streaming_time = duration.checked_sub(self.ttft_duration?)?.as_millis();
+        let streamed_tokens = self.response_tokens.saturating_sub(self.ttft_tokens) as u128;
+        if streaming_time == 0 || streamed_tokens == 0 {
+            return None;
+        }
+        Some((streamed_tokens * 1000 / streaming_time) as u64)
+    }
+
+    fn notify(&self, event: NotificationEvent, details: serde_json::Value) {
+        notifications::notify(event, &self.request_id, details, self.get_current_time());
+    }
+
+    fn unix_seconds(&self) -> u64 {
+        self.get_current_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
+    // a provider answering 429 with a retry-after is skipped by default routing until it passed
+    fn back_off_rate_limited_provider(&mut self) {
+        let provider_backoff = Rc::clone(&self.provider_backoff);
+        let provider_backoff = match provider_backoff.as_ref() {
+            Some(provider_backoff) => provider_backoff,
+            None => return,
+        };
+        if self.get_http_response_header(":status").as_deref()
+            != Some(StatusCode::TOO_MANY_REQUESTS.as_str())
+        {
+            return;
+        }
+        let retry_after = match self
+            .get_http_response_header(RETRY_AFTER_HEADER)
+            .and_then(|retry_after| routing::parse_retry_after(&retry_after))
+        {
+            Some(retry_after) => retry_after.min(provider_backoff.max_retry_after_seconds()),
+            None => return,
+        };
+
+        warn!(
+            "llm provider {} rate limited, backing off for {} seconds",
+            self.llm_provider().name,
+            retry_after
+        );
+        routing::cool_down_provider(&self.llm_provider().name, self.unix_seconds(), retry_after);
+        self.metrics
+            .provider_cooldowns
+            .with(&[(Dimension::Provider, &self.llm_provider().name)])
+            .increment(1);
+        self.notify(
+            NotificationEvent::CircuitBreakerOpen,
+            serde_json::json!({
+                "llm_provider": self.llm_provider().name,
+                "retry_after_seconds": retry_after,
+            }),
+        );
+        self.retry_after = Some(retry_after);
+        self.set_http_response_header("content-length", None);
+        self.set_http_response_header("content-type", Some("application/json"));
+    }
+
+    fn send_rate_limited(&self, retry_after: u64, body_size: usize) {
+        let body = serde_json::json!({
+            "error": {
+                "type": "rate_limited",
+                "message": format!(
+                    "llm provider {} is rate limited, retry after {} seconds",
+                    self.llm_provider().name,
+                    retry_after
+                ),
+                "llm_provider": self.llm_provider().name,
+                "retry_after_seconds": retry_after,
+            }
+        })
+        .to_string();
+        self.set_http_response_body(0, body_size, body.as_bytes());
+    }
+
+    fn send_unauthenticated(&self, error: jwt::Error) {
+        debug!(
+            "[R={}] request not authenticated: {}",
+            self.request_id, error
+        );
+        self.metrics.jwt_rejections.increment(1);
+        let status_code = match error {
+            jwt::Error::KeysUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::UNAUTHORIZED,
+        };
+        let body = serde_json::json!({
+            "error": {
+                "type": "invalid_token",
+                "message": error.to_string(),
+            }
+        })
+        .to_string();
+        self.send_http_response(
+            status_code.as_u16().into(),
+            vec![
+                ("content-type", "application/json"),
+                ("www-authenticate", "Bearer"),
+            ],
+            Some(body.as_bytes()),
+        );
+    }
+
+    fn enforce_ratelimits(
+        &mut self,
+        model: &str,
+        json_string: &str,
+    ) -> Result<(), ratelimit::Error> {
+        // Tokenize and record token count.
+        let token_count = tokenizer::token_count(model, json_string).unwrap_or(0);
+        self.input_tokens = token_count;
+
+        // Record the token count to metrics.
+        self.metrics
+            .input_sequence_length
+            .with(&[(Dimension::Model, model)])
+            .record(token_count as u64);
+        log::debug!(
+            "[R={}] Recorded input token count: {}",
+            self.request_id,
+            token_count
+        );
+
+        // Check if rate limiting needs to be applied.
+        if let Some(selector) = self.ratelimit_selector.clone() {
+            log::debug!(
+                "[R={}] Applying ratelimit for model: {}",
+                self.request_id,
+                model
+            );
+            self.release_in_flight();
+            // models unknown to the tokenizer and empty requests count 0 tokens, every request
+            // takes at least one
+            self.ratelimit_in_flight = ratelimit::ratelimits(None).read().unwrap().check_limit(
+                model.to_owned(),
+                selector,
+                NonZero::new(token_count as u32).unwrap_or(NonZero::<u32>::MIN),
+                self.get_current_time(),
+            )?;
+        } else {
+            log::debug!(
+                "[R={}] No rate limit applied for model: {}",
+                self.request_id,
+                model
+            );
+        }
+
+        Ok(())
+    }
+
+    fn release_in_flight(&mut self) {
+        if let Some(in_flight) = self.ratelimit_in_flight.take() {
+            ratelimit::ratelimits(None)
+                .read()
+                .unwrap()
+                .release(in_flight);
+        }
+    }
+
+    // streaming requests are rejected when their selector has all the streams it may have open,
+    // they are not queued as the streams only close once responses are done
+    fn open_stream(&mut self, model: &str) -> Result<(), ratelimit::Error> {
+        let selector = match self.ratelimit_selector.clone() {
+            Some(selector) if self.streaming_response => selector,
+            _ => return Ok(()),
+        };
+        self.stream_in_flight = ratelimit::ratelimits(None).read().unwrap().open_stream(
+            model.to_owned(),
+            selector,
+            self.get_current_time(),
+        )?;
+        Ok(())
+    }
+
+    // Requests of a shed priority are rejected when they hit a limit, the others wait in the
+    // request queue for it. False when the request can't wait. The selector is the one of the
+    // ratelimit that was hit, None when the llm provider is backing off.
+    fn queue_request(&mut self, model: &str, selector: Option<Header>) -> bool {
+        let prioritization = Rc::clone(&self.prioritization);
+        let prioritization = match prioritization.as_ref() {
+            Some(prioritization) => prioritization,
+            None => return false,
+        };
+        let labels = [(Dimension::Priority, self.priority.as_str())];
+        if prioritization.is_shed(self.priority) {
+            self.metrics.shed_rq.with(&labels).increment(1);
+            return false;
+        }
+
+        let queued_request = QueuedRequest {
+            context_id: self.context_id,
+            priority: self.priority,
+            queued_at: self.get_current_time(),
+            llm_provider: self.llm_provider().name.clone(),
+            model: model.to_string(),
+            selector,
+            tokens: NonZero::new(self.input_tokens as u32).unwrap_or(NonZero::<u32>::MIN),
+        };
+        if !priority::request_queue()
+            .write()
+            .unwrap()
+            .enqueue(queued_request, prioritization.max_queued())
+        {
+            debug!("[R={}] request queue is full", self.request_id);
+            self.metrics.shed_rq.with(&labels).increment(1);
+            return false;
+        }
+
+        debug!(
+            "[R={}] limit hit, {} priority request queued",
+            self.request_id,
+            self.priority.as_str()
+        );
+        self.metrics.queued_rq.with(&labels).increment(1);
+        self.queued = true;
+        true
+    }
+
+    fn send_provider_backing_off(&self) {
+        let body = serde_json::json!({
+            "error": {
+                "type": "rate_limited",
+                "message": format!(
+                    "llm provider {} is rate limited, {} priority requests are not queued",
+                    self.llm_provider().name,
+                    self.priority.as_str()
+                ),
+                "llm_provider": self.llm_provider().name,
+            }
+        })
+        .to_string();
+        self.send_http_response(
+            StatusCode::TOO_MANY_REQUESTS.as_u16().into(),
+            vec![("content-type", "application/json")],
+            Some(body.as_bytes()),
+        );
+    }
+
+    fn handle_embeddings_request_body(&mut self, body_size: usize) -> Action {
+        let mut embeddings_request: EmbeddingsRequest = match self
+            .get_http_request_body(0, body_size)
+            .map(|body_bytes| serde_json::from_slice(&body_bytes))
+        {
+            Some(Ok(embeddings_request)) => embeddings_request,
+            Some(Err(e)) => {
+                self.send_server_error(
+                    ServerError::Deserialization(e),
+                    Some(StatusCode::BAD_REQUEST),
+                );
+                return Action::Pause;
+            }
+            None => {
+                self.send_server_error(
+                    ServerError::LogicError(format!(
+                        "Failed to obtain body bytes even though body_size is {}",
+                        body_size
+                    )),
+                    None,
+                );
+                return Action::Pause;
+            }
+        };
+
+        // override model name from the embedding provider
+        if let Some(embedding_provider) = self.embedding_provider.as_ref() {
+            embeddings_request
+                .model
+                .clone_from(&embedding_provider.model);
+        }
+        self.metrics.embeddings_rq.increment(1);
+
+        // there is nothing to embed, nor tokens to count against the ratelimits
+        if embeddings_request.input.text().trim().is_empty() {
+            self.send_server_error(
+                ServerError::BadRequest {
+                    why: "input of the embeddings request is empty".to_string(),
+                },
+                Some(StatusCode::BAD_REQUEST),
+            );
+            return Action::Pause;
+        }
+
+        // embeddings are held to the models and the budget of the virtual key like chat requests
+        if !self.check_virtual_key_model(&embeddings_request.model) {
+            return Action::Pause;
+        }
+        if let Err(e) = self.enforce_budget() {
+            self.send_budget_exceeded(e);
+            return Action::Pause;
+        }
+
+        if let Err(e) =
+            self.enforce_ratelimits(&embeddings_request.model, &embeddings_request.input.text())
+        {
+            let selector = self.ratelimit_selector.clone();
+            if !self.queue_request(&embeddings_request.model, selector) {
+                self.notify(
+                    NotificationEvent::RatelimitBreach,
+                    serde_json::json!({
+                        "model": embeddings_request.model,
+                        "message": e.to_string(),
+                    }),
+                );
+                self.send_server_error(
+                    ServerError::ExceededRatelimit(e),
+                    Some(StatusCode::TOO_MANY_REQUESTS),
+                );
+                self.metrics
+                    .ratelimited_rq
+                    .with(&[(Dimension::Model, &embeddings_request.model)])
+                    .increment(1);
+                return Action::Continue;
+            }
+        }
+
+        let embeddings_request_str = serde_json::to_string(&embeddings_request).unwrap();
+        trace!(
+            "curve  => {:?}, body: {}",
+            embeddings_request.model,
+            embeddings_request_str
+        );
+        self.set_http_request_body(0, body_size, embeddings_request_str.as_bytes());
+
+        // resumed by the filter context once the request is admitted
+        if self.queued {
+            return Action::Pause;
+        }
+        Action::Continue
+    }
+
+    fn deserialize_request_body(
+        &self,
+        body_bytes: &[u8],
+    ) -> Result<ChatCompletionsRequest, ServerError> {
+        match self.request_api {
+            RequestApi::ChatCompletions => {
+                serde_json::from_slice(body_bytes).map_err(ServerError::Deserialization)
+            }
+            RequestApi::Completions => serde_json::from_slice::<CompletionsRequest>(body_bytes)
+                .map_err(ServerError::Deserialization)?
+                .try_into()
+                .map_err(|why| ServerError::BadRequest { why }),
+            RequestApi::Responses => serde_json::from_slice::<ResponsesRequest>(body_bytes)
+                .map_err(ServerError::Deserialization)?
+                .try_into()
+                .map_err(|why| ServerError::BadRequest { why }),
+        }
+    }
+
+    // translates the chat completions response back to the shape the client asked for
+    fn serialize_response_body(&self, response: ChatCompletionsResponse) -> Option<String> {
+        match self.request_api {
+            RequestApi::ChatCompletions => None,
+            RequestApi::Completions => {
+                serde_json::to_string(&CompletionsResponse::from(response)).ok()
+            }
+            RequestApi::Responses => serde_json::to_string(&ResponsesResponse::from(response)).ok(),
+        }
+    }
+
+    fn enforce_budget(&self) -> Result<(), cost::Error> {
+        match self.ratelimit_selector.as_ref() {
+            Some(selector) => cost::costs(None)
+                .read()
+                .unwrap()
+                .check_budget(selector, self.get_current_time()),
+            None => Ok(()),
+        }
+    }
+
+    fn finish_response(&mut self, current_time: SystemTime) {
+        if self.response_finished {
+            return;
+        }
+        self.response_finished = true;
+        self.record_cost(current_time);
+        self.write_access_log(current_time);
+    }
+
+    fn record_cost(&mut self, current_time: SystemTime) {
+        let costs = cost::costs(None);
+        self.cost =
+            costs
+                .read()
+                .unwrap()
+                .cost(self.model(), self.input_tokens, self.response_tokens);
+
+        if let (Some(cost), Some(selector)) = (self.cost, self.ratelimit_selector.as_ref()) {
+            costs.write().unwrap().record(selector, cost, current_time);
+        }
+    }
+
+    // calls an llm provider through the internal cluster, next to the request being proxied
+    fn dispatch_to_llm_provider(
+        &self,
+        llm_provider: &LlmProvider,
+        path: &str,
+        body: &str,
+        timeout: Duration,
+        callout: Callout<StreamContext>,
+    ) -> Result<u32, ClientError> {
+        let upstream_host = llm_provider.cluster_name();
+        let authorization_header = llm_provider
+            .access_key
+            .as_ref()
+            .map(|access_key| format!("Bearer {}", access_key));
+        let mut call_args = CallArgs::internal(&upstream_host, Method::POST, path)
+            .with_json_body(body.as_bytes())
+            .with_header(REQUEST_ID_HEADER, &self.request_id)
+            .with_timeout(timeout);
+        if let Some(authorization_header) = authorization_header.as_ref() {
+            call_args = call_args.with_header(AUTHORIZATION_HEADER, authorization_header);
+        }
+        self.http_call(call_args, callout)
+    }
+
+    // requests a summary of the messages before the split index from the summarization provider,
+    // the request is paused until it arrives. Returns whether the request was paused.
+    fn schedule_summary(&self, request: &ChatCompletionsRequest, body_size: usize) -> bool {
+        let summarization = match self.summarization.as_ref() {
+            Some(summarization) => summarization,
+            None => return false,
+        };
+        let split_index =
+            match summarization::needs_summary(summarization, &request.model, &request.messages) {
+                Some(split_index) => split_index,
+                None => return false,
+            };
+        let llm_provider = match self.llm_providers.get(&summarization.llm_provider) {
+            Some(llm_provider) => llm_provider,
+            None => return false,
+        };
+
+        let summary_request =
+            summarization::summary_request(&llm_provider.model, &request.messages, split_index);
+        let summary_request_str = match serde_json::to_string(&summary_request) {
+            Ok(summary_request_str) => summary_request_str,
+            Err(e) => {
+                warn!("could not serialize summary request: {}", e);
+                return false;
+            }
+        };
+        let summarized_request = request.clone();
+        let summary_model = llm_provider.model.clone();
+        let summary_input_tokens =
+            tokenizer::token_count(&summary_model, &summary_request_str).unwrap_or(0);
+        let callout = Callout::json(
+            "summarization",
+            move |context: &mut StreamContext, response| {
+                if let Ok(response) = response.as_ref() {
+                    context.record_summary_cost(&summary_model, summary_input_tokens, response);
+                }
+                context.on_summary_response(summarized_request, body_size, split_index, response)
+            },
+        );
+        match self.dispatch_to_llm_provider(
+            &llm_provider,
+            CHAT_COMPLETIONS_PATH,
+            &summary_request_str,
+            Duration::from_secs(SUMMARIZATION_TIMEOUT_SECONDS),
+            callout,
+        ) {
+            Ok(_) => {
+                debug!(
+                    "[R={}] summarizing {} messages with {}",
+                    self.request_id, split_index, llm_provider.name
+                );
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "failed to schedule summary request to {}: {:?}",
+                    llm_provider.name, e
+                );
+                self.metrics.summarization_failures.increment(1);
+                false
+            }
+        }
+    }
+
+    // the summary call is paid for from the budget of the request it was made for
+    fn record_summary_cost(
+        &self,
+        model: &str,
+        input_tokens: usize,
+        response: &ChatCompletionsResponse,
+    ) {
+        let output_tokens = response
+            .usage
+            .as_ref()
+            .map(|usage| usage.completion_tokens)
+            .unwrap_or_default();
+        let costs = cost::costs(None);
+        let cost = costs
+            .read()
+            .unwrap()
+            .cost(model, input_tokens, output_tokens);
+        if let (Some(cost), Some(selector)) = (cost, self.ratelimit_selector.as_ref()) {
+            let now = self.get_current_time();
+            costs.write().unwrap().record(selector, cost, now);
+        }
+    }
+
+    // the request is sent as is when no summary came back
+    fn on_summary_response(
+        &mut self,
+        mut request: ChatCompletionsRequest,
+        body_size: usize,
+        split_index: usize,
+        response: Result<ChatCompletionsResponse, callout::Error>,
+    ) {
+        match read_summary(response) {
+            Some(summary) => {
+                summarization::apply_summary(&mut request.messages, split_index, &summary);
+                self.metrics.summarized_rq.increment(1);
+            }
+            None => self.metrics.summarization_failures.increment(1),
+        }
+
+        if self.handle_chat_completions_request(request, body_size) == Action::Continue {
+            self.resume_http_request();
+        }
+    }
+
+    fn input_guard(&self, guard_type: &GuardType) -> Option<GuardOptions> {
+        Option::as_ref(&self.prompt_guards)?
+            .input_guard(guard_type)
+            .cloned()
+    }
+
+    fn output_guard(&self, guard_type: &GuardType) -> Option<GuardOptions> {
+        Option::as_ref(&self.prompt_guards)?
+            .output_guard(guard_type)
+            .cloned()
+    }
+
+    // sends the texts to the moderations api of the llm provider of the guard, the stream is
+    // paused until the verdict arrives. Returns whether the stream was paused.
+    fn schedule_moderation(
+        &self,
+        guard: &GuardOptions,
+        input: Vec<String>,
+        callout: Callout<StreamContext>,
+    ) -> bool {
+        let llm_provider = match guard
+            .llm_provider
+            .as_ref()
+            .and_then(|llm_provider| self.llm_providers.get(llm_provider))
+        {
+            Some(llm_provider) => llm_provider,
+            None => return false,
+        };
+        let moderation_request = ModerationRequest {
+            input,
+            model: guard.model.clone(),
+        };
+        let moderation_request_str = match serde_json::to_string(&moderation_request) {
+            Ok(moderation_request_str) => moderation_request_str,
+            Err(e) => {
+                warn!("could not serialize moderation request: {}", e);
+                return false;
+            }
+        };
+        match self.dispatch_to_llm_provider(
+            &llm_provider,
+            guard.path.as_deref().unwrap_or(MODERATIONS_PATH),
+            &moderation_request_str,
+            Duration::from_secs(MODERATION_TIMEOUT_SECONDS),
+            callout,
+        ) {
+            Ok(_) => {
+                debug!(
+                    "[R={}] moderating with {}",
+                    self.request_id, llm_provider.name
+                );
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "failed to schedule moderation request to {}: {:?}",
+                    llm_provider.name, e
+                );
+                self.metrics.guard_failures.increment(1);
+                false
+            }
+        }
+    }
+
+    // content the guard couldn't check goes through, the failure is counted
+    fn read_moderation(
+        &self,
+        response: Result<ModerationResponse, callout::Error>,
+    ) -> Option<ModerationResponse> {
+        match response {
+            Ok(moderation_response) => Some(moderation_response),
+            Err(e) => {
+                warn!("moderation request failed: {}", e);
+                self.metrics.guard_failures.increment(1);
+                None
+            }
+        }
+    }
+
+    // violations the guard acts on, a guard that monitors only logs and counts them
+    fn enforced_violations(
+        &self,
+        guard_type: &GuardType,
+        guard: &GuardOptions,
+        violations: Vec<String>,
+    ) -> Vec<String> {
+        if violations.is_empty() {
+            return violations;
+        }
+        self.metrics.guard_violations.increment(1);
+        self.notify(
+            NotificationEvent::GuardDetection,
+            serde_json::json!({
+                "guard": guard_type.to_string(),
+                "action": guard.action.unwrap_or_default(),
+                "violations": violations,
+            }),
+        );
+        if guard.action.unwrap_or_default() == GuardAction::Monitor {
+            info!(
+                "[R={}] {} guard found {:?}",
+                self.request_id, guard_type, violations
+            );
+            return Vec::new();
+        }
+        debug!(
+            "[R={}] {} guard acted on {:?}",
+            self.request_id, guard_type, violations
+        );
+        violations
+    }
+
+    fn moderation_violations(
+        &self,
+        guard: &GuardOptions,
+        result: &ModerationResult,
+    ) -> Vec<String> {
+        let violations = result.violations(guard.thresholds.as_ref());
+        self.enforced_violations(&GuardType::Moderation, guard, violations)
+    }
+
+    // scans the texts with a guard that needs no callout, the offending content is masked when
+    // the guard masks. Returns the violations the guard acts on.
+    fn scan_with_guard<'a>(
+        &self,
+        guard_type: &GuardType,
+        output: bool,
+        guard: &GuardOptions,
+        texts: impl Iterator<Item = &'a mut String>,
+    ) -> Vec<String> {
+        let mask = guard.action == Some(GuardAction::Mask);
+        let violations = guards::scan(guard_type, output, texts, mask);
+        self.enforced_violations(guard_type, guard, violations)
+    }
+
+    // denied terms in the choices are masked, or the choices are withheld. Returns whether the
+    // response was changed.
+    fn guard_output_deny_list(
+        &self,
+        guard: &GuardOptions,
+        response: &mut ChatCompletionsResponse,
+    ) -> bool {
+        let mut modified = false;
+        for choice in response.choices.iter_mut() {
+            let texts = choice
+                .message
+                .content
+                .iter_mut()
+                .flat_map(|c| c.texts_mut());
+            if self
+                .scan_with_guard(&GuardType::DenyList, true, guard, texts)
+                .is_empty()
+            {
+                continue;
+            }
+            if guard.action != Some(GuardAction::Mask) {
+                withhold_choice(choice, guard);
+            }
+            modified = true;
+        }
+        modified
+    }
+
+    fn send_guard_violation(
+        &self,
+        guard_type: &GuardType,
+        guard: &GuardOptions,
+        categories: Vec<String>,
+    ) {
+        let body = serde_json::json!({
+            "error": {
+                "type": "guard_violation",
+                "guard": guard_type.to_string(),
+                "message": guard.message(),
+                "categories": categories,
+            }
+        })
+        .to_string();
+        self.send_http_response(
+            StatusCode::BAD_REQUEST.as_u16().into(),
+            vec![("content-type", "application/json")],
+            Some(body.as_bytes()),
+        );
+    }
+
+    fn on_input_moderation_response(
+        &mut self,
+        request: ChatCompletionsRequest,
+        request_body_size: usize,
+        verdict: Result<ModerationResponse, callout::Error>,
+    ) {
+        if let (Some(guard), Some(moderation_response)) = (
+            self.input_guard(&GuardType::Moderation),
+            self.read_moderation(verdict),
+        ) {
+            let violations = moderation_response
+                .results
+                .first()
+                .map(|result| self.moderation_violations(&guard, result))
+                .unwrap_or_default();
+            if !violations.is_empty() {
+                self.send_guard_violation(&GuardType::Moderation, &guard, violations);
+                return;
+            }
+        }
+
+        if self.continue_chat_completions_request(request, request_body_size) == Action::Continue {
+            self.resume_http_request();
+        }
+    }
+
+    // choices in violation are replaced by the message of the guard
+    fn on_output_moderation_response(
+        &mut self,
+        mut response: ChatCompletionsResponse,
+        response_body_size: usize,
+        mut modified: bool,
+        verdict: Result<ModerationResponse, callout::Error>,
+    ) {
+        if let (Some(guard), Some(moderation_response)) = (
+            self.output_guard(&GuardType::Moderation),
+            self.read_moderation(verdict),
+        ) {
+            for (choice, result) in response
+                .choices
+                .iter_mut()
+                .zip(moderation_response.results.iter())
+            {
+                if !self.moderation_violations(&guard, result).is_empty() {
+                    withhold_choice(choice, &guard);
+                    modified = true;
+                }
+            }
+        }
+
+        self.write_response_body(response, response_body_size, modified);
+        self.resume_http_response();
+    }
+
+    // runs the output guards on a complete response and writes it back. Returns Pause while the
+    // moderation guard checks it.
+    fn guard_response(
+        &mut self,
+        mut response: ChatCompletionsResponse,
+        body_size: usize,
+        mut modified: bool,
+    ) -> Action {
+        if let Some(guard) = self.output_guard(&GuardType::DenyList) {
+            modified |= self.guard_output_deny_list(&guard, &mut response);
+        }
+
+        if let Some(guard) = self.output_guard(&GuardType::Moderation) {
+            let input = response
+                .choices
+                .iter()
+                .map(|choice| {
+                    choice
+                        .message
+                        .content
+                        .as_ref()
+                        .map(|content| content.text())
+                        .unwrap_or_default()
+                })
+                .collect();
+            let moderated_response = response.clone();
+            let callout = Callout::json(
+                "output_moderation",
+                move |context: &mut StreamContext, verdict| {
+                    context.on_output_moderation_response(
+                        moderated_response,
+                        body_size,
+                        modified,
+                        verdict,
+                    )
+                },
+            );
+            if self.schedule_moderation(&guard, input, callout) {
+                return Action::Pause;
+            }
+        }
+
+        self.write_response_body(response, body_size, modified);
+        Action::Continue
+    }
+
+    // choices that don't follow the json response_format of the request are repaired by the llm
+    // provider, the client gets an error once no repair is left. Returns Pause while the repair or
+    // the output guards are pending.
+    fn check_json_response(
+        &mut self,
+        mut response: ChatCompletionsResponse,
+        body_size: usize,
+        mut modified: bool,
+    ) -> Action {
+        let Some(response_format) = self.response_format.clone() else {
+            return self.guard_response(response, body_size, modified);
+        };
+
+        let mut invalid_choice = None;
+        // tool calls don't have to follow the response format
+        for (index, choice) in response
+            .choices
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, choice)| choice.message.tool_calls.is_none())
+        {
+            let content = choice
+                .message
+                .content
+                .as_ref()
+                .map(|content| content.text())
+                .unwrap_or_default();
+            match response_format.validate(&content) {
+                Ok(json) if json == content => {}
+                Ok(json) => {
+                    choice.message.content = Some(json.into());
+                    modified = true;
+                }
+                Err(error) => {
+                    invalid_choice = Some((index, content, error));
+                    break;
+                }
+            }
+        }
+
+        let Some((index, content, error)) = invalid_choice else {
+            return self.guard_response(response, body_size, modified);
+        };
+        debug!(
+            "[R={}] response is not valid json: {}",
+            self.request_id, error
+        );
+        self.metrics.json_validation_failures.increment(1);
+        let callout = Callout::json("json_repair", move |context: &mut StreamContext, repair| {
+            context.on_json_repair_response(response, body_size, index, repair)
+        });
+        if self.schedule_json_repair(&content, &error, callout) {
+            return Action::Pause;
+        }
+        self.send_invalid_json_response(&error);
+        Action::Continue
+    }
+
+    // asks the llm provider to correct its response while repairs are left. Returns whether the
+    // response was paused.
+    fn schedule_json_repair(
+        &mut self,
+        content: &str,
+        error: &str,
+        callout: Callout<StreamContext>,
+    ) -> bool {
+        if self.json_repairs_left == 0 {
+            return false;
+        }
+        let Some(request) = self.json_repair_request.as_ref() else {
+            return false;
+        };
+        let repair_request = json_mode::repair_request(request, content, error);
+        let repair_request_str = match serde_json::to_string(&repair_request) {
+            Ok(repair_request_str) => repair_request_str,
+            Err(e) => {
+                warn!("could not serialize json repair request: {}", e);
+                return false;
+            }
+        };
+        match self.dispatch_to_llm_provider(
+            self.llm_provider(),
+            CHAT_COMPLETIONS_PATH,
+            &repair_request_str,
+            Duration::from_secs(JSON_REPAIR_TIMEOUT_SECONDS),
+            callout,
+        ) {
+            Ok(_) => {
+                debug!(
+                    "[R={}] asking {} to repair its json response",
+                    self.request_id,
+                    self.llm_provider().name
+                );
+                self.json_repairs_left -= 1;
+                self.metrics.json_repairs.increment(1);
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "failed to schedule json repair request to {}: {:?}",
+                    self.llm_provider().name,
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    fn read_json_repair(
+        &mut self,
+        response: Result<ChatCompletionsResponse, callout::Error>,
+    ) -> Result<String, String> {
+        let response = response.map_err(|e| format!("repair request failed: {}", e))?;
+        if let Some(usage) = response.usage.as_ref() {
+            self.response_tokens += usage.completion_tokens;
+        }
+        Ok(response
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content.as_ref())
+            .map(|content| content.text())
+            .unwrap_or_default())
+    }
+
+    // the repaired content replaces the invalid choice and the choices are checked again
+    fn on_json_repair_response(
+        &mut self,
+        mut response: ChatCompletionsResponse,
+        response_body_size: usize,
+        index: usize,
+        repair: Result<ChatCompletionsResponse, callout::Error>,
+    ) {
+        match self.read_json_repair(repair) {
+            Ok(content) => {
+                if let Some(choice) = response.choices.get_mut(index) {
+                    choice.message.content = Some(content.into());
+                }
+                if self.check_json_response(response, response_body_size, true) == Action::Continue
+                {
+                    self.resume_http_response();
+                }
+            }
+            Err(error) => {
+                debug!(
+                    "[R={}] json response could not be repaired: {}",
+                    self.request_id, error
+                );
+                self.send_invalid_json_response(&error);
+            }
+        }
+    }
+
+    fn send_invalid_json_response(&mut self, error: &str) {
+        self.metrics.invalid_json_responses.increment(1);
+        self.complete_coalesced_requests(None);
+        let body = serde_json::json!({
+            "error": {
+                "type": "invalid_json_response",
+                "message": error,
+            }
+        })
+        .to_string();
+        self.send_http_response(
+            StatusCode::BAD_GATEWAY.as_u16().into(),
+            vec![("content-type", "application/json")],
+            Some(body.as_bytes()),
+        );
+    }
+
+    // writes the response in the shape the client asked for, a chat completions response is
+    // only rewritten when a guard changed it
+    fn write_response_body(
+        &mut self,
+        response: ChatCompletionsResponse,
+        body_size: usize,
+        modified: bool,
+    ) {
+        let response_body = match self.request_api {
+            RequestApi::ChatCompletions if modified => serde_json::to_string(&response).ok(),
+            _ => self.serialize_response_body(response),
+        };
+        if let Some(response_body) = response_body.as_ref() {
+            self.set_http_response_body(0, body_size, response_body.as_bytes());
+        }
+
+        // identical requests get the guarded and validated response the client gets
+        if self.coalescing_key.is_some() {
+            let response_body = match response_body {
+                Some(response_body) => response_body.into_bytes(),
+                None => self
+                    .get_http_response_body(0, body_size)
+                    .unwrap_or_default(),
+            };
+            self.complete_coalesced_requests(Some(&response_body));
+        }
+    }
+
+    // requests waiting for this one are answered with its response, without one they are sent to
+    // the llm provider themselves
+    fn complete_coalesced_requests(&mut self, response: Option<&[u8]>) {
+        if let Some(key) = self.coalescing_key.take() {
+            coalescing::in_flight_requests().write().unwrap().complete(
+                key,
+                self.context_id,
+                response,
+            );
+        }
+    }
+
+    fn continue_chat_completions_request(
+        &mut self,
+        mut request: ChatCompletionsRequest,
+        body_size: usize,
+    ) -> Action {
+        self.compress_messages(&mut request);
+
+        // the paid summary call is only made for requests within their limits and budget
+        if !self.admit_chat_completions_request(&request) {
+            return Action::Continue;
+        }
+
+        // conversations over the token budget are sent on once their older turns are summarized,
+        // queued requests are resumed by the filter context and are sent without a summary
+        if !self.queued && self.schedule_summary(&request, body_size) {
+            return Action::Pause;
+        }
+
+        self.handle_chat_completions_request(request, body_size)
+    }
+
+    // checks the stream, token and budget limits of the request, a local response is sent when
+    // one is hit. Returns whether the request was admitted or queued.
+    fn admit_chat_completions_request(&mut self, request: &ChatCompletionsRequest) -> bool {
+        // only use the tokens from the messages, excluding the metadata and json tags
+        let input_tokens_str = request.messages.iter().fold(String::new(), |acc, m| {
+            let content = m.content.as_ref().map(|content| content.text());
+            acc + " " + &content.unwrap_or_default()
+        });
+        if let Err(e) = self.open_stream(&request.model) {
+            self.send_server_error(
+                ServerError::ExceededRatelimit(e),
+                Some(StatusCode::TOO_MANY_REQUESTS),
+            );
+            self.metrics
+                .ratelimited_rq
+                .with(&[(Dimension::Model, &request.model)])
+                .increment(1);
+            return false;
+        }
+
+        // enforce ratelimits on ingress
+        if let Err(e) = self.enforce_ratelimits(&request.model, input_tokens_str.as_str()) {
+            let selector = self.ratelimit_selector.clone();
+            if !self.queue_request(&request.model, selector) {
+                self.notify(
+                    NotificationEvent::RatelimitBreach,
+                    serde_json::json!({
+                        "model": request.model,
+                        "message": e.to_string(),
+                    }),
+                );
+                self.send_server_error(
+                    ServerError::ExceededRatelimit(e),
+                    Some(StatusCode::TOO_MANY_REQUESTS),
+                );
+                self.metrics
+                    .ratelimited_rq
+                    .with(&[(Dimension::Model, &request.model)])
+                    .increment(1);
+                return false;
+            }
+        }
 
-        self.set_http_request_header("Authorization", Some(&authorization_header_value));
+        // with prioritization requests wait for a provider backing off instead of being sent to it
+        if self.prioritization.is_some()
+            && !self.queued
+            && routing::is_cooling_down(&self.llm_provider().name, self.unix_seconds())
+            && !self.queue_request(&request.model, None)
+        {
+            self.send_provider_backing_off();
+            return false;
+        }
 
-        Ok(())
+        if let Err(e) = self.enforce_budget() {
+            self.send_budget_exceeded(e);
+            return false;
+        }
+        true
     }
 
-    fn delete_content_length_header(&mut self) {
-        // Remove the Content-Length header because further body manipulations in the gateway logic will invalidate it.
-        // Server's generally throw away requests whose body length do not match the Content-Length header.
-        // However, a missing Content-Length header is not grounds for bad requests given that intermediary hops could
-        // manipulate the body in benign ways e.g., compression.
-        self.set_http_request_header("content-length", None);
-    }
+    fn handle_chat_completions_request(
+        &mut self,
+        mut deserialized_body: ChatCompletionsRequest,
+        body_size: usize,
+    ) -> Action {
+        if let Err(e) = self.fit_context_window(&mut deserialized_body) {
+            self.send_context_window_exceeded(e, &deserialized_body.model);
+            return Action::Continue;
+        }
 
-    fn save_ratelimit_header(&mut self) {
-        self.ratelimit_selector = self
-            .get_http_request_header(RATELIMIT_SELECTOR_HEADER_KEY)
-            .and_then(|key| {
-                self.get_http_request_header(&key)
-                    .map(|value| Header { key, value })
-            });
-    }
+        let chat_completion_request_str = serde_json::to_string(&deserialized_body).unwrap();
 
-    fn send_server_error(&self, error: ServerError, override_status_code: Option<StatusCode>) {
-        debug!("server error occurred: {}", error);
-        self.send_http_response(
-            override_status_code
-                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
-                .as_u16()
-                .into(),
-            vec![],
-            Some(format!("{error}").as_bytes()),
+        trace!(
+            "curve  => {:?}, body: {}",
+            deserialized_body.model,
+            chat_completion_request_str
         );
-    }
 
-    fn enforce_ratelimits(
-        &mut self,
-        model: &str,
-        json_string: &str,
-    ) -> Result<(), ratelimit::Error> {
-        // Tokenize and record token count.
-        let token_count = tokenizer::token_count(model, json_string).unwrap_or(0);
+        if deserialized_body.stream {
+            self.streaming_response = true;
+        }
+        if deserialized_body.stream && deserialized_body.stream_options.is_none() {
+            deserialized_body.stream_options = Some(StreamOptions {
+                include_usage: true,
+            });
+        }
 
-        // Record the token count to metrics.
-        self.metrics
-            .input_sequence_length
-            .record(token_count as u64);
-        log::debug!("Recorded input token count: {}", token_count);
+        let mirrored = self.mirror_request(&deserialized_body);
+        if let Some(audit) = self.audit.as_ref() {
+            // mirrored requests are always audited so that both responses can be compared
+            if mirrored || audit::sampled(audit.sampling_rate) {
+                self.audit_record = Some(AuditRecord {
+                    request_id: Some(self.request_id.clone()),
+                    provider: self.llm_provider().name.clone(),
+                    model: deserialized_body.model.clone(),
+                    request: chat_completion_request_str.clone(),
+                    response: String::new(),
+                    mirror: None,
+                });
+            }
+        }
 
-        // Check if rate limiting needs to be applied.
-        if let Some(selector) = self.ratelimit_selector.take() {
-            log::debug!("Applying ratelimit for model: {}", model);
-            ratelimit::ratelimits(None).read().unwrap().check_limit(
-                model.to_owned(),
-                selector,
-                NonZero::new(token_count as u32).unwrap(),
-            )?;
-        } else {
-            log::debug!("No rate limit applied for model: {}", model);
+        self.set_http_request_body(0, body_size, chat_completion_request_str.as_bytes());
+
+        // responses of prompt targets with an output schema are checked even without json_mode
+        let output_schema_retries = self
+            .prompt_target_config()
+            .filter(|prompt_target| prompt_target.output_schema.is_some())
+            .map(PromptTarget::output_schema_retries);
+        let json_mode = Option::as_ref(&self.json_mode);
+        if !deserialized_body.stream && (json_mode.is_some() || output_schema_retries.is_some()) {
+            self.response_format = ResponseFormat::from_request(&deserialized_body);
+        }
+        self.json_repairs_left = match (output_schema_retries, json_mode) {
+            (Some(retries), _) => retries,
+            (None, Some(json_mode))
+                if json_mode.on_invalid.unwrap_or_default() == OnInvalidJson::Repair =>
+            {
+                1
+            }
+            _ => 0,
+        };
+        if self.response_format.is_some() && self.json_repairs_left > 0 {
+            self.json_repair_request = Some(deserialized_body.clone());
         }
 
-        Ok(())
+        // resumed by the filter context once the request is admitted
+        if self.queued {
+            return Action::Pause;
+        }
+
+        if self.request_coalescing.is_some()
+            && !deserialized_body.stream
+            && self.request_api == RequestApi::ChatCompletions
+        {
+            let key = coalescing::request_key(
+                &self.llm_provider().name,
+                &chat_completion_request_str,
+                self.ratelimit_selector.as_ref(),
+            );
+            let now = self.get_current_time();
+            if coalescing::in_flight_requests()
+                .write()
+                .unwrap()
+                .join(key, self.context_id, now)
+            {
+                debug!(
+                    "[R={}] identical request in flight, waiting for its response",
+                    self.request_id
+                );
+                self.metrics.coalesced_rq.increment(1);
+                return Action::Pause;
+            }
+            self.coalescing_key = Some(key);
+        }
+
+        Action::Continue
     }
 }
 
@@ -172,40 +2113,91 @@ impl StreamContext {
 impl HttpContext for StreamContext {
     // Envoy's HTTP model is event driven. The WASM ABI has given implementors events to hook onto
     // the lifecycle of the http request and response.
-    fn on_http_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
-        self.select_llm_provider();
+    fn on_http_request_headers(&mut self, _num_headers: usize, end_of_stream: bool) -> Action {
+        // latencies are measured on the clock of the host, like the time of the first token
+        self.start_time = self.get_current_time();
 
-        // if endpoint is not set then use provider name as routing header so envoy can resolve the cluster name
-        if self.llm_provider().endpoint.is_none() {
-            self.add_http_request_header(
-                CURVE_ROUTING_HEADER,
-                &self.llm_provider().provider_interface.to_string(),
-            );
-        } else {
-            self.add_http_request_header(CURVE_ROUTING_HEADER, &self.llm_provider().name);
+        if self.handle_cors() {
+            return Action::Pause;
         }
 
-        if let Err(error) = self.modify_auth_headers() {
-            // ensure that the provider has an endpoint if the access key is missing else return a bad request
-            if self.llm_provider.as_ref().unwrap().endpoint.is_none() {
-                self.send_server_error(error, Some(StatusCode::BAD_REQUEST));
+        // the request id is forwarded upstream, one is generated if the client didn't send it
+        self.request_id = match self.get_http_request_header(REQUEST_ID_HEADER) {
+            Some(request_id) => request_id,
+            None => {
+                let request_id = tracing::random_request_id();
+                self.set_http_request_header(REQUEST_ID_HEADER, Some(&request_id));
+                request_id
             }
+        };
+
+        if let Err(e) = self.authenticate() {
+            self.send_unauthenticated(e);
+            return Action::Pause;
+        }
+
+        if !self.read_virtual_key() {
+            self.send_virtual_key_rejection(
+                StatusCode::UNAUTHORIZED,
+                serde_json::json!({ "type": "invalid_api_key" }),
+            );
+            return Action::Pause;
+        }
+
+        if let Err(e) = self.read_overrides() {
+            self.send_server_error(e, Some(StatusCode::FORBIDDEN));
+            return Action::Pause;
+        }
+        // the prompt target can pick the llm provider and model
+        self.prompt_target = self.get_http_request_header(CURVE_PROMPT_TARGET_HEADER);
+
+        // with model aliases the llm provider is picked once the model in the body is known
+        self.routing_deferred = self.model_aliases.is_some() && !end_of_stream;
+        if !self.routing_deferred {
+            // without a model the selection can't fail
+            let _ = self.select_llm_provider(None);
+            self.route_to_llm_provider();
         }
+
         self.delete_content_length_header();
+        if let Err(e) = self.save_content_encoding() {
+            self.send_content_encoding_error(e);
+            return Action::Pause;
+        }
         self.save_ratelimit_header();
+        self.save_priority();
 
-        self.is_chat_completions_request =
-            self.get_http_request_header(":path").unwrap_or_default() == CHAT_COMPLETIONS_PATH;
+        self.request_api = match self.get_http_request_header(":path").as_deref() {
+            Some(COMPLETIONS_PATH) => RequestApi::Completions,
+            Some(RESPONSES_PATH) => RequestApi::Responses,
+            Some(CHAT_COMPLETIONS_PATH) => {
+                self.is_chat_completions_request = true;
+                RequestApi::ChatCompletions
+            }
+            _ => RequestApi::ChatCompletions,
+        };
+        if self.request_api != RequestApi::ChatCompletions {
+            // upstream llms are always called with chat completions
+            self.is_chat_completions_request = true;
+            self.set_http_request_header(":path", Some(CHAT_COMPLETIONS_PATH));
+        }
 
         debug!(
-            "on_http_request_headers S[{}] req_headers={:?}",
+            "on_http_request_headers S[{}] R[{}] req_headers={:?}",
             self.context_id,
+            self.request_id,
             obfuscate_auth_header(&mut self.get_http_request_headers())
         );
 
-        self.request_id = self.get_http_request_header(REQUEST_ID_HEADER);
         self.traceparent = self.get_http_request_header(TRACE_PARENT_HEADER);
 
+        self.include_metadata = self
+            .get_http_request_header(CURVE_INCLUDE_METADATA_HEADER)
+            .is_some_and(|value| value == "true");
+
+        if self.routing_deferred {
+            return Action::Pause;
+        }
         Action::Continue
     }
 
@@ -221,21 +2213,38 @@ impl HttpContext for StreamContext {
             return Action::Pause;
         }
 
+        let body_size = match self.decode_request_body(body_size) {
+            Ok(body_size) => body_size,
+            Err(e) => {
+                self.send_content_encoding_error(e);
+                return Action::Pause;
+            }
+        };
+
+        if self.routing_deferred {
+            self.routing_deferred = false;
+            if let Err(e) = self.select_llm_provider_from_body(body_size) {
+                self.send_server_error(e, Some(StatusCode::BAD_REQUEST));
+                return Action::Pause;
+            }
+        }
+
         if body_size == 0 {
             return Action::Continue;
         }
 
+        if self.is_embeddings_request {
+            return self.handle_embeddings_request_body(body_size);
+        }
+
         // Deserialize body into spec.
         // Currently OpenAI API.
         let mut deserialized_body: ChatCompletionsRequest =
             match self.get_http_request_body(0, body_size) {
-                Some(body_bytes) => match serde_json::from_slice(&body_bytes) {
+                Some(body_bytes) => match self.deserialize_request_body(&body_bytes) {
                     Ok(deserialized) => deserialized,
                     Err(e) => {
-                        self.send_server_error(
-                            ServerError::Deserialization(e),
-                            Some(StatusCode::BAD_REQUEST),
-                        );
+                        self.send_server_error(e, Some(StatusCode::BAD_REQUEST));
                         return Action::Pause;
                     }
                 },
@@ -258,6 +2267,19 @@ impl HttpContext for StreamContext {
             message.model = None;
         }
 
+        // secrets and denied terms never reach the llm provider, nor the moderation api
+        for guard_type in [GuardType::Secrets, GuardType::DenyList] {
+            let Some(guard) = self.input_guard(&guard_type) else {
+                continue;
+            };
+            let texts = guards::user_texts(&mut deserialized_body.messages);
+            let violations = self.scan_with_guard(&guard_type, false, &guard, texts);
+            if !violations.is_empty() && guard.action != Some(GuardAction::Mask) {
+                self.send_guard_violation(&guard_type, &guard, violations);
+                return Action::Pause;
+            }
+        }
+
         self.user_message = deserialized_body
             .messages
             .iter()
@@ -266,55 +2288,90 @@ impl HttpContext for StreamContext {
             .cloned();
 
         // override model name from the llm provider
-        deserialized_body
-            .model
-            .clone_from(&self.llm_provider.as_ref().unwrap().model);
-        let chat_completion_request_str = serde_json::to_string(&deserialized_body).unwrap();
-
-        trace!(
-            "curve  => {:?}, body: {}",
-            deserialized_body.model,
-            chat_completion_request_str
+        deserialized_body.model = self.model().to_string();
+        llm_providers::adapt_request(
+            &mut deserialized_body,
+            &self.llm_provider().provider_interface,
         );
-
-        if deserialized_body.stream {
-            self.streaming_response = true;
-        }
-        if deserialized_body.stream && deserialized_body.stream_options.is_none() {
-            deserialized_body.stream_options = Some(StreamOptions {
-                include_usage: true,
-            });
+        if !self.check_virtual_key_model(&deserialized_body.model) {
+            return Action::Pause;
         }
 
-        // only use the tokens from the messages, excluding the metadata and json tags
-        let input_tokens_str = deserialized_body
-            .messages
-            .iter()
-            .fold(String::new(), |acc, m| {
-                acc + " " + m.content.as_ref().unwrap_or(&String::new())
-            });
-        // enforce ratelimits on ingress
-        if let Err(e) = self.enforce_ratelimits(&deserialized_body.model, input_tokens_str.as_str())
-        {
-            self.send_server_error(
-                ServerError::ExceededRatelimit(e),
-                Some(StatusCode::TOO_MANY_REQUESTS),
+        // the user input is checked by the moderation guard before anything else is done with it
+        let input = self
+            .user_message
+            .as_ref()
+            .and_then(|message| message.content.as_ref())
+            .map(|content| content.text());
+        if let (Some(guard), Some(input)) = (self.input_guard(&GuardType::Moderation), input) {
+            let moderated_request = deserialized_body.clone();
+            let callout = Callout::json(
+                "input_moderation",
+                move |context: &mut StreamContext, verdict| {
+                    context.on_input_moderation_response(moderated_request, body_size, verdict)
+                },
             );
-            self.metrics.ratelimited_rq.increment(1);
-            return Action::Continue;
+            if self.schedule_moderation(&guard, vec![input], callout) {
+                return Action::Pause;
+            }
         }
 
-        self.set_http_request_body(0, body_size, chat_completion_request_str.as_bytes());
-
-        Action::Continue
+        self.continue_chat_completions_request(deserialized_body, body_size)
     }
 
     fn on_http_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
         debug!(
-            "on_http_response_headers [S={}] end_stream={}",
-            self.context_id, _end_of_stream
+            "on_http_response_headers [S={}] [R={}] end_stream={}",
+            self.context_id, self.request_id, _end_of_stream
         );
 
+        self.set_http_response_header(CURVE_REQUEST_ID_HEADER, Some(&self.request_id));
+        self.add_cors_headers();
+
+        if self.llm_provider.is_some() {
+            let status = self.get_http_response_header(":status").unwrap_or_default();
+            let mut labels = self.metric_labels();
+            labels.push((Dimension::Status, &status));
+            self.metrics.llm_responses.with(&labels).increment(1);
+        }
+
+        if self.coalescing_key.is_some() {
+            // requests waiting on a failed request are sent to the provider themselves
+            let status = self.get_http_response_header(":status");
+            if status.as_deref() != Some(StatusCode::OK.as_str()) {
+                self.complete_coalesced_requests(None);
+            }
+        }
+
+        if let Some(experiment_arm) = self.experiment_arm.as_ref() {
+            self.set_http_response_header(CURVE_EXPERIMENT_HEADER, Some(experiment_arm));
+        }
+
+        if let Some(session) = self.session.as_ref() {
+            let status = self
+                .get_http_response_header(":status")
+                .and_then(|status| status.parse::<u16>().ok())
+                .unwrap_or_default();
+            // only provider errors move the session, client errors would fail on any provider
+            if status == StatusCode::TOO_MANY_REQUESTS.as_u16() || status >= 500 {
+                warn!(
+                    "session {} failing over from llm provider {} after status {}",
+                    session,
+                    self.llm_provider().name,
+                    status
+                );
+                routing::fail_over_session(session);
+                self.metrics.session_failovers.increment(1);
+            }
+        }
+
+        self.back_off_rate_limited_provider();
+
+        if self.request_api != RequestApi::ChatCompletions {
+            // the response body is rewritten, see on_http_response_body
+            self.set_http_response_header("content-length", None);
+        }
+
         self.set_property(
             vec!["metadata", "filter_metadata", "llm_filter", "user_prompt"],
             Some("hello world from filter".as_bytes()),
@@ -325,15 +2382,57 @@ impl HttpContext for StreamContext {
 
     fn on_http_response_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
         debug!(
-            "on_http_response_body [S={}] bytes={} end_stream={}",
-            self.context_id, body_size, end_of_stream
+            "on_http_response_body [S={}] [R={}] bytes={} end_stream={}",
+            self.context_id, self.request_id, body_size, end_of_stream
         );
 
+        if let Some(retry_after) = self.retry_after {
+            if !end_of_stream {
+                return Action::Pause;
+            }
+            self.send_rate_limited(retry_after, body_size);
+            return Action::Continue;
+        }
+
         if !self.is_chat_completions_request {
-            debug!("non-chatcompletion request");
+            debug!("[R={}] non-chatcompletion request", self.request_id);
+            return Action::Continue;
+        }
+
+        // buffer the whole response when it has to be translated for the client
+        if self.request_api != RequestApi::ChatCompletions && !end_of_stream {
+            return Action::Pause;
+        }
+
+        // the whole response is buffered as well when identical requests wait for it, they get it
+        // once the output guards and json mode are done with it, see write_response_body
+        if self.coalescing_key.is_some() && !end_of_stream {
+            return Action::Pause;
+        }
+
+        // chunks of passthrough providers go out as they are, only the end of the stream is handled
+        let passthrough_response = self.request_api == RequestApi::ChatCompletions
+            && self
+                .llm_provider
+                .as_ref()
+                .is_some_and(|llm_provider| llm_provider.passthrough_response.unwrap_or_default());
+        if passthrough_response && !(end_of_stream && body_size == 0) {
+            if end_of_stream {
+                self.finish_response(self.get_current_time());
+            }
             return Action::Continue;
         }
 
+        // non streamed responses are checked by json mode and the output guards once complete
+        let output_guards = [GuardType::Moderation, GuardType::DenyList];
+        if !self.streaming_response
+            && !end_of_stream
+            && (self.response_format.is_some()
+                || output_guards.iter().any(|g| self.output_guard(g).is_some()))
+        {
+            return Action::Pause;
+        }
+
         let current_time = get_current_time().unwrap();
         if end_of_stream && body_size == 0 {
             // All streaming responses end with bytes=0 and end_stream=true
@@ -342,21 +2441,40 @@ impl HttpContext for StreamContext {
                 Ok(duration) => {
                     // Convert the duration to milliseconds
                     let duration_ms = duration.as_millis();
-                    debug!("Total latency: {} milliseconds", duration_ms);
+                    debug!(
+                        "[R={}] Total latency: {} milliseconds",
+                        self.request_id, duration_ms
+                    );
                     // Record the latency to the latency histogram
-                    self.metrics.request_latency.record(duration_ms as u64);
+                    self.metrics
+                        .request_latency
+                        .with(&self.metric_labels())
+                        .record(duration_ms as u64);
 
                     if self.response_tokens > 0 {
                         // Compute the time per output token
                         let tpot = duration_ms as u64 / self.response_tokens as u64;
 
-                        debug!("Time per output token: {} milliseconds", tpot);
+                        debug!(
+                            "[R={}] Time per output token: {} milliseconds",
+                            self.request_id, tpot
+                        );
                         // Record the time per output token
-                        self.metrics.time_per_output_token.record(tpot);
+                        self.metrics
+                            .time_per_output_token
+                            .with(&self.metric_labels())
+                            .record(tpot);
+                    }
 
-                        debug!("Tokens per second: {}", 1000 / tpot);
-                        // Record the tokens per second
-                        self.metrics.tokens_per_second.record(1000 / tpot);
+                    if let Some(tokens_per_second) = self.tokens_per_second(duration) {
+                        debug!(
+                            "[R={}] Tokens per second: {}",
+                            self.request_id, tokens_per_second
+                        );
+                        self.metrics
+                            .tokens_per_second
+                            .with(&self.metric_labels())
+                            .record(tokens_per_second);
                     }
                 }
                 Err(e) => {
@@ -366,8 +2484,12 @@ impl HttpContext for StreamContext {
             // Record the output sequence length
             self.metrics
                 .output_sequence_length
+                .with(&self.metric_labels())
                 .record(self.response_tokens as u64);
 
+            self.finish_response(current_time);
+            self.write_audit_record();
+
             if let Some(traceparent) = self.traceparent.as_ref() {
                 let current_time_ns = current_time_ns();
 
@@ -415,8 +2537,8 @@ impl HttpContext for StreamContext {
             let chunk_start = 0;
             let chunk_size = body_size;
             debug!(
-                "streaming response reading, {}..{}",
-                chunk_start, chunk_size
+                "[R={}] streaming response reading, {}..{}",
+                self.request_id, chunk_start, chunk_size
             );
             let streaming_chunk = match self.get_http_response_body(0, chunk_size) {
                 Some(chunk) => chunk,
@@ -438,7 +2560,10 @@ impl HttpContext for StreamContext {
             }
             streaming_chunk
         } else {
-            debug!("non streaming response bytes read: 0:{}", body_size);
+            debug!(
+                "[R={}] non streaming response bytes read: 0:{}",
+                self.request_id, body_size
+            );
             match self.get_http_response_body(0, body_size) {
                 Some(body) => body,
                 None => {
@@ -448,13 +2573,15 @@ impl HttpContext for StreamContext {
             }
         };
 
-        let body_utf8 = match String::from_utf8(body) {
-            Ok(body_utf8) => body_utf8,
-            Err(e) => {
-                debug!("could not convert to utf8: {}", e);
-                return Action::Continue;
+        let content_type = self.get_http_response_header("content-type");
+        let body_utf8 = charset::decode(&body, content_type.as_deref());
+
+        if let Some(audit_record) = self.audit_record.as_mut() {
+            audit_record.response.push_str(&body_utf8);
+            if end_of_stream {
+                self.write_audit_record();
             }
-        };
+        }
 
         if self.streaming_response {
             let chat_completions_chunk_response_events =
@@ -462,15 +2589,16 @@ impl HttpContext for StreamContext {
                     Ok(response) => response,
                     Err(e) => {
                         debug!(
-                            "invalid streaming response: body str: {}, {:?}",
-                            body_utf8, e
+                            "[R={}] invalid streaming response: body str: {}, {:?}",
+                            self.request_id, body_utf8, e
                         );
                         return Action::Continue;
                     }
                 };
 
             if chat_completions_chunk_response_events.events.is_empty() {
-                debug!("empty streaming response");
+                debug!("[R={}] empty streaming response", self.request_id);
+                self.include_metadata_event(&body_utf8, body_size);
                 return Action::Continue;
             }
 
@@ -496,7 +2624,7 @@ impl HttpContext for StreamContext {
                 {
                     Ok(token_count) => token_count,
                     Err(e) => {
-                        debug!("could not get token count: {:?}", e);
+                        debug!("[R={}] could not get token count: {:?}", self.request_id, e);
                         return Action::Continue;
                     }
                 };
@@ -507,47 +2635,106 @@ impl HttpContext for StreamContext {
                 // if let Some(start_time) = self.start_time {
                 let current_time = get_current_time().unwrap();
                 self.ttft_time = Some(current_time_ns());
+                self.ttft_tokens = self.response_tokens;
                 match current_time.duration_since(self.start_time) {
                     Ok(duration) => {
                         let duration_ms = duration.as_millis();
-                        debug!("Time to First Token (TTFT): {} milliseconds", duration_ms);
+                        debug!(
+                            "[R={}] Time to First Token (TTFT): {} milliseconds",
+                            self.request_id, duration_ms
+                        );
                         self.ttft_duration = Some(duration);
-                        self.metrics.time_to_first_token.record(duration_ms as u64);
+                        self.metrics
+                            .time_to_first_token
+                            .with(&self.metric_labels())
+                            .record(duration_ms as u64);
                     }
                     Err(e) => {
                         warn!("SystemTime error: {:?}", e);
                     }
                 }
             }
+
+            self.include_metadata_event(&body_utf8, body_size);
         } else {
-            debug!("non streaming response");
+            debug!("[R={}] non streaming response", self.request_id);
             let chat_completions_response: ChatCompletionsResponse =
                 match serde_json::from_str(body_utf8.as_str()) {
                     Ok(de) => de,
                     Err(_e) => {
-                        debug!("invalid response: {}", body_utf8);
+                        debug!("[R={}] invalid response: {}", self.request_id, body_utf8);
+                        if end_of_stream {
+                            self.finish_response(current_time);
+                        }
                         return Action::Continue;
                     }
                 };
 
-            if chat_completions_response.usage.is_some() {
-                self.response_tokens += chat_completions_response
-                    .usage
-                    .as_ref()
-                    .unwrap()
-                    .completion_tokens;
+            self.response_tokens += match chat_completions_response.usage.as_ref() {
+                Some(usage) => usage.completion_tokens,
+                // the choices are counted when the provider leaves out the usage, all of them when
+                // more than one was asked for with n
+                None => completion_tokens(&chat_completions_response),
+            };
+            // non streamed and buffered responses end with their last bytes, not an empty body
+            if end_of_stream {
+                self.finish_response(current_time);
+            }
+
+            if self.check_json_response(chat_completions_response, body_size, false)
+                == Action::Pause
+            {
+                return Action::Pause;
             }
         }
 
         debug!(
-            "recv [S={}] total_tokens={} end_stream={}",
-            self.context_id, self.response_tokens, end_of_stream
+            "recv [S={}] [R={}] total_tokens={} end_stream={}",
+            self.context_id, self.request_id, self.response_tokens, end_of_stream
         );
+        if end_of_stream {
+            self.finish_response(current_time);
+        }
 
         Action::Continue
     }
 }
 
+// the choice is replaced by the message of the guard
+fn withhold_choice(choice: &mut Choice, guard: &GuardOptions) {
+    choice.message.content = Some(guard.message().to_string().into());
+    choice.message.tool_calls = None;
+    choice.finish_reason = Some("content_filter".to_string());
+}
+
+// the tokens of the contents of all choices, counted like the ones of streamed responses
+fn completion_tokens(response: &ChatCompletionsResponse) -> usize {
+    let contents = response
+        .choices
+        .iter()
+        .filter_map(|choice| choice.message.content.as_ref())
+        .map(|content| content.text())
+        .collect::<Vec<String>>()
+        .join("");
+    tokenizer::token_count("gpt-4", &contents).unwrap_or_default()
+}
+
+fn read_summary(response: Result<ChatCompletionsResponse, callout::Error>) -> Option<String> {
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("summary request failed: {}", e);
+            return None;
+        }
+    };
+    response
+        .choices
+        .first()
+        .and_then(|choice| choice.message.content.as_ref())
+        .map(|content| content.text())
+        .filter(|summary| !summary.trim().is_empty())
+}
+
 fn current_time_ns() -> u128 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -555,4 +2742,85 @@ fn current_time_ns() -> u128 {
         .as_nanos()
 }
 
-impl Context for StreamContext {}
+impl Client for StreamContext {
+    type CallContext = Callout<StreamContext>;
+
+    fn callouts(&self) -> &RefCell<HashMap<u32, Self::CallContext>> {
+        &self.callouts
+    }
+
+    fn waiting_calls(&self) -> Option<&RefCell<HashMap<u32, WaitingCall<Self::CallContext>>>> {
+        Some(&self.waiting_calls)
+    }
+
+    fn callout_metrics(&self) -> &CalloutMetrics {
+        &self.metrics.callouts
+    }
+
+    fn paused_stream_id(&self) -> Option<u32> {
+        Some(self.context_id)
+    }
+}
+
+impl Context for StreamContext {
+    fn on_http_call_response(
+        &mut self,
+        token_id: u32,
+        _num_headers: usize,
+        body_size: usize,
+        _num_trailers: usize,
+    ) {
+        // the response of a wake up call resumes the call waiting for a free slot
+        let waiting = self.waiting_calls.borrow().contains_key(&token_id);
+        let callout = match self.remove_call_context(token_id) {
+            Some(callout) => callout,
+            None if waiting => return,
+            None => {
+                warn!("no call context found for token_id: {}", token_id);
+                return;
+            }
+        };
+
+        let body = self
+            .get_http_call_response_body(0, body_size)
+            .unwrap_or_default();
+        let response = self.call_response(body);
+        debug!(
+            "[R={}] {} response: {}",
+            self.request_id,
+            callout.name(),
+            response.status
+        );
+        callout.handle(self, response);
+    }
+
+    fn on_done(&mut self) -> bool {
+        drain::paused_streams()
+            .write()
+            .unwrap()
+            .done(self.context_id);
+        // a stream that ended without a response body sends the waiting requests themselves
+        self.complete_coalesced_requests(None);
+        self.release_in_flight();
+        if let Some(stream_in_flight) = self.stream_in_flight.take() {
+            ratelimit::ratelimits(None)
+                .read()
+                .unwrap()
+                .release(stream_in_flight);
+        }
+        if self.queued {
+            // admitted requests count against the concurrency limit that let them through
+            let in_flight = priority::request_queue()
+                .write()
+                .unwrap()
+                .remove(self.context_id);
+            if let Some(in_flight) = in_flight {
+                ratelimit::ratelimits(None)
+                    .read()
+                    .unwrap()
+                    .release(in_flight);
+            }
+        }
+        true
+    }
+}
@@ -18,6 +18,17 @@ fn wasm_module() -> String {
 fn request_headers_expectations(module: &mut Tester, http_context: i32) {
     module
         .call_proxy_on_request_headers(http_context, 0, false)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("x-request-id"))
+        .returning(Some("req-1"))
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("x-curve -provider"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("x-curve -model"))
+        .returning(None)
+        .expect_get_header_map_value(
+            Some(MapType::HttpRequestHeaders),
+            Some("x-curve -prompt-target"),
+        )
+        .returning(None)
         .expect_get_header_map_value(
             Some(MapType::HttpRequestHeaders),
             Some("x-curve -llm-provider-hint"),
@@ -25,9 +36,12 @@ fn request_headers_expectations(module: &mut Tester, http_context: i32) {
         .returning(Some("default"))
         .expect_log(
             Some(LogLevel::Debug),
-            Some("llm provider hint: Some(Default)"),
+            Some("[R=req-1] llm provider hint: Some(Default)"),
+        )
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("[R=req-1] selected llm: open-ai-gpt-4"),
         )
-        .expect_log(Some(LogLevel::Debug), Some("selected llm: open-ai-gpt-4"))
         .expect_add_header_map_value(
             Some(MapType::HttpRequestHeaders),
             Some("x-curve -llm-provider"),
@@ -39,6 +53,10 @@ fn request_headers_expectations(module: &mut Tester, http_context: i32) {
             Some("Bearer secret_key"),
         )
         .expect_remove_header_map_value(Some(MapType::HttpRequestHeaders), Some("content-length"))
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("accept-encoding"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("content-encoding"))
+        .returning(None)
         .expect_get_header_map_value(
             Some(MapType::HttpRequestHeaders),
             Some("x-curve -ratelimit-selector"),
@@ -51,10 +69,13 @@ fn request_headers_expectations(module: &mut Tester, http_context: i32) {
         .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some(":path"))
         .returning(Some("/v1/chat/completions"))
         .expect_log(Some(LogLevel::Debug), None)
-        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("x-request-id"))
-        .returning(None)
         .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("traceparent"))
         .returning(None)
+        .expect_get_header_map_value(
+            Some(MapType::HttpRequestHeaders),
+            Some("x-curve -include-metadata"),
+        )
+        .returning(None)
         .execute_and_expect(ReturnType::Action(Action::Continue))
         .unwrap();
 }
@@ -75,13 +96,24 @@ fn setup_filter(module: &mut Tester, config: &str) -> i32 {
     module
         .call_proxy_on_context_create(filter_context, 0)
         .expect_metric_creation(MetricType::Gauge, "active_http_calls")
-        .expect_metric_creation(MetricType::Counter, "ratelimited_rq")
-        .expect_metric_creation(MetricType::Histogram, "time_to_first_token")
-        .expect_metric_creation(MetricType::Histogram, "time_per_output_token")
-        .expect_metric_creation(MetricType::Histogram, "tokens_per_second")
-        .expect_metric_creation(MetricType::Histogram, "request_latency")
-        .expect_metric_creation(MetricType::Histogram, "output_sequence_length")
-        .expect_metric_creation(MetricType::Histogram, "input_sequence_length")
+        .expect_metric_creation(MetricType::Counter, "embeddings_rq")
+        .expect_metric_creation(MetricType::Counter, "mirrored_rq")
+        .expect_metric_creation(MetricType::Counter, "session_failovers")
+        .expect_metric_creation(MetricType::Counter, "coalesced_rq")
+        .expect_metric_creation(MetricType::Counter, "jwt_rejections")
+        .expect_metric_creation(MetricType::Counter, "virtual_key_rejections")
+        .expect_metric_creation(MetricType::Counter, "summarized_rq")
+        .expect_metric_creation(MetricType::Counter, "summarization_failures")
+        .expect_metric_creation(MetricType::Counter, "context_window_rejections")
+        .expect_metric_creation(MetricType::Counter, "context_window_truncations")
+        .expect_metric_creation(MetricType::Counter, "compressed_rq")
+        .expect_metric_creation(MetricType::Counter, "compression_saved_tokens")
+        .expect_metric_creation(MetricType::Counter, "guard_violations")
+        .expect_metric_creation(MetricType::Counter, "guard_failures")
+        .expect_metric_creation(MetricType::Counter, "json_repairs")
+        .expect_metric_creation(MetricType::Counter, "invalid_json_responses")
+        .expect_metric_creation(MetricType::Counter, "json_validation_failures")
+        .expect_metric_creation(MetricType::Counter, "aborted_rq")
         .execute_and_expect(ReturnType::None)
         .unwrap();
 
@@ -219,7 +251,8 @@ fn llm_gateway_successful_request_to_open_ai_chat_completions() {
         .returning(Some(chat_completions_request_body))
         .expect_log(Some(LogLevel::Trace), None)
         .expect_log(Some(LogLevel::Debug), None)
-        .expect_metric_record("input_sequence_length", 21)
+        .expect_metric_creation(MetricType::Histogram, "input_sequence_length.model.gpt-4")
+        .expect_metric_record("input_sequence_length.model.gpt-4", 21)
         .expect_log(Some(LogLevel::Debug), None)
         .expect_log(Some(LogLevel::Debug), None)
         .expect_log(Some(LogLevel::Debug), None)
@@ -340,7 +373,8 @@ fn llm_gateway_request_ratelimited() {
         // The actual call is not important in this test, we just need to grab the token_id
         .expect_log(Some(LogLevel::Trace), None)
         .expect_log(Some(LogLevel::Debug), None)
-        .expect_metric_record("input_sequence_length", 107)
+        .expect_metric_creation(MetricType::Histogram, "input_sequence_length.model.gpt-4")
+        .expect_metric_record("input_sequence_length.model.gpt-4", 107)
         .expect_log(Some(LogLevel::Debug), None)
         .expect_log(Some(LogLevel::Debug), None)
         .expect_log(Some(LogLevel::Debug), None)
@@ -351,7 +385,8 @@ fn llm_gateway_request_ratelimited() {
             None,
             None,
         )
-        .expect_metric_increment("ratelimited_rq", 1)
+        .expect_metric_creation(MetricType::Counter, "ratelimited_rq.model.gpt-4")
+        .expect_metric_increment("ratelimited_rq.model.gpt-4", 1)
         .execute_and_expect(ReturnType::Action(Action::Continue))
         .unwrap();
 }
@@ -406,7 +441,8 @@ fn llm_gateway_request_not_ratelimited() {
         // The actual call is not important in this test, we just need to grab the token_id
         .expect_log(Some(LogLevel::Trace), None)
         .expect_log(Some(LogLevel::Debug), None)
-        .expect_metric_record("input_sequence_length", 29)
+        .expect_metric_creation(MetricType::Histogram, "input_sequence_length.model.gpt-4")
+        .expect_metric_record("input_sequence_length.model.gpt-4", 29)
         .expect_log(Some(LogLevel::Debug), None)
         .expect_log(Some(LogLevel::Debug), None)
         .expect_log(Some(LogLevel::Debug), None)
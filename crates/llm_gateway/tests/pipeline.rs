@@ -0,0 +1,607 @@
+// The llm gateway run natively against the mock host of test_harness. Callouts of the guards are
+// answered with canned responses, so routing and guarding can be followed from the client request
+// to the request sent on to the llm provider.
+extern crate llm_gateway;
+
+use common::consts::{
+    CHAT_COMPLETIONS_PATH, CURVE_INTERNAL_CLUSTER_NAME, CURVE_PROVIDER_HINT_HEADER,
+    CURVE_ROUTING_HEADER, CURVE_UPSTREAM_HOST_HEADER, EMBEDDINGS_PATH, MODERATIONS_PATH,
+    RATELIMIT_SELECTOR_HEADER_KEY,
+};
+use serde_json::{json, Value};
+use serial_test::serial;
+use std::time::Duration;
+use test_harness::{Action, CallResponse, Host, Stream};
+
+const CONFIG: &str = r#"
+version: "0.1-beta"
+
+listener:
+  address: 0.0.0.0
+  port: 10000
+  message_format: huggingface
+  connect_timeout: 0.005s
+
+llm_providers:
+  - name: open-ai-gpt-4
+    provider_interface: openai
+    access_key: secret_key
+    model: gpt-4
+    default: true
+  - name: open-ai-gpt-4o
+    provider_interface: openai
+    access_key: secret_key
+    model: gpt-4o
+"#;
+
+const DENY_LIST_GUARD: &str = r#"
+prompt_guards:
+  input_guards:
+    deny_list:
+      words:
+        - project-x
+      on_exception:
+        message: "I can't talk about that."
+"#;
+
+const MODERATION_GUARD: &str = r#"
+prompt_guards:
+  input_guards:
+    moderation:
+      llm_provider: open-ai-gpt-4
+"#;
+
+fn start_stream(host: &mut Host, config: &str, headers: &[(&str, &str)]) -> Stream {
+    assert!(host.configure(config));
+    let stream = host.create_stream();
+    let mut request_headers = vec![
+        (":method", "POST"),
+        (":path", CHAT_COMPLETIONS_PATH),
+        ("content-type", "application/json"),
+    ];
+    request_headers.extend_from_slice(headers);
+    assert_eq!(
+        host.send_request_headers(stream, &request_headers, false),
+        Action::Continue
+    );
+    stream
+}
+
+fn chat_completions_request(prompt: &str) -> Vec<u8> {
+    serde_json::to_vec(&json!({
+        "model": "gpt-4",
+        "messages": [{ "role": "user", "content": prompt }],
+    }))
+    .unwrap()
+}
+
+fn stream_chunk(contents: &[&str]) -> Vec<u8> {
+    contents
+        .iter()
+        .map(|content| {
+            let event = json!({
+                "model": "gpt-4",
+                "choices": [{ "delta": { "content": content }, "finish_reason": null }],
+            });
+            format!("data: {}\n\n", event)
+        })
+        .collect::<String>()
+        .into_bytes()
+}
+
+fn moderation_response(flagged: bool) -> CallResponse {
+    CallResponse::json(
+        200,
+        &json!({
+            "results": [{ "flagged": flagged, "categories": { "violence": flagged } }],
+        }),
+    )
+}
+
+#[test]
+#[serial]
+fn request_is_routed_to_the_default_provider() {
+    let mut host = Host::new();
+    let stream = start_stream(&mut host, CONFIG, &[]);
+
+    assert_eq!(
+        host.request_header(stream, CURVE_ROUTING_HEADER).as_deref(),
+        Some("openai")
+    );
+    assert_eq!(
+        host.request_header(stream, "authorization").as_deref(),
+        Some("Bearer secret_key")
+    );
+
+    let body = chat_completions_request("hello");
+    assert_eq!(
+        host.send_request_body(stream, &body, true),
+        Action::Continue
+    );
+    let llm_request: Value = serde_json::from_slice(&host.request_body(stream)).unwrap();
+    assert_eq!(llm_request["model"], "gpt-4");
+    assert!(host.local_response(stream).is_none());
+    assert!(host.http_calls().is_empty());
+}
+
+#[test]
+#[serial]
+fn provider_hint_picks_the_provider() {
+    let mut host = Host::new();
+    let stream = start_stream(
+        &mut host,
+        CONFIG,
+        &[(CURVE_PROVIDER_HINT_HEADER, "open-ai-gpt-4o")],
+    );
+
+    let body = chat_completions_request("hello");
+    assert_eq!(
+        host.send_request_body(stream, &body, true),
+        Action::Continue
+    );
+    let llm_request: Value = serde_json::from_slice(&host.request_body(stream)).unwrap();
+    assert_eq!(llm_request["model"], "gpt-4o");
+}
+
+#[test]
+#[serial]
+fn deny_list_guard_blocks_the_request() {
+    let mut host = Host::new();
+    let config = format!("{}{}", CONFIG, DENY_LIST_GUARD);
+    let stream = start_stream(&mut host, &config, &[]);
+
+    let body = chat_completions_request("what is the status of project-x?");
+    assert_eq!(host.send_request_body(stream, &body, true), Action::Pause);
+
+    let local_response = host.local_response(stream).unwrap();
+    assert_eq!(local_response.status, 400);
+    let error = &local_response.json()["error"];
+    assert_eq!(error["type"], "guard_violation");
+    assert_eq!(error["guard"], "deny_list");
+    assert_eq!(error["message"], "I can't talk about that.");
+}
+
+#[test]
+#[serial]
+fn moderation_guard_blocks_flagged_input() {
+    let mut host = Host::new();
+    let config = format!("{}{}", CONFIG, MODERATION_GUARD);
+    let stream = start_stream(&mut host, &config, &[]);
+
+    let body = chat_completions_request("something violent");
+    assert_eq!(host.send_request_body(stream, &body, true), Action::Pause);
+
+    let calls = host.http_calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].upstream, CURVE_INTERNAL_CLUSTER_NAME);
+    assert_eq!(calls[0].header(CURVE_UPSTREAM_HOST_HEADER), Some("openai"));
+    assert_eq!(calls[0].path(), MODERATIONS_PATH);
+    assert_eq!(calls[0].json()["input"][0], "something violent");
+
+    host.mock_call(MODERATIONS_PATH, moderation_response(true));
+    host.run_calls();
+
+    let local_response = host.local_response(stream).unwrap();
+    assert_eq!(local_response.status, 400);
+    assert_eq!(
+        local_response.json()["error"]["categories"],
+        json!(["violence"])
+    );
+    assert!(!host.request_resumed(stream));
+}
+
+#[test]
+#[serial]
+fn moderation_guard_lets_clean_input_through() {
+    let mut host = Host::new();
+    let config = format!("{}{}", CONFIG, MODERATION_GUARD);
+    let stream = start_stream(&mut host, &config, &[]);
+
+    let body = chat_completions_request("hello");
+    assert_eq!(host.send_request_body(stream, &body, true), Action::Pause);
+    host.mock_call(MODERATIONS_PATH, moderation_response(false));
+    host.run_calls();
+
+    assert!(host.local_response(stream).is_none());
+    assert!(host.request_resumed(stream));
+    let llm_request: Value = serde_json::from_slice(&host.request_body(stream)).unwrap();
+    assert!(llm_request["messages"][0]["content"]
+        .to_string()
+        .contains("hello"));
+}
+
+#[test]
+#[serial]
+fn streaming_rate_is_recorded_per_provider() {
+    let mut host = Host::new();
+    let stream = start_stream(&mut host, CONFIG, &[]);
+
+    let body = serde_json::to_vec(&json!({
+        "model": "gpt-4",
+        "messages": [{ "role": "user", "content": "hello" }],
+        "stream": true,
+    }))
+    .unwrap();
+    assert_eq!(
+        host.send_request_body(stream, &body, true),
+        Action::Continue
+    );
+    host.send_response_headers(
+        stream,
+        &[(":status", "200"), ("content-type", "text/event-stream")],
+        false,
+    );
+
+    host.advance_time(Duration::from_millis(400));
+    host.send_response_body(stream, &stream_chunk(&["Hello"]), false);
+    host.advance_time(Duration::from_millis(2000));
+    host.send_response_body(
+        stream,
+        &stream_chunk(&[" world", ",", " how", " are", " you", "?"]),
+        false,
+    );
+    host.send_response_body(stream, b"", true);
+
+    assert_eq!(
+        host.metric("time_to_first_token.provider.open-ai-gpt-4.model.gpt-4"),
+        Some(400)
+    );
+    // the six tokens after the first one came in over two seconds
+    assert_eq!(
+        host.metric("tokens_per_second.provider.open-ai-gpt-4.model.gpt-4"),
+        Some(3)
+    );
+    assert_eq!(
+        host.metric("output_sequence_length.provider.open-ai-gpt-4.model.gpt-4"),
+        Some(7)
+    );
+}
+
+const BUDGET: &str = r#"
+costs:
+  prices:
+    - model: gpt-4
+      input_per_million_tokens: 30
+      output_per_million_tokens: 60
+  budgets:
+    - selector:
+        key: x-team
+      limit: 0.0001
+      period: day
+"#;
+
+fn send_request(host: &mut Host, headers: &[(&str, &str)], prompt: &str) -> Stream {
+    let stream = host.create_stream();
+    let mut request_headers = vec![
+        (":method", "POST"),
+        (":path", CHAT_COMPLETIONS_PATH),
+        ("content-type", "application/json"),
+    ];
+    request_headers.extend_from_slice(headers);
+    host.send_request_headers(stream, &request_headers, false);
+    host.send_request_body(stream, &chat_completions_request(prompt), true);
+    stream
+}
+
+#[test]
+#[serial]
+fn spend_of_non_streamed_responses_counts_against_the_budget() {
+    let mut host = Host::new();
+    assert!(host.configure(&format!("{}{}", CONFIG, BUDGET)));
+    let headers = [(RATELIMIT_SELECTOR_HEADER_KEY, "x-team"), ("x-team", "red")];
+
+    let stream = send_request(&mut host, &headers, "hello");
+    assert!(host.local_response(stream).is_none());
+    host.send_response_headers(
+        stream,
+        &[(":status", "200"), ("content-type", "application/json")],
+        false,
+    );
+    let response = json!({
+        "model": "gpt-4",
+        "choices": [{
+            "index": 0,
+            "finish_reason": "stop",
+            "message": { "role": "assistant", "content": "Hello! How can I help?" },
+        }],
+        "usage": { "prompt_tokens": 8, "completion_tokens": 7, "total_tokens": 15 },
+    });
+    // the whole response comes in one piece that ends the stream
+    assert_eq!(
+        host.send_response_body(stream, &serde_json::to_vec(&response).unwrap(), true),
+        Action::Continue
+    );
+
+    // the first response spent more than the budget of the team
+    let stream = send_request(&mut host, &headers, "hello again");
+    let local_response = host.local_response(stream).unwrap();
+    assert_eq!(local_response.status, 402);
+    assert_eq!(local_response.json()["error"]["type"], "budget_exceeded");
+
+    // other teams have their own budget
+    let stream = send_request(
+        &mut host,
+        &[
+            (RATELIMIT_SELECTOR_HEADER_KEY, "x-team"),
+            ("x-team", "blue"),
+        ],
+        "hello",
+    );
+    assert!(host.local_response(stream).is_none());
+}
+
+const COALESCED_OUTPUT_GUARD: &str = r#"
+request_coalescing:
+  timeout_seconds: 30
+prompt_guards:
+  output_guards:
+    deny_list:
+      words:
+        - project-x
+      action: mask
+"#;
+
+#[test]
+#[serial]
+fn coalesced_requests_get_the_guarded_response() {
+    let mut host = Host::new();
+    assert!(host.configure(&format!("{}{}", CONFIG, COALESCED_OUTPUT_GUARD)));
+
+    let leader = send_request(&mut host, &[], "what is new?");
+    let waiter = send_request(&mut host, &[], "what is new?");
+    assert!(host.local_response(leader).is_none());
+    assert!(host.local_response(waiter).is_none());
+    assert!(!host.request_resumed(waiter));
+
+    host.send_response_headers(
+        leader,
+        &[(":status", "200"), ("content-type", "application/json")],
+        false,
+    );
+    let response = json!({
+        "model": "gpt-4",
+        "choices": [{
+            "index": 0,
+            "finish_reason": "stop",
+            "message": { "role": "assistant", "content": "project-x launches today" },
+        }],
+    });
+    assert_eq!(
+        host.send_response_body(leader, &serde_json::to_vec(&response).unwrap(), true),
+        Action::Continue
+    );
+    let guarded = host.response_body(leader);
+    assert!(!String::from_utf8_lossy(&guarded).contains("project-x"));
+
+    host.tick();
+    let local_response = host.local_response(waiter).unwrap();
+    assert_eq!(local_response.status, 200);
+    assert_eq!(local_response.body, guarded);
+}
+
+const VIRTUAL_KEYS: &str = r#"
+virtual_keys:
+  keys:
+    - name: team-a
+      key: team-a-key
+      models:
+        - gpt-4o
+"#;
+
+#[test]
+#[serial]
+fn model_of_the_virtual_key_is_checked_before_any_callout() {
+    let mut host = Host::new();
+    let config = format!("{}{}{}", CONFIG, MODERATION_GUARD, VIRTUAL_KEYS);
+    let stream = start_stream(
+        &mut host,
+        &config,
+        &[("authorization", "Bearer team-a-key")],
+    );
+
+    let body = chat_completions_request("hello");
+    assert_eq!(host.send_request_body(stream, &body, true), Action::Pause);
+
+    // the moderation callout is never made for a model the key may not call
+    assert!(host.http_calls().is_empty());
+    let local_response = host.local_response(stream).unwrap();
+    assert_eq!(local_response.status, 403);
+    assert_eq!(local_response.json()["error"]["type"], "model_not_allowed");
+    assert_eq!(local_response.json()["error"]["model"], "gpt-4");
+}
+
+#[test]
+#[serial]
+fn embeddings_are_held_to_the_models_of_the_virtual_key() {
+    let mut host = Host::new();
+    assert!(host.configure(&format!("{}{}", CONFIG, VIRTUAL_KEYS)));
+    let stream = host.create_stream();
+    host.send_request_headers(
+        stream,
+        &[
+            (":method", "POST"),
+            (":path", EMBEDDINGS_PATH),
+            ("content-type", "application/json"),
+            ("authorization", "Bearer team-a-key"),
+        ],
+        false,
+    );
+    let body = serde_json::to_vec(&json!({
+        "model": "text-embedding-3-small",
+        "input": "hello",
+    }))
+    .unwrap();
+    assert_eq!(host.send_request_body(stream, &body, true), Action::Pause);
+
+    let local_response = host.local_response(stream).unwrap();
+    assert_eq!(local_response.status, 403);
+    assert_eq!(local_response.json()["error"]["type"], "model_not_allowed");
+    assert!(!host.request_resumed(stream));
+}
+
+#[test]
+#[serial]
+fn virtual_key_request_for_a_model_unknown_to_the_tokenizer_goes_on() {
+    let mut host = Host::new();
+    let config = format!(
+        "{}{}",
+        CONFIG,
+        r#"  - name: mistral-large
+    provider_interface: mistral
+    access_key: secret_key
+    model: mistral-large-latest
+virtual_keys:
+  keys:
+    - name: team-a
+      key: team-a-key
+      models:
+        - mistral-large-latest
+"#
+    );
+    let stream = start_stream(
+        &mut host,
+        &config,
+        &[
+            ("authorization", "Bearer team-a-key"),
+            (CURVE_PROVIDER_HINT_HEADER, "mistral-large"),
+        ],
+    );
+
+    // the tokenizer counts no tokens for the model, the ratelimit of the key still applies
+    let body = serde_json::to_vec(&json!({
+        "model": "mistral-large-latest",
+        "messages": [{ "role": "user", "content": "hello" }],
+    }))
+    .unwrap();
+    assert_eq!(
+        host.send_request_body(stream, &body, true),
+        Action::Continue
+    );
+    assert!(host.local_response(stream).is_none());
+    let llm_request: Value = serde_json::from_slice(&host.request_body(stream)).unwrap();
+    assert_eq!(llm_request["model"], "mistral-large-latest");
+}
+
+fn send_embeddings_request(host: &mut Host, body: Value) -> (Stream, Action) {
+    let stream = host.create_stream();
+    host.send_request_headers(
+        stream,
+        &[
+            (":method", "POST"),
+            (":path", EMBEDDINGS_PATH),
+            ("content-type", "application/json"),
+            (RATELIMIT_SELECTOR_HEADER_KEY, "x-user-id"),
+            ("x-user-id", "alice"),
+        ],
+        false,
+    );
+    let action = host.send_request_body(stream, &serde_json::to_vec(&body).unwrap(), true);
+    (stream, action)
+}
+
+#[test]
+#[serial]
+fn embeddings_without_input_are_rejected_before_they_are_ratelimited() {
+    let mut host = Host::new();
+    assert!(host.configure(CONFIG));
+
+    let (stream, action) = send_embeddings_request(
+        &mut host,
+        json!({ "model": "text-embedding-3-small", "input": "" }),
+    );
+    assert_eq!(action, Action::Pause);
+    assert_eq!(host.local_response(stream).unwrap().status, 400);
+
+    let (stream, action) = send_embeddings_request(
+        &mut host,
+        json!({ "model": "text-embedding-3-small", "input": ["", " "] }),
+    );
+    assert_eq!(action, Action::Pause);
+    assert_eq!(host.local_response(stream).unwrap().status, 400);
+}
+
+#[test]
+#[serial]
+fn embeddings_of_a_model_unknown_to_the_tokenizer_are_ratelimited() {
+    let mut host = Host::new();
+    assert!(host.configure(CONFIG));
+
+    let (stream, action) = send_embeddings_request(
+        &mut host,
+        json!({ "model": "nomic-embed-text", "input": "hello" }),
+    );
+    assert_eq!(action, Action::Continue);
+    assert!(host.local_response(stream).is_none());
+    let request: Value = serde_json::from_slice(&host.request_body(stream)).unwrap();
+    assert_eq!(request["model"], "nomic-embed-text");
+}
+
+const SUMMARIZATION: &str = r#"
+summarization:
+  llm_provider: open-ai-gpt-4
+  max_tokens: 10
+  keep_messages: 1
+"#;
+
+fn long_conversation() -> Vec<u8> {
+    serde_json::to_vec(&json!({
+        "model": "gpt-4",
+        "messages": [
+            { "role": "user", "content": "tell me everything about the history of the roman empire" },
+            { "role": "assistant", "content": "the roman empire was founded in 27 BC by augustus" },
+            { "role": "user", "content": "and how did it end?" },
+        ],
+    }))
+    .unwrap()
+}
+
+#[test]
+#[serial]
+fn summary_is_only_requested_within_the_budget_and_paid_from_it() {
+    let mut host = Host::new();
+    assert!(host.configure(&format!("{}{}{}", CONFIG, BUDGET, SUMMARIZATION)));
+    let request_headers = [
+        (":method", "POST"),
+        (":path", CHAT_COMPLETIONS_PATH),
+        ("content-type", "application/json"),
+        (RATELIMIT_SELECTOR_HEADER_KEY, "x-team"),
+        ("x-team", "red"),
+    ];
+
+    let stream = host.create_stream();
+    host.send_request_headers(stream, &request_headers, false);
+    assert_eq!(
+        host.send_request_body(stream, &long_conversation(), true),
+        Action::Pause
+    );
+    let calls = host.http_calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].path(), CHAT_COMPLETIONS_PATH);
+
+    host.mock_call(
+        CHAT_COMPLETIONS_PATH,
+        CallResponse::json(
+            200,
+            &json!({
+                "model": "gpt-4",
+                "choices": [{
+                    "index": 0,
+                    "finish_reason": "stop",
+                    "message": { "role": "assistant", "content": "the user asked about rome" },
+                }],
+                "usage": { "completion_tokens": 5000 },
+            }),
+        ),
+    );
+    host.run_calls();
+    assert!(host.request_resumed(stream));
+
+    // the summary spent the budget of the team, no summary is requested for the next request
+    let stream = host.create_stream();
+    host.send_request_headers(stream, &request_headers, false);
+    host.send_request_body(stream, &long_conversation(), true);
+    assert!(host.http_calls().is_empty());
+    let local_response = host.local_response(stream).unwrap();
+    assert_eq!(local_response.status, 402);
+    assert_eq!(local_response.json()["error"]["type"], "budget_exceeded");
+}
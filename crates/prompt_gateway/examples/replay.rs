@@ -0,0 +1,59 @@
+// Replays a request captured with debug_capture against the gateway, with the callouts answered
+// from the trace:
+//
+//   cargo run -p prompt_gateway --example replay -- <config> <trace>
+//
+// The config is the curve config the gateway runs with, the trace one of the traces posted to the
+// debug capture sink.
+extern crate prompt_gateway;
+
+use common::capture::CaptureTrace;
+use std::{env, fs, process};
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 2 {
+        eprintln!("usage: replay <config> <trace>");
+        process::exit(2);
+    }
+    let config = read(&args[0]);
+    let trace: CaptureTrace = serde_json::from_str(&read(&args[1])).unwrap_or_else(|e| {
+        eprintln!("invalid trace {}: {}", args[1], e);
+        process::exit(1);
+    });
+
+    let replay = test_harness::replay(&config, &trace).unwrap_or_else(|e| {
+        eprintln!("could not replay {}: {}", trace.request_id, e);
+        process::exit(1);
+    });
+
+    println!("request: {}", trace.request_id);
+    for call in &replay.calls {
+        println!("callout: {} {}", call.upstream, call.path());
+    }
+    println!(
+        "prompt target: {} (captured: {})",
+        replay.prompt_target().unwrap_or("none"),
+        trace.prompt_target.as_deref().unwrap_or("none")
+    );
+    match replay.local_response.as_ref() {
+        Some(response) => println!("response: {} {}", response.status, response.text()),
+        None => println!(
+            "request to the llm: {}",
+            String::from_utf8_lossy(&replay.request_body)
+        ),
+    }
+    for divergence in &replay.divergences {
+        println!("divergence: {}", divergence);
+    }
+    if !replay.divergences.is_empty() {
+        process::exit(1);
+    }
+}
+
+fn read(path: &str) -> String {
+    fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("could not read {}: {}", path, e);
+        process::exit(1);
+    })
+}
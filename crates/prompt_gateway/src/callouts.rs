@@ -0,0 +1,189 @@
+use crate::stream_context::{StreamCallContext, StreamContext};
+use common::callout::{Callout, CalloutResponse};
+use common::configuration::HookPoint;
+use common::errors::ServerError;
+use common::http::Client;
+use http::StatusCode;
+use log::warn;
+
+// Callouts of the stream, each built with the handler of its response when it is dispatched. The
+// handlers only get the responses of the statuses they handle, other statuses fail the request.
+pub type StreamCallout = Callout<StreamContext>;
+
+// builds the callout of a call made for the stream, passed along to the code dispatching the call
+pub type CalloutBuilder = fn(StreamCallContext) -> StreamCallout;
+
+type Handler = fn(&mut StreamContext, Vec<u8>, StreamCallContext);
+
+// first stage of intent matching, narrows down the prompt targets to a group
+pub fn fc_group(callout_context: StreamCallContext) -> StreamCallout {
+    failing_over(
+        "fc_group",
+        callout_context,
+        StreamContext::curve _fc_group_response_handler,
+    )
+}
+
+pub fn fc(callout_context: StreamCallContext) -> StreamCallout {
+    failing_over(
+        "fc",
+        callout_context,
+        StreamContext::curve _fc_response_handler,
+    )
+}
+
+// accepted api calls are left to the handler, they may be async operations
+pub fn function_call(callout_context: StreamCallContext) -> StreamCallout {
+    expecting(
+        "function_call",
+        &[StatusCode::OK, StatusCode::ACCEPTED],
+        callout_context,
+        StreamContext::api_call_response_handler,
+    )
+}
+
+// the endpoint response only reaches the llm once the guard let it through
+pub fn tool_response_guard(callout_context: StreamCallContext) -> StreamCallout {
+    expecting(
+        "tool_response_guard",
+        &[StatusCode::OK],
+        callout_context,
+        StreamContext::tool_response_guard_handler,
+    )
+}
+
+// shadow responses never reach the conversation, errors included
+pub fn shadow_call(callout_context: StreamCallContext) -> StreamCallout {
+    Callout::new(
+        "shadow_call",
+        move |context: &mut StreamContext, response| {
+            context.shadow_call_response_handler(
+                response.status.as_str(),
+                response.body,
+                callout_context,
+            )
+        },
+    )
+}
+
+// errors of chain steps are handled as configured for the step
+pub fn chain_step(callout_context: StreamCallContext) -> StreamCallout {
+    Callout::new(
+        "chain_step",
+        move |context: &mut StreamContext, response| {
+            context.chain_step_response_handler(
+                response.status.as_str(),
+                response.body,
+                callout_context,
+            )
+        },
+    )
+}
+
+pub fn default_target(callout_context: StreamCallContext) -> StreamCallout {
+    expecting(
+        "default_target",
+        &[StatusCode::OK],
+        callout_context,
+        StreamContext::default_target_handler,
+    )
+}
+
+pub fn error_target(callout_context: StreamCallContext) -> StreamCallout {
+    expecting(
+        "error_target",
+        &[StatusCode::OK],
+        callout_context,
+        |context, body, _| context.error_target_handler(body),
+    )
+}
+
+// the request goes on from the point of the hook once it answered
+pub fn pre_guard_hook(callout_context: StreamCallContext) -> StreamCallout {
+    hook(callout_context, |context, body, callout_context| {
+        context.hook_response_handler(HookPoint::PreGuard, body, callout_context)
+    })
+}
+
+pub fn post_intent_hook(callout_context: StreamCallContext) -> StreamCallout {
+    hook(callout_context, |context, body, callout_context| {
+        context.hook_response_handler(HookPoint::PostIntent, body, callout_context)
+    })
+}
+
+pub fn pre_provider_hook(callout_context: StreamCallContext) -> StreamCallout {
+    hook(callout_context, |context, body, callout_context| {
+        context.hook_response_handler(HookPoint::PreProvider, body, callout_context)
+    })
+}
+
+pub fn post_response_hook(callout_context: StreamCallContext) -> StreamCallout {
+    hook(callout_context, |context, body, callout_context| {
+        context.hook_response_handler(HookPoint::PostResponse, body, callout_context)
+    })
+}
+
+fn hook(callout_context: StreamCallContext, handler: Handler) -> StreamCallout {
+    expecting("hook", &[StatusCode::OK], callout_context, handler)
+}
+
+pub fn error_response(callout_context: StreamCallContext) -> StreamCallout {
+    expecting(
+        "error_response",
+        &[StatusCode::OK],
+        callout_context,
+        |context, body, _| context.error_response_handler(body),
+    )
+}
+
+fn expecting(
+    name: &'static str,
+    statuses: &'static [StatusCode],
+    callout_context: StreamCallContext,
+    handler: Handler,
+) -> StreamCallout {
+    Callout::new(name, move |context: &mut StreamContext, response| {
+        if !statuses.contains(&response.status) {
+            let error = upstream_error(context, &response, &callout_context);
+            return context.send_server_error(error, Some(response.status));
+        }
+        handler(context, response.body, callout_context)
+    })
+}
+
+// function calling that fails falls back as configured in the overrides and failure policies
+fn failing_over(
+    name: &'static str,
+    callout_context: StreamCallContext,
+    handler: Handler,
+) -> StreamCallout {
+    Callout::new(name, move |context: &mut StreamContext, response| {
+        if response.status != StatusCode::OK {
+            let error = upstream_error(context, &response, &callout_context);
+            return context.handle_function_calling_failure(
+                error,
+                Some(response.status),
+                callout_context,
+            );
+        }
+        handler(context, response.body, callout_context)
+    })
+}
+
+fn upstream_error(
+    context: &StreamContext,
+    response: &CalloutResponse,
+    callout_context: &StreamCallContext,
+) -> ServerError {
+    let server_error = ServerError::Upstream {
+        host: callout_context.upstream_cluster.clone().unwrap_or_default(),
+        path: callout_context
+            .upstream_cluster_path
+            .clone()
+            .unwrap_or_default(),
+        status: response.status.as_str().to_string(),
+        body: context.call_response_text(&response.body),
+    };
+    warn!("filter received non 2xx code: {:?}", server_error);
+    server_error
+}
@@ -1,12 +1,9 @@
-use std::str::FromStr;
-
-use common::errors::ServerError;
-use common::stats::IncrementingMetric;
-use http::StatusCode;
+use common::drain;
+use common::http::Client;
 use log::{debug, warn};
 use proxy_wasm::traits::Context;
 
-use crate::stream_context::{ResponseHandlerType, StreamContext};
+use crate::stream_context::StreamContext;
 
 impl Context for StreamContext {
     fn on_http_call_response(
@@ -16,41 +13,42 @@ impl Context for StreamContext {
         body_size: usize,
         _num_trailers: usize,
     ) {
-        let callout_context = self
-            .callouts
-            .get_mut()
-            .remove(&token_id)
-            .expect("invalid token_id");
-        self.metrics.active_http_calls.increment(-1);
+        // the response of a wake up call resumes the call waiting for a free slot
+        let waiting = self.waiting_calls.borrow().contains_key(&token_id);
+        let callout = match self.remove_call_context(token_id) {
+            Some(callout) => callout,
+            None if waiting => return,
+            None => {
+                warn!(
+                    "[R={}] response of unknown http call with id={}",
+                    self.request_id, token_id
+                );
+                return;
+            }
+        };
 
         let body = self
             .get_http_call_response_body(0, body_size)
             .unwrap_or(vec![]);
+        self.capture_call_response(token_id, &body);
 
-        let http_status = self
-            .get_http_call_response_header(":status")
-            .unwrap_or(StatusCode::OK.as_str().to_string());
-        debug!("http call response code: {}", http_status);
-        if http_status != StatusCode::OK.as_str() {
-            let server_error = ServerError::Upstream {
-                host: callout_context.upstream_cluster.unwrap(),
-                path: callout_context.upstream_cluster_path.unwrap(),
-                status: http_status.clone(),
-                body: String::from_utf8(body).unwrap(),
-            };
-            warn!("filter received non 2xx code: {:?}", server_error);
-            return self.send_server_error(
-                server_error,
-                Some(StatusCode::from_str(http_status.as_str()).unwrap()),
-            );
-        }
+        // the callout decides what to do with its response, see callouts.rs
+        let response = self.call_response(body);
+        debug!(
+            "[R={}] http call response of {}, code: {}",
+            self.request_id,
+            callout.name(),
+            response.status
+        );
+        callout.handle(self, response);
+    }
 
-        debug!("http call response handler type: {:?}", callout_context.response_handler_type);
-        #[cfg_attr(any(), rustfmt::skip)]
-        match callout_context.response_handler_type {
-            ResponseHandlerType::CurveFC => self.curve _fc_response_handler(body, callout_context),
-            ResponseHandlerType::FunctionCall => self.api_call_response_handler(body, callout_context),
-            ResponseHandlerType::DefaultTarget =>self.default_target_handler(body, callout_context),
-        }
+    fn on_done(&mut self) -> bool {
+        drain::paused_streams()
+            .write()
+            .unwrap()
+            .done(self.context_id);
+        self.finish_capture();
+        true
     }
 }
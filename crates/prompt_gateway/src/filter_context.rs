@@ -1,17 +1,42 @@
 use crate::metrics::Metrics;
 use crate::stream_context::StreamContext;
-use common::configuration::{Configuration, Overrides, PromptGuards, PromptTarget, Tracing};
-use common::http::Client;
-use common::stats::Gauge;
-use log::debug;
+use common::callout_limits;
+use common::config_validation;
+use common::configuration::{
+    AccessControl, Admin, BodyEncoding, ClientToolsMode, Configuration, Cors, DebugCapture,
+    ErrorTargetDetail, FailurePolicies, Hook, JwtAuth, LlmProvider, Overrides, Pipeline,
+    PromptGuards, PromptTarget, PromptTargetGroup, RequestLimits, Tracing,
+};
+use common::consts::{
+    CAPTURE_TIMEOUT_SECONDS, DEFAULT_GUARD_MODEL, DEFAULT_INTENT_MODEL, JWKS_FETCH_TIMEOUT_SECONDS,
+    NOTIFICATION_TIMEOUT_SECONDS,
+};
+use common::drain;
+use common::fault_injection;
+use common::http::{CallArgs, CalloutMetrics, Client};
+use common::jwt;
+use common::logging;
+use common::notifications::{self, Batch};
+use common::ratelimit;
+use common::stats::IncrementingMetric;
+use http::Method;
+use log::{debug, error, warn};
 use proxy_wasm::traits::*;
 use proxy_wasm::types::*;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+// callouts of the filter are the JWKS request of jwt_auth, the notifications and the capture traces
 #[derive(Debug)]
-pub struct FilterCallContext {}
+pub struct FilterCallContext {
+    // notifications posted to the webhook, posted again when the webhook doesn't accept them
+    notification_batch: Option<Batch>,
+    // a trace posted to the debug capture sink, traces the sink doesn't accept are dropped
+    capture: bool,
+}
 
 #[derive(Debug)]
 pub struct FilterContext {
@@ -21,8 +46,31 @@ pub struct FilterContext {
     overrides: Rc<Option<Overrides>>,
     system_prompt: Rc<Option<String>>,
     prompt_targets: Rc<HashMap<String, PromptTarget>>,
+    prompt_target_groups: Rc<HashMap<String, PromptTargetGroup>>,
+    error_target: Rc<Option<ErrorTargetDetail>>,
     prompt_guards: Rc<PromptGuards>,
     tracing: Rc<Option<Tracing>>,
+    request_limits: Rc<Option<RequestLimits>>,
+    client_tools_mode: ClientToolsMode,
+    function_calling_provider: Rc<Option<LlmProvider>>,
+    function_calling_stream: bool,
+    intent_model: Rc<String>,
+    guard_model: Rc<String>,
+    admin: Rc<Option<Admin>>,
+    // the config candidate prompt targets are checked against, only kept for the admin routes
+    configuration: Rc<Option<Configuration>>,
+    access_control: Rc<Option<AccessControl>>,
+    jwt_auth: Rc<Option<JwtAuth>>,
+    failure_policies: Rc<Option<FailurePolicies>>,
+    pipeline: Rc<Option<Pipeline>>,
+    hooks: Rc<Vec<Hook>>,
+    cors: Rc<Option<Cors>>,
+    body_encoding: Rc<Option<BodyEncoding>>,
+    debug_capture: Rc<Option<DebugCapture>>,
+    // traces of the captured requests waiting to be posted to the sink
+    capture_queue: Arc<Mutex<VecDeque<String>>>,
+    // the vm is shutting down, it is done once the callouts flushing the notifications returned
+    draining: bool,
 }
 
 impl FilterContext {
@@ -32,13 +80,130 @@ impl FilterContext {
             metrics: Rc::new(Metrics::new()),
             system_prompt: Rc::new(None),
             prompt_targets: Rc::new(HashMap::new()),
+            prompt_target_groups: Rc::new(HashMap::new()),
+            error_target: Rc::new(None),
             overrides: Rc::new(None),
             prompt_guards: Rc::new(PromptGuards::default()),
             tracing: Rc::new(None),
+            request_limits: Rc::new(None),
+            client_tools_mode: ClientToolsMode::default(),
+            function_calling_provider: Rc::new(None),
+            function_calling_stream: false,
+            intent_model: Rc::new(DEFAULT_INTENT_MODEL.to_string()),
+            guard_model: Rc::new(DEFAULT_GUARD_MODEL.to_string()),
+            admin: Rc::new(None),
+            configuration: Rc::new(None),
+            access_control: Rc::new(None),
+            jwt_auth: Rc::new(None),
+            failure_policies: Rc::new(None),
+            pipeline: Rc::new(None),
+            hooks: Rc::new(Vec::new()),
+            cors: Rc::new(None),
+            body_encoding: Rc::new(None),
+            debug_capture: Rc::new(None),
+            capture_queue: Arc::new(Mutex::new(VecDeque::new())),
+            draining: false,
+        }
+    }
+}
+
+impl FilterContext {
+    // the signing keys of jwt_auth are fetched through the internal listener once they are stale
+    fn fetch_jwks(&self) {
+        let jwks = match Option::as_ref(&self.jwt_auth) {
+            Some(jwt_auth) => &jwt_auth.jwks,
+            None => return,
+        };
+        let now = self.get_current_time();
+        let jwks_cache = jwt::jwks_cache();
+        if !jwks_cache.read().unwrap().should_fetch(now, jwks.ttl()) {
+            return;
+        }
+
+        let call_args = CallArgs::internal(&jwks.endpoint, Method::GET, jwks.path())
+            .with_timeout(Duration::from_secs(JWKS_FETCH_TIMEOUT_SECONDS));
+        let call_context = FilterCallContext {
+            notification_batch: None,
+            capture: false,
+        };
+        match self.http_call(call_args, call_context) {
+            Ok(_) => jwks_cache.write().unwrap().fetching(now),
+            Err(error) => warn!(
+                "failed to schedule jwks request to {}: {:?}",
+                jwks.endpoint, error
+            ),
         }
     }
 }
 
+impl FilterContext {
+    // post the queued notifications in batches to the notifications webhook
+    fn send_notifications(&self) {
+        let (webhook, batches) = {
+            let mut notifier = notifications::notifier().write().unwrap();
+            match notifier.webhook().cloned() {
+                Some(webhook) => (webhook, notifier.batches()),
+                None => return,
+            }
+        };
+        let path = webhook.path.clone().unwrap_or(String::from("/"));
+        let auth_header = webhook.auth.as_ref().and_then(|auth| auth.static_header());
+
+        for batch in batches {
+            let mut call_args = CallArgs::internal(&webhook.name, Method::POST, &path)
+                .with_json_body(batch.body.as_bytes())
+                .with_timeout(Duration::from_secs(NOTIFICATION_TIMEOUT_SECONDS));
+            if let Some((key, value)) = auth_header.as_ref() {
+                call_args = call_args.with_header(key, value);
+            }
+            let call_context = FilterCallContext {
+                notification_batch: Some(batch.clone()),
+                capture: false,
+            };
+            if let Err(error) = self.http_call(call_args, call_context) {
+                warn!(
+                    "failed to schedule notifications to {}: {:?}",
+                    webhook.name, error
+                );
+                notifications::notifier().write().unwrap().retry(batch);
+            }
+        }
+    }
+}
+
+impl FilterContext {
+    // post the traces of the captured requests to the debug capture sink
+    fn send_captures(&self) {
+        let Some(debug_capture) = self.debug_capture.as_ref() else {
+            return;
+        };
+        let sink = &debug_capture.sink;
+        let path = sink.path.clone().unwrap_or(String::from("/"));
+        let auth_header = sink.auth.as_ref().and_then(|auth| auth.static_header());
+
+        let _ = self.capture_queue.try_lock().map(|mut capture_queue| {
+            while let Some(trace) = capture_queue.pop_front() {
+                let mut call_args = CallArgs::internal(&sink.name, Method::POST, &path)
+                    .with_json_body(trace.as_bytes())
+                    .with_timeout(Duration::from_secs(CAPTURE_TIMEOUT_SECONDS));
+                if let Some((key, value)) = auth_header.as_ref() {
+                    call_args = call_args.with_header(key, value);
+                }
+                let call_context = FilterCallContext {
+                    notification_batch: None,
+                    capture: true,
+                };
+                if let Err(error) = self.http_call(call_args, call_context) {
+                    warn!(
+                        "failed to schedule capture trace to {}: {:?}",
+                        sink.name, error
+                    );
+                }
+            }
+        });
+    }
+}
+
 impl Client for FilterContext {
     type CallContext = FilterCallContext;
 
@@ -46,39 +211,151 @@ impl Client for FilterContext {
         &self.callouts
     }
 
-    fn active_http_calls(&self) -> &Gauge {
-        &self.metrics.active_http_calls
+    fn callout_metrics(&self) -> &CalloutMetrics {
+        &self.metrics.callouts
     }
 }
 
-impl Context for FilterContext {}
+impl Context for FilterContext {
+    fn on_http_call_response(
+        &mut self,
+        token_id: u32,
+        _num_headers: usize,
+        body_size: usize,
+        _num_trailers: usize,
+    ) {
+        let call_context = match self.remove_call_context(token_id) {
+            Some(call_context) => call_context,
+            None => {
+                warn!("http call response for unknown token_id: {}", token_id);
+                return;
+            }
+        };
+        if self.draining && self.callouts.borrow().is_empty() {
+            self.done();
+        }
+
+        let status = self.get_http_call_response_header(":status");
+        if let Some(batch) = call_context.notification_batch {
+            if !status
+                .as_ref()
+                .is_some_and(|status| status.starts_with('2'))
+            {
+                warn!("notification webhook responded with status {:?}", status);
+                notifications::notifier().write().unwrap().retry(batch);
+            }
+            return;
+        }
+        if call_context.capture {
+            if !status
+                .as_ref()
+                .is_some_and(|status| status.starts_with('2'))
+            {
+                warn!("capture sink responded with status {:?}", status);
+            }
+            return;
+        }
+        if status.as_deref() != Some("200") {
+            warn!("jwks request failed with status {:?}", status);
+            return;
+        }
+        let body = self
+            .get_http_call_response_body(0, body_size)
+            .unwrap_or_default();
+        let now = self.get_current_time();
+        if let Err(e) = jwt::jwks_cache().write().unwrap().update(&body, now) {
+            warn!("could not parse jwks: {}", e);
+        }
+    }
+}
 
 // RootContext allows the Rust code to reach into the Envoy Config
 impl RootContext for FilterContext {
     fn on_configure(&mut self, _: usize) -> bool {
-        let config_bytes = self
-            .get_plugin_configuration()
-            .expect("Curve config cannot be empty");
+        let config_bytes = match self.get_plugin_configuration() {
+            Some(config_bytes) => config_bytes,
+            None => {
+                error!("invalid config: config cannot be empty");
+                return false;
+            }
+        };
 
-        let config: Configuration = match serde_yaml::from_slice(&config_bytes) {
+        let config = match config_validation::parse(&config_bytes) {
             Ok(config) => config,
-            Err(err) => panic!("Invalid curve  config \"{:?}\"", err),
+            Err(errors) => {
+                for error in errors {
+                    error!("invalid config: {}", error);
+                }
+                return false;
+            }
         };
 
+        logging::configure(config.logging.as_ref());
+        callout_limits::configure(config.callout_limits.as_deref());
+        fault_injection::configure(config.fault_injection.as_deref());
+        notifications::configure(config.notifications.as_ref());
+
+        self.configuration = Rc::new(config.admin.is_some().then(|| config.clone()));
+
+        self.intent_model = Rc::new(config.intent_model().to_string());
+        self.guard_model = Rc::new(config.guard_model().to_string());
         self.overrides = Rc::new(config.overrides);
+        self.client_tools_mode = config.listener.client_tools.unwrap_or_default();
+
+        let prompt_targets_config = config.prompt_targets.unwrap_or_default();
+        ratelimit::prompt_target_ratelimits(Some(&prompt_targets_config));
+
+        let mut prompt_target_groups = HashMap::new();
+        for group in config.prompt_target_groups.unwrap_or_default() {
+            prompt_target_groups.insert(group.name.clone(), group);
+        }
 
         let mut prompt_targets = HashMap::new();
-        for pt in config.prompt_targets.unwrap_or_default() {
+        for pt in prompt_targets_config {
             prompt_targets.insert(pt.name.clone(), pt.clone());
         }
         self.system_prompt = Rc::new(config.system_prompt);
         self.prompt_targets = Rc::new(prompt_targets);
+        self.prompt_target_groups = Rc::new(prompt_target_groups);
+        self.error_target = Rc::new(config.error_target);
 
         if let Some(prompt_guards) = config.prompt_guards {
             self.prompt_guards = Rc::new(prompt_guards)
         }
 
         self.tracing = Rc::new(config.tracing);
+        self.request_limits = Rc::new(config.request_limits);
+        self.admin = Rc::new(config.admin);
+        self.access_control = Rc::new(config.access_control);
+        self.jwt_auth = Rc::new(config.jwt_auth);
+        self.failure_policies = Rc::new(config.failure_policies);
+        self.pipeline = Rc::new(config.pipeline);
+        self.hooks = Rc::new(config.hooks.unwrap_or_default());
+        self.cors = Rc::new(config.cors);
+        self.body_encoding = Rc::new(config.body_encoding);
+        self.debug_capture = Rc::new(config.debug_capture);
+        if self.jwt_auth.is_some() || config.notifications.is_some() || self.debug_capture.is_some()
+        {
+            // the signing keys are fetched, the notifications and capture traces are sent on tick
+            self.set_tick_period(Duration::from_secs(1));
+        }
+
+        self.function_calling_stream = config
+            .function_calling
+            .as_ref()
+            .and_then(|function_calling| function_calling.stream)
+            .unwrap_or_default();
+        let function_calling_provider = config
+            .function_calling
+            .and_then(|function_calling| function_calling.llm_provider)
+            .and_then(|provider_name| {
+                config
+                    .llm_providers
+                    .iter()
+                    .find(|provider| provider.name == provider_name)
+                    .cloned()
+            });
+        self.function_calling_provider = Rc::new(function_calling_provider);
 
         true
     }
@@ -94,8 +371,27 @@ impl RootContext for FilterContext {
             Rc::clone(&self.metrics),
             Rc::clone(&self.system_prompt),
             Rc::clone(&self.prompt_targets),
+            Rc::clone(&self.prompt_target_groups),
+            Rc::clone(&self.error_target),
             Rc::clone(&self.overrides),
             Rc::clone(&self.tracing),
+            Rc::clone(&self.request_limits),
+            self.client_tools_mode,
+            Rc::clone(&self.function_calling_provider),
+            self.function_calling_stream,
+            Rc::clone(&self.intent_model),
+            Rc::clone(&self.guard_model),
+            Rc::clone(&self.admin),
+            Rc::clone(&self.configuration),
+            Rc::clone(&self.access_control),
+            Rc::clone(&self.jwt_auth),
+            Rc::clone(&self.failure_policies),
+            Rc::clone(&self.pipeline),
+            Rc::clone(&self.hooks),
+            Rc::clone(&self.cors),
+            Rc::clone(&self.body_encoding),
+            Rc::clone(&self.debug_capture),
+            Arc::clone(&self.capture_queue),
         )))
     }
 
@@ -106,4 +402,26 @@ impl RootContext for FilterContext {
     fn on_vm_start(&mut self, _: usize) -> bool {
         true
     }
+
+    fn on_tick(&mut self) {
+        self.fetch_jwks();
+        self.send_notifications();
+        self.send_captures();
+    }
+
+    // Streams waiting on callouts are answered with a 503, the notifications and capture traces are
+    // flushed. The vm is done once the flushing callouts returned.
+    fn on_done(&mut self) -> bool {
+        let context_ids = drain::paused_streams().write().unwrap().take();
+        let aborted = drain::abort_streams(&context_ids);
+        if aborted > 0 {
+            warn!("vm shutting down, aborted {} requests", aborted);
+            self.metrics.aborted_rq.increment(aborted as i64);
+        }
+
+        self.send_notifications();
+        self.send_captures();
+        self.draining = true;
+        self.callouts.borrow().is_empty()
+    }
 }
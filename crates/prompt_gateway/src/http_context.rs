@@ -1,16 +1,18 @@
-use crate::stream_context::{ResponseHandlerType, StreamCallContext, StreamContext};
+use crate::stream_context::StreamContext;
 use common::{
-    api::open_ai::{
-        self, CurveState, ChatCompletionStreamResponse, ChatCompletionTool, ChatCompletionsRequest,
-    },
+    api::open_ai::{self, ChatCompletionStreamResponse, ChatCompletionsRequest},
+    charset, conditions,
+    configuration::{ClientToolsMode, EndpointAuth},
     consts::{
-        CURVE_FC_MODEL_NAME, CURVE_INTERNAL_CLUSTER_NAME, CURVE_STATE_HEADER,
-        CURVE_UPSTREAM_HOST_HEADER, ASSISTANT_ROLE, CHAT_COMPLETIONS_PATH, HEALTHZ_PATH,
-        MODEL_SERVER_NAME, REQUEST_ID_HEADER, TOOL_ROLE, TRACE_PARENT_HEADER, USER_ROLE,
+        ASSISTANT_ROLE, CHAT_COMPLETIONS_PATH, CURVE_CACHE_BYPASS_HEADER,
+        CURVE_CLIENT_TOOLS_HEADER, CURVE_DEBUG_ROUTE_PATH, CURVE_FC_MODEL_NAME,
+        CURVE_PROMPT_TARGET_HEADER, CURVE_REQUEST_ID_HEADER, CURVE_STATE_HEADER,
+        CURVE_VALIDATE_PROMPT_TARGET_PATH, HEALTHZ_PATH, REQUEST_ID_HEADER, TOOL_ROLE,
+        TRACE_PARENT_HEADER,
     },
     errors::ServerError,
-    http::{CallArgs, Client},
     pii::obfuscate_auth_header,
+    tracing,
 };
 use http::StatusCode;
 use log::{debug, trace, warn};
@@ -18,7 +20,8 @@ use proxy_wasm::{traits::HttpContext, types::Action};
 use serde_json::Value;
 use std::{
     collections::HashMap,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 // HttpContext is the trait that allows the Rust code to interact with HTTP objects.
@@ -31,6 +34,8 @@ impl HttpContext for StreamContext {
         // However, a missing Content-Length header is not grounds for bad requests given that intermediary hops could
         // manipulate the body in benign ways e.g., compression.
         self.set_http_request_header("content-length", None);
+        // the llm gateway trusts the prompt target header, only the prompt gateway may set it
+        self.set_http_request_header(CURVE_PROMPT_TARGET_HEADER, None);
 
         let request_path = self.get_http_request_header(":path").unwrap_or_default();
         if request_path == HEALTHZ_PATH {
@@ -38,16 +43,102 @@ impl HttpContext for StreamContext {
             return Action::Continue;
         }
 
+        if self.handle_cors() {
+            return Action::Continue;
+        }
+
+        if let Err(e) = self.save_content_encoding() {
+            self.send_content_encoding_error(e);
+            return Action::Continue;
+        }
+
+        if request_path == CURVE_DEBUG_ROUTE_PATH {
+            if let Err(status_code) = self.check_admin_access() {
+                self.send_http_response(status_code.as_u16().into(), vec![], None);
+                return Action::Continue;
+            }
+            self.debug_route = true;
+        }
+
+        if request_path == CURVE_VALIDATE_PROMPT_TARGET_PATH {
+            if let Err(status_code) = self.check_admin_access() {
+                self.send_http_response(status_code.as_u16().into(), vec![], None);
+                return Action::Continue;
+            }
+            self.validate_prompt_target = true;
+        }
+
         self.is_chat_completions_request = request_path == CHAT_COMPLETIONS_PATH;
 
+        // the request id is forwarded upstream, one is generated if the client didn't send it
+        self.request_id = match self.get_http_request_header(REQUEST_ID_HEADER) {
+            Some(request_id) => request_id,
+            None => {
+                let request_id = tracing::random_request_id();
+                self.set_http_request_header(REQUEST_ID_HEADER, Some(&request_id));
+                request_id
+            }
+        };
+
+        if self.is_chat_completions_request {
+            self.start_capture();
+        }
+
+        // admin routes are authorized with the admin token instead
+        if !self.debug_route && !self.validate_prompt_target {
+            if let Err(e) = self.authenticate() {
+                self.send_unauthenticated(e);
+                return Action::Pause;
+            }
+        }
+
         trace!(
-            "on_http_request_headers S[{}] req_headers={:?}",
+            "on_http_request_headers S[{}] R[{}] req_headers={:?}",
             self.context_id,
+            self.request_id,
             obfuscate_auth_header(&mut self.get_http_request_headers())
         );
 
-        self.request_id = self.get_http_request_header(REQUEST_ID_HEADER);
         self.traceparent = self.get_http_request_header(TRACE_PARENT_HEADER);
+
+        if let Some(client_tools) = self.get_http_request_header(CURVE_CLIENT_TOOLS_HEADER) {
+            match client_tools.parse::<ClientToolsMode>() {
+                Ok(client_tools_mode) => self.client_tools_mode = client_tools_mode,
+                Err(e) => warn!("ignoring {} header: {}", CURVE_CLIENT_TOOLS_HEADER, e),
+            }
+        }
+
+        // capture inbound headers that prompt target endpoints forward as auth
+        let prompt_targets = Rc::clone(&self.prompt_targets);
+        for prompt_target in prompt_targets.values() {
+            for endpoint in prompt_target.endpoints() {
+                if let Some(EndpointAuth::Passthrough { header }) = endpoint.auth.as_ref() {
+                    if let Some(value) = self.get_http_request_header(header) {
+                        self.passthrough_headers.insert(header.clone(), value);
+                    }
+                }
+            }
+        }
+
+        if let Some(access_control) = self.access_control.as_ref() {
+            self.client_identity = self.get_http_request_header(&access_control.header);
+        }
+
+        let target_conditions = prompt_targets
+            .values()
+            .filter_map(|pt| pt.conditions.as_ref());
+        for header in conditions::headers(target_conditions) {
+            if let Some(value) = self.get_http_request_header(header) {
+                self.condition_headers.insert(header.to_string(), value);
+            }
+        }
+
+        if prompt_targets.values().any(|pt| pt.cache.is_some()) {
+            self.cache_bypass = self
+                .get_http_request_header(CURVE_CACHE_BYPASS_HEADER)
+                .is_some_and(|value| value == "true");
+        }
+
         Action::Continue
     }
 
@@ -55,10 +146,45 @@ impl HttpContext for StreamContext {
         // Let the client send the gateway all the data before sending to the LLM_provider.
         // TODO: consider a streaming API.
 
+        // reject oversized bodies as soon as the buffered size crosses the limit
+        if let Some(max_body_bytes) = self
+            .request_limits
+            .as_ref()
+            .as_ref()
+            .and_then(|limits| limits.max_body_bytes)
+        {
+            if body_size > max_body_bytes {
+                self.reject_request(
+                    ServerError::RequestLimitExceeded {
+                        limit: "max_body_bytes".to_string(),
+                        actual: body_size,
+                        max: max_body_bytes,
+                    },
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                );
+                return Action::Pause;
+            }
+        }
+
         if !end_of_stream {
             return Action::Pause;
         }
 
+        let body_size = match self.decode_request_body(body_size) {
+            Ok(body_size) => body_size,
+            Err(e) => {
+                self.send_content_encoding_error(e);
+                return Action::Pause;
+            }
+        };
+        self.capture_request(body_size);
+
+        if self.validate_prompt_target {
+            let body = self.get_http_request_body(0, body_size).unwrap_or_default();
+            self.send_prompt_target_validation(&body);
+            return Action::Pause;
+        }
+
         if body_size == 0 {
             return Action::Continue;
         }
@@ -86,7 +212,8 @@ impl HttpContext for StreamContext {
         };
 
         debug!(
-            "developer => curve: {}",
+            "[R={}] developer => curve: {}",
+            self.request_id,
             String::from_utf8_lossy(&body_bytes)
         );
 
@@ -103,105 +230,17 @@ impl HttpContext for StreamContext {
             }
         };
 
-        self.curve _state = match deserialized_body.metadata {
-            Some(ref metadata) => {
-                if metadata.contains_key(CURVE_STATE_HEADER) {
-                    let curve _state_str = metadata[CURVE_STATE_HEADER].clone();
-                    let curve _state: Vec<CurveState> = serde_json::from_str(&curve _state_str).unwrap();
-                    Some(curve _state)
-                } else {
-                    None
-                }
-            }
-            None => None,
-        };
-
-        self.streaming_response = deserialized_body.stream;
-
-        let last_user_prompt = match deserialized_body
-            .messages
-            .iter()
-            .filter(|msg| msg.role == USER_ROLE)
-            .last()
-        {
-            Some(content) => content,
-            None => {
-                warn!("No messages in the request body");
-                return Action::Continue;
-            }
-        };
-
-        self.user_prompt = Some(last_user_prompt.clone());
-
-        // convert prompt targets to ChatCompletionTool
-        let tool_calls: Vec<ChatCompletionTool> = self
-            .prompt_targets
-            .iter()
-            .map(|(_, pt)| pt.into())
-            .collect();
-
-        let curve _fc_chat_completion_request = ChatCompletionsRequest {
-            messages: deserialized_body.messages.clone(),
-            metadata: deserialized_body.metadata.clone(),
-            stream: deserialized_body.stream,
-            model: "--".to_string(),
-            stream_options: deserialized_body.stream_options.clone(),
-            tools: Some(tool_calls),
-        };
-
-        self.chat_completions_request = Some(deserialized_body);
-
-        let json_data = match serde_json::to_string(&curve _fc_chat_completion_request) {
-            Ok(json_data) => json_data,
-            Err(error) => {
-                self.send_server_error(ServerError::Serialization(error), None);
-                return Action::Pause;
-            }
-        };
-
-        debug!("curve => curve fc: {}", json_data);
-
-        let mut headers = vec![
-            (CURVE_UPSTREAM_HOST_HEADER, MODEL_SERVER_NAME),
-            (":method", "POST"),
-            (":path", "/function_calling"),
-            ("content-type", "application/json"),
-            (":authority", MODEL_SERVER_NAME),
-        ];
-
-        if self.request_id.is_some() {
-            headers.push((REQUEST_ID_HEADER, self.request_id.as_ref().unwrap()));
-        }
-
-        if self.traceparent.is_some() {
-            headers.push((TRACE_PARENT_HEADER, self.traceparent.as_ref().unwrap()));
-        }
-
-        let call_args = CallArgs::new(
-            CURVE_INTERNAL_CLUSTER_NAME,
-            "/function_calling",
-            headers,
-            Some(json_data.as_bytes()),
-            vec![],
-            Duration::from_secs(5),
-        );
-
-        let call_context = StreamCallContext {
-            response_handler_type: ResponseHandlerType::CurveFC,
-            user_message: self.user_prompt.as_ref().unwrap().content.clone(),
-            prompt_target_name: None,
-            request_body: self.chat_completions_request.as_ref().unwrap().clone(),
-            similarity_scores: None,
-            upstream_cluster: Some(CURVE_INTERNAL_CLUSTER_NAME.to_string()),
-            upstream_cluster_path: Some("/function_calling".to_string()),
-        };
-
-        if let Err(e) = self.http_call(call_args, call_context) {
-            debug!("http_call failed: {:?}", e);
-            self.send_server_error(ServerError::HttpDispatch(e), None);
+        if let Err(e) = self.check_request_limits(&deserialized_body) {
+            let status_code = match e {
+                ServerError::RequestLimitExceeded { .. } => StatusCode::BAD_REQUEST,
+                // the limit could not be checked
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            self.reject_request(e, status_code);
+            return Action::Pause;
         }
 
-        Action::Pause
+        self.run_pre_guard_hooks(deserialized_body)
     }
 
     fn on_http_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
@@ -213,6 +252,13 @@ impl HttpContext for StreamContext {
         // delete content-lenght header let envoy calculate it, because we modify the response body
         // that would result in a different content-length
         self.set_http_response_header("content-length", None);
+        self.set_http_response_header(CURVE_REQUEST_ID_HEADER, Some(&self.request_id));
+        self.add_cors_headers();
+        // held back until the body tells whether the error target answers instead, or until the
+        // post response hooks ran
+        if self.may_forward_error_response() || self.has_post_response_hooks() {
+            return Action::Pause;
+        }
         Action::Continue
     }
 
@@ -225,7 +271,7 @@ impl HttpContext for StreamContext {
         );
 
         if !self.is_chat_completions_request {
-            debug!("non-gpt request");
+            debug!("[R={}] non-gpt request", self.request_id);
             return Action::Continue;
         }
 
@@ -242,6 +288,15 @@ impl HttpContext for StreamContext {
             return Action::Continue;
         }
 
+        // error responses are read whole, the error target may answer instead
+        if !end_of_stream && self.may_forward_error_response() {
+            return Action::Pause;
+        }
+
+        if !end_of_stream && self.has_post_response_hooks() {
+            return Action::Pause;
+        }
+
         let body = if self.streaming_response {
             let streaming_chunk = match self.get_http_response_body(0, body_size) {
                 Some(chunk) => chunk,
@@ -264,7 +319,10 @@ impl HttpContext for StreamContext {
 
             streaming_chunk
         } else {
-            debug!("non streaming response bytes read: 0:{}", body_size);
+            debug!(
+                "[R={}] non streaming response bytes read: 0:{}",
+                self.request_id, body_size
+            );
             match self.get_http_response_body(0, body_size) {
                 Some(body) => body,
                 None => {
@@ -274,13 +332,16 @@ impl HttpContext for StreamContext {
             }
         };
 
-        let body_utf8 = match String::from_utf8(body) {
-            Ok(body_utf8) => body_utf8,
-            Err(e) => {
-                debug!("could not convert to utf8: {}", e);
-                return Action::Continue;
-            }
-        };
+        let content_type = self.get_http_response_header("content-type");
+        let body_utf8 = charset::decode(&body, content_type.as_deref());
+
+        if self.forward_error_response(&body_utf8, body_size) {
+            return Action::Pause;
+        }
+
+        // the post response hooks get the response as it goes back to the client
+        let mut response_body = body_utf8.clone();
+        let mut response_body_size = body_size;
 
         if self.streaming_response {
             trace!("streaming response");
@@ -345,12 +406,21 @@ impl HttpContext for StreamContext {
                         serde_json::Value::String(curve _state_str),
                     );
                     let data_serialized = serde_json::to_string(&data).unwrap();
-                    debug!("curve <= developer: {}", data_serialized);
+                    debug!(
+                        "[R={}] curve <= developer: {}",
+                        self.request_id, data_serialized
+                    );
                     self.set_http_response_body(0, body_size, data_serialized.as_bytes());
+                    response_body_size = data_serialized.len();
+                    response_body = data_serialized;
                 };
             }
         }
 
+        if end_of_stream && self.schedule_post_response_hook(&response_body, response_body_size) {
+            return Action::Pause;
+        }
+
         trace!("recv [S={}] end_stream={}", self.context_id, end_of_stream);
 
         Action::Continue
@@ -1,7 +1,7 @@
 use filter_context::FilterContext;
 use proxy_wasm::traits::*;
-use proxy_wasm::types::*;
 
+mod callouts;
 mod context;
 mod filter_context;
 mod http_context;
@@ -9,7 +9,7 @@ mod metrics;
 mod stream_context;
 
 proxy_wasm::main! {{
-    proxy_wasm::set_log_level(LogLevel::Trace);
+    common::logging::init();
     proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
         Box::new(FilterContext::new())
     });
@@ -1,14 +1,43 @@
-use common::stats::Gauge;
+use common::http::CalloutMetrics;
+use common::stats::{Counter, CounterFamily};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Debug)]
 pub struct Metrics {
-    pub active_http_calls: Gauge,
+    pub callouts: CalloutMetrics,
+    pub request_limit_rejections: Counter,
+    pub ratelimited_rq: CounterFamily,
+    pub cache_hits: CounterFamily,
+    pub shadow_calls: CounterFamily,
+    pub shed_callouts: Counter,
+    pub authorized_prompt_targets: CounterFamily,
+    pub unauthorized_prompt_targets: CounterFamily,
+    pub jwt_rejections: Counter,
+    pub fail_open_rq: Counter,
+    pub fallback_matches: Counter,
+    pub hook_responses: Counter,
+    pub clarification_questions: Counter,
+    pub error_target_forwards: CounterFamily,
+    pub aborted_rq: Counter,
 }
 
 impl Metrics {
     pub fn new() -> Metrics {
         Metrics {
-            active_http_calls: Gauge::new(String::from("active_http_calls")),
+            callouts: CalloutMetrics::new(),
+            request_limit_rejections: Counter::new(String::from("request_limit_rejections")),
+            ratelimited_rq: CounterFamily::new("ratelimited_rq"),
+            cache_hits: CounterFamily::new("cache_hits"),
+            shadow_calls: CounterFamily::new("shadow_calls"),
+            shed_callouts: Counter::new(String::from("shed_callouts")),
+            authorized_prompt_targets: CounterFamily::new("authorized_prompt_targets"),
+            unauthorized_prompt_targets: CounterFamily::new("unauthorized_prompt_targets"),
+            jwt_rejections: Counter::new(String::from("jwt_rejections")),
+            fail_open_rq: Counter::new(String::from("fail_open_rq")),
+            fallback_matches: Counter::new(String::from("fallback_matches")),
+            hook_responses: Counter::new(String::from("hook_responses")),
+            clarification_questions: Counter::new(String::from("clarification_questions")),
+            error_target_forwards: CounterFamily::new("error_target_forwards"),
+            aborted_rq: Counter::new(String::from("aborted_rq")),
         }
     }
 }
@@ -1,39 +1,67 @@
+use crate::callouts::{self, CalloutBuilder, StreamCallout};
 use crate::metrics::Metrics;
+use common::access_control;
+use common::api::hooks::{HookRequest, HookResponse};
 use common::api::open_ai::{
-    to_server_events, CurveState, ChatCompletionStreamResponse, ChatCompletionsRequest,
-    ChatCompletionsResponse, Message, ModelServerResponse, ToolCall,
+    is_server_events, to_server_events, ChatCompletionStreamResponse, ChatCompletionTool,
+    ChatCompletionsRequest, ChatCompletionsResponse, ClarificationState, CurveState,
+    FunctionCallDetail, Message, ModelServerResponse, ToolCall, ToolType,
+};
+use common::api::prompt_guard::{PromptGuardRequest, PromptGuardResponse, PromptGuardTask};
+use common::audit;
+use common::capture::CaptureTrace;
+use common::chain;
+use common::conditions;
+use common::config_validation;
+use common::configuration::{
+    AccessControl, Admin, AsyncOperation, BodyEncoding, ClientToolsMode, Configuration, Cors,
+    DebugCapture, EndpointAuth, EndpointDetails, ErrorEvent, ErrorTargetDetail, FailurePolicies,
+    FailurePolicy, GuardType, Hook, HookPoint, IntentFallback, JwtAuth, LlmProvider,
+    NotificationEvent, OnStepError, OnUnauthorized, Overrides, ParameterCollection, Pipeline,
+    PipelineStage, PromptTarget, PromptTargetGroup, RequestLimits, ResponseMode, ToolResponse,
+    ToolResponseRole, Tracing,
 };
-use common::configuration::{Overrides, PromptTarget, Tracing};
 use common::consts::{
+    ASSISTANT_ROLE, AUTHORIZATION_HEADER, CHAT_COMPLETIONS_PATH, CLARIFICATION_QUESTION,
     CURVE_FC_MODEL_NAME, CURVE_FC_REQUEST_TIMEOUT_MS, CURVE_INTERNAL_CLUSTER_NAME,
-    CURVE_UPSTREAM_HOST_HEADER, ASSISTANT_ROLE, MESSAGES_KEY, REQUEST_ID_HEADER, SYSTEM_ROLE,
-    TOOL_ROLE, TRACE_PARENT_HEADER, USER_ROLE,
+    CURVE_PARAMETER_COLLECTION_START_KEY, CURVE_PROMPT_TARGET_HEADER, CURVE_STATE_HEADER,
+    DEFAULT_GUARD_MESSAGE, GUARDRAILS_PATH, MESSAGES_KEY, MODEL_SERVER_NAME, REQUEST_ID_HEADER,
+    SYSTEM_ROLE, TOOL_RESPONSE_NOTE, TOOL_ROLE, TRACE_PARENT_HEADER, USER_ROLE,
+};
+use common::content_encoding::{
+    self, ContentEncoding, ACCEPT_ENCODING_HEADER, CONTENT_ENCODING_HEADER,
 };
-use common::errors::ServerError;
-use common::http::{CallArgs, Client};
-use common::stats::Gauge;
+use common::cors;
+use common::errors::{ClientError, ServerError};
+use common::http::{CallArgs, CalloutMetrics, Client, WaitingCall};
+use common::intent_fallback;
+use common::jwt;
+use common::metric_names::Dimension;
+use common::notifications;
+use common::parameter_collection;
+use common::ratelimit;
+use common::response_cache::{self, CacheEntry};
+use common::stats::IncrementingMetric;
+use common::template::{format_date, render_template};
+use common::tokenizer;
 use derivative::Derivative;
-use http::StatusCode;
+use http::{Method, StatusCode};
 use log::{debug, warn};
 use proxy_wasm::traits::*;
+use proxy_wasm::types::Action;
+use serde::Serialize;
 use serde_yaml::Value;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::num::NonZero;
 use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Clone)]
-pub enum ResponseHandlerType {
-    CurveFC,
-    FunctionCall,
-    DefaultTarget,
-}
-
 #[derive(Clone, Derivative)]
 #[derivative(Debug)]
 pub struct StreamCallContext {
-    pub response_handler_type: ResponseHandlerType,
     pub user_message: Option<String>,
     pub prompt_target_name: Option<String>,
     #[derivative(Debug = "ignore")]
@@ -43,12 +71,43 @@ pub struct StreamCallContext {
     pub upstream_cluster_path: Option<String>,
 }
 
+// routing decision returned by the debug route instead of dispatching the request
+#[derive(Debug, Default, Serialize)]
+pub struct RouteDecision {
+    // matched, default_target, no_match, parameter_collection, clarification, client_tool or
+    // passthrough
+    pub decision: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_target_group: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<HashMap<String, Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shadow: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl RouteDecision {
+    pub fn new(decision: &'static str) -> Self {
+        RouteDecision {
+            decision,
+            ..Default::default()
+        }
+    }
+}
+
 pub struct StreamContext {
     system_prompt: Rc<Option<String>>,
     pub prompt_targets: Rc<HashMap<String, PromptTarget>>,
-    _overrides: Rc<Option<Overrides>>,
+    pub prompt_target_groups: Rc<HashMap<String, PromptTargetGroup>>,
+    matched_prompt_target_group: Option<String>,
+    error_target: Rc<Option<ErrorTargetDetail>>,
+    overrides: Rc<Option<Overrides>>,
     pub metrics: Rc<Metrics>,
-    pub callouts: RefCell<HashMap<u32, StreamCallContext>>,
+    pub callouts: RefCell<HashMap<u32, StreamCallout>>,
+    pub waiting_calls: RefCell<HashMap<u32, WaitingCall<StreamCallout>>>,
     pub context_id: u32,
     pub tool_calls: Option<Vec<ToolCall>>,
     pub tool_call_response: Option<String>,
@@ -58,11 +117,60 @@ pub struct StreamContext {
     pub streaming_response: bool,
     pub is_chat_completions_request: bool,
     pub chat_completions_request: Option<ChatCompletionsRequest>,
-    pub request_id: Option<String>,
+    pub request_id: String,
     pub start_upstream_llm_request_time: u128,
     pub time_to_first_token: Option<u128>,
     pub traceparent: Option<String>,
+    pub passthrough_headers: HashMap<String, String>,
+    // request headers the conditions of prompt targets are evaluated on
+    pub condition_headers: HashMap<String, String>,
     pub _tracing: Rc<Option<Tracing>>,
+    pub request_limits: Rc<Option<RequestLimits>>,
+    pub client_tools_mode: ClientToolsMode,
+    pub function_calling_provider: Rc<Option<LlmProvider>>,
+    pub function_calling_stream: bool,
+    // model the model server matches intents with
+    intent_model: Rc<String>,
+    // model the model server guards endpoint responses with
+    guard_model: Rc<String>,
+    pub cache_bypass: bool,
+    response_cache_key: Option<String>,
+    pub admin: Rc<Option<Admin>>,
+    pub debug_route: bool,
+    configuration: Rc<Option<Configuration>>,
+    pub validate_prompt_target: bool,
+    pub access_control: Rc<Option<AccessControl>>,
+    // identity of the client the access control of prompt targets is checked for
+    pub client_identity: Option<String>,
+    jwt_auth: Rc<Option<JwtAuth>>,
+    failure_policies: Rc<Option<FailurePolicies>>,
+    pipeline: Rc<Option<Pipeline>>,
+    hooks: Rc<Vec<Hook>>,
+    // hooks of each point that already ran on the request
+    hooks_run: HashMap<HookPoint, usize>,
+    // request of the client as it was routed, post response hooks get its messages
+    client_request: Option<ChatCompletionsRequest>,
+    // size of the llm response the post response hooks may replace
+    post_response_body_size: usize,
+    // prompt target with an output schema the llm answer was asked for
+    pub output_schema_target: Option<String>,
+    // size of the llm response the error target answer replaces
+    error_response_body_size: usize,
+    // error reported to the error target, an error is only reported once
+    error_report: RefCell<Option<serde_json::Value>>,
+    // step of the prompt target chain being called and the responses of the steps called so far
+    chain_step: usize,
+    step_responses: serde_json::Map<String, serde_json::Value>,
+    cors: Rc<Option<Cors>>,
+    // origin of a browser request, the response carries the cors headers it is allowed
+    cors_origin: Option<String>,
+    body_encoding: Rc<Option<BodyEncoding>>,
+    // how the client compressed the request body
+    request_encoding: Option<ContentEncoding>,
+    debug_capture: Rc<Option<DebugCapture>>,
+    capture_queue: Arc<Mutex<VecDeque<String>>>,
+    // trace of the callouts of a request sampled for debug capture
+    capture: Option<RefCell<CaptureTrace>>,
 }
 
 impl StreamContext {
@@ -72,15 +180,38 @@ impl StreamContext {
         metrics: Rc<Metrics>,
         system_prompt: Rc<Option<String>>,
         prompt_targets: Rc<HashMap<String, PromptTarget>>,
+        prompt_target_groups: Rc<HashMap<String, PromptTargetGroup>>,
+        error_target: Rc<Option<ErrorTargetDetail>>,
         overrides: Rc<Option<Overrides>>,
         tracing: Rc<Option<Tracing>>,
+        request_limits: Rc<Option<RequestLimits>>,
+        client_tools_mode: ClientToolsMode,
+        function_calling_provider: Rc<Option<LlmProvider>>,
+        function_calling_stream: bool,
+        intent_model: Rc<String>,
+        guard_model: Rc<String>,
+        admin: Rc<Option<Admin>>,
+        configuration: Rc<Option<Configuration>>,
+        access_control: Rc<Option<AccessControl>>,
+        jwt_auth: Rc<Option<JwtAuth>>,
+        failure_policies: Rc<Option<FailurePolicies>>,
+        pipeline: Rc<Option<Pipeline>>,
+        hooks: Rc<Vec<Hook>>,
+        cors: Rc<Option<Cors>>,
+        body_encoding: Rc<Option<BodyEncoding>>,
+        debug_capture: Rc<Option<DebugCapture>>,
+        capture_queue: Arc<Mutex<VecDeque<String>>>,
     ) -> Self {
         StreamContext {
             context_id,
             metrics,
             system_prompt,
             prompt_targets,
+            prompt_target_groups,
+            matched_prompt_target_group: None,
+            error_target,
             callouts: RefCell::new(HashMap::new()),
+            waiting_calls: RefCell::new(HashMap::new()),
             chat_completions_request: None,
             tool_calls: None,
             tool_call_response: None,
@@ -89,304 +220,1967 @@ impl StreamContext {
             streaming_response: false,
             user_prompt: None,
             is_chat_completions_request: false,
-            _overrides: overrides,
-            request_id: None,
+            overrides,
+            request_id: String::new(),
             traceparent: None,
+            passthrough_headers: HashMap::new(),
+            condition_headers: HashMap::new(),
             _tracing: tracing,
+            request_limits,
+            client_tools_mode,
+            function_calling_provider,
+            function_calling_stream,
+            intent_model,
+            guard_model,
+            cache_bypass: false,
+            response_cache_key: None,
             start_upstream_llm_request_time: 0,
             time_to_first_token: None,
+            admin,
+            debug_route: false,
+            configuration,
+            validate_prompt_target: false,
+            access_control,
+            client_identity: None,
+            jwt_auth,
+            failure_policies,
+            pipeline,
+            hooks,
+            hooks_run: HashMap::new(),
+            client_request: None,
+            post_response_body_size: 0,
+            output_schema_target: None,
+            error_response_body_size: 0,
+            error_report: RefCell::new(None),
+            chain_step: 0,
+            step_responses: serde_json::Map::new(),
+            cors,
+            cors_origin: None,
+            body_encoding,
+            request_encoding: None,
+            debug_capture,
+            capture_queue,
+            capture: None,
         }
     }
 
     pub fn send_server_error(&self, error: ServerError, override_status_code: Option<StatusCode>) {
+        // calls shed by the callout limits are retried by the client once the cluster has capacity
+        let status_code = match error {
+            ServerError::HttpDispatch(ClientError::CalloutLimitExceeded { .. }) => {
+                self.metrics.shed_callouts.increment(1);
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            _ => override_status_code.unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+        };
+        if self.forwards_error(ErrorEvent::ServerError) {
+            let report = serde_json::json!({
+                "type": "server_error",
+                "status": status_code.as_u16(),
+                "message": error.to_string(),
+            });
+            if self.forward_to_error_target(
+                report,
+                self.called_prompt_target(),
+                callouts::error_target,
+            ) {
+                return;
+            }
+        }
         self.send_http_response(
-            override_status_code
-                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
-                .as_u16()
-                .into(),
+            status_code.as_u16().into(),
             vec![],
             Some(format!("{error}").as_bytes()),
         );
     }
 
-    fn _trace_curve _internal(&self) -> bool {
-        match self._tracing.as_ref() {
-            Some(tracing) => match tracing.trace_curve _internal.as_ref() {
-                Some(trace_curve _internal) => *trace_curve _internal,
-                None => false,
-            },
-            None => false,
+    // admin routes are only served when the admin section is configured
+    pub fn check_admin_access(&self) -> Result<(), StatusCode> {
+        let admin = self.admin.as_ref().as_ref().ok_or(StatusCode::NOT_FOUND)?;
+        if let Some(token) = admin.token.as_ref() {
+            let authorization = self.get_http_request_header(AUTHORIZATION_HEADER);
+            if authorization != Some(format!("Bearer {}", token)) {
+                return Err(StatusCode::UNAUTHORIZED);
+            }
         }
+        Ok(())
     }
 
-    pub fn curve _fc_response_handler(
+    // Answers cors preflight requests, true when the request was one. The origin of other requests
+    // is kept for the cors headers of the response.
+    pub fn handle_cors(&mut self) -> bool {
+        let cors = Rc::clone(&self.cors);
+        let Some(cors) = Option::as_ref(&cors) else {
+            return false;
+        };
+        let Some(origin) = self.get_http_request_header(cors::ORIGIN_HEADER) else {
+            return false;
+        };
+        let is_preflight = self.get_http_request_header(":method").as_deref() == Some("OPTIONS")
+            && self
+                .get_http_request_header(cors::ACCESS_CONTROL_REQUEST_METHOD_HEADER)
+                .is_some();
+        if !is_preflight {
+            self.cors_origin = Some(origin);
+            return false;
+        }
+        match cors::preflight_headers(cors, &origin) {
+            Some(headers) => {
+                let headers = headers
+                    .iter()
+                    .map(|(name, value)| (*name, value.as_str()))
+                    .collect();
+                self.send_http_response(StatusCode::NO_CONTENT.as_u16().into(), headers, None);
+            }
+            None => {
+                debug!("cors preflight from origin {} not allowed", origin);
+                self.send_http_response(StatusCode::FORBIDDEN.as_u16().into(), vec![], None);
+            }
+        }
+        true
+    }
+
+    pub fn add_cors_headers(&self) {
+        if let (Some(cors), Some(origin)) = (Option::as_ref(&self.cors), self.cors_origin.as_ref())
+        {
+            for (name, value) in cors::response_headers(cors, origin).unwrap_or_default() {
+                self.set_http_response_header(name, Some(&value));
+            }
+        }
+    }
+
+    // Reads how the request body is compressed. The gateway reads the responses of the upstream,
+    // they are asked for without compression, envoy's compressor compresses them for the client.
+    pub fn save_content_encoding(&mut self) -> Result<(), content_encoding::Error> {
+        if self
+            .get_http_request_header(ACCEPT_ENCODING_HEADER)
+            .is_some()
+        {
+            self.set_http_request_header(ACCEPT_ENCODING_HEADER, None);
+        }
+        if let Some(encoding) = self.get_http_request_header(CONTENT_ENCODING_HEADER) {
+            self.request_encoding = ContentEncoding::parse(&encoding)?;
+            // the body goes upstream decompressed
+            self.set_http_request_header(CONTENT_ENCODING_HEADER, None);
+        }
+        Ok(())
+    }
+
+    // replaces a compressed request body by the decompressed one, returns the size of the body
+    pub fn decode_request_body(
         &mut self,
-        body: Vec<u8>,
-        mut callout_context: StreamCallContext,
-    ) {
-        let body_str = String::from_utf8(body).unwrap();
-        debug!("curve <= curve fc response: {}", body_str);
+        body_size: usize,
+    ) -> Result<usize, content_encoding::Error> {
+        let Some(encoding) = self.request_encoding.take() else {
+            return Ok(body_size);
+        };
+        let max_bytes = Option::as_ref(&self.body_encoding)
+            .map(BodyEncoding::max_decompressed_bytes)
+            .unwrap_or_else(|| BodyEncoding::default().max_decompressed_bytes());
+        let body = self.get_http_request_body(0, body_size).unwrap_or_default();
+        let decoded = content_encoding::decode(encoding, &body, max_bytes)?;
+        self.set_http_request_body(0, body_size, &decoded);
+        Ok(decoded.len())
+    }
 
-        let server_response: ModelServerResponse = match serde_json::from_str(&body_str) {
-            Ok(curve _fc_response) => curve _fc_response,
-            Err(e) => {
-                warn!(
-                    "error deserializing curve fc response: {}, body: {}",
-                    e, body_str
-                );
-                return self.send_server_error(ServerError::Deserialization(e), None);
+    // a sample of requests is captured with the callouts they make, see common::capture
+    pub fn start_capture(&mut self) {
+        let Some(debug_capture) = self.debug_capture.as_ref() else {
+            return;
+        };
+        if audit::sampled(debug_capture.sampling_rate) {
+            let trace = CaptureTrace::new(&self.request_id, self.get_current_time());
+            self.capture = Some(RefCell::new(trace));
+        }
+    }
+
+    pub fn capture_request(&self, body_size: usize) {
+        if let Some(capture) = self.capture.as_ref() {
+            let headers = self.get_http_request_headers();
+            let body = self.get_http_request_body(0, body_size).unwrap_or_default();
+            capture.borrow_mut().record_request(headers, &body);
+        }
+    }
+
+    // the trace of a captured request is posted to the sink once the request is done
+    pub fn finish_capture(&mut self) {
+        let Some(capture) = self.capture.take() else {
+            return;
+        };
+        let mut trace = capture.into_inner();
+        trace.prompt_target = self.called_prompt_target();
+        match serde_json::to_string(&trace) {
+            Ok(trace_str) => self.capture_queue.lock().unwrap().push_back(trace_str),
+            Err(e) => warn!("could not serialize capture trace: {}", e),
+        }
+    }
+
+    pub fn send_content_encoding_error(&self, error: content_encoding::Error) {
+        let status_code = match error {
+            content_encoding::Error::Unsupported(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            content_encoding::Error::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            content_encoding::Error::Invalid(_) => StatusCode::BAD_REQUEST,
+        };
+        self.send_server_error(ServerError::ContentEncoding(error), Some(status_code));
+    }
+
+    // validates the token of the request, the claims are forwarded as the configured headers
+    pub fn authenticate(&self) -> Result<(), jwt::Error> {
+        let jwt_auth = match Option::as_ref(&self.jwt_auth) {
+            Some(jwt_auth) => jwt_auth,
+            None => return Ok(()),
+        };
+        let header = jwt_auth.header.as_deref().unwrap_or(AUTHORIZATION_HEADER);
+        let token = self.get_http_request_header(header);
+        let claims = jwt::authenticate(jwt_auth, token.as_deref(), self.get_current_time())?;
+        for (header, value) in jwt::claim_headers(jwt_auth, &claims) {
+            self.set_http_request_header(header, value.as_deref());
+        }
+        Ok(())
+    }
+
+    pub fn send_unauthenticated(&self, error: jwt::Error) {
+        debug!(
+            "[R={}] request not authenticated: {}",
+            self.request_id, error
+        );
+        self.metrics.jwt_rejections.increment(1);
+        let status_code = match error {
+            jwt::Error::KeysUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::UNAUTHORIZED,
+        };
+        let body = serde_json::json!({
+            "error": {
+                "type": "invalid_token",
+                "message": error.to_string(),
             }
+        })
+        .to_string();
+        self.send_http_response(
+            status_code.as_u16().into(),
+            vec![
+                ("content-type", "application/json"),
+                ("www-authenticate", "Bearer"),
+            ],
+            Some(body.as_bytes()),
+        );
+    }
+
+    pub fn send_route_decision(&self, mut decision: RouteDecision) {
+        decision
+            .prompt_target_group
+            .clone_from(&self.matched_prompt_target_group);
+        debug!("[R={}] route decision: {:?}", self.request_id, decision);
+        let body = serde_json::to_string(&decision).unwrap();
+        self.send_http_response(
+            StatusCode::OK.as_u16().into(),
+            vec![("content-type", "application/json")],
+            Some(body.as_bytes()),
+        );
+    }
+
+    // dry run of a prompt target in yaml or json, it is checked against the running config
+    pub fn send_prompt_target_validation(&self, body: &[u8]) {
+        let configuration = self
+            .configuration
+            .as_ref()
+            .as_ref()
+            .expect("the config should be kept when admin routes are served");
+        let (errors, warnings) = match config_validation::check_prompt_target(configuration, body) {
+            Ok(prompt_target) => (
+                vec![],
+                config_validation::prompt_target_warnings(&prompt_target),
+            ),
+            Err(errors) => (
+                errors.iter().map(|error| error.to_string()).collect(),
+                vec![],
+            ),
         };
+        let body = serde_json::json!({
+            "valid": errors.is_empty(),
+            "errors": errors,
+            "warnings": warnings,
+        })
+        .to_string();
+        self.send_http_response(
+            StatusCode::OK.as_u16().into(),
+            vec![("content-type", "application/json")],
+            Some(body.as_bytes()),
+        );
+    }
 
-        let curve _fc_response = match server_response {
-            ModelServerResponse::ChatCompletionsResponse(response) => response,
-            ModelServerResponse::ModelServerErrorResponse(response) => {
-                debug!("curve <= curve fc error response: {}", response.result);
-                if response.result == "No intent matched" {
-                    if let Some(default_prompt_target) = self
-                        .prompt_targets
-                        .values()
-                        .find(|pt| pt.default.unwrap_or(false))
-                    {
-                        debug!("default prompt target found, forwarding request to default prompt target");
-                        let endpoint = default_prompt_target.endpoint.clone().unwrap();
-                        let upstream_path: String = endpoint.path.unwrap_or(String::from("/"));
-
-                        let upstream_endpoint = endpoint.name;
-                        let mut params = HashMap::new();
-                        params.insert(
-                            MESSAGES_KEY.to_string(),
-                            callout_context.request_body.messages.clone(),
-                        );
-                        let curve _messages_json = serde_json::to_string(&params).unwrap();
-                        let timeout_str = CURVE_FC_REQUEST_TIMEOUT_MS.to_string();
-
-                        let mut headers = vec![
-                            (":method", "POST"),
-                            (CURVE_UPSTREAM_HOST_HEADER, &upstream_endpoint),
-                            (":path", &upstream_path),
-                            (":authority", &upstream_endpoint),
-                            ("content-type", "application/json"),
-                            ("x-envoy-max-retries", "3"),
-                            ("x-envoy-upstream-rq-timeout-ms", timeout_str.as_str()),
-                        ];
-
-                        if self.request_id.is_some() {
-                            headers.push((REQUEST_ID_HEADER, self.request_id.as_ref().unwrap()));
-                        }
+    // pre guard hooks get the request of the client before anything else runs on it
+    pub fn run_pre_guard_hooks(&mut self, request: ChatCompletionsRequest) -> Action {
+        // hooks answering the request themselves answer in the format the client asked for
+        self.streaming_response = request.stream;
+        let Some(hook) = self.next_hook(HookPoint::PreGuard) else {
+            return self.route_request(request);
+        };
+        let callout_context = StreamCallContext {
+            user_message: None,
+            prompt_target_name: None,
+            request_body: request,
+            similarity_scores: None,
+            upstream_cluster: None,
+            upstream_cluster_path: None,
+        };
+        self.schedule_hook_request(hook, callout_context, None, callouts::pre_guard_hook);
+        Action::Pause
+    }
 
-                        // if self.trace_curve _internal() && self.traceparent.is_some() {
-                        //     headers.push((TRACE_PARENT_HEADER, self.traceparent.as_ref().unwrap()));
-                        // }
-
-                        let call_args = CallArgs::new(
-                            CURVE_INTERNAL_CLUSTER_NAME,
-                            &upstream_path,
-                            headers,
-                            Some(curve _messages_json.as_bytes()),
-                            vec![],
-                            Duration::from_secs(5),
-                        );
-                        callout_context.response_handler_type = ResponseHandlerType::DefaultTarget;
-                        callout_context.prompt_target_name =
-                            Some(default_prompt_target.name.clone());
-
-                        if let Err(e) = self.http_call(call_args, callout_context) {
-                            warn!("error dispatching default prompt target request: {}", e);
-                            return self.send_server_error(
-                                ServerError::HttpDispatch(e),
-                                Some(StatusCode::BAD_REQUEST),
-                            );
-                        }
-                        return;
-                    }
+    // requests with their own tools or answering a client tool call go to the llm as they are, the
+    // prompt target of the others is resolved
+    pub fn route_request(&mut self, request: ChatCompletionsRequest) -> Action {
+        self.client_request = Some(request.clone());
+
+        self.curve _state = match request.metadata {
+            Some(ref metadata) => {
+                if metadata.contains_key(CURVE_STATE_HEADER) {
+                    let curve _state_str = metadata[CURVE_STATE_HEADER].clone();
+                    let curve _state: Vec<CurveState> =
+                        serde_json::from_str(&curve _state_str).unwrap();
+                    Some(curve _state)
+                } else {
+                    None
                 }
-                return self.send_server_error(
-                    ServerError::LogicError(response.result),
-                    Some(StatusCode::BAD_REQUEST),
-                );
             }
+            None => None,
         };
 
-        curve _fc_response.choices[0]
-            .message
-            .tool_calls
-            .clone_into(&mut self.tool_calls);
+        let last_user_prompt = match request
+            .messages
+            .iter()
+            .filter(|msg| msg.role == USER_ROLE)
+            .last()
+        {
+            Some(content) => content,
+            None => {
+                warn!("No messages in the request body");
+                return self.skip_prompt_target_resolution();
+            }
+        };
 
-        if self.tool_calls.as_ref().unwrap().len() > 1 {
-            warn!(
-                "multiple tool calls not supported yet, tool_calls count found: {}",
-                self.tool_calls.as_ref().unwrap().len()
+        self.user_prompt = Some(last_user_prompt.clone());
+
+        let client_tools = request.tools.clone().unwrap_or_default();
+        if self.client_tools_mode == ClientToolsMode::Passthrough && !client_tools.is_empty() {
+            debug!(
+                "[R={}] client sent tools, skipping prompt target resolution",
+                self.request_id
             );
+            return self.skip_prompt_target_resolution();
         }
 
-        if self.tool_calls.is_none() || self.tool_calls.as_ref().unwrap().is_empty() {
-            // This means that Curve FC did not have enough information to resolve the function call
-            // Curve FC probably responded with a message asking for more information.
-            // Let's send the response back to the user to initialize lightweight dialog for parameter collection
+        // the client is doing its own function calling and sent back the response of its tool
+        let client_tool_call_ids = self.client_tool_call_ids(&request.messages);
+        if let Some(last_message) = request.messages.last() {
+            if last_message.role == TOOL_ROLE
+                && is_client_tool_message(last_message, &client_tool_call_ids)
+            {
+                debug!(
+                    "[R={}] client sent tool response, skipping prompt target resolution",
+                    self.request_id
+                );
+                return self.skip_prompt_target_resolution();
+            }
+        }
 
-            //TODO: add resolver name to the response so the client can send the response back to the correct resolver
+        self.chat_completions_request = Some(request);
 
-            let direct_response_str = if self.streaming_response {
-                let chunks = vec![
-                    ChatCompletionStreamResponse::new(
-                        None,
-                        Some(ASSISTANT_ROLE.to_string()),
-                        Some(CURVE_FC_MODEL_NAME.to_owned()),
-                        None,
-                    ),
-                    ChatCompletionStreamResponse::new(
-                        Some(
-                            curve _fc_response.choices[0]
-                                .message
-                                .content
-                                .as_ref()
-                                .unwrap()
-                                .clone(),
-                        ),
-                        None,
-                        Some(CURVE_FC_MODEL_NAME.to_owned()),
-                        None,
-                    ),
-                ];
+        self.resolve_prompt_target()
+    }
 
-                to_server_events(chunks)
-            } else {
-                body_str
-            };
+    // runs the stages of the pipeline until one of them resolves the prompt target or schedules a
+    // call that will
+    pub fn resolve_prompt_target(&mut self) -> Action {
+        let stages = match Option::as_ref(&self.pipeline) {
+            Some(pipeline) => pipeline.stages.clone(),
+            None => PipelineStage::ALL.to_vec(),
+        };
+        for stage in stages {
+            debug!("[R={}] pipeline stage: {:?}", self.request_id, stage);
+            match stage {
+                PipelineStage::FollowUp => {
+                    if let Some(candidates) = self.clarification_candidates() {
+                        self.continue_with_candidates(candidates);
+                        return Action::Pause;
+                    }
+                    if let Some(prompt_target) = self.follow_up_prompt_target() {
+                        self.continue_with_prompt_target(prompt_target);
+                        return Action::Pause;
+                    }
+                }
+                // with prompt target groups intent matching is done in two stages, a group is
+                // picked first and then a prompt target within that group
+                PipelineStage::GroupMatching => {
+                    if !self.prompt_target_groups.is_empty() {
+                        let tools: Vec<ChatCompletionTool> = self
+                            .prompt_target_groups
+                            .values()
+                            .map(|group| group.into())
+                            .collect();
+                        self.schedule_function_calling_request(tools, callouts::fc_group);
+                        return Action::Pause;
+                    }
+                }
+                PipelineStage::FunctionCalling => {
+                    let tools = self.prompt_target_tools(None);
+                    self.schedule_function_calling_request(tools, callouts::fc);
+                    return Action::Pause;
+                }
+            }
+        }
+        self.skip_prompt_target_resolution()
+    }
 
-            self.tool_calls = None;
-            return self.send_http_response(
-                StatusCode::OK.as_u16().into(),
-                vec![],
-                Some(direct_response_str.as_bytes()),
-            );
+    // requests that skip prompt target resolution are sent to the llm as they are
+    pub fn skip_prompt_target_resolution(&self) -> Action {
+        if self.debug_route {
+            self.send_route_decision(RouteDecision::new("passthrough"));
+            return Action::Pause;
         }
+        Action::Continue
+    }
 
-        // update prompt target name from the tool call
-        callout_context.prompt_target_name =
-            Some(self.tool_calls.as_ref().unwrap()[0].function.name.clone());
+    pub fn reject_request(&self, error: ServerError, status_code: StatusCode) {
+        self.metrics.request_limit_rejections.increment(1);
+        self.send_server_error(error, Some(status_code));
+    }
 
-        self.schedule_api_call_request(callout_context);
+    pub fn check_request_limits(
+        &self,
+        request: &ChatCompletionsRequest,
+    ) -> Result<(), ServerError> {
+        let request_limits = match self.request_limits.as_ref() {
+            Some(request_limits) => request_limits,
+            None => return Ok(()),
+        };
+
+        if let Some(max_messages) = request_limits.max_messages {
+            if request.messages.len() > max_messages {
+                return Err(ServerError::RequestLimitExceeded {
+                    limit: "max_messages".to_string(),
+                    actual: request.messages.len(),
+                    max: max_messages,
+                });
+            }
+        }
+
+        if let Some(max_tokens) = request_limits.max_tokens {
+            let text = request
+                .messages
+                .iter()
+                .filter_map(|msg| msg.content.as_ref().map(|content| content.text()))
+                .collect::<Vec<String>>()
+                .join("\n");
+            // not every model name is known to the tokenizer, gpt-4 bpe is close enough for a limit.
+            // A request that can't be counted is not let through uncounted.
+            let token_count = tokenizer::token_count("gpt-4", &text).map_err(|e| {
+                ServerError::LogicError(format!(
+                    "could not count the tokens of the request for the max_tokens limit: {}",
+                    e
+                ))
+            })?;
+            if token_count > max_tokens {
+                return Err(ServerError::RequestLimitExceeded {
+                    limit: "max_tokens".to_string(),
+                    actual: token_count,
+                    max: max_tokens,
+                });
+            }
+        }
+
+        Ok(())
     }
 
-    fn schedule_api_call_request(&mut self, mut callout_context: StreamCallContext) {
-        let tools_call_name = self.tool_calls.as_ref().unwrap()[0].function.name.clone();
+    // Curve-FC takes the request as is, llm providers get the openai chat completions format
+    pub fn function_calling_request_body(
+        &self,
+        mut request: ChatCompletionsRequest,
+    ) -> Result<String, serde_json::Error> {
+        let provider = match self.function_calling_provider.as_ref() {
+            Some(provider) => provider,
+            None => return serde_json::to_string(&request),
+        };
 
-        let prompt_target = self.prompt_targets.get(&tools_call_name).unwrap().clone();
+        request.model = provider.model.clone();
+        request.stream = self.function_calling_stream;
+        request.stream_options = None;
+        request.metadata = None;
+        let tools = request.tools.take().unwrap_or_default();
 
-        let mut tool_params = self.tool_calls.as_ref().unwrap()[0]
-            .function
-            .arguments
-            .clone();
-        tool_params.insert(
-            String::from(MESSAGES_KEY),
-            serde_yaml::to_value(&callout_context.request_body.messages).unwrap(),
-        );
+        let mut request_json = serde_json::to_value(&request)?;
+        request_json["tools"] = tools.iter().map(|tool| tool.to_openai_tool()).collect();
+        serde_json::to_string(&request_json)
+    }
 
-        let tool_params_json_str = serde_json::to_string(&tool_params).unwrap();
+    // tools offered to function calling, prompt targets of the group (plus the ones without a
+    // group) when a group was matched, all prompt targets otherwise
+    pub fn prompt_target_tools(&self, group: Option<&str>) -> Vec<ChatCompletionTool> {
+        let mut tools: Vec<ChatCompletionTool> = self
+            .prompt_targets
+            .values()
+            .filter(|pt| in_group(pt, group))
+            .flat_map(|pt| pt.tools())
+            .collect();
 
-        let endpoint = prompt_target.endpoint.unwrap();
-        let path: String = endpoint.path.unwrap_or(String::from("/"));
+        if self.client_tools_mode == ClientToolsMode::Merge {
+            let client_tools = self
+                .chat_completions_request
+                .as_ref()
+                .and_then(|request| request.tools.clone())
+                .unwrap_or_default();
+            tools.extend(client_tools);
+        }
 
-        // only add params that are of string, number and bool type
-        let url_params = tool_params
+        tools
+    }
+
+    // ids of the tool calls made for the client's own tools, i.e. neither a prompt target nor a
+    // prompt target group. These and their tool responses belong to the client's conversation.
+    pub fn client_tool_call_ids(&self, messages: &[Message]) -> HashSet<String> {
+        messages
             .iter()
-            .filter(|(_, value)| value.is_number() || value.is_string() || value.is_bool())
-            .map(|(key, value)| match value {
-                Value::Number(n) => (key.clone(), n.to_string()),
-                Value::String(s) => (key.clone(), s.clone()),
-                Value::Bool(b) => (key.clone(), b.to_string()),
-                Value::Null => todo!(),
-                Value::Sequence(_) => todo!(),
-                Value::Mapping(_) => todo!(),
-                Value::Tagged(_) => todo!(),
+            .filter_map(|m| m.tool_calls.as_ref())
+            .flatten()
+            .filter(|tool_call| {
+                !self.prompt_targets.contains_key(&tool_call.function.name)
+                    && !self
+                        .prompt_target_groups
+                        .contains_key(&tool_call.function.name)
             })
-            .collect::<HashMap<String, String>>();
+            .map(|tool_call| tool_call.id.clone())
+            .collect()
+    }
 
-        let path = match common::path::replace_params_in_path(&path, &url_params) {
-            Ok(path) => path,
-            Err(e) => {
-                return self.send_server_error(
-                    ServerError::BadRequest {
-                        why: format!("error replacing params in path: {}", e),
-                    },
-                    Some(StatusCode::BAD_REQUEST),
-                );
+    pub fn schedule_function_calling_request(
+        &mut self,
+        tools: Vec<ChatCompletionTool>,
+        callout: CalloutBuilder,
+    ) {
+        let request_body = self.chat_completions_request.as_ref().unwrap();
+        let intent_history_turns =
+            Option::as_ref(&self.overrides).and_then(|overrides| overrides.intent_history_turns);
+        // function calling only understands text, images etc. are left out
+        let messages = history_window(&request_body.messages, intent_history_turns)
+            .into_iter()
+            .map(|mut message| {
+                if let Some(content) = message.content.as_ref() {
+                    if content.has_non_text_parts() {
+                        message.content = Some(content.text().into());
+                    }
+                }
+                message
+            })
+            .collect();
+        let curve _fc_chat_completion_request = ChatCompletionsRequest {
+            messages,
+            metadata: request_body.metadata.clone(),
+            stream: request_body.stream || self.function_calling_stream,
+            model: self.intent_model.to_string(),
+            stream_options: request_body.stream_options.clone(),
+            tools: Some(tools),
+            extra_fields: HashMap::new(),
+        };
+
+        let json_data = match self.function_calling_request_body(curve _fc_chat_completion_request) {
+            Ok(json_data) => json_data,
+            Err(error) => {
+                return self.send_server_error(ServerError::Serialization(error), None);
             }
         };
 
-        let http_method = endpoint.method.unwrap_or_default().to_string();
-        let mut headers = vec![
-            (CURVE_UPSTREAM_HOST_HEADER, endpoint.name.as_str()),
-            (":method", &http_method),
-            (":path", &path),
-            (":authority", endpoint.name.as_str()),
-            ("content-type", "application/json"),
-            ("x-envoy-max-retries", "3"),
-        ];
+        debug!("[R={}] curve => curve fc: {}", self.request_id, json_data);
+
+        let function_calling_provider = Rc::clone(&self.function_calling_provider);
+        let (upstream_host, upstream_path) = match function_calling_provider.as_ref() {
+            Some(provider) => (provider.cluster_name(), CHAT_COMPLETIONS_PATH),
+            None => (MODEL_SERVER_NAME.to_string(), "/function_calling"),
+        };
+        let authorization_header = function_calling_provider
+            .as_ref()
+            .as_ref()
+            .and_then(|provider| provider.access_key.as_ref())
+            .map(|access_key| format!("Bearer {}", access_key));
+
+        let mut call_args = CallArgs::internal(&upstream_host, Method::POST, upstream_path)
+            .with_json_body(json_data.as_bytes())
+            .with_timeout(Duration::from_secs(5));
+        if let Some(authorization_header) = authorization_header.as_ref() {
+            call_args = call_args.with_header(AUTHORIZATION_HEADER, authorization_header);
+        }
+        call_args = call_args.with_header(REQUEST_ID_HEADER, &self.request_id);
+        if let Some(traceparent) = self.traceparent.as_ref() {
+            call_args = call_args.with_header(TRACE_PARENT_HEADER, traceparent);
+        }
+
+        let call_context = StreamCallContext {
+            user_message: self
+                .user_prompt
+                .as_ref()
+                .unwrap()
+                .content
+                .as_ref()
+                .map(|content| content.text()),
+            prompt_target_name: None,
+            request_body: self.chat_completions_request.as_ref().unwrap().clone(),
+            similarity_scores: None,
+            upstream_cluster: Some(CURVE_INTERNAL_CLUSTER_NAME.to_string()),
+            upstream_cluster_path: Some(upstream_path.to_string()),
+        };
 
-        if self.request_id.is_some() {
-            headers.push((REQUEST_ID_HEADER, self.request_id.as_ref().unwrap()));
+        if let Err(e) = self.http_call(call_args, callout(call_context.clone())) {
+            debug!("[R={}] http_call failed: {:?}", self.request_id, e);
+            self.handle_function_calling_failure(ServerError::HttpDispatch(e), None, call_context);
         }
+    }
 
-        if self.traceparent.is_some() {
-            headers.push((TRACE_PARENT_HEADER, self.traceparent.as_ref().unwrap()));
+    fn _trace_curve _internal(&self) -> bool {
+        match self._tracing.as_ref() {
+            Some(tracing) => match tracing.trace_curve _internal.as_ref() {
+                Some(trace_curve _internal) => *trace_curve _internal,
+                None => false,
+            },
+            None => false,
         }
+    }
 
-        let call_args = CallArgs::new(
-            CURVE_INTERNAL_CLUSTER_NAME,
-            &path,
-            headers,
-            Some(tool_params_json_str.as_bytes()),
-            vec![],
-            Duration::from_secs(5),
-        );
+    // short follow ups like "yes" or "the blue one" match poorly against every prompt target, when
+    // the request carries the curve state of a prompt target they continue with that target
+    pub fn follow_up_prompt_target(&self) -> Option<PromptTarget> {
+        let max_tokens = Option::as_ref(&self.overrides)?.follow_up_max_tokens?;
+        let CurveState::ToolCall(tool_calls) = self.curve _state.as_ref()?.last()? else {
+            return None;
+        };
+        let prompt_target = self
+            .prompt_targets
+            .get(&tool_calls.last()?.tool_call.name)?;
+
+        let text = self.user_prompt.as_ref()?.content.as_ref()?.text();
+        // not every model name is known to the tokenizer, gpt-4 bpe is close enough for a limit
+        let token_count = tokenizer::token_count("gpt-4", &text).unwrap_or(usize::MAX);
+        (token_count <= max_tokens).then(|| prompt_target.clone())
+    }
 
+    // offers function calling only the prompt target the conversation continues with, so that the
+    // arguments are still extracted from the follow up
+    pub fn continue_with_prompt_target(&mut self, prompt_target: PromptTarget) {
         debug!(
-            "curve => api call, endpoint: {}{}, body: {}",
-            endpoint.name.as_str(),
-            path,
-            tool_params_json_str
+            "[R={}] short follow up, continuing with prompt target: {}",
+            self.request_id, prompt_target.name
         );
+        let tools = vec![(&prompt_target).into()];
+        self.matched_prompt_target_group = prompt_target.group;
+        self.schedule_function_calling_request(tools, callouts::fc);
+    }
 
-        callout_context.upstream_cluster = Some(endpoint.name.to_owned());
-        callout_context.upstream_cluster_path = Some(path.to_owned());
-        callout_context.response_handler_type = ResponseHandlerType::FunctionCall;
+    // the prompt targets of the clarification question the request answers
+    pub fn clarification_candidates(&self) -> Option<Vec<String>> {
+        match self.curve _state.as_ref()?.last()? {
+            CurveState::Clarification(clarification) => Some(clarification.candidates.clone()),
+            _ => None,
+        }
+    }
 
-        if let Err(e) = self.http_call(call_args, callout_context) {
+    // the answer to a clarification question is matched against the candidates only
+    pub fn continue_with_candidates(&mut self, candidates: Vec<String>) {
+        debug!(
+            "[R={}] answer to clarification question, candidates: {:?}",
+            self.request_id, candidates
+        );
+        let tools = candidates
+            .iter()
+            .filter_map(|name| self.prompt_targets.get(name))
+            .map(|prompt_target| prompt_target.into())
+            .collect();
+        self.schedule_function_calling_request(tools, callouts::fc);
+    }
+
+    // distinct prompt targets of the tool calls, more than one leaves the intent ambiguous
+    fn ambiguous_candidates(&self) -> Vec<String> {
+        let clarify = Option::as_ref(&self.overrides)
+            .and_then(|overrides| overrides.clarify_ambiguous_intent)
+            .unwrap_or_default();
+        if !clarify {
+            return Vec::new();
+        }
+        let mut candidates: Vec<String> = Vec::new();
+        for tool_call in self.tool_calls.iter().flatten() {
+            let name = &tool_call.function.name;
+            if self.prompt_targets.contains_key(name) && !candidates.contains(name) {
+                candidates.push(name.clone());
+            }
+        }
+        candidates
+    }
+
+    // asks the user which of the prompt targets they meant, the candidates are kept in the curve
+    // state of the response. Streamed responses carry no metadata, streaming clients answer
+    // without the candidates and the answer is matched against all prompt targets.
+    fn send_clarification_question(&mut self, candidates: Vec<String>) {
+        self.metrics.clarification_questions.increment(1);
+        self.tool_calls = None;
+        let options: Vec<String> = candidates
+            .iter()
+            .filter_map(|name| self.prompt_targets.get(name))
+            .map(|prompt_target| format!("- {}", prompt_target.description))
+            .collect();
+        let question = format!("{}\n{}", CLARIFICATION_QUESTION, options.join("\n"));
+
+        let response_str = if self.streaming_response {
+            let chunks = vec![
+                ChatCompletionStreamResponse::new(
+                    None,
+                    Some(ASSISTANT_ROLE.to_string()),
+                    Some(CURVE_FC_MODEL_NAME.to_owned()),
+                    None,
+                ),
+                ChatCompletionStreamResponse::new(
+                    Some(question),
+                    None,
+                    Some(CURVE_FC_MODEL_NAME.to_owned()),
+                    None,
+                ),
+            ];
+            to_server_events(chunks)
+        } else {
+            let curve _state = vec![CurveState::Clarification(ClarificationState { candidates })];
+            let mut response = ChatCompletionsResponse::new(question);
+            response.metadata = Some(HashMap::from([(
+                CURVE_STATE_HEADER.to_string(),
+                serde_json::to_string(&curve _state).unwrap(),
+            )]));
+            match serde_json::to_string(&response) {
+                Ok(response_str) => response_str,
+                Err(e) => return self.send_server_error(ServerError::Serialization(e), None),
+            }
+        };
+
+        self.send_http_response(
+            StatusCode::OK.as_u16().into(),
+            vec![],
+            Some(response_str.as_bytes()),
+        );
+    }
+
+    pub fn default_prompt_target(&self) -> Option<PromptTarget> {
+        self.prompt_targets
+            .values()
+            .find(|pt| pt.default.unwrap_or(false))
+            .cloned()
+    }
+
+    // where requests go when the function calling service can't be reached, they fail without an
+    // intent fallback in the overrides
+    pub fn intent_fallback(&self) -> Option<IntentFallback> {
+        self.overrides
+            .as_ref()
+            .as_ref()
+            .and_then(|overrides| overrides.intent_fallback)
+    }
+
+    // a prompt target the prompt shares enough words with, for when function calling failed. Only
+    // targets that can be called without extracted parameters are considered.
+    fn keyword_matched_prompt_target(&self) -> Option<PromptTarget> {
+        let prompt = self.user_prompt.as_ref()?.content.as_ref()?.text();
+        let candidates = self.prompt_targets.values().filter(|prompt_target| {
+            !prompt_target.default.unwrap_or_default()
+                && intent_fallback::callable_without_arguments(prompt_target)
+        });
+        intent_fallback::best_match(&prompt, candidates).cloned()
+    }
+
+    // a failed function calling request falls back to the prompt target matched by keywords and
+    // then to the default prompt target, or straight to the default prompt target, as set in the
+    // overrides. Otherwise the failure policy decides whether the request fails or goes on without
+    // a prompt target
+    pub fn handle_function_calling_failure(
+        &mut self,
+        error: ServerError,
+        override_status_code: Option<StatusCode>,
+        callout_context: StreamCallContext,
+    ) {
+        // keywords only stand in for function calling that is unavailable, not for its answers
+        let unavailable = !matches!(error, ServerError::LogicError(_));
+        if let Some(intent_fallback) = self.intent_fallback() {
+            let match_keywords = unavailable && intent_fallback == IntentFallback::Keywords;
+            if let Some(prompt_target) = match_keywords
+                .then(|| self.keyword_matched_prompt_target())
+                .flatten()
+            {
+                warn!(
+                    "function calling failed: {}, falling back to keyword matched prompt target {}",
+                    error, prompt_target.name
+                );
+                self.metrics.fallback_matches.increment(1);
+                self.tool_calls = Some(vec![ToolCall {
+                    id: format!("fallback_{}", self.request_id),
+                    tool_type: ToolType::Function,
+                    function: FunctionCallDetail {
+                        arguments: intent_fallback::default_arguments(&prompt_target),
+                        name: prompt_target.name,
+                    },
+                }]);
+                return self.dispatch_tool_call(callout_context);
+            }
+            if let Some(default_prompt_target) = self.default_prompt_target() {
+                warn!(
+                    "function calling failed: {}, falling back to default prompt target",
+                    error
+                );
+                return self
+                    .schedule_default_target_request(default_prompt_target, callout_context);
+            }
+        }
+
+        let policy = Option::as_ref(&self.failure_policies)
+            .and_then(|failure_policies| failure_policies.function_calling)
+            .unwrap_or_default();
+        match policy {
+            FailurePolicy::FailOpen => {
+                warn!(
+                    "function calling failed: {}, continuing without prompt target",
+                    error
+                );
+                self.metrics.fail_open_rq.increment(1);
+                self.continue_without_prompt_target(callout_context)
+            }
+            FailurePolicy::FailClosed => self.send_server_error(error, override_status_code),
+        }
+    }
+
+    pub fn schedule_default_target_request(
+        &mut self,
+        default_prompt_target: PromptTarget,
+        mut callout_context: StreamCallContext,
+    ) {
+        let endpoint = default_prompt_target.endpoint.clone().unwrap();
+        callout_context.prompt_target_name = Some(default_prompt_target.name);
+        self.schedule_messages_request(endpoint, callout_context, callouts::default_target);
+    }
+
+    // posts the conversation to the endpoint, used for the default and the error target
+    fn schedule_messages_request(
+        &mut self,
+        endpoint: EndpointDetails,
+        callout_context: StreamCallContext,
+        callout: CalloutBuilder,
+    ) {
+        let mut params = HashMap::new();
+        params.insert(
+            MESSAGES_KEY.to_string(),
+            callout_context.request_body.messages.clone(),
+        );
+        let curve _messages_json = serde_json::to_string(&params).unwrap();
+        self.schedule_endpoint_request(endpoint, curve _messages_json, callout_context, callout);
+    }
+
+    fn schedule_endpoint_request(
+        &self,
+        endpoint: EndpointDetails,
+        body: String,
+        callout_context: StreamCallContext,
+        callout: CalloutBuilder,
+    ) {
+        let auth_header = self.endpoint_auth_header(&endpoint);
+        let upstream_path: String = endpoint.path.unwrap_or(String::from("/"));
+
+        let upstream_endpoint = endpoint.name.clone();
+        let timeout_str = CURVE_FC_REQUEST_TIMEOUT_MS.to_string();
+
+        let mut call_args = CallArgs::internal(&upstream_endpoint, Method::POST, &upstream_path)
+            .with_json_body(body.as_bytes())
+            .with_header("x-envoy-max-retries", "3")
+            .with_header("x-envoy-upstream-rq-timeout-ms", &timeout_str)
+            .with_timeout(Duration::from_secs(5));
+        if let Some((key, value)) = auth_header.as_ref() {
+            call_args = call_args.with_header(key, value);
+        }
+        call_args = call_args.with_header(REQUEST_ID_HEADER, &self.request_id);
+
+        // if self.trace_curve _internal() && self.traceparent.is_some() {
+        //     call_args = call_args.with_header(TRACE_PARENT_HEADER, traceparent);
+        // }
+
+        if let Err(e) = self.http_call(call_args, callout(callout_context)) {
+            warn!("error dispatching request to {}: {}", upstream_endpoint, e);
             self.send_server_error(ServerError::HttpDispatch(e), Some(StatusCode::BAD_REQUEST));
         }
-    }
+    }
+
+    // first stage of intent matching when prompt target groups are configured, the matched group
+    // narrows down the prompt targets offered in the second stage. If no group could be matched
+    // all prompt targets are offered.
+    pub fn curve _fc_group_response_handler(
+        &mut self,
+        body: Vec<u8>,
+        callout_context: StreamCallContext,
+    ) {
+        let body_str = self.call_response_text(&body);
+        debug!(
+            "[R={}] curve <= curve fc group response: {}",
+            self.request_id, body_str
+        );
+
+        let server_response: ModelServerResponse = match serde_json::from_str(&body_str) {
+            Ok(server_response) => server_response,
+            Err(e) => {
+                warn!(
+                    "error deserializing curve fc group response: {}, body: {}",
+                    e, body_str
+                );
+                return self.handle_function_calling_failure(
+                    ServerError::Deserialization(e),
+                    None,
+                    callout_context,
+                );
+            }
+        };
+
+        let group = match server_response {
+            ModelServerResponse::ChatCompletionsResponse(response) => response
+                .choices
+                .first()
+                .and_then(|choice| choice.message.tool_calls.as_ref())
+                .and_then(|tool_calls| tool_calls.first())
+                .map(|tool_call| tool_call.function.name.clone())
+                .filter(|group| self.prompt_target_groups.contains_key(group)),
+            ModelServerResponse::ModelServerErrorResponse(response) => {
+                debug!(
+                    "[R={}] curve <= curve fc group error response: {}",
+                    self.request_id, response.result
+                );
+                None
+            }
+        };
+
+        match group.as_ref() {
+            Some(group) => debug!(
+                "[R={}] prompt target group matched: {}",
+                self.request_id, group
+            ),
+            None => debug!(
+                "[R={}] no prompt target group matched, using all prompt targets",
+                self.request_id
+            ),
+        }
+
+        let tools = self.prompt_target_tools(group.as_deref());
+        self.matched_prompt_target_group = group;
+        self.schedule_function_calling_request(tools, callouts::fc);
+    }
+
+    pub fn curve _fc_response_handler(
+        &mut self,
+        body: Vec<u8>,
+        mut callout_context: StreamCallContext,
+    ) {
+        let mut body_str = self.call_response_text(&body);
+        debug!(
+            "[R={}] curve <= curve fc response: {}",
+            self.request_id, body_str
+        );
+
+        // a streamed response is assembled from its tool call deltas, body_str is then replaced by
+        // the assembled response for clients that don't stream
+        let fc_stream = is_server_events(&body_str).then_some(body_str.clone());
+        let server_response: ModelServerResponse = match fc_stream.as_deref() {
+            Some(fc_stream) => match ChatCompletionsResponse::try_from(fc_stream) {
+                Ok(curve _fc_response) => {
+                    body_str = match serde_json::to_string(&curve _fc_response) {
+                        Ok(body_str) => body_str,
+                        Err(e) => {
+                            return self.send_server_error(ServerError::Serialization(e), None)
+                        }
+                    };
+                    ModelServerResponse::ChatCompletionsResponse(curve _fc_response)
+                }
+                Err(e) => {
+                    warn!(
+                        "error assembling streamed curve fc response: {}, body: {}",
+                        e, body_str
+                    );
+                    return self.handle_function_calling_failure(
+                        ServerError::Streaming(e),
+                        None,
+                        callout_context,
+                    );
+                }
+            },
+            None => match serde_json::from_str(&body_str) {
+                Ok(curve _fc_response) => curve _fc_response,
+                Err(e) => {
+                    warn!(
+                        "error deserializing curve fc response: {}, body: {}",
+                        e, body_str
+                    );
+                    return self.handle_function_calling_failure(
+                        ServerError::Deserialization(e),
+                        None,
+                        callout_context,
+                    );
+                }
+            },
+        };
+
+        let curve _fc_response = match server_response {
+            ModelServerResponse::ChatCompletionsResponse(response) => response,
+            ModelServerResponse::ModelServerErrorResponse(response) => {
+                debug!(
+                    "[R={}] curve <= curve fc error response: {}",
+                    self.request_id, response.result
+                );
+                if self.debug_route {
+                    let default_prompt_target = self.default_prompt_target();
+                    return self.send_route_decision(RouteDecision {
+                        prompt_target: default_prompt_target.map(|pt| pt.name),
+                        message: Some(response.result),
+                        ..RouteDecision::new("no_match")
+                    });
+                }
+                if response.result == "No intent matched" {
+                    if let Some(default_prompt_target) = self.default_prompt_target() {
+                        debug!("[R={}] default prompt target found, forwarding request to default prompt target", self.request_id);
+                        return self.schedule_default_target_request(
+                            default_prompt_target,
+                            callout_context,
+                        );
+                    }
+                }
+                return self.handle_function_calling_failure(
+                    ServerError::LogicError(response.result),
+                    Some(StatusCode::BAD_REQUEST),
+                    callout_context,
+                );
+            }
+        };
+
+        curve _fc_response.choices[0]
+            .message
+            .tool_calls
+            .clone_into(&mut self.tool_calls);
+
+        // tool calls to an alias are made to the prompt target it names
+        for tool_call in self.tool_calls.iter_mut().flatten() {
+            if let Some(prompt_target) = self
+                .prompt_targets
+                .values()
+                .find(|pt| pt.has_alias(&tool_call.function.name))
+            {
+                tool_call.function.name = prompt_target.name.clone();
+            }
+        }
+
+        if self.tool_calls.as_ref().unwrap().len() > 1 {
+            warn!(
+                "multiple tool calls not supported yet, tool_calls count found: {}",
+                self.tool_calls.as_ref().unwrap().len()
+            );
+        }
+
+        if self.tool_calls.is_none() || self.tool_calls.as_ref().unwrap().is_empty() {
+            // This means that Curve FC did not have enough information to resolve the function call
+            // Curve FC probably responded with a message asking for more information.
+            // Let's send the response back to the user to initialize lightweight dialog for parameter collection
+
+            //TODO: add resolver name to the response so the client can send the response back to the correct resolver
+
+            if self.debug_route {
+                return self.send_route_decision(RouteDecision {
+                    message: curve _fc_response.choices[0]
+                        .message
+                        .content
+                        .as_ref()
+                        .map(|content| content.text()),
+                    ..RouteDecision::new("parameter_collection")
+                });
+            }
+
+            let limits = self.parameter_collection_limits();
+            let turns =
+                parameter_collection::collection_turns(&callout_context.request_body.messages);
+            let started_at = match turns {
+                0 => None,
+                _ => callout_context
+                    .request_body
+                    .metadata
+                    .as_ref()
+                    .and_then(|metadata| metadata.get(CURVE_PARAMETER_COLLECTION_START_KEY))
+                    .and_then(|started_at| started_at.parse::<u64>().ok()),
+            };
+            if let Some(limits) = limits.as_ref() {
+                if let Err(e) =
+                    parameter_collection::check_limits(limits, turns, started_at, SystemTime::now())
+                {
+                    self.tool_calls = None;
+                    return self.abandon_parameter_collection(e, limits, callout_context);
+                }
+            }
+
+            // the question is forwarded to streaming clients as the resolver streamed it
+            let fc_stream = fc_stream.filter(|_| self.streaming_response);
+            let direct_response_str = if let Some(fc_stream) = fc_stream {
+                fc_stream
+            } else if self.streaming_response {
+                let chunks = vec![
+                    ChatCompletionStreamResponse::new(
+                        None,
+                        Some(ASSISTANT_ROLE.to_string()),
+                        Some(CURVE_FC_MODEL_NAME.to_owned()),
+                        None,
+                    ),
+                    ChatCompletionStreamResponse::new(
+                        Some(
+                            curve _fc_response.choices[0]
+                                .message
+                                .content
+                                .as_ref()
+                                .unwrap()
+                                .text(),
+                        ),
+                        None,
+                        Some(CURVE_FC_MODEL_NAME.to_owned()),
+                        None,
+                    ),
+                ];
+
+                to_server_events(chunks)
+            } else if limits.is_some_and(|limits| limits.timeout_seconds.is_some()) {
+                let started_at = started_at
+                    .unwrap_or_else(|| parameter_collection::unix_seconds(SystemTime::now()));
+                add_parameter_collection_start(body_str, started_at)
+            } else {
+                body_str
+            };
+
+            self.tool_calls = None;
+            return self.send_http_response(
+                StatusCode::OK.as_u16().into(),
+                vec![],
+                Some(direct_response_str.as_bytes()),
+            );
+        }
+
+        let candidates = self.ambiguous_candidates();
+        if candidates.len() > 1 {
+            if self.debug_route {
+                return self.send_route_decision(RouteDecision {
+                    message: Some(candidates.join(", ")),
+                    ..RouteDecision::new("clarification")
+                });
+            }
+            return self.send_clarification_question(candidates);
+        }
+
+        // in merge mode curve fc may pick one of the client's own tools, hand the tool call back to the client
+        let tool_name = &self.tool_calls.as_ref().unwrap()[0].function.name;
+        if self.debug_route {
+            let function = &self.tool_calls.as_ref().unwrap()[0].function;
+            let decision = match self.prompt_targets.get(tool_name) {
+                Some(prompt_target) => RouteDecision {
+                    shadow: prompt_target.shadow,
+                    ..RouteDecision::new("matched")
+                },
+                None => RouteDecision::new("client_tool"),
+            };
+            return self.send_route_decision(RouteDecision {
+                prompt_target: Some(function.name.clone()),
+                arguments: Some(function.arguments.clone()),
+                ..decision
+            });
+        }
+        if self.client_tools_mode == ClientToolsMode::Merge
+            && !self.prompt_targets.contains_key(tool_name)
+        {
+            debug!(
+                "[R={}] curve fc picked client tool: {}",
+                self.request_id, tool_name
+            );
+            if self.streaming_response {
+                let client_tool_response_str =
+                    to_server_events(vec![ChatCompletionStreamResponse::new(
+                        None,
+                        Some(ASSISTANT_ROLE.to_string()),
+                        Some(CURVE_FC_MODEL_NAME.to_owned()),
+                        self.tool_calls.take(),
+                    )]);
+                return self.send_http_response(
+                    StatusCode::OK.as_u16().into(),
+                    vec![],
+                    Some(client_tool_response_str.as_bytes()),
+                );
+            }
+
+            // only the tool call goes back to the client, not the rest of the curve fc response
+            let mut client_tool_response = ChatCompletionsResponse::new(String::new());
+            let choice = &mut client_tool_response.choices[0];
+            choice.message.content = None;
+            choice.message.tool_calls = self.tool_calls.take();
+            choice.finish_reason = Some("tool_calls".to_string());
+            let client_tool_response_str = serde_json::to_string(&client_tool_response).unwrap();
+            return self.send_http_response(
+                StatusCode::OK.as_u16().into(),
+                vec![("content-type", "application/json")],
+                Some(client_tool_response_str.as_bytes()),
+            );
+        }
+
+        self.dispatch_tool_call(callout_context);
+    }
+
+    // calls the prompt target of the tool call, once the client is allowed to and its conditions
+    // hold
+    fn dispatch_tool_call(&mut self, mut callout_context: StreamCallContext) {
+        let tool_name = self.tool_calls.as_ref().unwrap()[0].function.name.clone();
+        // update prompt target name from the tool call
+        callout_context.prompt_target_name = Some(tool_name.clone());
+
+        if let Some(hook) = self.next_hook(HookPoint::PostIntent) {
+            return self.schedule_hook_request(
+                hook,
+                callout_context,
+                None,
+                callouts::post_intent_hook,
+            );
+        }
+
+        let access_control = Rc::clone(&self.access_control);
+        if let Some(access_control) = Option::as_ref(&access_control) {
+            let identity = self.client_identity.as_deref();
+            if !access_control::is_allowed(access_control, identity, &tool_name) {
+                warn!(
+                    "client {} is not allowed to call prompt target {}",
+                    identity.unwrap_or("<none>"),
+                    tool_name
+                );
+                self.metrics
+                    .unauthorized_prompt_targets
+                    .with(&[(Dimension::Target, &tool_name)])
+                    .increment(1);
+                self.tool_calls = None;
+                return self.handle_unauthorized_prompt_target(
+                    access_control.on_unauthorized.unwrap_or_default(),
+                    &tool_name,
+                    callout_context,
+                );
+            }
+            debug!(
+                "[R={}] client {} is allowed to call prompt target {}",
+                self.request_id,
+                identity.unwrap_or_default(),
+                tool_name
+            );
+            self.metrics
+                .authorized_prompt_targets
+                .with(&[(Dimension::Target, &tool_name)])
+                .increment(1);
+        }
+
+        let prompt_targets = Rc::clone(&self.prompt_targets);
+        if let Some(conditions) = prompt_targets[&tool_name].conditions.as_ref() {
+            let now = self.get_current_time();
+            if let Err(e) = conditions::check(conditions, &self.condition_headers, now) {
+                debug!(
+                    "[R={}] conditions of prompt target {} not met: {}",
+                    self.request_id, tool_name, e
+                );
+                let response = ChatCompletionsResponse::new(conditions.refusal_message().into());
+                self.tool_calls = None;
+                return match serde_json::to_vec(&response) {
+                    Ok(body) => self.send_target_response(body),
+                    Err(e) => self.send_server_error(ServerError::Serialization(e), None),
+                };
+            }
+        }
+
+        if self.prompt_targets[&tool_name].shadow.unwrap_or_default() {
+            return self.schedule_shadow_call_request(callout_context);
+        }
+
+        self.schedule_api_call_request(callout_context);
+    }
+
+    fn parameter_collection_limits(&self) -> Option<ParameterCollection> {
+        let group = self.matched_prompt_target_group.as_deref();
+        parameter_collection::strictest(
+            self.prompt_targets
+                .values()
+                .filter(|pt| in_group(pt, group))
+                .filter_map(|pt| pt.parameter_collection.as_ref()),
+        )
+    }
+
+    fn abandon_parameter_collection(
+        &mut self,
+        error: parameter_collection::Error,
+        limits: &ParameterCollection,
+        callout_context: StreamCallContext,
+    ) {
+        warn!("abandoning parameter collection: {}", error);
+        if limits.forward_to_error_target.unwrap_or_default() {
+            let report = serde_json::json!({
+                "type": "parameter_collection",
+                "message": error.to_string(),
+            });
+            if self.forward_to_error_target(
+                report,
+                callout_context.prompt_target_name,
+                callouts::error_target,
+            ) {
+                return;
+            }
+            warn!("no error target configured, returning error to the client");
+        }
+
+        let body = serde_json::json!({ "error": error }).to_string();
+        self.send_http_response(
+            StatusCode::UNPROCESSABLE_ENTITY.as_u16().into(),
+            vec![("content-type", "application/json")],
+            Some(body.as_bytes()),
+        );
+    }
+
+    fn enforce_prompt_target_ratelimits(
+        &self,
+        prompt_target_name: &str,
+        body: &str,
+    ) -> Result<(), ratelimit::Error> {
+        // not every model name is known to the tokenizer, gpt-4 bpe is close enough for a limit
+        let token_count = tokenizer::token_count("gpt-4", body).unwrap_or(0);
+        let tokens_used = NonZero::new(u32::try_from(token_count).unwrap_or(u32::MAX))
+            .unwrap_or(NonZero::<u32>::MIN);
+        ratelimit::prompt_target_ratelimits(None)
+            .read()
+            .unwrap()
+            .check_limit(prompt_target_name, tokens_used, self.get_current_time())
+    }
+
+    fn schedule_api_call_request(&mut self, callout_context: StreamCallContext) {
+        let tools_call_name = self.tool_calls.as_ref().unwrap()[0].function.name.clone();
+
+        let prompt_target = self.prompt_targets.get(&tools_call_name).unwrap().clone();
+
+        let mut tool_params = self.tool_calls.as_ref().unwrap()[0]
+            .function
+            .arguments
+            .clone();
+
+        if prompt_target.cache.is_some() {
+            let cache_key = response_cache::cache_key(&tools_call_name, &tool_params);
+            if !self.cache_bypass {
+                if let Some(cached_response) = self.cached_response(&cache_key) {
+                    debug!(
+                        "[R={}] curve <= cached api call response, key: {}",
+                        self.request_id, cache_key
+                    );
+                    self.metrics
+                        .cache_hits
+                        .with(&[(Dimension::Target, &tools_call_name)])
+                        .increment(1);
+                    self.tool_call_response = Some(cached_response);
+                    return self.send_api_response_to_llm(callout_context);
+                }
+            }
+            self.response_cache_key = Some(cache_key);
+        }
+
+        tool_params.insert(
+            String::from(MESSAGES_KEY),
+            serde_yaml::to_value(&callout_context.request_body.messages).unwrap(),
+        );
+
+        let tool_params_json_str = serde_json::to_string(&tool_params).unwrap();
+
+        if let Err(e) =
+            self.enforce_prompt_target_ratelimits(&tools_call_name, &tool_params_json_str)
+        {
+            self.metrics
+                .ratelimited_rq
+                .with(&[(Dimension::Target, &tools_call_name)])
+                .increment(1);
+            notifications::notify(
+                NotificationEvent::RatelimitBreach,
+                &self.request_id,
+                serde_json::json!({ "prompt_target": tools_call_name, "message": e.to_string() }),
+                self.get_current_time(),
+            );
+            return self.send_server_error(
+                ServerError::ExceededRatelimit(e),
+                Some(StatusCode::TOO_MANY_REQUESTS),
+            );
+        }
+
+        if prompt_target.steps.is_some() {
+            self.chain_step = 0;
+            self.step_responses.clear();
+            return self.schedule_chain_step(callout_context);
+        }
+
+        let endpoint = prompt_target.endpoint.unwrap();
+        if let Err(e) = self.dispatch_api_call(
+            endpoint,
+            &tool_params,
+            &tool_params_json_str,
+            callouts::function_call,
+            callout_context,
+        ) {
+            self.send_server_error(e, Some(StatusCode::BAD_REQUEST));
+        }
+    }
+
+    // calls the current step of the prompt target chain, steps whose conditions don't hold are
+    // skipped. Once all the steps are done the llm gets their responses keyed by step name.
+    fn schedule_chain_step(&mut self, callout_context: StreamCallContext) {
+        let tool_call = &self.tool_calls.as_ref().unwrap()[0];
+        let prompt_target = self.prompt_targets[&tool_call.function.name].clone();
+        let steps = prompt_target.steps.unwrap_or_default();
+
+        let mut params = match serde_json::to_value(&tool_call.function.arguments) {
+            Ok(serde_json::Value::Object(params)) => params,
+            _ => serde_json::Map::new(),
+        };
+        params.insert(
+            String::from(MESSAGES_KEY),
+            serde_json::to_value(&callout_context.request_body.messages).unwrap(),
+        );
+
+        let step = loop {
+            match steps.get(self.chain_step) {
+                Some(step) if chain::should_run(step, &params, &self.step_responses) => break step,
+                Some(step) => {
+                    debug!(
+                        "[R={}] skipping chain step {}, its conditions don't hold",
+                        self.request_id, step.name
+                    );
+                    self.chain_step += 1;
+                }
+                None => {
+                    self.tool_call_response =
+                        Some(serde_json::Value::Object(self.step_responses.clone()).to_string());
+                    if let Some(cache_key) = self.response_cache_key.take() {
+                        self.cache_response(&cache_key, Some(&prompt_target.name));
+                    }
+                    return self.send_api_response_to_llm(callout_context);
+                }
+            }
+        };
+
+        let inputs = chain::step_inputs(step, &params, &self.step_responses);
+        let inputs_json_str = serde_json::Value::Object(inputs.clone()).to_string();
+        let inputs: HashMap<String, Value> = inputs
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, serde_yaml::to_value(value).ok()?)))
+            .collect();
+
+        debug!(
+            "[R={}] curve => chain step {} of prompt target {}",
+            self.request_id, step.name, prompt_target.name
+        );
+        if let Err(e) = self.dispatch_api_call(
+            step.endpoint.clone(),
+            &inputs,
+            &inputs_json_str,
+            callouts::chain_step,
+            callout_context,
+        ) {
+            self.send_server_error(e, Some(StatusCode::BAD_REQUEST));
+        }
+    }
+
+    pub fn chain_step_response_handler(
+        &mut self,
+        http_status: &str,
+        body: Vec<u8>,
+        callout_context: StreamCallContext,
+    ) {
+        let tool_call_name = &self.tool_calls.as_ref().unwrap()[0].function.name;
+        let prompt_targets = Rc::clone(&self.prompt_targets);
+        let steps = prompt_targets[tool_call_name].steps.as_ref().unwrap();
+        let step = &steps[self.chain_step];
+        debug!(
+            "[R={}] curve <= chain step {} response, http_status: {}",
+            self.request_id, step.name, http_status
+        );
+
+        let body_str = self.call_response_text(&body);
+        let step_response = if http_status == StatusCode::OK.as_str() {
+            serde_json::from_slice(&body).unwrap_or(serde_json::Value::String(body_str))
+        } else {
+            if step.on_error.unwrap_or_default() == OnStepError::Abort {
+                warn!(
+                    "chain step {} responded with non 2xx status code: {}",
+                    step.name, http_status
+                );
+                return self.send_server_error(
+                    ServerError::Upstream {
+                        host: callout_context.upstream_cluster.unwrap(),
+                        path: callout_context.upstream_cluster_path.unwrap(),
+                        status: http_status.to_string(),
+                        body: body_str,
+                    },
+                    StatusCode::from_str(http_status).ok(),
+                );
+            }
+            // responses of failed steps are never cached
+            self.response_cache_key = None;
+            serde_json::json!({ "error": { "status": http_status, "body": body_str } })
+        };
+
+        self.step_responses.insert(step.name.clone(), step_response);
+        self.chain_step = chain::next_step(steps, self.chain_step);
+        self.schedule_chain_step(callout_context);
+    }
+
+    // shadow targets are called with the resolved parameters like any other target, but the
+    // conversation continues as if no prompt target matched and the response is only logged
+    fn schedule_shadow_call_request(&mut self, callout_context: StreamCallContext) {
+        let tool_call = self.tool_calls.take().unwrap().remove(0);
+        let prompt_target = self.prompt_targets[&tool_call.function.name].clone();
+
+        let mut tool_params = tool_call.function.arguments;
+        tool_params.insert(
+            String::from(MESSAGES_KEY),
+            serde_yaml::to_value(&callout_context.request_body.messages).unwrap(),
+        );
+        let tool_params_json_str = serde_json::to_string(&tool_params).unwrap();
+
+        if let Err(e) =
+            self.enforce_prompt_target_ratelimits(&prompt_target.name, &tool_params_json_str)
+        {
+            self.metrics
+                .ratelimited_rq
+                .with(&[(Dimension::Target, &prompt_target.name)])
+                .increment(1);
+            warn!("skipping shadow call to {}: {}", prompt_target.name, e);
+        } else if let Err(e) = self.dispatch_api_call(
+            prompt_target.endpoint.unwrap(),
+            &tool_params,
+            &tool_params_json_str,
+            callouts::shadow_call,
+            callout_context.clone(),
+        ) {
+            warn!(
+                "error dispatching shadow call to {}: {}",
+                prompt_target.name, e
+            );
+        } else {
+            self.metrics
+                .shadow_calls
+                .with(&[(Dimension::Target, &prompt_target.name)])
+                .increment(1);
+        }
+
+        self.continue_without_prompt_target(callout_context);
+    }
+
+    // the request goes on as if no prompt target had matched
+    fn continue_without_prompt_target(&mut self, mut callout_context: StreamCallContext) {
+        callout_context.prompt_target_name = None;
+        match self.default_prompt_target() {
+            Some(default_prompt_target) => {
+                self.schedule_default_target_request(default_prompt_target, callout_context)
+            }
+            None => {
+                let messages = self.filter_out_curve _messages(&callout_context);
+                self.send_messages_to_llm(messages, callout_context);
+            }
+        }
+    }
+
+    fn handle_unauthorized_prompt_target(
+        &mut self,
+        on_unauthorized: OnUnauthorized,
+        prompt_target_name: &str,
+        callout_context: StreamCallContext,
+    ) {
+        match on_unauthorized {
+            OnUnauthorized::Llm => self.continue_without_prompt_target(callout_context),
+            OnUnauthorized::Reject => {
+                let body = serde_json::json!({
+                    "error": {
+                        "type": "unauthorized_prompt_target",
+                        "prompt_target": prompt_target_name,
+                    }
+                })
+                .to_string();
+                self.send_http_response(
+                    StatusCode::FORBIDDEN.as_u16().into(),
+                    vec![("content-type", "application/json")],
+                    Some(body.as_bytes()),
+                );
+            }
+        }
+    }
+
+    fn dispatch_api_call(
+        &self,
+        endpoint: EndpointDetails,
+        tool_params: &HashMap<String, Value>,
+        tool_params_json_str: &str,
+        callout: CalloutBuilder,
+        mut callout_context: StreamCallContext,
+    ) -> Result<(), ServerError> {
+        let auth_header = self.endpoint_auth_header(&endpoint);
+        let path_template = endpoint.path.as_deref().unwrap_or("/");
+
+        // only scalar params can be put in the path
+        let url_params = tool_params
+            .iter()
+            .filter_map(|(key, value)| Some((key.clone(), common::path::param_value(value)?)))
+            .collect::<HashMap<String, String>>();
+
+        let mut path =
+            common::path::replace_params_in_path(path_template, &url_params).map_err(|e| {
+                ServerError::BadRequest {
+                    why: format!("error replacing params in path: {}", e),
+                }
+            })?;
+
+        let http_method = Method::from(&endpoint.method.clone().unwrap_or_default());
+        // get requests have no body, the arguments of the tool call that are not in the path go in
+        // the query string. The conversation stays out of urls and access logs.
+        if http_method == Method::GET {
+            let query = common::path::query_string(tool_params.iter().filter(|(key, _)| {
+                key.as_str() != MESSAGES_KEY && !path_template.contains(&format!("{{{}}}", key))
+            }));
+            path = common::path::with_query(&path, &query);
+        }
+
+        let mut call_args = CallArgs::internal(&endpoint.name, http_method.clone(), &path);
+        if http_method != Method::GET {
+            call_args = call_args.with_json_body(tool_params_json_str.as_bytes());
+        }
+        call_args = call_args
+            .with_header("x-envoy-max-retries", "3")
+            .with_timeout(Duration::from_secs(5));
+        if let Some((key, value)) = auth_header.as_ref() {
+            call_args = call_args.with_header(key, value);
+        }
+        call_args = call_args.with_header(REQUEST_ID_HEADER, &self.request_id);
+        if let Some(traceparent) = self.traceparent.as_ref() {
+            call_args = call_args.with_header(TRACE_PARENT_HEADER, traceparent);
+        }
+
+        debug!(
+            "[R={}] curve => api call, endpoint: {}{}, body: {}",
+            self.request_id,
+            endpoint.name.as_str(),
+            path,
+            tool_params_json_str
+        );
+
+        callout_context.upstream_cluster = Some(endpoint.name.to_owned());
+        callout_context.upstream_cluster_path = Some(path.to_owned());
+
+        self.http_call(call_args, callout(callout_context))
+            .map(|_| ())
+            .map_err(ServerError::HttpDispatch)
+    }
+
+    fn endpoint_auth_header(&self, endpoint: &EndpointDetails) -> Option<(String, String)> {
+        match endpoint.auth.as_ref()? {
+            EndpointAuth::Passthrough { header } => match self.passthrough_headers.get(header) {
+                Some(value) => Some((header.clone(), value.clone())),
+                None => {
+                    warn!(
+                        "passthrough auth header {} not found in request, endpoint: {}",
+                        header, endpoint.name
+                    );
+                    None
+                }
+            },
+            auth => auth.static_header(),
+        }
+    }
+
+    pub fn api_call_response_handler(&mut self, body: Vec<u8>, callout_context: StreamCallContext) {
+        let http_status = self
+            .get_http_call_response_header(":status")
+            .unwrap_or(StatusCode::OK.as_str().to_string());
+        debug!(
+            "[R={}] api_call_response_handler: http_status: {}",
+            self.request_id, http_status
+        );
+        let async_operation = callout_context
+            .prompt_target_name
+            .as_ref()
+            .and_then(|name| self.prompt_targets.get(name))
+            .and_then(|prompt_target| prompt_target.async_operation.clone());
+        if let Some(async_operation) = async_operation {
+            if http_status == StatusCode::ACCEPTED.as_str() {
+                return self.async_operation_response_handler(
+                    body,
+                    &async_operation,
+                    callout_context,
+                );
+            }
+        }
+        if http_status != StatusCode::OK.as_str() {
+            warn!(
+                "api server responded with non 2xx status code: {}",
+                http_status
+            );
+            return self.send_server_error(
+                ServerError::Upstream {
+                    host: callout_context.upstream_cluster.unwrap(),
+                    path: callout_context.upstream_cluster_path.unwrap(),
+                    status: http_status.clone(),
+                    body: self.call_response_text(&body),
+                },
+                Some(StatusCode::from_str(http_status.as_str()).unwrap()),
+            );
+        }
+        self.tool_call_response = Some(self.call_response_text(&body));
+        debug!(
+            "[R={}] curve <= api call response: {}",
+            self.request_id,
+            self.tool_call_response.as_ref().unwrap()
+        );
+
+        if let Some(cache_key) = self.response_cache_key.take() {
+            self.cache_response(&cache_key, callout_context.prompt_target_name.as_deref());
+        }
+
+        self.send_api_response_to_llm(callout_context);
+    }
+
+    // the endpoint accepted a long running operation, the llm lets the user know it is in progress
+    // and the operation id ends up in the conversation for the status target to pick up
+    fn async_operation_response_handler(
+        &mut self,
+        body: Vec<u8>,
+        async_operation: &AsyncOperation,
+        callout_context: StreamCallContext,
+    ) {
+        let operation_id_field = async_operation.operation_id_field();
+        let operation_id = serde_json::from_slice::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|body| match body.get(operation_id_field)? {
+                serde_json::Value::String(operation_id) => Some(operation_id.clone()),
+                serde_json::Value::Number(operation_id) => Some(operation_id.to_string()),
+                _ => None,
+            });
+        let operation_id = match operation_id {
+            Some(operation_id) => operation_id,
+            None => {
+                return self.send_server_error(
+                    ServerError::Upstream {
+                        host: callout_context.upstream_cluster.unwrap(),
+                        path: callout_context.upstream_cluster_path.unwrap(),
+                        status: StatusCode::ACCEPTED.as_str().to_string(),
+                        body: format!(
+                            "{} not found in response: {}",
+                            operation_id_field,
+                            self.call_response_text(&body)
+                        ),
+                    },
+                    Some(StatusCode::BAD_GATEWAY),
+                );
+            }
+        };
+        debug!(
+            "[R={}] curve <= async operation accepted, operation id: {}",
+            self.request_id, operation_id
+        );
+
+        // in progress responses are never cached, the result isn't known yet
+        self.response_cache_key = None;
+        self.tool_call_response = Some(
+            serde_json::json!({
+                "status": "in_progress",
+                operation_id_field: operation_id,
+                "message": format!(
+                    "the operation was accepted and is still in progress, its status can be \
+                     checked with {}",
+                    async_operation.status_target
+                ),
+            })
+            .to_string(),
+        );
+        self.send_api_response_to_llm(callout_context);
+    }
+
+    pub fn shadow_call_response_handler(
+        &self,
+        http_status: &str,
+        body: Vec<u8>,
+        callout_context: StreamCallContext,
+    ) {
+        debug!(
+            "[R={}] curve <= shadow call response, prompt target: {}, status: {}, body: {}",
+            self.request_id,
+            callout_context.prompt_target_name.unwrap_or_default(),
+            http_status,
+            self.call_response_text(&body)
+        );
+    }
+
+    fn cached_response(&self, cache_key: &str) -> Option<String> {
+        let (data, _) = self.get_shared_data(cache_key);
+        let entry: CacheEntry = serde_json::from_slice(&data?).ok()?;
+        if entry.is_expired(SystemTime::now()) {
+            return None;
+        }
+        Some(entry.response)
+    }
+
+    fn cache_response(&self, cache_key: &str, prompt_target_name: Option<&str>) {
+        let ttl = match prompt_target_name
+            .and_then(|name| self.prompt_targets.get(name))
+            .and_then(|pt| pt.cache.as_ref())
+        {
+            Some(cache) => Duration::from_secs(cache.ttl_seconds),
+            None => return,
+        };
+
+        let entry = CacheEntry::new(
+            self.tool_call_response.clone().unwrap_or_default(),
+            ttl,
+            SystemTime::now(),
+        );
+        let entry_bytes = serde_json::to_vec(&entry).unwrap();
+        if let Err(e) = self.set_shared_data(cache_key, Some(&entry_bytes), None) {
+            warn!(
+                "error caching api call response, key: {}: {:?}",
+                cache_key, e
+            );
+        }
+    }
+
+    // how the endpoint response of the called prompt target is handed to the llm
+    fn tool_response(&self, callout_context: &StreamCallContext) -> ToolResponse {
+        callout_context
+            .prompt_target_name
+            .as_ref()
+            .and_then(|name| self.prompt_targets.get(name))
+            .and_then(|prompt_target| prompt_target.tool_response.clone())
+            .unwrap_or_default()
+    }
+
+    fn send_api_response_to_llm(&mut self, callout_context: StreamCallContext) {
+        let response_mode = callout_context
+            .prompt_target_name
+            .as_ref()
+            .and_then(|name| self.prompt_targets.get(name))
+            .and_then(|prompt_target| prompt_target.response_mode)
+            .unwrap_or_default();
+        if response_mode != ResponseMode::Llm {
+            return self.send_direct_response(response_mode);
+        }
+
+        let tool_response = self.tool_response(&callout_context);
+        if tool_response.guard.unwrap_or_default() {
+            return self.guard_tool_call_response(callout_context);
+        }
+        self.add_api_response_to_messages(callout_context);
+    }
+
+    // the jailbreak guard of the model server checks the endpoint response before the llm gets it
+    fn guard_tool_call_response(&mut self, mut callout_context: StreamCallContext) {
+        let guard_request = PromptGuardRequest {
+            input: self.tool_call_response.clone().unwrap_or_default(),
+            task: PromptGuardTask::Jailbreak,
+            model: Some(self.guard_model.to_string()),
+        };
+        let json_data = match serde_json::to_string(&guard_request) {
+            Ok(json_data) => json_data,
+            Err(error) => {
+                return self.send_server_error(ServerError::Serialization(error), None);
+            }
+        };
+
+        let mut call_args = CallArgs::internal(MODEL_SERVER_NAME, Method::POST, GUARDRAILS_PATH)
+            .with_json_body(json_data.as_bytes())
+            .with_timeout(Duration::from_secs(5))
+            .with_header(REQUEST_ID_HEADER, &self.request_id);
+        if let Some(traceparent) = self.traceparent.as_ref() {
+            call_args = call_args.with_header(TRACE_PARENT_HEADER, traceparent);
+        }
+
+        callout_context.upstream_cluster = Some(MODEL_SERVER_NAME.to_string());
+        callout_context.upstream_cluster_path = Some(GUARDRAILS_PATH.to_string());
+        if let Err(e) = self.http_call(call_args, callouts::tool_response_guard(callout_context)) {
+            self.send_server_error(ServerError::HttpDispatch(e), None);
+        }
+    }
+
+    pub fn tool_response_guard_handler(
+        &mut self,
+        body: Vec<u8>,
+        callout_context: StreamCallContext,
+    ) {
+        let guard_response: PromptGuardResponse = match serde_json::from_slice(&body) {
+            Ok(guard_response) => guard_response,
+            Err(e) => {
+                return self.send_server_error(ServerError::Deserialization(e), None);
+            }
+        };
+        if !guard_response.is_jailbreak() {
+            return self.add_api_response_to_messages(callout_context);
+        }
 
-    pub fn api_call_response_handler(&mut self, body: Vec<u8>, callout_context: StreamCallContext) {
-        let http_status = self
-            .get_http_call_response_header(":status")
-            .unwrap_or(StatusCode::OK.as_str().to_string());
-        debug!("api_call_response_handler: http_status: {}", http_status);
-        if http_status != StatusCode::OK.as_str() {
-            warn!(
-                "api server responded with non 2xx status code: {}",
-                http_status
-            );
-            return self.send_server_error(
-                ServerError::Upstream {
-                    host: callout_context.upstream_cluster.unwrap(),
-                    path: callout_context.upstream_cluster_path.unwrap(),
-                    status: http_status.clone(),
-                    body: String::from_utf8(body).unwrap(),
-                },
-                Some(StatusCode::from_str(http_status.as_str()).unwrap()),
-            );
+        let prompt_target_name = callout_context.prompt_target_name.clone();
+        warn!(
+            "[R={}] jailbreak guard flagged the response of prompt target {}",
+            self.request_id,
+            prompt_target_name.as_deref().unwrap_or_default()
+        );
+        let message = self
+            .tool_response(&callout_context)
+            .on_exception_message
+            .unwrap_or(DEFAULT_GUARD_MESSAGE.to_string());
+        let report = serde_json::json!({
+            "type": "guard_violation",
+            "guard": GuardType::Jailbreak.to_string(),
+            "message": message,
+            "prompt_target": prompt_target_name,
+        });
+        if self.forwards_error(ErrorEvent::GuardViolation)
+            && self.forward_to_error_target(
+                report.clone(),
+                prompt_target_name,
+                callouts::error_target,
+            )
+        {
+            return;
         }
-        self.tool_call_response = Some(String::from_utf8(body).unwrap());
-        debug!(
-            "curve <= api call response: {}",
-            self.tool_call_response.as_ref().unwrap()
+        let body = serde_json::json!({ "error": report }).to_string();
+        self.send_http_response(
+            StatusCode::BAD_GATEWAY.as_u16().into(),
+            vec![("content-type", "application/json")],
+            Some(body.as_bytes()),
         );
+    }
 
+    fn add_api_response_to_messages(&mut self, callout_context: StreamCallContext) {
         let mut messages = self.filter_out_curve _messages(&callout_context);
 
+        // the user prompt stays as is and the response answers the tool call, the llm is told
+        // that the tool message is data
+        let role = self
+            .tool_response(&callout_context)
+            .role
+            .unwrap_or_default();
+        let has_tool_call = self
+            .tool_calls
+            .as_ref()
+            .is_some_and(|tool_calls| !tool_calls.is_empty());
+        if role == ToolResponseRole::Tool && has_tool_call {
+            messages.push(Message {
+                role: SYSTEM_ROLE.to_string(),
+                content: Some(TOOL_RESPONSE_NOTE.to_string().into()),
+                model: None,
+                tool_calls: None,
+                tool_call_id: None,
+                name: None,
+            });
+            let mut tool_call_message = self.generate_toll_call_message();
+            tool_call_message.model = None;
+            messages.push(tool_call_message);
+            messages.push(self.generate_api_response_message());
+            return self.send_messages_to_llm(messages, callout_context);
+        }
+
         let user_message = match messages.pop() {
             Some(user_message) => user_message,
             None => {
@@ -399,11 +2193,10 @@ impl StreamContext {
             }
         };
 
-        let final_prompt = format!(
-            "{}\ncontext: {}",
-            user_message.content.unwrap(),
-            self.tool_call_response.as_ref().unwrap()
-        );
+        let final_prompt = user_message
+            .content
+            .unwrap()
+            .with_context(self.tool_call_response.as_ref().unwrap());
 
         // add original user prompt
         messages.push({
@@ -413,16 +2206,73 @@ impl StreamContext {
                 model: None,
                 tool_calls: None,
                 tool_call_id: None,
+                name: None,
             }
         });
 
+        self.send_messages_to_llm(messages, callout_context);
+    }
+
+    // the endpoint response goes back to the client without the final llm call
+    fn send_direct_response(&mut self, response_mode: ResponseMode) {
+        let api_response = self.tool_call_response.clone().unwrap_or_default();
+        debug!(
+            "[R={}] curve => direct response: {}",
+            self.request_id, api_response
+        );
+        if response_mode == ResponseMode::DirectChat {
+            let response = ChatCompletionsResponse::new(api_response);
+            return match serde_json::to_vec(&response) {
+                Ok(body) => self.send_target_response(body),
+                Err(e) => self.send_server_error(ServerError::Serialization(e), None),
+            };
+        }
+        self.send_http_response(
+            StatusCode::OK.as_u16().into(),
+            vec![("content-type", "application/json")],
+            Some(api_response.as_bytes()),
+        );
+    }
+
+    fn send_messages_to_llm(
+        &mut self,
+        messages: Vec<Message>,
+        mut callout_context: StreamCallContext,
+    ) {
+        if let Some(hook) = self.next_hook(HookPoint::PreProvider) {
+            callout_context.request_body.messages = messages;
+            return self.schedule_hook_request(
+                hook,
+                callout_context,
+                None,
+                callouts::pre_provider_hook,
+            );
+        }
+
+        // client tools are only forwarded to the llm in merge mode
+        let tools = match self.client_tools_mode {
+            ClientToolsMode::Merge => callout_context.request_body.tools,
+            _ => None,
+        };
+
+        // the output schema of the prompt target applies unless the client asked for a format
+        let mut extra_fields = callout_context.request_body.extra_fields;
+        if let Some(prompt_target) = callout_context
+            .prompt_target_name
+            .as_ref()
+            .and_then(|name| self.prompt_targets.get(name))
+        {
+            self.with_output_schema(prompt_target.clone(), &mut extra_fields);
+        }
+
         let chat_completions_request: ChatCompletionsRequest = ChatCompletionsRequest {
             model: callout_context.request_body.model,
             messages,
-            tools: None,
+            tools,
             stream: callout_context.request_body.stream,
             stream_options: callout_context.request_body.stream_options,
             metadata: None,
+            extra_fields,
         };
 
         let llm_request_str = match serde_json::to_string(&chat_completions_request) {
@@ -431,7 +2281,16 @@ impl StreamContext {
                 return self.send_server_error(ServerError::Serialization(e), None);
             }
         };
-        debug!("curve => llm request: {}", llm_request_str);
+        debug!(
+            "[R={}] curve => llm request: {}",
+            self.request_id, llm_request_str
+        );
+
+        // let the llm gateway know which prompt target produced the request
+        self.set_http_request_header(
+            CURVE_PROMPT_TARGET_HEADER,
+            callout_context.prompt_target_name.as_deref(),
+        );
 
         self.start_upstream_llm_request_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -442,6 +2301,177 @@ impl StreamContext {
         self.resume_http_request();
     }
 
+    // hooks of a point run one after the other in the order they are configured
+    fn next_hook(&self, point: HookPoint) -> Option<Hook> {
+        let hooks_run = self.hooks_run.get(&point).copied().unwrap_or_default();
+        self.hooks
+            .iter()
+            .filter(|hook| hook.point == point)
+            .nth(hooks_run)
+            .cloned()
+    }
+
+    fn schedule_hook_request(
+        &mut self,
+        hook: Hook,
+        callout_context: StreamCallContext,
+        response: Option<ChatCompletionsResponse>,
+        callout: CalloutBuilder,
+    ) {
+        let hook_request = HookRequest {
+            hook: hook.name.clone(),
+            point: hook.point,
+            messages: callout_context.request_body.messages.clone(),
+            prompt_target: callout_context.prompt_target_name.clone(),
+            response,
+        };
+        let hook_request_str = match serde_json::to_string(&hook_request) {
+            Ok(hook_request_str) => hook_request_str,
+            Err(e) => return self.send_server_error(ServerError::Serialization(e), None),
+        };
+        debug!(
+            "[R={}] curve => hook {}: {}",
+            self.request_id, hook.name, hook_request_str
+        );
+        self.schedule_endpoint_request(hook.endpoint, hook_request_str, callout_context, callout);
+    }
+
+    // the hook either rewrites the messages, which then go on to the next hook or from the point
+    // of the hook, or answers the request itself
+    pub fn hook_response_handler(
+        &mut self,
+        point: HookPoint,
+        body: Vec<u8>,
+        mut callout_context: StreamCallContext,
+    ) {
+        *self.hooks_run.entry(point).or_default() += 1;
+        debug!(
+            "[R={}] curve <= hook response: {}",
+            self.request_id,
+            self.call_response_text(&body)
+        );
+        let hook_response: HookResponse = match serde_json::from_slice(&body) {
+            Ok(hook_response) => hook_response,
+            Err(e) => {
+                warn!("error deserializing hook response: {}", e);
+                return self.send_server_error(ServerError::Deserialization(e), None);
+            }
+        };
+
+        if point == HookPoint::PostResponse {
+            return self.post_response_hook_handler(hook_response);
+        }
+
+        if let Some(response) = hook_response.response {
+            self.metrics.hook_responses.increment(1);
+            return match serde_json::to_vec(&response) {
+                Ok(body) => self.send_target_response(body),
+                Err(e) => self.send_server_error(ServerError::Serialization(e), None),
+            };
+        }
+
+        let rewritten = hook_response.messages.is_some();
+        if let Some(messages) = hook_response.messages {
+            callout_context.request_body.messages = messages;
+        }
+        match point {
+            HookPoint::PreGuard => {
+                // the rewritten request replaces the one of the client
+                if rewritten {
+                    let body = match serde_json::to_vec(&callout_context.request_body) {
+                        Ok(body) => body,
+                        Err(e) => {
+                            return self.send_server_error(ServerError::Serialization(e), None)
+                        }
+                    };
+                    self.set_http_request_body(0, self.request_body_size, &body);
+                    self.request_body_size = body.len();
+                }
+                if self.run_pre_guard_hooks(callout_context.request_body) == Action::Continue {
+                    self.resume_http_request();
+                }
+            }
+            HookPoint::PostIntent => self.dispatch_tool_call(callout_context),
+            HookPoint::PreProvider => {
+                let messages = callout_context.request_body.messages.clone();
+                self.send_messages_to_llm(messages, callout_context);
+            }
+            HookPoint::PostResponse => unreachable!(),
+        }
+    }
+
+    // non streamed responses are read whole when post response hooks run on them
+    pub fn has_post_response_hooks(&self) -> bool {
+        !self.streaming_response
+            && self.client_request.is_some()
+            && self.next_hook(HookPoint::PostResponse).is_some()
+    }
+
+    // non streamed llm responses go through the post response hooks before they reach the client.
+    // Returns whether the response waits for a hook.
+    pub fn schedule_post_response_hook(&mut self, body: &str, body_size: usize) -> bool {
+        if self.streaming_response {
+            return false;
+        }
+        let (Some(hook), Some(request_body)) = (
+            self.next_hook(HookPoint::PostResponse),
+            self.client_request.clone(),
+        ) else {
+            return false;
+        };
+        let response: ChatCompletionsResponse = match serde_json::from_str(body) {
+            Ok(response) => response,
+            Err(e) => {
+                warn!(
+                    "could not deserialize response, skipping post response hooks: {}",
+                    e
+                );
+                return false;
+            }
+        };
+        let callout_context = StreamCallContext {
+            user_message: None,
+            prompt_target_name: self.called_prompt_target(),
+            request_body,
+            similarity_scores: None,
+            upstream_cluster: None,
+            upstream_cluster_path: None,
+        };
+        self.post_response_body_size = body_size;
+        self.schedule_hook_request(
+            hook,
+            callout_context,
+            Some(response),
+            callouts::post_response_hook,
+        );
+        true
+    }
+
+    // the response of the hook replaces the llm response, which then goes on to the next hook or
+    // the client
+    fn post_response_hook_handler(&mut self, hook_response: HookResponse) {
+        let body = match hook_response.response {
+            Some(response) => {
+                self.metrics.hook_responses.increment(1);
+                let body = match serde_json::to_string(&response) {
+                    Ok(body) => body,
+                    Err(e) => return self.send_server_error(ServerError::Serialization(e), None),
+                };
+                self.set_http_response_body(0, self.post_response_body_size, body.as_bytes());
+                body
+            }
+            None => {
+                let body = self
+                    .get_http_response_body(0, self.post_response_body_size)
+                    .unwrap_or_default();
+                String::from_utf8_lossy(&body).into_owned()
+            }
+        };
+        if !self.schedule_post_response_hook(&body, body.len()) {
+            self.resume_http_response();
+        }
+    }
+
     fn filter_out_curve _messages(&mut self, callout_context: &StreamCallContext) -> Vec<Message> {
         let mut messages: Vec<Message> = Vec::new();
         // add system prompt
@@ -449,31 +2479,42 @@ impl StreamContext {
         let system_prompt = match callout_context.prompt_target_name.as_ref() {
             None => self.system_prompt.as_ref().clone(),
             Some(prompt_target_name) => {
-                let prompt_system_prompt = self
-                    .prompt_targets
-                    .get(prompt_target_name)
-                    .unwrap()
-                    .clone()
-                    .system_prompt;
+                let prompt_system_prompt = self.prompt_target_system_prompt(
+                    self.prompt_targets.get(prompt_target_name).unwrap(),
+                );
                 match prompt_system_prompt {
                     None => self.system_prompt.as_ref().clone(),
                     Some(system_prompt) => Some(system_prompt),
                 }
             }
         };
-        if system_prompt.is_some() {
+        if let Some(system_prompt) = system_prompt {
             let system_prompt_message = Message {
                 role: SYSTEM_ROLE.to_string(),
-                content: system_prompt,
+                content: Some(
+                    self.render_system_prompt(
+                        &system_prompt,
+                        callout_context.prompt_target_name.as_deref(),
+                    )
+                    .into(),
+                ),
                 model: None,
                 tool_calls: None,
                 tool_call_id: None,
+                name: None,
             };
             messages.push(system_prompt_message);
         }
 
         // don't send tools message and api response to chat gpt
+        let client_tool_call_ids =
+            self.client_tool_call_ids(&callout_context.request_body.messages);
         for m in callout_context.request_body.messages.iter() {
+            // tool calls and responses of the client's own tools are kept as is
+            if is_client_tool_message(m, &client_tool_call_ids) {
+                messages.push(m.clone());
+                continue;
+            }
             // don't send api response and tool calls to upstream LLMs
             if m.role == TOOL_ROLE
                 || m.content.is_none()
@@ -487,6 +2528,40 @@ impl StreamContext {
         messages
     }
 
+    // system prompt of the prompt target, falling back to the one of its group
+    fn prompt_target_system_prompt(&self, prompt_target: &PromptTarget) -> Option<String> {
+        prompt_target.system_prompt.clone().or_else(|| {
+            prompt_target
+                .group
+                .as_ref()
+                .and_then(|group| self.prompt_target_groups.get(group))
+                .and_then(|group| group.system_prompt.clone())
+        })
+    }
+
+    // supported variables: {date}, {prompt_target_name}, {user_header:<header name>} and
+    // {api_response:<field.path>} which is looked up in the json response of the prompt target
+    fn render_system_prompt(
+        &self,
+        system_prompt: &str,
+        prompt_target_name: Option<&str>,
+    ) -> String {
+        render_template(system_prompt, |name, arg| match (name, arg) {
+            ("date", None) => Some(format_date(SystemTime::now())),
+            ("prompt_target_name", None) => prompt_target_name.map(|name| name.to_string()),
+            ("user_header", Some(header)) => self.get_http_request_header(header),
+            ("api_response", Some(field_path)) => {
+                let api_response: serde_json::Value =
+                    serde_json::from_str(self.tool_call_response.as_ref()?).ok()?;
+                match api_response.pointer(&format!("/{}", field_path.replace('.', "/")))? {
+                    serde_json::Value::String(value) => Some(value.clone()),
+                    value => Some(value.to_string()),
+                }
+            }
+            _ => None,
+        })
+    }
+
     pub fn generate_toll_call_message(&mut self) -> Message {
         Message {
             role: ASSISTANT_ROLE.to_string(),
@@ -494,20 +2569,263 @@ impl StreamContext {
             model: Some(CURVE_FC_MODEL_NAME.to_string()),
             tool_calls: self.tool_calls.clone(),
             tool_call_id: None,
+            name: None,
         }
     }
 
     pub fn generate_api_response_message(&mut self) -> Message {
         Message {
             role: TOOL_ROLE.to_string(),
-            content: self.tool_call_response.clone(),
+            content: self
+                .tool_call_response
+                .clone()
+                .map(|response| response.into()),
             model: None,
             tool_calls: None,
             tool_call_id: Some(self.tool_calls.as_ref().unwrap()[0].id.clone()),
+            name: None,
+        }
+    }
+
+    // the error target response, or its configured message, is returned to the client
+    pub fn error_target_handler(&self, body: Vec<u8>) {
+        self.send_target_response(self.error_target_body(body));
+    }
+
+    fn with_output_schema(
+        &mut self,
+        prompt_target: PromptTarget,
+        extra_fields: &mut HashMap<String, serde_json::Value>,
+    ) {
+        let Some(response_format) = prompt_target.response_format() else {
+            return;
+        };
+        extra_fields
+            .entry("response_format".to_string())
+            .or_insert(response_format);
+        self.output_schema_target = Some(prompt_target.name);
+    }
+
+    fn error_target_endpoint(&self) -> Option<EndpointDetails> {
+        self.error_target
+            .as_ref()
+            .as_ref()
+            .and_then(|error_target| error_target.endpoint.clone())
+    }
+
+    // the prompt target function calling picked
+    fn called_prompt_target(&self) -> Option<String> {
+        Some(self.tool_calls.as_ref()?.first()?.function.name.clone())
+    }
+
+    fn forwards_error(&self, event: ErrorEvent) -> bool {
+        self.error_target
+            .as_ref()
+            .as_ref()
+            .is_some_and(|error_target| error_target.forwards(event))
+    }
+
+    // sends the conversation with the error report to the error target, whose response is handled
+    // by the response handler. An error is reported once, errors of the error target itself are
+    // returned to the client. Returns whether the error target was called.
+    fn forward_to_error_target(
+        &self,
+        report: serde_json::Value,
+        prompt_target_name: Option<String>,
+        callout: CalloutBuilder,
+    ) -> bool {
+        let (Some(endpoint), Some(request_body)) = (
+            self.error_target_endpoint(),
+            self.chat_completions_request.clone(),
+        ) else {
+            return false;
+        };
+        if self.error_report.borrow().is_some() {
+            return false;
+        }
+        debug!(
+            "[R={}] curve => error target, error: {}",
+            self.request_id, report
+        );
+
+        let body = serde_json::json!({
+            MESSAGES_KEY: request_body.messages,
+            "error": report,
+        })
+        .to_string();
+        *self.error_report.borrow_mut() = Some(report);
+        self.metrics
+            .error_target_forwards
+            .with(&[(
+                Dimension::Target,
+                prompt_target_name.as_deref().unwrap_or_default(),
+            )])
+            .increment(1);
+
+        let callout_context = StreamCallContext {
+            user_message: None,
+            prompt_target_name,
+            request_body,
+            similarity_scores: None,
+            upstream_cluster: None,
+            upstream_cluster_path: None,
+        };
+        self.schedule_endpoint_request(endpoint, body, callout_context, callout);
+        true
+    }
+
+    // the configured message replaces the error target response
+    fn error_target_body(&self, body: Vec<u8>) -> Vec<u8> {
+        let Some(message) = self
+            .error_target
+            .as_ref()
+            .as_ref()
+            .and_then(|error_target| error_target.message.as_ref())
+        else {
+            return body;
+        };
+        let error_report = self.error_report.borrow();
+        let message = render_template(message, |name, _| match name {
+            "error_type" => Some(error_report.as_ref()?.get("type")?.as_str()?.to_string()),
+            "error_message" => Some(error_report.as_ref()?.get("message")?.to_string()),
+            "request_id" => Some(self.request_id.clone()),
+            _ => None,
+        });
+        serde_json::to_vec(&ChatCompletionsResponse::new(message)).unwrap_or(body)
+    }
+
+    // whether the llm response may be an error the error target answers instead: an answer that
+    // failed the output schema of the prompt target, a provider failure or a guard violation
+    pub fn may_forward_error_response(&self) -> bool {
+        if self.error_target_endpoint().is_none() {
+            return false;
+        }
+        let status = self
+            .get_http_response_header(":status")
+            .and_then(|status| status.parse::<u16>().ok())
+            .and_then(|status| StatusCode::from_u16(status).ok());
+        match status {
+            Some(StatusCode::BAD_GATEWAY) if self.output_schema_target.is_some() => true,
+            Some(StatusCode::BAD_REQUEST) => self.forwards_error(ErrorEvent::GuardViolation),
+            Some(status) if status.is_server_error() => {
+                self.forwards_error(ErrorEvent::ProviderFailure)
+            }
+            _ => false,
+        }
+    }
+
+    // the conversation is sent to the error target when the llm gateway answers
+    // invalid_json_response once the llm couldn't follow the output schema, or with a forwarded
+    // error. Returns whether the response waits for the error target.
+    pub fn forward_error_response(&mut self, body: &str, body_size: usize) -> bool {
+        if !self.may_forward_error_response() {
+            return false;
+        }
+        let status = self.get_http_response_header(":status").unwrap_or_default();
+        let error = serde_json::from_str::<serde_json::Value>(body)
+            .ok()
+            .and_then(|body| body.get("error").cloned());
+        let error_type = error
+            .as_ref()
+            .and_then(|error| error.get("type")?.as_str())
+            .unwrap_or_default();
+        let error_type = match error_type {
+            "invalid_json_response" if self.output_schema_target.is_some() => {
+                "invalid_json_response"
+            }
+            "guard_violation" if self.forwards_error(ErrorEvent::GuardViolation) => {
+                "guard_violation"
+            }
+            _ if status.starts_with('5') && self.forwards_error(ErrorEvent::ProviderFailure) => {
+                "provider_failure"
+            }
+            _ => return false,
+        };
+        let report = serde_json::json!({
+            "type": error_type,
+            "status": status,
+            "message": error.unwrap_or_else(|| serde_json::Value::from(body)),
+        });
+
+        let prompt_target_name = match error_type {
+            "invalid_json_response" => self.output_schema_target.clone(),
+            _ => self.called_prompt_target(),
+        };
+        if !self.forward_to_error_target(report, prompt_target_name, callouts::error_response) {
+            return false;
+        }
+        self.error_response_body_size = body_size;
+        true
+    }
+
+    // the error target response replaces the llm response
+    pub fn error_response_handler(&mut self, body: Vec<u8>) {
+        let body = match self.target_response_body(self.error_target_body(body)) {
+            Ok(body) => body,
+            Err(e) => return self.send_server_error(e, None),
+        };
+        self.set_http_response_header(":status", Some(StatusCode::OK.as_str()));
+        self.set_http_response_body(0, self.error_response_body_size, body.as_bytes());
+        self.resume_http_response();
+    }
+
+    // sends the chat completions response of a default or error target back to the client
+    fn send_target_response(&self, body: Vec<u8>) {
+        match self.target_response_body(body) {
+            Ok(body) => self.send_http_response(
+                StatusCode::OK.as_u16().into(),
+                vec![],
+                Some(body.as_bytes()),
+            ),
+            Err(e) => self.send_server_error(e, None),
         }
     }
 
-    pub fn default_target_handler(&self, body: Vec<u8>, mut callout_context: StreamCallContext) {
+    // streaming clients get the chat completions response as server events
+    fn target_response_body(&self, body: Vec<u8>) -> Result<String, ServerError> {
+        if !self.streaming_response {
+            return Ok(self.call_response_text(&body));
+        }
+        let chat_completion_response =
+            match serde_json::from_slice::<ChatCompletionsResponse>(&body) {
+                Ok(chat_completion_response) => chat_completion_response,
+                Err(e) => {
+                    warn!(
+                        "error deserializing target response: {}, body str: {}",
+                        e,
+                        self.call_response_text(&body)
+                    );
+                    return Err(ServerError::Deserialization(e));
+                }
+            };
+
+        let chunks = vec![
+            ChatCompletionStreamResponse::new(
+                None,
+                Some(ASSISTANT_ROLE.to_string()),
+                Some(chat_completion_response.model.clone()),
+                None,
+            ),
+            ChatCompletionStreamResponse::new(
+                chat_completion_response.choices[0]
+                    .message
+                    .content
+                    .as_ref()
+                    .map(|content| content.text()),
+                None,
+                Some(chat_completion_response.model.clone()),
+                None,
+            ),
+        ];
+
+        Ok(to_server_events(chunks))
+    }
+
+    pub fn default_target_handler(
+        &mut self,
+        body: Vec<u8>,
+        mut callout_context: StreamCallContext,
+    ) {
         let prompt_target = self
             .prompt_targets
             .get(callout_context.prompt_target_name.as_ref().unwrap())
@@ -519,46 +2837,7 @@ impl StreamContext {
             .auto_llm_dispatch_on_response
             .unwrap_or_default()
         {
-            let default_target_response_str = if self.streaming_response {
-                let chat_completion_response =
-                    match serde_json::from_slice::<ChatCompletionsResponse>(&body) {
-                        Ok(chat_completion_response) => chat_completion_response,
-                        Err(e) => {
-                            warn!(
-                                "error deserializing default target response: {}, body str: {}",
-                                e,
-                                String::from_utf8(body).unwrap()
-                            );
-                            return self.send_server_error(ServerError::Deserialization(e), None);
-                        }
-                    };
-
-                let chunks = vec![
-                    ChatCompletionStreamResponse::new(
-                        None,
-                        Some(ASSISTANT_ROLE.to_string()),
-                        Some(chat_completion_response.model.clone()),
-                        None,
-                    ),
-                    ChatCompletionStreamResponse::new(
-                        chat_completion_response.choices[0].message.content.clone(),
-                        None,
-                        Some(chat_completion_response.model.clone()),
-                        None,
-                    ),
-                ];
-
-                to_server_events(chunks)
-            } else {
-                String::from_utf8(body).unwrap()
-            };
-
-            self.send_http_response(
-                StatusCode::OK.as_u16().into(),
-                vec![],
-                Some(default_target_response_str.as_bytes()),
-            );
-            return;
+            return self.send_target_response(body);
         }
 
         let chat_completions_resp: ChatCompletionsResponse = match serde_json::from_slice(&body) {
@@ -567,7 +2846,7 @@ impl StreamContext {
                 warn!(
                     "error deserializing default target response: {}, body str: {}",
                     e,
-                    String::from_utf8(body).unwrap()
+                    self.call_response_text(&body)
                 );
                 return self.send_server_error(ServerError::Deserialization(e), None);
             }
@@ -575,15 +2854,19 @@ impl StreamContext {
 
         let mut messages = Vec::new();
         // add system prompt
-        match prompt_target.system_prompt.as_ref() {
+        match self.prompt_target_system_prompt(&prompt_target) {
             None => {}
             Some(system_prompt) => {
                 let system_prompt_message = Message {
                     role: SYSTEM_ROLE.to_string(),
-                    content: Some(system_prompt.clone()),
+                    content: Some(
+                        self.render_system_prompt(&system_prompt, Some(&prompt_target.name))
+                            .into(),
+                    ),
                     model: None,
                     tool_calls: None,
                     tool_call_id: None,
+                    name: None,
                 };
                 messages.push(system_prompt_message);
             }
@@ -595,18 +2878,23 @@ impl StreamContext {
             .message
             .content
             .as_ref()
-            .unwrap();
+            .unwrap()
+            .text();
 
         let user_message = messages.pop().unwrap();
-        let message = format!("{}\ncontext: {}", user_message.content.unwrap(), api_resp);
+        let message = user_message.content.unwrap().with_context(&api_resp);
         messages.push(Message {
             role: USER_ROLE.to_string(),
             content: Some(message),
             model: None,
             tool_calls: None,
             tool_call_id: None,
+            name: None,
         });
 
+        let mut extra_fields = callout_context.request_body.extra_fields;
+        self.with_output_schema(prompt_target.clone(), &mut extra_fields);
+
         let chat_completion_request = ChatCompletionsRequest {
             model: self
                 .chat_completions_request
@@ -619,23 +2907,102 @@ impl StreamContext {
             stream: callout_context.request_body.stream,
             stream_options: callout_context.request_body.stream_options,
             metadata: None,
+            extra_fields,
         };
 
         let json_resp = serde_json::to_string(&chat_completion_request).unwrap();
-        debug!("curve => (default target) llm request: {}", json_resp);
+        debug!(
+            "[R={}] curve => (default target) llm request: {}",
+            self.request_id, json_resp
+        );
+        self.set_http_request_header(CURVE_PROMPT_TARGET_HEADER, Some(&prompt_target.name));
         self.set_http_request_body(0, self.request_body_size, json_resp.as_bytes());
         self.resume_http_request();
     }
 }
 
+// the start of the dialog is handed to the client in the response metadata, like the curve state
+// it is expected back in the metadata of the next request
+fn add_parameter_collection_start(body_str: String, started_at: u64) -> String {
+    let mut response: serde_json::Value = match serde_json::from_str(&body_str) {
+        Ok(response) => response,
+        Err(_) => return body_str,
+    };
+    if let serde_json::Value::Object(ref mut map) = response {
+        let metadata = map
+            .entry("metadata")
+            .or_insert(serde_json::Value::Object(serde_json::Map::new()));
+        if !metadata.is_object() {
+            *metadata = serde_json::Value::Object(serde_json::Map::new());
+        }
+        metadata.as_object_mut().unwrap().insert(
+            CURVE_PARAMETER_COLLECTION_START_KEY.to_string(),
+            serde_json::Value::String(started_at.to_string()),
+        );
+    }
+    response.to_string()
+}
+
+// the conversation from the last `turns` user messages on, system messages are always kept
+fn history_window(messages: &[Message], turns: Option<usize>) -> Vec<Message> {
+    let start = turns
+        .and_then(|turns| {
+            messages
+                .iter()
+                .enumerate()
+                .rev()
+                .filter(|(_, message)| message.role == USER_ROLE)
+                .nth(turns.saturating_sub(1))
+        })
+        .map(|(index, _)| index)
+        .unwrap_or(0);
+
+    messages
+        .iter()
+        .enumerate()
+        .filter(|(index, message)| *index >= start || message.role == SYSTEM_ROLE)
+        .map(|(_, message)| message.clone())
+        .collect()
+}
+
+fn in_group(prompt_target: &PromptTarget, group: Option<&str>) -> bool {
+    match (group, prompt_target.group.as_deref()) {
+        (Some(group), Some(prompt_target_group)) => group == prompt_target_group,
+        _ => true,
+    }
+}
+
+pub fn is_client_tool_message(message: &Message, client_tool_call_ids: &HashSet<String>) -> bool {
+    match message.tool_call_id.as_ref() {
+        Some(tool_call_id) => client_tool_call_ids.contains(tool_call_id),
+        None => message.tool_calls.as_ref().is_some_and(|tool_calls| {
+            tool_calls
+                .iter()
+                .any(|tool_call| client_tool_call_ids.contains(&tool_call.id))
+        }),
+    }
+}
+
 impl Client for StreamContext {
-    type CallContext = StreamCallContext;
+    type CallContext = StreamCallout;
 
     fn callouts(&self) -> &RefCell<HashMap<u32, Self::CallContext>> {
         &self.callouts
     }
 
-    fn active_http_calls(&self) -> &Gauge {
-        &self.metrics.active_http_calls
+    fn waiting_calls(&self) -> Option<&RefCell<HashMap<u32, WaitingCall<Self::CallContext>>>> {
+        Some(&self.waiting_calls)
+    }
+
+    fn callout_metrics(&self) -> &CalloutMetrics {
+        &self.metrics.callouts
+    }
+
+    fn paused_stream_id(&self) -> Option<u32> {
+        Some(self.context_id)
+    }
+
+    fn capture(&self) -> Option<&RefCell<CaptureTrace>> {
+        self.capture.as_ref()
     }
 }
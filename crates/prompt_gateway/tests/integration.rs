@@ -27,13 +27,27 @@ fn request_headers_expectations(module: &mut Tester, http_context: i32) {
         .expect_remove_header_map_value(Some(MapType::HttpRequestHeaders), Some("content-length"))
         .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some(":path"))
         .returning(Some("/v1/chat/completions"))
-        .expect_get_header_map_pairs(Some(MapType::HttpRequestHeaders))
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("accept-encoding"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("content-encoding"))
         .returning(None)
-        .expect_log(Some(LogLevel::Trace), None)
         .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("x-request-id"))
         .returning(None)
+        .expect_replace_header_map_value(
+            Some(MapType::HttpRequestHeaders),
+            Some("x-request-id"),
+            None,
+        )
+        .expect_get_header_map_pairs(Some(MapType::HttpRequestHeaders))
+        .returning(None)
+        .expect_log(Some(LogLevel::Trace), None)
         .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("traceparent"))
         .returning(None)
+        .expect_get_header_map_value(
+            Some(MapType::HttpRequestHeaders),
+            Some("x-curve -client-tools"),
+        )
+        .returning(None)
         .execute_and_expect(ReturnType::Action(Action::Continue))
         .unwrap();
 }
@@ -79,14 +93,16 @@ fn normal_flow(module: &mut Tester, filter_context: i32, http_context: i32) {
                 ("x-curve -upstream", "server"),
                 (":method", "POST"),
                 (":path", "/function_calling"),
-                ("content-type", "application/json"),
                 (":authority", "server"),
+                ("content-type", "application/json"),
             ]),
             None,
             None,
             None,
         )
         .returning(Some(1))
+        .expect_metric_creation(MetricType::Counter, "callouts.cluster.server")
+        .expect_metric_increment("callouts.cluster.server", 1)
         .expect_log(Some(LogLevel::Debug), None)
         .expect_log(Some(LogLevel::Debug), None)
         .expect_log(Some(LogLevel::Trace), None)
@@ -101,6 +117,14 @@ fn setup_filter(module: &mut Tester, config: &str) -> i32 {
     module
         .call_proxy_on_context_create(filter_context, 0)
         .expect_metric_creation(MetricType::Gauge, "active_http_calls")
+        .expect_metric_creation(MetricType::Counter, "request_limit_rejections")
+        .expect_metric_creation(MetricType::Counter, "shed_callouts")
+        .expect_metric_creation(MetricType::Counter, "jwt_rejections")
+        .expect_metric_creation(MetricType::Counter, "fail_open_rq")
+        .expect_metric_creation(MetricType::Counter, "fallback_matches")
+        .expect_metric_creation(MetricType::Counter, "hook_responses")
+        .expect_metric_creation(MetricType::Counter, "clarification_questions")
+        .expect_metric_creation(MetricType::Counter, "aborted_rq")
         .execute_and_expect(ReturnType::None)
         .unwrap();
 
@@ -239,6 +263,8 @@ fn prompt_gateway_successful_request_to_open_ai_chat_completions() {
         .expect_log(Some(LogLevel::Trace), None)
         .expect_http_call(Some("curve _internal"), None, None, None, None)
         .returning(Some(4))
+        .expect_metric_creation(MetricType::Counter, "callouts.cluster.server")
+        .expect_metric_increment("callouts.cluster.server", 1)
         .expect_metric_increment("active_http_calls", 1)
         .execute_and_expect(ReturnType::Action(Action::Pause))
         .unwrap();
@@ -357,6 +383,7 @@ fn prompt_gateway_request_to_llm_gateway() {
                 }]),
                 model: None,
                 tool_call_id: None,
+                name: None,
             },
         }],
         model: String::from("test"),
@@ -369,7 +396,8 @@ fn prompt_gateway_request_to_llm_gateway() {
         .expect_metric_increment("active_http_calls", -1)
         .expect_get_buffer_bytes(Some(BufferType::HttpCallResponseBody))
         .returning(Some(&curve _fc_resp_str))
-        .expect_log(Some(LogLevel::Debug), None)
+        .expect_get_header_map_value(Some(MapType::HttpCallResponseHeaders), Some("content-type"))
+        .returning(Some("application/json"))
         .expect_log(Some(LogLevel::Debug), None)
         .expect_log(Some(LogLevel::Debug), None)
         .expect_log(Some(LogLevel::Debug), None)
@@ -390,6 +418,8 @@ fn prompt_gateway_request_to_llm_gateway() {
             None,
         )
         .returning(Some(2))
+        .expect_metric_creation(MetricType::Counter, "callouts.cluster.api_server")
+        .expect_metric_increment("callouts.cluster.api_server", 1)
         .expect_metric_increment("active_http_calls", 1)
         .execute_and_expect(ReturnType::None)
         .unwrap();
@@ -403,11 +433,17 @@ fn prompt_gateway_request_to_llm_gateway() {
         .expect_log(Some(LogLevel::Debug), None)
         .expect_get_header_map_value(Some(MapType::HttpCallResponseHeaders), Some(":status"))
         .returning(Some("200"))
+        .expect_get_header_map_value(Some(MapType::HttpCallResponseHeaders), Some("content-type"))
+        .returning(Some("text/plain"))
         .expect_log(Some(LogLevel::Debug), None)
         .expect_log(Some(LogLevel::Debug), None)
         .expect_log(Some(LogLevel::Debug), None)
         .expect_log(Some(LogLevel::Debug), None)
-        .expect_log(Some(LogLevel::Debug), None)
+        .expect_replace_header_map_value(
+            Some(MapType::HttpRequestHeaders),
+            Some("x-curve -prompt-target"),
+            Some("weather_forecast"),
+        )
         .expect_set_buffer_bytes(Some(BufferType::HttpRequestBody), None)
         .execute_and_expect(ReturnType::None)
         .unwrap();
@@ -421,10 +457,11 @@ fn prompt_gateway_request_to_llm_gateway() {
             index: Some(0),
             message: Message {
                 role: "assistant".to_string(),
-                content: Some("hello from fake llm gateway".to_string()),
+                content: Some("hello from fake llm gateway".to_string().into()),
                 model: None,
                 tool_calls: None,
                 tool_call_id: None,
+                name: None,
             },
         }],
         model: String::from("test"),
@@ -440,6 +477,8 @@ fn prompt_gateway_request_to_llm_gateway() {
         )
         .expect_get_buffer_bytes(Some(BufferType::HttpResponseBody))
         .returning(Some(chat_completion_response_str.as_str()))
+        .expect_get_header_map_value(Some(MapType::HttpResponseHeaders), Some("content-type"))
+        .returning(Some("application/json"))
         .expect_log(Some(LogLevel::Trace), None)
         .expect_log(Some(LogLevel::Debug), None)
         .expect_set_buffer_bytes(Some(BufferType::HttpResponseBody), None)
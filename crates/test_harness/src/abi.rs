@@ -0,0 +1,607 @@
+// The host calls the sdk imports, answered from the state of the harness. Buffers handed to the
+// sdk are allocated here and freed by the sdk.
+
+use crate::host::{HttpCall, LocalResponse};
+use crate::state::{deserialize_map, find_header, serialize_map, try_with_state, with_state};
+use proxy_wasm::types::{BufferType, LogLevel, MapType, MetricType, Status, StreamType};
+use std::collections::VecDeque;
+use std::time::{Duration, UNIX_EPOCH};
+
+unsafe fn slice<'a>(data: *const u8, size: usize) -> &'a [u8] {
+    if data.is_null() || size == 0 {
+        return &[];
+    }
+    std::slice::from_raw_parts(data, size)
+}
+
+unsafe fn string(data: *const u8, size: usize) -> String {
+    String::from_utf8_lossy(slice(data, size)).into_owned()
+}
+
+unsafe fn return_bytes(bytes: Vec<u8>, return_data: *mut *mut u8, return_size: *mut usize) {
+    *return_size = bytes.len();
+    *return_data = Box::into_raw(bytes.into_boxed_slice()) as *mut u8;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_log(
+    level: LogLevel,
+    message_data: *const u8,
+    message_size: usize,
+) -> Status {
+    let message = format!(
+        "[{}] {}",
+        format!("{:?}", level).to_lowercase(),
+        string(message_data, message_size)
+    );
+    eprintln!("{}", message);
+    try_with_state(|state| state.logs.push(message));
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_log_level(return_level: *mut LogLevel) -> Status {
+    *return_level = LogLevel::Trace;
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_current_time_nanoseconds(return_time: *mut u64) -> Status {
+    let now = with_state(|state| state.now);
+    *return_time = now.duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+    Status::Ok
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_set_tick_period_milliseconds(period: u32) -> Status {
+    with_state(|state| state.tick_period = Some(Duration::from_millis(period.into())));
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_buffer_bytes(
+    buffer_type: BufferType,
+    start: usize,
+    max_size: usize,
+    return_buffer_data: *mut *mut u8,
+    return_buffer_size: *mut usize,
+) -> Status {
+    let bytes = with_state(|state| {
+        let buffer = state.buffer(buffer_type)?;
+        let start = start.min(buffer.len());
+        let end = start.saturating_add(max_size).min(buffer.len());
+        Some(buffer[start..end].to_vec())
+    });
+    match bytes {
+        Some(bytes) => {
+            return_bytes(bytes, return_buffer_data, return_buffer_size);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_set_buffer_bytes(
+    buffer_type: BufferType,
+    start: usize,
+    size: usize,
+    buffer_data: *const u8,
+    buffer_size: usize,
+) -> Status {
+    let data = slice(buffer_data, buffer_size);
+    with_state(|state| match state.buffer(buffer_type) {
+        Some(buffer) => {
+            let start = start.min(buffer.len());
+            let end = start.saturating_add(size).min(buffer.len());
+            buffer.splice(start..end, data.iter().copied());
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_header_map_pairs(
+    map_type: MapType,
+    return_map_data: *mut *mut u8,
+    return_map_size: *mut usize,
+) -> Status {
+    match with_state(|state| state.map(map_type).map(|map| serialize_map(map))) {
+        Some(bytes) => {
+            return_bytes(bytes, return_map_data, return_map_size);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_set_header_map_pairs(
+    map_type: MapType,
+    map_data: *const u8,
+    map_size: usize,
+) -> Status {
+    let pairs = deserialize_map(slice(map_data, map_size));
+    with_state(|state| match state.map(map_type) {
+        Some(map) => {
+            *map = pairs;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    let key = string(key_data, key_size);
+    let value = with_state(|state| {
+        find_header(state.map(map_type)?, &key).map(|value| value.as_bytes().to_vec())
+    });
+    match value {
+        Some(value) => {
+            return_bytes(value, return_value_data, return_value_size);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_replace_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let key = string(key_data, key_size);
+    let value = string(value_data, value_size);
+    with_state(|state| match state.map(map_type) {
+        Some(map) => {
+            map.retain(|(name, _)| !name.eq_ignore_ascii_case(&key));
+            map.push((key, value));
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_remove_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+) -> Status {
+    let key = string(key_data, key_size);
+    with_state(|state| match state.map(map_type) {
+        Some(map) => {
+            map.retain(|(name, _)| !name.eq_ignore_ascii_case(&key));
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_add_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let key = string(key_data, key_size);
+    let value = string(value_data, value_size);
+    with_state(|state| match state.map(map_type) {
+        Some(map) => {
+            map.push((key, value));
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_property(
+    path_data: *const u8,
+    path_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    let path = slice(path_data, path_size);
+    match with_state(|state| state.properties.get(path).cloned()) {
+        Some(value) => {
+            return_bytes(value, return_value_data, return_value_size);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_set_property(
+    path_data: *const u8,
+    path_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let path = slice(path_data, path_size).to_vec();
+    let value = slice(value_data, value_size).to_vec();
+    with_state(|state| state.properties.insert(path, value));
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_shared_data(
+    key_data: *const u8,
+    key_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+    return_cas: *mut u32,
+) -> Status {
+    let key = string(key_data, key_size);
+    match with_state(|state| state.shared_data.get(&key).cloned()) {
+        Some((value, cas)) => {
+            return_bytes(value, return_value_data, return_value_size);
+            *return_cas = cas;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_set_shared_data(
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+    cas: u32,
+) -> Status {
+    let key = string(key_data, key_size);
+    let value = slice(value_data, value_size).to_vec();
+    with_state(|state| {
+        let current_cas = state.shared_data.get(&key).map_or(0, |(_, cas)| *cas);
+        if cas != 0 && cas != current_cas {
+            return Status::CasMismatch;
+        }
+        state.shared_data.insert(key, (value, current_cas + 1));
+        Status::Ok
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_register_shared_queue(
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = string(name_data, name_size);
+    *return_id =
+        with_state(
+            |state| match state.queues.iter().position(|(queue, _)| *queue == name) {
+                Some(index) => index as u32 + 1,
+                None => {
+                    state.queues.push((name, VecDeque::new()));
+                    state.queues.len() as u32
+                }
+            },
+        );
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_resolve_shared_queue(
+    _vm_id_data: *const u8,
+    _vm_id_size: usize,
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = string(name_data, name_size);
+    match with_state(|state| state.queues.iter().position(|(queue, _)| *queue == name)) {
+        Some(index) => {
+            *return_id = index as u32 + 1;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_dequeue_shared_queue(
+    queue_id: u32,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    let value = with_state(|state| {
+        let (_, queue) = state.queues.get_mut(queue_id.checked_sub(1)? as usize)?;
+        Some(queue.pop_front())
+    });
+    match value {
+        Some(Some(value)) => {
+            return_bytes(value, return_value_data, return_value_size);
+            Status::Ok
+        }
+        Some(None) => Status::Empty,
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_enqueue_shared_queue(
+    queue_id: u32,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let value = slice(value_data, value_size).to_vec();
+    with_state(|state| {
+        match queue_id
+            .checked_sub(1)
+            .and_then(|index| state.queues.get_mut(index as usize))
+        {
+            Some((_, queue)) => {
+                queue.push_back(value);
+                Status::Ok
+            }
+            None => Status::NotFound,
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_continue_stream(stream_type: StreamType) -> Status {
+    with_state(|state| match (state.stream(), stream_type) {
+        (Some(stream), StreamType::HttpRequest) => {
+            stream.request_resumed = true;
+            Status::Ok
+        }
+        (Some(stream), StreamType::HttpResponse) => {
+            stream.response_resumed = true;
+            Status::Ok
+        }
+        _ => Status::BadArgument,
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_close_stream(_stream_type: StreamType) -> Status {
+    with_state(|state| match state.stream() {
+        Some(stream) => {
+            stream.closed = true;
+            Status::Ok
+        }
+        None => Status::BadArgument,
+    })
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn proxy_send_local_response(
+    status_code: u32,
+    _status_code_details_data: *const u8,
+    _status_code_details_size: usize,
+    body_data: *const u8,
+    body_size: usize,
+    headers_data: *const u8,
+    headers_size: usize,
+    _grpc_status: i32,
+) -> Status {
+    let local_response = LocalResponse {
+        status: status_code,
+        headers: deserialize_map(slice(headers_data, headers_size)),
+        body: slice(body_data, body_size).to_vec(),
+    };
+    with_state(|state| match state.stream() {
+        Some(stream) => {
+            stream.local_response = Some(local_response);
+            Status::Ok
+        }
+        None => Status::BadArgument,
+    })
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn proxy_http_call(
+    upstream_data: *const u8,
+    upstream_size: usize,
+    headers_data: *const u8,
+    headers_size: usize,
+    body_data: *const u8,
+    body_size: usize,
+    _trailers_data: *const u8,
+    _trailers_size: usize,
+    timeout: u32,
+    return_token: *mut u32,
+) -> Status {
+    let upstream = string(upstream_data, upstream_size);
+    let headers = deserialize_map(slice(headers_data, headers_size));
+    // envoy refuses callouts without the pseudo headers it needs to route them
+    if upstream.is_empty()
+        || find_header(&headers, ":path").is_none()
+        || find_header(&headers, ":method").is_none()
+        || find_header(&headers, ":authority").is_none()
+    {
+        return Status::BadArgument;
+    }
+    let body = slice(body_data, body_size).to_vec();
+    *return_token = with_state(|state| {
+        let token = state.next_token;
+        state.next_token += 1;
+        state.http_calls.push(HttpCall {
+            token,
+            context_id: state.effective_context,
+            upstream,
+            headers,
+            body,
+            timeout: Duration::from_millis(timeout.into()),
+        });
+        token
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "C" fn proxy_grpc_call(
+    _upstream_data: *const u8,
+    _upstream_size: usize,
+    _service_name_data: *const u8,
+    _service_name_size: usize,
+    _method_name_data: *const u8,
+    _method_name_size: usize,
+    _initial_metadata_data: *const u8,
+    _initial_metadata_size: usize,
+    _message_data_data: *const u8,
+    _message_data_size: usize,
+    _timeout: u32,
+    _return_callout_id: *mut u32,
+) -> Status {
+    Status::InternalFailure
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "C" fn proxy_grpc_stream(
+    _upstream_data: *const u8,
+    _upstream_size: usize,
+    _service_name_data: *const u8,
+    _service_name_size: usize,
+    _method_name_data: *const u8,
+    _method_name_size: usize,
+    _initial_metadata_data: *const u8,
+    _initial_metadata_size: usize,
+    _return_stream_id: *mut u32,
+) -> Status {
+    Status::InternalFailure
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_grpc_send(
+    _token: u32,
+    _message_ptr: *const u8,
+    _message_len: usize,
+    _end_stream: bool,
+) -> Status {
+    Status::InternalFailure
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_grpc_cancel(_token_id: u32) -> Status {
+    Status::InternalFailure
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_grpc_close(_token_id: u32) -> Status {
+    Status::InternalFailure
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_status(
+    return_code: *mut u32,
+    return_message_data: *mut *mut u8,
+    return_message_size: *mut usize,
+) -> Status {
+    *return_code = 0;
+    return_bytes(Vec::new(), return_message_data, return_message_size);
+    Status::Ok
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_set_effective_context(context_id: u32) -> Status {
+    with_state(|state| state.effective_context = context_id);
+    Status::Ok
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_call_foreign_function(
+    _function_name_data: *const u8,
+    _function_name_size: usize,
+    _arguments_data: *const u8,
+    _arguments_size: usize,
+    _results_data: *mut *mut u8,
+    _results_size: *mut usize,
+) -> Status {
+    Status::NotFound
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_done() -> Status {
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_define_metric(
+    _metric_type: MetricType,
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = string(name_data, name_size);
+    *return_id =
+        with_state(
+            |state| match state.metrics.iter().position(|(metric, _)| *metric == name) {
+                Some(index) => index as u32 + 1,
+                None => {
+                    state.metrics.push((name, 0));
+                    state.metrics.len() as u32
+                }
+            },
+        );
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_metric(metric_id: u32, return_value: *mut u64) -> Status {
+    let value = with_state(|state| {
+        let index = metric_id.checked_sub(1)? as usize;
+        state.metrics.get(index).map(|(_, value)| *value)
+    });
+    match value {
+        Some(value) => {
+            *return_value = value;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_record_metric(metric_id: u32, value: u64) -> Status {
+    with_state(|state| {
+        match metric_id
+            .checked_sub(1)
+            .and_then(|index| state.metrics.get_mut(index as usize))
+        {
+            Some((_, metric)) => {
+                *metric = value;
+                Status::Ok
+            }
+            None => Status::NotFound,
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_increment_metric(metric_id: u32, offset: i64) -> Status {
+    with_state(|state| {
+        match metric_id
+            .checked_sub(1)
+            .and_then(|index| state.metrics.get_mut(index as usize))
+        {
+            Some((_, metric)) => {
+                *metric = metric.wrapping_add_signed(offset);
+                Status::Ok
+            }
+            None => Status::NotFound,
+        }
+    })
+}
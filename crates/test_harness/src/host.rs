@@ -0,0 +1,399 @@
+use crate::state::{find_header, with_state, State, StreamState};
+use proxy_wasm::types::Action;
+use serde::Serialize;
+use std::cell::Cell;
+use std::time::{Duration, SystemTime};
+
+// exports of the sdk and the start function of the filter linked into the test
+extern "C" {
+    fn _initialize();
+    fn proxy_on_context_create(context_id: u32, root_context_id: u32);
+    fn proxy_on_vm_start(context_id: u32, vm_configuration_size: usize) -> bool;
+    fn proxy_on_configure(context_id: u32, plugin_configuration_size: usize) -> bool;
+    fn proxy_on_tick(context_id: u32);
+    fn proxy_on_request_headers(context_id: u32, num_headers: usize, end_of_stream: bool)
+        -> Action;
+    fn proxy_on_request_body(context_id: u32, body_size: usize, end_of_stream: bool) -> Action;
+    fn proxy_on_response_headers(
+        context_id: u32,
+        num_headers: usize,
+        end_of_stream: bool,
+    ) -> Action;
+    fn proxy_on_response_body(context_id: u32, body_size: usize, end_of_stream: bool) -> Action;
+    fn proxy_on_http_call_response(
+        context_id: u32,
+        token_id: u32,
+        num_headers: usize,
+        body_size: usize,
+        num_trailers: usize,
+    );
+    fn proxy_on_done(context_id: u32) -> bool;
+    fn proxy_on_log(context_id: u32);
+    fn proxy_on_delete(context_id: u32);
+}
+
+thread_local! {
+    // the sdk keeps its contexts per thread for as long as the thread lives, the filter is started
+    // once per thread and context ids are never reused
+    static STARTED: Cell<bool> = const { Cell::new(false) };
+    static NEXT_CONTEXT_ID: Cell<u32> = const { Cell::new(1) };
+}
+
+fn next_context_id() -> u32 {
+    NEXT_CONTEXT_ID.with(|next| next.replace(next.get() + 1))
+}
+
+// calls to the same path are answered with the same response until the number of calls shows the
+// filter is looping
+const MAX_CANNED_CALLS: usize = 100;
+
+// a callout of the filter waiting for its response
+#[derive(Debug, Clone)]
+pub struct HttpCall {
+    pub token: u32,
+    // the stream that made the callout
+    pub context_id: u32,
+    pub upstream: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub timeout: Duration,
+}
+
+impl HttpCall {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn path(&self) -> &str {
+        self.header(":path").unwrap_or_default()
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).expect("callout body should be json")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CallResponse {
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl CallResponse {
+    pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        CallResponse {
+            headers: vec![(String::from(":status"), status.to_string())],
+            body: body.into(),
+        }
+    }
+
+    pub fn json<T: Serialize>(status: u16, body: &T) -> Self {
+        let body = serde_json::to_vec(body).expect("response body should serialize");
+        CallResponse::new(status, body).with_header("content-type", "application/json")
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+// a response the filter sent instead of passing the stream on
+#[derive(Debug, Clone)]
+pub struct LocalResponse {
+    pub status: u32,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl LocalResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).expect("local response body should be json")
+    }
+}
+
+// handle of an http stream, the id of its context
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stream(pub u32);
+
+pub struct Host {
+    root_context_id: u32,
+}
+
+impl Default for Host {
+    fn default() -> Self {
+        Host::new()
+    }
+}
+
+impl Host {
+    // resets the host of the thread and creates the root context of the filter
+    pub fn new() -> Self {
+        with_state(|state| *state = State::default());
+        if !STARTED.with(|started| started.replace(true)) {
+            unsafe { _initialize() };
+        }
+        let root_context_id = next_context_id();
+        enter(root_context_id);
+        unsafe { proxy_on_context_create(root_context_id, 0) };
+        Host { root_context_id }
+    }
+
+    // starts the vm and configures the filter, returns whether the filter accepted the config
+    pub fn configure(&mut self, config: &str) -> bool {
+        with_state(|state| state.plugin_configuration = config.as_bytes().to_vec());
+        enter(self.root_context_id);
+        unsafe {
+            proxy_on_vm_start(self.root_context_id, 0)
+                && proxy_on_configure(self.root_context_id, config.len())
+        }
+    }
+
+    pub fn create_stream(&mut self) -> Stream {
+        let context_id = next_context_id();
+        with_state(|state| state.streams.insert(context_id, StreamState::default()));
+        enter(context_id);
+        unsafe { proxy_on_context_create(context_id, self.root_context_id) };
+        Stream(context_id)
+    }
+
+    pub fn send_request_headers(
+        &mut self,
+        stream: Stream,
+        headers: &[(&str, &str)],
+        end_of_stream: bool,
+    ) -> Action {
+        with_stream(stream, |state| state.request_headers = to_pairs(headers));
+        enter(stream.0);
+        unsafe { proxy_on_request_headers(stream.0, headers.len(), end_of_stream) }
+    }
+
+    // the chunk is added to the buffered body, the filter is told the size of the whole buffer
+    pub fn send_request_body(
+        &mut self,
+        stream: Stream,
+        chunk: &[u8],
+        end_of_stream: bool,
+    ) -> Action {
+        let body_size = with_stream(stream, |state| {
+            state.request_body.extend_from_slice(chunk);
+            state.request_body.len()
+        });
+        enter(stream.0);
+        unsafe { proxy_on_request_body(stream.0, body_size, end_of_stream) }
+    }
+
+    pub fn send_response_headers(
+        &mut self,
+        stream: Stream,
+        headers: &[(&str, &str)],
+        end_of_stream: bool,
+    ) -> Action {
+        with_stream(stream, |state| state.response_headers = to_pairs(headers));
+        enter(stream.0);
+        unsafe { proxy_on_response_headers(stream.0, headers.len(), end_of_stream) }
+    }
+
+    // like the request body, except that envoy sends the buffer on when the filter continues, the
+    // chunks after that start a new buffer
+    pub fn send_response_body(
+        &mut self,
+        stream: Stream,
+        chunk: &[u8],
+        end_of_stream: bool,
+    ) -> Action {
+        let body_size = with_stream(stream, |state| {
+            if state.response_body_sent {
+                state.response_body.clear();
+            }
+            state.response_body.extend_from_slice(chunk);
+            state.response_body.len()
+        });
+        enter(stream.0);
+        let action = unsafe { proxy_on_response_body(stream.0, body_size, end_of_stream) };
+        with_stream(stream, |state| {
+            state.response_body_sent = action == Action::Continue
+        });
+        action
+    }
+
+    // ends the stream like envoy does once the response is sent
+    pub fn finish_stream(&mut self, stream: Stream) {
+        enter(stream.0);
+        unsafe {
+            proxy_on_done(stream.0);
+            proxy_on_log(stream.0);
+            proxy_on_delete(stream.0);
+        }
+    }
+
+    pub fn tick(&mut self) {
+        enter(self.root_context_id);
+        unsafe { proxy_on_tick(self.root_context_id) };
+    }
+
+    // the vm is torn down, returns whether the filter is done right away
+    pub fn shutdown(&mut self) -> bool {
+        enter(self.root_context_id);
+        unsafe { proxy_on_done(self.root_context_id) }
+    }
+
+    // callouts of the filter that have not been answered yet
+    pub fn http_calls(&self) -> Vec<HttpCall> {
+        with_state(|state| state.http_calls.clone())
+    }
+
+    pub fn respond(&mut self, token: u32, response: CallResponse) {
+        let (num_headers, body_size) = (response.headers.len(), response.body.len());
+        with_state(|state| {
+            let index = state
+                .http_calls
+                .iter()
+                .position(|call| call.token == token)
+                .unwrap_or_else(|| panic!("no callout waiting with token {}", token));
+            state.http_calls.remove(index);
+            state.call_response = Some(response);
+            state.effective_context = self.root_context_id;
+        });
+        unsafe {
+            proxy_on_http_call_response(self.root_context_id, token, num_headers, body_size, 0)
+        };
+        with_state(|state| state.call_response = None);
+    }
+
+    // callouts to the path are answered with the response by run_calls
+    pub fn mock_call(&mut self, path: &str, response: CallResponse) {
+        with_state(|state| state.canned_responses.insert(path.to_string(), response));
+    }
+
+    // answers the callouts with the canned responses of their paths, callouts made while handling
+    // a response included. Returns the callouts answered, the ones without a canned response are
+    // left waiting.
+    pub fn run_calls(&mut self) -> Vec<HttpCall> {
+        let mut answered = Vec::new();
+        loop {
+            let next = with_state(|state| {
+                state.http_calls.iter().find_map(|call| {
+                    let response = state.canned_responses.get(call.path())?;
+                    Some((call.clone(), response.clone()))
+                })
+            });
+            let Some((call, response)) = next else {
+                return answered;
+            };
+            assert!(
+                answered.len() < MAX_CANNED_CALLS,
+                "more than {} callouts answered, the filter keeps calling {}",
+                MAX_CANNED_CALLS,
+                call.path()
+            );
+            self.respond(call.token, response);
+            answered.push(call);
+        }
+    }
+
+    pub fn local_response(&self, stream: Stream) -> Option<LocalResponse> {
+        with_stream(stream, |state| state.local_response.clone())
+    }
+
+    pub fn request_resumed(&self, stream: Stream) -> bool {
+        with_stream(stream, |state| state.request_resumed)
+    }
+
+    pub fn response_resumed(&self, stream: Stream) -> bool {
+        with_stream(stream, |state| state.response_resumed)
+    }
+
+    pub fn request_headers(&self, stream: Stream) -> Vec<(String, String)> {
+        with_stream(stream, |state| state.request_headers.clone())
+    }
+
+    pub fn request_header(&self, stream: Stream, name: &str) -> Option<String> {
+        with_stream(stream, |state| {
+            find_header(&state.request_headers, name).map(String::from)
+        })
+    }
+
+    pub fn response_header(&self, stream: Stream, name: &str) -> Option<String> {
+        with_stream(stream, |state| {
+            find_header(&state.response_headers, name).map(String::from)
+        })
+    }
+
+    pub fn request_body(&self, stream: Stream) -> Vec<u8> {
+        with_stream(stream, |state| state.request_body.clone())
+    }
+
+    pub fn response_body(&self, stream: Stream) -> Vec<u8> {
+        with_stream(stream, |state| state.response_body.clone())
+    }
+
+    pub fn metric(&self, name: &str) -> Option<u64> {
+        with_state(|state| {
+            state
+                .metrics
+                .iter()
+                .find(|(metric, _)| metric == name)
+                .map(|(_, value)| *value)
+        })
+    }
+
+    pub fn logs(&self) -> Vec<String> {
+        with_state(|state| state.logs.clone())
+    }
+
+    pub fn tick_period(&self) -> Option<Duration> {
+        with_state(|state| state.tick_period)
+    }
+
+    pub fn set_time(&mut self, now: SystemTime) {
+        with_state(|state| state.now = now);
+    }
+
+    pub fn advance_time(&mut self, duration: Duration) {
+        with_state(|state| state.now += duration);
+    }
+
+    // the path is given in segments, e.g. ["request", "id"]
+    pub fn set_property(&mut self, path: &[&str], value: &[u8]) {
+        let mut key = Vec::new();
+        for segment in path {
+            key.extend_from_slice(segment.as_bytes());
+            key.push(0);
+        }
+        with_state(|state| state.properties.insert(key, value.to_vec()));
+    }
+
+    pub fn shared_data(&self, key: &str) -> Option<Vec<u8>> {
+        with_state(|state| state.shared_data.get(key).map(|(value, _)| value.clone()))
+    }
+}
+
+fn enter(context_id: u32) {
+    with_state(|state| state.effective_context = context_id);
+}
+
+fn with_stream<R>(stream: Stream, f: impl FnOnce(&mut StreamState) -> R) -> R {
+    with_state(|state| {
+        let state = state
+            .streams
+            .get_mut(&stream.0)
+            .unwrap_or_else(|| panic!("unknown stream {}", stream.0));
+        f(state)
+    })
+}
+
+fn to_pairs(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
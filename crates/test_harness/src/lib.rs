@@ -0,0 +1,16 @@
+// A mock of the proxy-wasm host for running the filters natively in `cargo test`. The host calls
+// of the sdk are answered from the state of the harness: header maps, bodies, callouts with their
+// canned responses, shared data, properties and metrics. Tests drive the filter through Host the
+// way envoy would and then look at what the filter did to the stream.
+//
+// The filter has to be linked into the test binary, e.g. with `extern crate prompt_gateway;`, its
+// proxy_wasm::main! block is run when the first host of a thread is created.
+
+mod abi;
+mod host;
+mod replay;
+mod state;
+
+pub use host::{CallResponse, Host, HttpCall, LocalResponse, Stream};
+pub use proxy_wasm::types::Action;
+pub use replay::{replay, Replay};
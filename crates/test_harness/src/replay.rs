@@ -0,0 +1,108 @@
+use crate::host::{CallResponse, Host, HttpCall, LocalResponse};
+use crate::state::find_header;
+use common::capture::{CaptureTrace, CapturedCallout};
+use common::consts::{CURVE_PROMPT_TARGET_HEADER, CURVE_UPSTREAM_HOST_HEADER};
+use serde_json::Value;
+
+// what the filter did with a replayed request
+#[derive(Debug)]
+pub struct Replay {
+    // callouts the filter made, in the order they were answered
+    pub calls: Vec<HttpCall>,
+    pub local_response: Option<LocalResponse>,
+    pub request_resumed: bool,
+    // the request as it was sent on to the llm
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: Vec<u8>,
+    // callouts of the replay that differ from the captured ones
+    pub divergences: Vec<String>,
+}
+
+impl Replay {
+    pub fn prompt_target(&self) -> Option<&str> {
+        find_header(&self.request_headers, CURVE_PROMPT_TARGET_HEADER)
+    }
+}
+
+// Runs a captured request through the filter again. The callouts are answered with the captured
+// responses in the order they arrived, at the time the request was captured, so the filter takes
+// the decisions it took in production as long as the config is the same. Fails when the filter
+// rejects the config.
+pub fn replay(config: &str, trace: &CaptureTrace) -> Result<Replay, String> {
+    let mut host = Host::new();
+    host.set_time(trace.started_at());
+    if !host.configure(config) {
+        return Err(String::from("the filter rejected the config"));
+    }
+
+    let stream = host.create_stream();
+    let headers: Vec<(&str, &str)> = trace
+        .request_headers
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    host.send_request_headers(stream, &headers, false);
+    host.send_request_body(stream, trace.request_body.as_bytes(), true);
+
+    let mut calls = Vec::new();
+    let mut divergences = Vec::new();
+    for callout in &trace.callouts {
+        let pending = host.http_calls();
+        let Some(call) = pending.iter().find(|call| same_callout(call, callout)) else {
+            divergences.push(format!(
+                "callout to {} {} was not made",
+                callout.upstream, callout.path
+            ));
+            continue;
+        };
+        if !same_body(&call.body, callout.request_body.as_deref()) {
+            divergences.push(format!(
+                "callout to {} {} was made with another body",
+                callout.upstream, callout.path
+            ));
+        }
+        let response = CallResponse {
+            headers: callout.response_headers.clone(),
+            body: callout.response_body.clone().into_bytes(),
+        };
+        host.respond(call.token, response);
+        calls.push(call.clone());
+    }
+    for call in host.http_calls() {
+        divergences.push(format!(
+            "callout to {} {} was not captured",
+            upstream(&call),
+            call.path()
+        ));
+    }
+
+    Ok(Replay {
+        calls,
+        local_response: host.local_response(stream),
+        request_resumed: host.request_resumed(stream),
+        request_headers: host.request_headers(stream),
+        request_body: host.request_body(stream),
+        divergences,
+    })
+}
+
+fn upstream(call: &HttpCall) -> &str {
+    call.header(CURVE_UPSTREAM_HOST_HEADER)
+        .unwrap_or(call.upstream.as_str())
+}
+
+fn same_callout(call: &HttpCall, callout: &CapturedCallout) -> bool {
+    upstream(call) == callout.upstream && call.path() == callout.path
+}
+
+// json bodies are compared as values, the order of their keys may differ
+fn same_body(body: &[u8], captured: Option<&str>) -> bool {
+    let captured = captured.unwrap_or_default();
+    match (
+        serde_json::from_slice::<Value>(body),
+        serde_json::from_str::<Value>(captured),
+    ) {
+        (Ok(body), Ok(captured)) => body == captured,
+        _ => body == captured.as_bytes(),
+    }
+}
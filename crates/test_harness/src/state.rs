@@ -0,0 +1,171 @@
+use crate::host::{CallResponse, HttpCall, LocalResponse};
+use proxy_wasm::types::{BufferType, MapType};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+// what the host knows about an http stream
+#[derive(Debug, Default)]
+pub struct StreamState {
+    pub request_headers: Vec<(String, String)>,
+    pub request_trailers: Vec<(String, String)>,
+    pub request_body: Vec<u8>,
+    pub response_headers: Vec<(String, String)>,
+    pub response_trailers: Vec<(String, String)>,
+    pub response_body: Vec<u8>,
+    // the buffered response body went on to the client, the next chunk starts a new buffer
+    pub response_body_sent: bool,
+    pub local_response: Option<LocalResponse>,
+    pub request_resumed: bool,
+    pub response_resumed: bool,
+    pub closed: bool,
+}
+
+#[derive(Debug)]
+pub struct State {
+    // the context the host calls of the filter apply to
+    pub effective_context: u32,
+    pub streams: HashMap<u32, StreamState>,
+    pub vm_configuration: Vec<u8>,
+    pub plugin_configuration: Vec<u8>,
+    // callouts waiting for their response
+    pub http_calls: Vec<HttpCall>,
+    pub next_token: u32,
+    // responses by :path of the callouts they answer
+    pub canned_responses: HashMap<String, CallResponse>,
+    // the callout response the filter is handling
+    pub call_response: Option<CallResponse>,
+    pub properties: HashMap<Vec<u8>, Vec<u8>>,
+    pub shared_data: HashMap<String, (Vec<u8>, u32)>,
+    pub queues: Vec<(String, VecDeque<Vec<u8>>)>,
+    pub metrics: Vec<(String, u64)>,
+    pub logs: Vec<String>,
+    pub tick_period: Option<Duration>,
+    pub now: SystemTime,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            effective_context: 0,
+            streams: HashMap::new(),
+            vm_configuration: Vec::new(),
+            plugin_configuration: Vec::new(),
+            http_calls: Vec::new(),
+            next_token: 1,
+            canned_responses: HashMap::new(),
+            call_response: None,
+            properties: HashMap::new(),
+            shared_data: HashMap::new(),
+            queues: Vec::new(),
+            metrics: Vec::new(),
+            logs: Vec::new(),
+            tick_period: None,
+            now: SystemTime::now(),
+        }
+    }
+}
+
+impl State {
+    pub fn stream(&mut self) -> Option<&mut StreamState> {
+        self.streams.get_mut(&self.effective_context)
+    }
+
+    pub fn map(&mut self, map_type: MapType) -> Option<&mut Vec<(String, String)>> {
+        if let MapType::HttpCallResponseHeaders = map_type {
+            return self
+                .call_response
+                .as_mut()
+                .map(|response| &mut response.headers);
+        }
+        let stream = self.stream()?;
+        match map_type {
+            MapType::HttpRequestHeaders => Some(&mut stream.request_headers),
+            MapType::HttpRequestTrailers => Some(&mut stream.request_trailers),
+            MapType::HttpResponseHeaders => Some(&mut stream.response_headers),
+            MapType::HttpResponseTrailers => Some(&mut stream.response_trailers),
+            _ => None,
+        }
+    }
+
+    pub fn buffer(&mut self, buffer_type: BufferType) -> Option<&mut Vec<u8>> {
+        match buffer_type {
+            BufferType::VmConfiguration => Some(&mut self.vm_configuration),
+            BufferType::PluginConfiguration => Some(&mut self.plugin_configuration),
+            BufferType::HttpCallResponseBody => self
+                .call_response
+                .as_mut()
+                .map(|response| &mut response.body),
+            BufferType::HttpRequestBody => self.stream().map(|stream| &mut stream.request_body),
+            BufferType::HttpResponseBody => self.stream().map(|stream| &mut stream.response_body),
+            _ => None,
+        }
+    }
+}
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::default());
+}
+
+// the state must not be borrowed across calls into the filter, they call back into the host
+pub fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    STATE.with(|state| f(&mut state.borrow_mut()))
+}
+
+// for logging from the panic hook, the state may be borrowed by the host call that panicked
+pub fn try_with_state(f: impl FnOnce(&mut State)) {
+    STATE.with(|state| {
+        if let Ok(mut state) = state.try_borrow_mut() {
+            f(&mut state)
+        }
+    })
+}
+
+pub fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+// header maps cross the abi as the number of pairs, the sizes of the keys and values and then the
+// keys and values with a nul after each
+pub fn serialize_map(map: &[(String, String)]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&(map.len() as u32).to_le_bytes());
+    for (key, value) in map {
+        bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    }
+    for (key, value) in map {
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(value.as_bytes());
+        bytes.push(0);
+    }
+    bytes
+}
+
+pub fn deserialize_map(bytes: &[u8]) -> Vec<(String, String)> {
+    let read_u32 = |offset: usize| -> usize {
+        let mut word = [0; 4];
+        word.copy_from_slice(&bytes[offset..offset + 4]);
+        u32::from_le_bytes(word) as usize
+    };
+    if bytes.len() < 4 {
+        return Vec::new();
+    }
+    let pairs = read_u32(0);
+    let mut data = 4 + pairs * 8;
+    let mut map = Vec::with_capacity(pairs);
+    for pair in 0..pairs {
+        let key_size = read_u32(4 + pair * 8);
+        let value_size = read_u32(8 + pair * 8);
+        let key = String::from_utf8_lossy(&bytes[data..data + key_size]).into_owned();
+        data += key_size + 1;
+        let value = String::from_utf8_lossy(&bytes[data..data + value_size]).into_owned();
+        data += value_size + 1;
+        map.push((key, value));
+    }
+    map
+}
@@ -15,6 +15,65 @@ CURVE_CONFIG_SCHEMA_FILE = os.getenv(
     "CURVE_CONFIG_SCHEMA_FILE", "curve_config_schema.yaml"
 )
 
+SECRET_ENV_PREFIX = "env:"
+SECRET_FILE_PREFIX = "file:"
+
+
+def resolve_secret(value, field):
+    """Resolve an env:NAME or file:/path secret, any other value is used as is."""
+    if not isinstance(value, str):
+        return value
+    if value.startswith(SECRET_ENV_PREFIX):
+        name = value[len(SECRET_ENV_PREFIX) :]
+        secret = os.getenv(name)
+        if secret is None:
+            raise Exception(f"{field}: environment variable {name} is not set")
+        return secret
+    if value.startswith(SECRET_FILE_PREFIX):
+        path = value[len(SECRET_FILE_PREFIX) :]
+        try:
+            with open(path, "r") as file:
+                return file.read().strip()
+        except OSError as e:
+            raise Exception(f"{field}: could not read secret file {path}: {e.strerror}")
+    return value
+
+
+def endpoint_auth_fields(endpoint, path):
+    auth = (endpoint or {}).get("auth") or {}
+    return [
+        (auth, key, f"{path}.auth.{key}") for key in ["token", "value"] if key in auth
+    ]
+
+
+def secret_fields(config_yaml):
+    """(parent, key, path) of every secret in the curve config."""
+    fields = []
+    for index, llm_provider in enumerate(config_yaml.get("llm_providers") or []):
+        if "access_key" in llm_provider:
+            fields.append(
+                (llm_provider, "access_key", f"llm_providers[{index}].access_key")
+            )
+    for index, prompt_target in enumerate(config_yaml.get("prompt_targets") or []):
+        fields.extend(
+            endpoint_auth_fields(
+                prompt_target.get("endpoint"), f"prompt_targets[{index}].endpoint"
+            )
+        )
+    access_log = config_yaml.get("access_log") or {}
+    fields.extend(endpoint_auth_fields(access_log.get("sink"), "access_log.sink"))
+    audit = config_yaml.get("audit") or {}
+    fields.extend(endpoint_auth_fields(audit.get("audit_sink"), "audit.audit_sink"))
+    virtual_keys = config_yaml.get("virtual_keys") or {}
+    for index, virtual_key in enumerate(virtual_keys.get("keys") or []):
+        fields.append((virtual_key, "key", f"virtual_keys.keys[{index}].key"))
+    return fields
+
+
+def resolve_secrets(config_yaml):
+    for parent, key, path in secret_fields(config_yaml):
+        parent[key] = resolve_secret(parent[key], path)
+
 
 def validate_and_render_schema():
     env = Environment(loader=FileSystemLoader("./"))
@@ -36,6 +95,12 @@ def validate_and_render_schema():
     config_schema_yaml = yaml.safe_load(curve_config_schema)
     inferred_clusters = {}
 
+    try:
+        resolve_secrets(config_yaml)
+    except Exception as e:
+        print(str(e))
+        exit(1)  # a secret could not be resolved. Exit
+
     endpoints = config_yaml.get("endpoints", {})
 
     # override the inferred clusters with the ones defined in the config
@@ -94,6 +159,12 @@ def validate_and_render_schema():
         "curve _llm_providers": config_yaml["llm_providers"],
         "curve _tracing": curve _tracing,
         "local_llms": llms_with_endpoint,
+        # callouts waiting for a free slot are woken up by the fault filter too
+        "fault_injection": "fault_injection" in config_yaml
+        or any(
+            limit.get("overflow") == "wait"
+            for limit in config_yaml.get("callout_limits", [])
+        ),
     }
 
     rendered = template.render(data)
@@ -5,7 +5,7 @@ import sys
 import glob
 import docker
 from docker.errors import DockerException
-from cli.utils import getLogger, update_docker_host_env
+from cli.utils import getLogger, secret_volumes, update_docker_host_env
 from cli.consts import (
     CURVEGW_DOCKER_IMAGE,
     CURVEGW_DOCKER_NAME,
@@ -20,7 +20,7 @@ from dotenv import dotenv_values
 log = getLogger(__name__)
 
 
-def start_curve_docker(client, curve_config_file, env):
+def start_curve_docker(client, curve_config_file, env, secret_files=()):
     logs_path = "~/curve_logs"
     logs_path_abs = os.path.expanduser(logs_path)
 
@@ -42,6 +42,7 @@ def start_curve_docker(client, curve_config_file, env):
             },
             "/etc/ssl/cert.pem": {"bind": "/etc/ssl/cert.pem", "mode": "ro"},
             logs_path_abs: {"bind": "/var/log"},
+            **secret_volumes(secret_files),
         },
         environment={
             "OTEL_TRACING_HTTP_ENDPOINT": "http://host.docker.internal:4318/v1/traces",
@@ -100,7 +101,9 @@ def stream_access_logs(follow):
     )
 
 
-def start_curve (curve_config_file, env, log_timeout=120, foreground=False):
+def start_curve (
+    curve_config_file, env, secret_files=(), log_timeout=120, foreground=False
+):
     """
     Start Docker Compose in detached mode and stream logs until services are healthy.
 
@@ -128,7 +131,7 @@ def start_curve (curve_config_file, env, log_timeout=120, foreground=False):
         except docker.errors.NotFound as e:
             pass
 
-        container = start_curve_docker(client, curve_config_file, env)
+        container = start_curve_docker(client, curve_config_file, env, secret_files)
 
         start_time = time.time()
 
@@ -7,7 +7,7 @@ import importlib.metadata
 from cli import targets
 from cli.utils import (
     getLogger,
-    get_llm_provider_access_keys,
+    get_secret_sources,
     load_env_file_to_dict,
     validate_schema,
 )
@@ -172,29 +172,20 @@ def up(file, path, service, foreground):
         log.info(f"Error: {curve_config_file} does not exist.")
         return
 
-    log.info(f"Validating {curve_config_file}")
-
-    try:
-        validate_schema(curve_config_file)
-    except Exception as e:
-        log.info(f"Exiting curve up: validation failed")
-        log.info(f"Error: {str(e)}")
-        sys.exit(1)
-
-    log.info("Starting curve  model server and curve  gateway")
-
-    # Set the CURVE_CONFIG_FILE environment variable
+    # secrets are resolved inside the container: $NAME and env:NAME secrets are staged
+    # from the environment, file: secrets are mounted at the same path
     env_stage = {}
     env = os.environ.copy()
-    # check if access_keys are preesnt in the config file
-    access_keys = get_llm_provider_access_keys(curve_config_file=curve_config_file)
+    secret_names, secret_files = get_secret_sources(curve_config_file=curve_config_file)
 
-    # remove duplicates
-    access_keys = set(access_keys)
-    # remove the $ from the access_keys
-    access_keys = [item[1:] if item.startswith("$") else item for item in access_keys]
+    for secret_file in secret_files:
+        if not os.path.isabs(secret_file) or not os.path.isfile(secret_file):
+            log.info(
+                f"Secret file: {secret_file} not found or not absolute. Exiting Start"
+            )
+            sys.exit(1)
 
-    if access_keys:
+    if secret_names:
         if file:
             app_env_file = os.path.join(
                 os.path.dirname(os.path.abspath(file)), ".env"
@@ -206,29 +197,40 @@ def up(file, path, service, foreground):
         if not os.path.exists(
             app_env_file
         ):  # check to see if the environment variables in the current environment or not
-            for access_key in access_keys:
-                if env.get(access_key) is None:
-                    log.info(f"Access Key: {access_key} not found. Exiting Start")
+            for secret_name in secret_names:
+                if env.get(secret_name) is None:
+                    log.info(f"Secret: {secret_name} not found. Exiting Start")
                     sys.exit(1)
                 else:
-                    env_stage[access_key] = env.get(access_key)
+                    env_stage[secret_name] = env.get(secret_name)
         else:  # .env file exists, use that to send parameters to Curve
             env_file_dict = load_env_file_to_dict(app_env_file)
-            for access_key in access_keys:
-                if env_file_dict.get(access_key) is None:
-                    log.info(f"Access Key: {access_key} not found. Exiting Start")
+            for secret_name in secret_names:
+                if env_file_dict.get(secret_name) is None:
+                    log.info(f"Secret: {secret_name} not found. Exiting Start")
                     sys.exit(1)
                 else:
-                    env_stage[access_key] = env_file_dict[access_key]
+                    env_stage[secret_name] = env_file_dict[secret_name]
 
     env.update(env_stage)
 
+    log.info(f"Validating {curve_config_file}")
+
+    try:
+        validate_schema(curve_config_file, env_stage, secret_files)
+    except Exception as e:
+        log.info(f"Exiting curve up: validation failed")
+        log.info(f"Error: {str(e)}")
+        sys.exit(1)
+
+    log.info("Starting curve  model server and curve  gateway")
+
     if service == SERVICE_NAME_CURVEGW:
-        start_curve (curve_config_file, env, foreground=foreground)
+        start_curve (curve_config_file, env, secret_files, foreground=foreground)
     else:
         download_models_from_hf()
         start_curve _modelserver(foreground)
-        start_curve (curve_config_file, env, foreground=foreground)
+        start_curve (curve_config_file, env, secret_files, foreground=foreground)
 
 
 @click.command()
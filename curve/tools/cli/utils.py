@@ -5,6 +5,7 @@ import docker
 from docker.errors import DockerException
 
 from cli.consts import CURVEGW_DOCKER_IMAGE, CURVEGW_DOCKER_NAME
+from cli.config_generator import SECRET_ENV_PREFIX, SECRET_FILE_PREFIX, secret_fields
 
 logging.basicConfig(
     level=logging.INFO,
@@ -38,7 +39,12 @@ def update_docker_host_env():
         os.environ["DOCKER_HOST"] = docker_host
 
 
-def validate_schema(curve_config_file: str) -> None:
+def secret_volumes(secret_files):
+    """Mount the file: secrets at the same path in the container."""
+    return {path: {"bind": path, "mode": "ro"} for path in secret_files}
+
+
+def validate_schema(curve_config_file: str, secret_env=None, secret_files=()) -> None:
     try:
         try:
             client = docker.from_env()
@@ -54,7 +60,10 @@ def validate_schema(curve_config_file: str) -> None:
                     "bind": "/app/curve_config.yaml",
                     "mode": "ro",
                 },
+                **secret_volumes(secret_files),
             },
+            # the secrets are resolved while the config is rendered
+            environment=secret_env or {},
             entrypoint=["python", "config_generator.py"],
             detach=True,
         )
@@ -78,18 +87,26 @@ def validate_schema(curve_config_file: str) -> None:
         raise ValueError(f"Failed to create container: {e}")
 
 
-def get_llm_provider_access_keys(curve_config_file):
+def get_secret_sources(curve_config_file):
+    """Environment variables and files the secrets of the curve config are read from."""
     with open(curve_config_file, "r") as file:
         curve_config = file.read()
         curve_config_yaml = yaml.safe_load(curve_config)
 
-    access_key_list = []
-    for llm_provider in curve_config_yaml.get("llm_providers", []):
-        acess_key = llm_provider.get("access_key")
-        if acess_key is not None:
-            access_key_list.append(acess_key)
-
-    return access_key_list
+    env_names = set()
+    file_paths = set()
+    for parent, key, _ in secret_fields(curve_config_yaml):
+        value = parent[key]
+        if not isinstance(value, str):
+            continue
+        if value.startswith("$"):
+            env_names.add(value[1:])
+        elif value.startswith(SECRET_ENV_PREFIX):
+            env_names.add(value[len(SECRET_ENV_PREFIX) :])
+        elif value.startswith(SECRET_FILE_PREFIX):
+            file_paths.add(value[len(SECRET_FILE_PREFIX) :])
+
+    return sorted(env_names), sorted(file_paths)
 
 
 def load_env_file_to_dict(file_path):
@@ -34,3 +34,16 @@ handler_map = {
     ),
     "Curve-Guard": get_guardrail_handler(CURVE_GUARD_MODEL_ALIAS),
 }
+
+
+def get_guard_handler(model_name: str = None):
+    """
+    Returns the handler of a guard model, guard models other than Curve-Guard are loaded the first
+    time they are asked for and kept in the handler map.
+    """
+
+    model_name = model_name or "Curve-Guard"
+    if model_name not in handler_map:
+        handler_map[model_name] = get_guardrail_handler(model_name)
+
+    return handler_map[model_name]
@@ -125,6 +125,8 @@ class CurveIntentHandler(CurveBaseHandler):
         """
         logger.info("[Curve-Intent] - ChatCompletion")
 
+        model_name = req.model or self.model_name
+
         # In the case that no tools are available, simply return `No` to avoid making a call
         if len(req.tools) == 0:
             model_response = Message(content="No", tool_calls=[])
@@ -138,7 +140,7 @@ class CurveIntentHandler(CurveBaseHandler):
 
             model_response = self.client.chat.completions.create(
                 messages=messages,
-                model=self.model_name,
+                model=model_name,
                 stream=False,
                 extra_body=self.generation_params,
             )
@@ -150,7 +152,7 @@ class CurveIntentHandler(CurveBaseHandler):
             )
 
         chat_completion_response = ChatCompletionResponse(
-            choices=[Choice(message=model_response)], model=self.model_name
+            choices=[Choice(message=model_response)], model=model_name
         )
 
         return chat_completion_response
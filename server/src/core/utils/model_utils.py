@@ -16,6 +16,8 @@ class Message(BaseModel):
 class ChatMessage(BaseModel):
     messages: List[Message] = []
     tools: List[Dict[str, Any]] = []
+    # intent model set in the gateway config, the default intent model when not set
+    model: Optional[str] = None
 
 
 class Choice(BaseModel):
@@ -36,6 +38,8 @@ class ChatCompletionResponse(BaseModel):
 class GuardRequest(BaseModel):
     input: str
     task: str
+    # guard model set in the gateway config, the default guard model when not set
+    model: Optional[str] = None
 
 
 class GuardResponse(BaseModel):
@@ -4,7 +4,7 @@ import time
 import logging
 import src.commons.utils as utils
 
-from src.commons.globals import handler_map
+from src.commons.globals import get_guard_handler, handler_map
 from src.core.utils.model_utils import (
     ChatMessage,
     ChatCompletionResponse,
@@ -128,7 +128,7 @@ async def guardrails(req: GuardRequest, res: Response, max_num_words=300):
 
     try:
         guard_start_time = time.perf_counter()
-        final_response = handler_map["Curve-Guard"].predict(req)
+        final_response = get_guard_handler(req.model).predict(req)
         guard_latency = time.perf_counter() - guard_start_time
         final_response.metadata = {
             "guard_latency": round(guard_latency * 1000, 3),